@@ -0,0 +1,21 @@
+// validate-config checks a plugin config file without starting a validator:
+// it prints the problems found by Config::validate and exits with 1 if there are any.
+use firehose_geyser_plugin::config::Config;
+use std::process::exit;
+
+fn main() {
+    let Some(path) = std::env::args().nth(1) else {
+        eprintln!("usage: validate-config <config.json>");
+        exit(2);
+    };
+
+    let problems = Config::check_file(&path);
+    if problems.is_empty() {
+        println!("{}: ok", path);
+        return;
+    }
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    exit(1);
+}
@@ -1,174 +1,2845 @@
-use crate::pb::sf::solana::r#type::v1::{AccountBlock, Block};
-use crate::state::{BlockInfo, ACC_MUTEX, BLOCK_MUTEX, CURSOR_MUTEX};
-use log::{debug, info};
+use crate::config::{
+    Compression, FireBlockTemplate, OutputFormat, TemplateField, TemplatePart, WriterStallAction,
+};
+use crate::cursor::Cursor;
+use crate::diff::{drop_account_diffs, restore_diffed_data, take_diffed_data};
+use crate::fifo::try_open_fifo;
+use crate::filters::AccountFilter;
+use crate::pb::account_plugins::TransactionBlock;
+use crate::pb::sf::solana::r#type::v1::{Account, AccountBlock, Block};
+use crate::sink::BlockSink;
+use crate::state::BlockInfo;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use flate2::write::GzEncoder;
+use log::{debug, error, info, warn};
 use prost::Message;
-use rbase64;
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::File;
-use std::io::Write;
+use std::io::{ErrorKind, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Mutex, TryLockError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-pub struct BlockPrinter {
-    noop: bool,
-    out_block: Option<File>,
-    out_account: Option<File>,
+// PayloadBuffer holds the protobuf and base64 buffers of one output stream.
+// They are cleared and reused for every block so that, once grown to the size of a big block,
+// printing does not allocate anymore. Each destination keeps its own buffer behind its lock.
+#[derive(Default)]
+pub struct PayloadBuffer {
+    encoded: Vec<u8>,
+    compressed: Vec<u8>,
+    payload: String,
 }
 
-impl BlockPrinter {
-    pub fn new(out_block: Option<File>, out_account: Option<File>, noop: bool) -> Self {
-        BlockPrinter {
-            noop,
-            out_block,
-            out_account,
+impl PayloadBuffer {
+    pub fn encode_proto<M: Message>(&mut self, message: &M, compression: Compression) -> &[u8] {
+        self.encoded.clear();
+        message
+            .encode(&mut self.encoded)
+            .expect("encoding into a vec cannot run out of capacity");
+        match compression {
+            Compression::None => &self.encoded,
+            Compression::Gzip => {
+                self.compressed.clear();
+                let mut encoder =
+                    GzEncoder::new(&mut self.compressed, flate2::Compression::default());
+                encoder
+                    .write_all(&self.encoded)
+                    .and_then(|()| encoder.try_finish())
+                    .expect("compressing into a vec cannot fail");
+                drop(encoder);
+                &self.compressed
+            }
         }
     }
 
-    pub fn print_init(
-        &mut self,
-        block_type: &str,
-        account_block_type: &str,
-    ) -> std::io::Result<()> {
-        if self.noop {
-            debug!(
-                "printing init for type {} and {} (noop mode)",
-                block_type, account_block_type
-            );
-            Ok(())
-        } else {
-            if let Some(ref mut out_block) = self.out_block {
-                if let Err(e) = writeln!(out_block, "FIRE INIT 3.0 {block_type}") {
-                    return Err(e);
+    pub fn encode<M: Message>(&mut self, message: &M, compression: Compression) -> &str {
+        self.encode_proto(message, compression);
+        let encoded = match compression {
+            Compression::None => &self.encoded,
+            Compression::Gzip => &self.compressed,
+        };
+        self.payload.clear();
+        BASE64.encode_string(encoded, &mut self.payload);
+        &self.payload
+    }
+}
+
+// OutputMessage is what can be written by write_block. With the json-lines feature,
+// the messages must also be serializable to JSON.
+#[cfg(not(feature = "json-lines"))]
+pub trait OutputMessage: Message {}
+#[cfg(not(feature = "json-lines"))]
+impl<M: Message> OutputMessage for M {}
+
+#[cfg(feature = "json-lines")]
+pub trait OutputMessage: Message + serde::Serialize {}
+#[cfg(feature = "json-lines")]
+impl<M: Message + serde::Serialize> OutputMessage for M {}
+
+// prost_types::Timestamp does not implement Serialize, it is written as `{"seconds": .., "nanos": ..}`
+#[cfg(feature = "json-lines")]
+pub fn serialize_timestamp<S: serde::Serializer>(
+    timestamp: &Option<prost_types::Timestamp>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    use serde::ser::SerializeStruct;
+    match timestamp {
+        None => serializer.serialize_none(),
+        Some(timestamp) => {
+            let mut state = serializer.serialize_struct("Timestamp", 2)?;
+            state.serialize_field("seconds", &timestamp.seconds)?;
+            state.serialize_field("nanos", &timestamp.nanos)?;
+            state.end()
+        }
+    }
+}
+
+#[cfg(feature = "json-lines")]
+#[derive(serde::Serialize)]
+struct JsonLine<'a, M> {
+    slot: u64,
+    block_hash: &'a str,
+    parent_slot: u64,
+    parent_hash: &'a str,
+    lib: u64,
+    timestamp_nano: i64,
+    payload: &'a M,
+}
+
+// BlockHeader holds the fields written before the payload on a FIRE BLOCK line
+#[derive(Clone)]
+pub struct BlockHeader {
+    pub slot: u64,
+    pub block_hash: String,
+    pub parent_slot: u64,
+    pub parent_hash: String,
+    pub lib: u64,
+    pub timestamp_nano: i64,
+    pub block_height: Option<u64>,
+}
+
+impl BlockHeader {
+    pub fn new(block_info: &BlockInfo, lib: u64) -> Self {
+        BlockHeader {
+            slot: block_info.slot,
+            block_hash: block_info.block_hash.clone(),
+            parent_slot: block_info.parent_slot,
+            parent_hash: block_info.parent_hash.clone(),
+            lib,
+            timestamp_nano: block_info.timestamp.seconds * 1_000_000_000,
+            block_height: block_info.height,
+        }
+    }
+}
+
+// payload_checksum is the xxh3 hash of the base64 payload as written on the line, in 16 hex digits:
+// a reader can tell a line that was cut while being written.
+pub fn payload_checksum(payload: &str) -> String {
+    format!("{:016x}", xxhash_rust::xxh3::xxh3_64(payload.as_bytes()))
+}
+
+// write_block writes one message in the given output format:
+// * FireText: the header fields laid out by the template, by default
+//   `FIRE BLOCK {slot} {block_hash} {parent_slot} {parent_hash} {lib} {timestamp_nano} {base64 payload}`,
+//   followed by ` {checksum}` with payload_checksum (see payload_checksum()).
+// * LengthPrefixedProto: the length of the protobuf payload as a 4-byte big-endian integer, followed by the payload.
+// * JsonLines: the header fields and the payload as one JSON object on a single line.
+// With compression, the protobuf payload is compressed before being encoded, the JSON lines are never compressed.
+#[allow(clippy::too_many_arguments)]
+pub fn write_block<W: Write, M: OutputMessage>(
+    out: &mut W,
+    buffer: &mut PayloadBuffer,
+    output_format: OutputFormat,
+    with_checksum: bool,
+    compression: Compression,
+    template: &FireBlockTemplate,
+    header: &BlockHeader,
+    message: &M,
+) -> std::io::Result<()> {
+    match output_format {
+        OutputFormat::FireText => {
+            let payload = buffer.encode(message, compression);
+            for part in template.parts() {
+                match part {
+                    TemplatePart::Text(text) => out.write_all(text.as_bytes())?,
+                    TemplatePart::Field(TemplateField::Slot) => write!(out, "{}", header.slot)?,
+                    TemplatePart::Field(TemplateField::BlockHash) => {
+                        out.write_all(header.block_hash.as_bytes())?
+                    }
+                    TemplatePart::Field(TemplateField::ParentSlot) => {
+                        write!(out, "{}", header.parent_slot)?
+                    }
+                    TemplatePart::Field(TemplateField::ParentHash) => {
+                        out.write_all(header.parent_hash.as_bytes())?
+                    }
+                    TemplatePart::Field(TemplateField::Lib) => write!(out, "{}", header.lib)?,
+                    TemplatePart::Field(TemplateField::TimestampNano) => {
+                        write!(out, "{}", header.timestamp_nano)?
+                    }
+                    TemplatePart::Field(TemplateField::BlockHeight) => {
+                        write!(out, "{}", header.block_height.unwrap_or(0))?
+                    }
+                    TemplatePart::Field(TemplateField::Payload) => {
+                        out.write_all(payload.as_bytes())?
+                    }
                 }
             }
-            if let Some(ref mut out_account) = self.out_account {
-                if let Err(e) = writeln!(out_account, "FIRE INIT 3.0 {account_block_type}") {
-                    return Err(e);
+            if with_checksum {
+                write!(out, " {}", payload_checksum(payload))?;
+            }
+            out.write_all(b"\n")
+        }
+        OutputFormat::LengthPrefixedProto => {
+            let encoded = buffer.encode_proto(message, compression);
+            let length = u32::try_from(encoded.len()).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("block {} is too big to be framed", header.slot),
+                )
+            })?;
+            out.write_all(&length.to_be_bytes())?;
+            out.write_all(encoded)
+        }
+        #[cfg(feature = "json-lines")]
+        OutputFormat::JsonLines => {
+            let line = JsonLine {
+                slot: header.slot,
+                block_hash: &header.block_hash,
+                parent_slot: header.parent_slot,
+                parent_hash: &header.parent_hash,
+                lib: header.lib,
+                timestamp_nano: header.timestamp_nano,
+                payload: message,
+            };
+            serde_json::to_writer(&mut *out, &line)?;
+            out.write_all(b"\n")
+        }
+    }
+}
+
+// block hash written in the headers of the startup snapshot chunks, there is no block behind them
+pub const SNAPSHOT_BLOCK_HASH: &str = "snapshot";
+
+// prefix of the block hash written in the headers of the placeholders of the skipped slots
+pub const SKIPPED_BLOCK_HASH: &str = "skipped";
+
+// skipped_block_hash is the block id of the placeholder of a skipped slot, the slot makes it unique
+pub fn skipped_block_hash(slot: u64) -> String {
+    format!("{}-{}", SKIPPED_BLOCK_HASH, slot)
+}
+
+// version of the FIRE protocol written in the init lines
+pub const FIRE_PROTOCOL_VERSION: &str = "3.0";
+
+// FireProtocol is the layout of the lines of a FIRE protocol version. The minor versions of a major version
+// write the same lines, a new major version gets its own variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FireProtocol {
+    V3,
+}
+
+impl FireProtocol {
+    // parse accepts a `MAJOR.MINOR` version of a supported major version
+    pub fn parse(version: &str) -> Result<Self, String> {
+        let major = version
+            .split_once('.')
+            .filter(|(_, minor)| !minor.is_empty() && minor.bytes().all(|b| b.is_ascii_digit()))
+            .map(|(major, _)| major);
+        match major {
+            Some("3") => Ok(FireProtocol::V3),
+            _ => Err(format!(
+                "unsupported FIRE protocol version {:?}, expecting 3.x",
+                version
+            )),
+        }
+    }
+
+    // init_line is the first line of a stream of `message_type` messages.
+    // The FIRE BLOCK lines follow the fire_block_template, its default is the 3.x layout.
+    fn init_line(self, version: &str, message_type: &str, capabilities: &[String]) -> String {
+        match self {
+            // `FIRE INIT {version} {type}`, followed by the capabilities if any
+            FireProtocol::V3 => {
+                let mut line = format!("FIRE INIT {} {}", version, message_type);
+                for capability in capabilities {
+                    line.push(' ');
+                    line.push_str(capability);
                 }
+                line
+            }
+        }
+    }
+}
+
+pub struct PrinterOptions {
+    pub noop: bool,
+    pub output_format: OutputFormat,
+    pub fire_block_template: FireBlockTemplate,
+    pub cursor_write_interval_slots: u64,
+    // the cursor only moves over slots confirmed through confirm_slot
+    pub cursor_requires_confirmation: bool,
+    pub protocol_version: String,
+    // `key=value` entries appended to the init lines, so readers know how the stream differs from the default
+    pub init_capabilities: Vec<String>,
+    pub payload_checksum: bool,
+    // account blocks without any account are not written, the cursor does not wait for them
+    pub skip_empty_account_blocks: bool,
+    // write a FIRE IDLE line when no slot was written for this long, see IdleMarker
+    pub idle_marker_interval: Option<Duration>,
+    pub block_compression: Compression,
+    // of the account block outputs, the account outputs of their own included
+    pub account_compression: Compression,
+    // report the writers when none of them finished a write for this long while some are queued, see Watchdog
+    pub writer_stall_timeout: Option<Duration>,
+    pub writer_stall_action: WriterStallAction,
+}
+
+impl Default for PrinterOptions {
+    fn default() -> Self {
+        PrinterOptions {
+            noop: false,
+            output_format: OutputFormat::default(),
+            fire_block_template: FireBlockTemplate::default(),
+            cursor_write_interval_slots: 1,
+            cursor_requires_confirmation: false,
+            protocol_version: FIRE_PROTOCOL_VERSION.to_string(),
+            init_capabilities: vec![],
+            payload_checksum: false,
+            skip_empty_account_blocks: false,
+            idle_marker_interval: None,
+            block_compression: Compression::None,
+            account_compression: Compression::None,
+            writer_stall_timeout: None,
+            writer_stall_action: WriterStallAction::default(),
+        }
+    }
+}
+
+// AccountOutput is an account output of its own, it gets the account blocks with only the accounts matching its filter
+pub struct AccountOutput {
+    pub name: String,
+    pub filter: AccountFilter,
+    pub out: File,
+    // the destination file, the output is reopened from it when the printer was built with_reopen
+    pub path: String,
+}
+
+// AccountOutputDestination is an account output once handed to the printer
+struct AccountOutputDestination {
+    filter: AccountFilter,
+    destination: Arc<Destination>,
+    path: String,
+}
+
+impl AccountOutputDestination {
+    fn account_block(&self, account_block: &AccountBlock) -> AccountBlock {
+        AccountBlock {
+            slot: account_block.slot,
+            hash: account_block.hash.clone(),
+            parent_slot: account_block.parent_slot,
+            parent_hash: account_block.parent_hash.clone(),
+            timestamp: account_block.timestamp.clone(),
+            accounts: account_block
+                .accounts
+                .iter()
+                .filter(|account| self.filter.matches(&account.address, &account.owner))
+                .cloned()
+                .collect(),
+            snapshot: account_block.snapshot,
+        }
+    }
+}
+
+// the first reopen of a disconnected destination is tried after REOPEN_MIN_BACKOFF, the delay doubles up to REOPEN_MAX_BACKOFF
+const REOPEN_MIN_BACKOFF: Duration = Duration::from_millis(100);
+const REOPEN_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+// number of slot writes a destination holds while its writer thread is busy, the next slots are dropped
+const WRITER_QUEUE_SLOTS: usize = 64;
+
+type WriteJob = Box<dyn FnOnce() + Send>;
+
+// Destination is an output file, written by its own writer thread which takes the slots from a bounded queue,
+// in order (see queue_write). The init lines and the snapshot are written from the caller, under the same lock.
+// When the reader of a FIFO closes its end, the write fails with a broken pipe and the destination is disconnected.
+// Without a path, the slots written to it are then dropped: the cursor stays before the first dropped slot until
+// a restart sends them again. With a path, the FIFO is reopened once a new reader is attached, retried with a
+// backoff, the init lines are written again and then the slot whose write failed. The next slots wait in the
+// queue meanwhile, once it is full they are dropped as well.
+struct Destination {
+    name: String,
+    state: Mutex<DestinationState>,
+    queue: SyncSender<WriteJob>,
+}
+
+struct DestinationState {
+    // None while disconnected
+    out: Option<File>,
+    path: Option<String>,
+    init_lines: Vec<String>,
+    backoff: Duration,
+    retry_at: Instant,
+    // reopens of all the destinations of the printer, and their count when this one was last reopened: the
+    // account diffs made before it are against data its new reader does not have, see BlockSink::diff_base_lost
+    reopens: Arc<AtomicU64>,
+    reopened_at: u64,
+    buffer: PayloadBuffer,
+}
+
+impl Destination {
+    fn new(name: &str, out: File) -> Arc<Self> {
+        // the thread stops once the destination is dropped, with the sender of the queue
+        let (queue, jobs) = sync_channel::<WriteJob>(WRITER_QUEUE_SLOTS);
+        std::thread::spawn(move || {
+            for job in jobs {
+                job();
+            }
+        });
+        Arc::new(Destination {
+            name: name.to_string(),
+            state: Mutex::new(DestinationState {
+                out: Some(out),
+                path: None,
+                init_lines: vec![],
+                backoff: REOPEN_MIN_BACKOFF,
+                retry_at: Instant::now(),
+                reopens: Arc::new(AtomicU64::new(0)),
+                reopened_at: 0,
+                buffer: PayloadBuffer::default(),
+            }),
+            queue,
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, DestinationState> {
+        self.state.lock().expect("destination lock poisoned")
+    }
+
+    fn set_path(&self, path: &str, reopens: &Arc<AtomicU64>) {
+        if !path.is_empty() {
+            let mut state = self.lock();
+            state.path = Some(path.to_string());
+            state.reopens = reopens.clone();
+        }
+    }
+
+    // write returns false when the destination is disconnected and nothing was written.
+    // Only a broken pipe disconnects it, any other error is returned.
+    fn write(
+        &self,
+        mut write: impl FnMut(&mut File, &mut PayloadBuffer) -> std::io::Result<()>,
+    ) -> std::io::Result<bool> {
+        self.write_locked(&mut self.lock(), &mut |out, buffer, _| write(out, buffer))
+    }
+
+    // try_write is write for the lines that can be left out: nothing is written while a slot write waits for a reader
+    fn try_write(
+        &self,
+        mut write: impl FnMut(&mut File) -> std::io::Result<()>,
+    ) -> std::io::Result<bool> {
+        match self.state.try_lock() {
+            Ok(mut state) => self.write_locked(&mut state, &mut |out, _, _| write(out)),
+            Err(TryLockError::WouldBlock) => Ok(false),
+            Err(TryLockError::Poisoned(_)) => panic!("destination lock poisoned"),
+        }
+    }
+
+    // write_slot is write for a slot: a destination with a path does not drop it, the write waits for a new reader
+    // and is done again once the FIFO is reopened. The next slots wait in the queue behind it.
+    // `diff_epoch` is the count of reopens the account diffs of the slot were made after, None for a replay: the
+    // write is told to send the full data of the accounts when the reader may not have the data they are made against.
+    fn write_slot(
+        &self,
+        diff_epoch: Option<u64>,
+        mut write: impl FnMut(&mut File, &mut PayloadBuffer, bool) -> std::io::Result<()>,
+    ) -> std::io::Result<bool> {
+        let mut state = self.lock();
+        loop {
+            let written = self.write_locked(&mut state, &mut |out, buffer, reopened_at| {
+                write(
+                    out,
+                    buffer,
+                    diff_epoch.is_none_or(|epoch| epoch < reopened_at),
+                )
+            })?;
+            if written {
+                return Ok(true);
+            }
+            if state.path.is_none() {
+                return Ok(false);
+            }
+            std::thread::sleep(state.retry_at.saturating_duration_since(Instant::now()));
+        }
+    }
+
+    // write_locked gives `write` the buffer of the destination and the count of reopens when it was last reopened
+    fn write_locked(
+        &self,
+        state: &mut DestinationState,
+        write: &mut impl FnMut(&mut File, &mut PayloadBuffer, u64) -> std::io::Result<()>,
+    ) -> std::io::Result<bool> {
+        if state.out.is_none() && !self.reopen(state) {
+            return Ok(false);
+        }
+        let reopened_at = state.reopened_at;
+        let out = state.out.as_mut().expect("connected above");
+        match write(out, &mut state.buffer, reopened_at) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == ErrorKind::BrokenPipe => {
+                error!("the reader of {} went away: {}", self.name, err);
+                state.out = None;
+                state.backoff = REOPEN_MIN_BACKOFF;
+                state.retry_at = Instant::now() + state.backoff;
+                Ok(false)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // write_init writes the init lines, they are kept to be written again when the destination is reopened
+    fn write_init(&self, lines: Vec<String>) -> std::io::Result<()> {
+        self.lock().init_lines = lines.clone();
+        self.write(|out, _| {
+            for line in &lines {
+                writeln!(out, "{line}")?;
             }
             Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn reopen(&self, state: &mut DestinationState) -> bool {
+        let Some(path) = state.path.clone() else {
+            return false;
+        };
+        if Instant::now() < state.retry_at {
+            return false;
+        }
+        let reopened = try_open_fifo(&path).and_then(|out| match out {
+            Some(mut out) => {
+                for line in &state.init_lines {
+                    writeln!(out, "{line}")?;
+                }
+                Ok(Some(out))
+            }
+            None => Ok(None),
+        });
+        match reopened {
+            Ok(Some(out)) => {
+                info!("a reader is back on {}, reopened {}", self.name, path);
+                state.out = Some(out);
+                state.reopened_at = state.reopens.fetch_add(1, Ordering::SeqCst) + 1;
+                return true;
+            }
+            Ok(None) => debug!("no reader on {} yet", path),
+            Err(err) => warn!("cannot reopen {} at {}: {}", self.name, path, err),
+        }
+        state.backoff = (state.backoff * 2).min(REOPEN_MAX_BACKOFF);
+        state.retry_at = Instant::now() + state.backoff;
+        false
+    }
+}
+
+// CombinedOutput gets both the blocks and the account blocks, the type of each line is written before its payload
+struct CombinedOutput {
+    destination: Arc<Destination>,
+    block_template: Arc<FireBlockTemplate>,
+    account_template: Arc<FireBlockTemplate>,
+}
+
+// Activity is the last slot handed to the outputs and when, 0 and the start of the printer before the first one
+struct Activity {
+    last_slot: u64,
+    at: Instant,
+}
+
+// IdleMarker writes a `FIRE IDLE {last_slot} {timestamp_nano}` line to every destination when no slot was written
+// for `interval`, and again every `interval` while it stays idle, so that a reader can tell an idle plugin from a
+// wedged one. The line carries no block, the cursor does not move. The thread stops when the marker is dropped.
+struct IdleMarker {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl IdleMarker {
+    fn spawn(
+        interval: Duration,
+        activity: Arc<Mutex<Activity>>,
+        destinations: Vec<Arc<Destination>>,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let mut last_marker: Option<Instant> = None;
+                while !stop.load(Ordering::Relaxed) {
+                    let (last_slot, idle_since) = {
+                        let activity = activity.lock().expect("activity lock poisoned");
+                        (activity.last_slot, activity.at)
+                    };
+                    let due = idle_since.max(last_marker.unwrap_or(idle_since)) + interval;
+                    if Instant::now() >= due {
+                        let timestamp_nano = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap_or_default()
+                            .as_nanos();
+                        for destination in &destinations {
+                            if let Err(err) = destination.try_write(|out| {
+                                writeln!(out, "FIRE IDLE {} {}", last_slot, timestamp_nano)
+                            }) {
+                                warn!("cannot write idle marker to {}: {}", destination.name, err);
+                            }
+                        }
+                        last_marker = Some(Instant::now());
+                    }
+                    // short sleeps so that dropping the marker does not wait for a whole interval
+                    std::thread::sleep(interval.min(Duration::from_millis(100)));
+                }
+            })
+        };
+        IdleMarker {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for IdleMarker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// WriterProgress counts the queued writes that did not finish yet, and when the last one finished
+// (or when the first of them was queued, after a time without any)
+struct WriterProgress {
+    queued: usize,
+    since: Instant,
+}
+
+impl WriterProgress {
+    fn queue(&mut self) {
+        if self.queued == 0 {
+            self.since = Instant::now();
         }
+        self.queued += 1;
     }
 
-    pub fn print(
+    fn done(&mut self) {
+        self.queued = self.queued.saturating_sub(1);
+        self.since = Instant::now();
+    }
+
+    // cancel forgets a write that could not be queued, it did not finish anything
+    fn cancel(&mut self) {
+        self.queued = self.queued.saturating_sub(1);
+    }
+}
+
+// queue_write hands the write of a slot to the writer thread of the destination, tracked by the progress the
+// Watchdog looks at. The writes of a destination are done in the order they were queued. When its queue is full,
+// the slot is dropped rather than waited for, as by a disconnected destination: `cursor` stops before it.
+fn queue_write(
+    progress: &Arc<Mutex<WriterProgress>>,
+    destination: &Arc<Destination>,
+    slot: u64,
+    cursor: Option<&Mutex<CursorTracker>>,
+    write: impl FnOnce(&Destination) + Send + 'static,
+) {
+    progress.lock().expect("progress lock poisoned").queue();
+    let job: WriteJob = {
+        let progress = progress.clone();
+        let destination = destination.clone();
+        Box::new(move || {
+            write(&destination);
+            progress.lock().expect("progress lock poisoned").done();
+        })
+    };
+    if let Err(err) = destination.queue.try_send(job) {
+        progress.lock().expect("progress lock poisoned").cancel();
+        error!(
+            "cannot queue slot {} to {}: {}",
+            slot, destination.name, err
+        );
+        if let Some(cursor) = cursor {
+            drop_cursor(cursor, slot, &destination.name);
+        }
+    }
+}
+
+// Watchdog reports the writers when none of them finished a write for `timeout` while some are queued, and again
+// every `timeout` while it lasts: a destination whose reader is stuck blocks its write, and the later slots
+// wait behind it until its queue is full. A blocked write cannot be interrupted, WriterStallAction::Exit stops the process so that it
+// is restarted from the cursor. A writer that panicked never finishes either, it is reported the same way.
+// The thread stops when the watchdog is dropped.
+struct Watchdog {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    fn spawn(
+        timeout: Duration,
+        action: WriterStallAction,
+        progress: Arc<Mutex<WriterProgress>>,
+        stalls: Arc<AtomicU64>,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let mut last_report: Option<Instant> = None;
+                while !stop.load(Ordering::Relaxed) {
+                    let (queued, since) = {
+                        let progress = progress.lock().expect("progress lock poisoned");
+                        (progress.queued, progress.since)
+                    };
+                    let due = since.max(last_report.unwrap_or(since)) + timeout;
+                    if queued > 0 && Instant::now() >= due {
+                        stalls.fetch_add(1, Ordering::Relaxed);
+                        error!(
+                            "no write finished for {:?} while {} writes are queued: a destination reader is stuck or a writer failed",
+                            since.elapsed(),
+                            queued
+                        );
+                        if action == WriterStallAction::Exit {
+                            error!("exiting, writer_stall_action is exit");
+                            std::process::exit(1);
+                        }
+                        last_report = Some(Instant::now());
+                    }
+                    std::thread::sleep(timeout.min(Duration::from_millis(100)));
+                }
+            })
+        };
+        Watchdog {
+            stop,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+pub struct BlockPrinter {
+    noop: bool,
+    output_format: OutputFormat,
+    fire_block_template: Arc<FireBlockTemplate>,
+    out_block: Option<Arc<Destination>>,
+    out_account: Option<Arc<Destination>>,
+    out_transaction: Option<Arc<Destination>>,
+    account_outputs: Vec<AccountOutputDestination>,
+    out_combined: Option<CombinedOutput>,
+    cursor: Arc<Mutex<CursorTracker>>,
+    protocol_version: String,
+    init_capabilities: Vec<String>,
+    payload_checksum: bool,
+    skip_empty_account_blocks: bool,
+    activity: Arc<Mutex<Activity>>,
+    idle_marker_interval: Option<Duration>,
+    idle_marker: Option<IdleMarker>,
+    block_compression: Compression,
+    account_compression: Compression,
+    progress: Arc<Mutex<WriterProgress>>,
+    writer_stalls: Arc<AtomicU64>,
+    _watchdog: Option<Watchdog>,
+    // reopens of the destinations, and their count when State was last told about them by diff_base_lost
+    reopens: Arc<AtomicU64>,
+    diff_epoch: u64,
+}
+
+impl BlockPrinter {
+    pub fn new(
+        out_block: Option<File>,
+        out_account: Option<File>,
+        out_transaction: Option<File>,
+        options: PrinterOptions,
+    ) -> Self {
+        let progress = Arc::new(Mutex::new(WriterProgress {
+            queued: 0,
+            since: Instant::now(),
+        }));
+        let writer_stalls = Arc::new(AtomicU64::new(0));
+        let watchdog = options
+            .writer_stall_timeout
+            .filter(|_| !options.noop)
+            .map(|timeout| {
+                Watchdog::spawn(
+                    timeout,
+                    options.writer_stall_action,
+                    progress.clone(),
+                    writer_stalls.clone(),
+                )
+            });
+        BlockPrinter {
+            noop: options.noop,
+            output_format: options.output_format,
+            fire_block_template: Arc::new(options.fire_block_template),
+            out_block: out_block.map(|out| Destination::new("out_block", out)),
+            out_account: out_account.map(|out| Destination::new("out_account", out)),
+            out_transaction: out_transaction.map(|out| Destination::new("out_transaction", out)),
+            account_outputs: vec![],
+            out_combined: None,
+            cursor: Arc::new(Mutex::new(CursorTracker::new(
+                options.cursor_write_interval_slots,
+                options.cursor_requires_confirmation,
+            ))),
+            protocol_version: options.protocol_version,
+            init_capabilities: options.init_capabilities,
+            payload_checksum: options.payload_checksum,
+            skip_empty_account_blocks: options.skip_empty_account_blocks,
+            activity: Arc::new(Mutex::new(Activity {
+                last_slot: 0,
+                at: Instant::now(),
+            })),
+            idle_marker_interval: options.idle_marker_interval,
+            idle_marker: None,
+            block_compression: options.block_compression,
+            account_compression: options.account_compression,
+            progress,
+            writer_stalls,
+            _watchdog: watchdog,
+            reopens: Arc::new(AtomicU64::new(0)),
+            diff_epoch: 0,
+        }
+    }
+
+    // writer_stalls is the number of times the Watchdog reported the writers
+    pub fn writer_stalls(&self) -> u64 {
+        self.writer_stalls.load(Ordering::Relaxed)
+    }
+
+    // destinations lists every output, each once
+    fn destinations(&self) -> Vec<Arc<Destination>> {
+        [&self.out_block, &self.out_account, &self.out_transaction]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .chain(
+                self.out_combined
+                    .iter()
+                    .map(|combined| combined.destination.clone()),
+            )
+            .chain(
+                self.account_outputs
+                    .iter()
+                    .map(|output| output.destination.clone()),
+            )
+            .collect()
+    }
+
+    pub fn with_account_outputs(mut self, account_outputs: Vec<AccountOutput>) -> Self {
+        self.account_outputs = account_outputs
+            .into_iter()
+            .map(|output| AccountOutputDestination {
+                filter: output.filter,
+                destination: Destination::new(&output.name, output.out),
+                path: output.path,
+            })
+            .collect();
+        self
+    }
+
+    // with_combined_output writes the blocks and the account blocks to the same output, as a single sink of the cursor.
+    // The message types of the lines are the ones given to print_init.
+    pub fn with_combined_output(mut self, out: File) -> Self {
+        self.out_combined = Some(CombinedOutput {
+            destination: Destination::new("out_combined", out),
+            block_template: self.fire_block_template.clone(),
+            account_template: self.fire_block_template.clone(),
+        });
+        self
+    }
+
+    // with_reopen lets the destinations be reopened from their path once their reader went away and a new one is attached,
+    // see Destination. It must be called after the outputs are set, the account outputs are reopened from their own path.
+    pub fn with_reopen(
+        self,
+        block_path: &str,
+        account_path: &str,
+        transaction_path: &str,
+        combined_path: &str,
+    ) -> Self {
+        let destinations = [
+            (&self.out_block, block_path),
+            (&self.out_account, account_path),
+            (&self.out_transaction, transaction_path),
+        ];
+        for (destination, path) in destinations {
+            if let Some(destination) = destination {
+                destination.set_path(path, &self.reopens);
+            }
+        }
+        if let Some(combined) = &self.out_combined {
+            combined.destination.set_path(combined_path, &self.reopens);
+        }
+        for output in &self.account_outputs {
+            output.destination.set_path(&output.path, &self.reopens);
+        }
+        self
+    }
+
+    // active_sinks counts the outputs the slot is written to, besides the account outputs of their own.
+    // The account output is left out when its block is skipped.
+    fn active_sinks(&self, skip_account_block: bool) -> usize {
+        self.out_block.is_some() as usize
+            + (self.out_account.is_some() && !skip_account_block) as usize
+            + self.out_transaction.is_some() as usize
+            + self.out_combined.is_some() as usize
+    }
+
+    // init_line is written in the layout of the configured protocol version, see FireProtocol.
+    // The compression of the stream of the message type is announced as `compression=gzip`.
+    fn init_line(&self, message_type: &str, compression: Compression) -> std::io::Result<String> {
+        let protocol = FireProtocol::parse(&self.protocol_version)
+            .map_err(|msg| std::io::Error::new(ErrorKind::InvalidInput, msg))?;
+        let mut capabilities = self.init_capabilities.clone();
+        if compression != Compression::None {
+            capabilities.push(format!("compression={}", compression.as_str()));
+        }
+        Ok(protocol.init_line(&self.protocol_version, message_type, &capabilities))
+    }
+
+    fn write_slot(
         &mut self,
         block_info: &BlockInfo,
         lib: u64,
-        block: Block,
-        account_block: AccountBlock,
-        cursor_path: &str,
+        mut block: Block,
+        mut account_block: AccountBlock,
+        cursor_path: Option<&str>,
     ) -> std::io::Result<()> {
         let slot = block_info.slot;
-        let parent_slot = block_info.parent_slot;
-        let timestamp_nano = block_info.timestamp.seconds * 1_000_000_000;
+        *self.activity.lock().expect("activity lock poisoned") = Activity {
+            last_slot: slot,
+            at: Instant::now(),
+        };
+        let header = BlockHeader::new(block_info, lib);
         let noop = self.noop;
-        if let Some(out_block) = &self.out_block {
-            let mut out_block = out_block.try_clone().expect("cannot clone out_block");
-            let block_hash = block_info.block_hash.clone();
-            let parent_hash = block_info.parent_hash.clone();
-            let cursor_path = cursor_path.to_string();
+        let output_format = self.output_format;
+        let with_checksum = self.payload_checksum;
+        let block_compression = self.block_compression;
+        let account_compression = self.account_compression;
+        let template = self.fire_block_template.clone();
+        // a replay may go to readers that did not get the slots the diffs are made against
+        let diff_epoch = cursor_path.is_some().then_some(self.diff_epoch);
+        let skip_account_block =
+            self.skip_empty_account_blocks && account_block.accounts.is_empty();
+        let output_blocks: Vec<(Arc<Destination>, AccountBlock)> = self
+            .account_outputs
+            .iter()
+            .map(|output| {
+                (
+                    output.destination.clone(),
+                    output.account_block(&account_block),
+                )
+            })
+            .filter(|(_, block)| !(self.skip_empty_account_blocks && block.accounts.is_empty()))
+            .collect();
 
-            std::thread::spawn(move || {
-                let encoded_block = block.encode_to_vec();
-                let base64_encoded_block = rbase64::encode(&encoded_block);
-                let payload = base64_encoded_block;
+        // in noop mode nothing is written, so the cursor must not move either
+        if let Some(cursor_path) = cursor_path.filter(|_| !noop) {
+            register_cursor(
+                &self.cursor,
+                cursor_path,
+                slot,
+                &header.block_hash,
+                self.active_sinks(skip_account_block) + output_blocks.len(),
+            );
+        }
+        // the cursor a slot dropped by a full queue stops
+        let tracker = (cursor_path.is_some() && !noop).then_some(&*self.cursor);
 
-                info!("printing block {} {} with transaction count of {}", block.slot, block_hash, block.transactions.len());
+        // built before the block is moved to its writer thread
+        let transaction_block = self
+            .out_transaction
+            .as_ref()
+            .map(|_| transaction_block(&block));
 
-                if noop {
-                    info!("printing block {} (noop mode)", slot);
-                } else {
-                    let _lock = BLOCK_MUTEX.lock().expect("block_mutex lock poisoned");
-                    writeln!(out_block, "FIRE BLOCK {slot} {block_hash} {parent_slot} {parent_hash} {lib} {timestamp_nano} {payload}").expect("cannot write to out_block");
-                    write_cursor(&cursor_path, slot);
-                }
+        // both messages go through one thread, so the slot is acknowledged once for the combined output
+        if let Some(combined) = &self.out_combined {
+            let block = match self.out_block {
+                Some(_) => block.clone(),
+                None => std::mem::take(&mut block),
+            };
+            let mut account_block = (!skip_account_block).then(|| match self.out_account {
+                Some(_) => account_block.clone(),
+                None => std::mem::take(&mut account_block),
             });
-        } else {
-            write_cursor(cursor_path, slot); // must still be called twice
+            let header = header.clone();
+            let block_template = combined.block_template.clone();
+            let account_template = combined.account_template.clone();
+            let cursor_path = cursor_path.map(str::to_string);
+            let cursor = self.cursor.clone();
+
+            queue_write(
+                &self.progress,
+                &combined.destination,
+                slot,
+                tracker,
+                move |out_combined| {
+                    if noop {
+                        info!("printing block and account_block {} (noop mode)", slot);
+                    } else {
+                        let written = out_combined
+                            .write_slot(diff_epoch, |out, buffer, full_data| {
+                                write_block(
+                                    out,
+                                    buffer,
+                                    output_format,
+                                    with_checksum,
+                                    block_compression,
+                                    &block_template,
+                                    &header,
+                                    &block,
+                                )?;
+                                match account_block.as_mut() {
+                                    Some(account_block) => with_account_data(
+                                        account_block,
+                                        full_data,
+                                        |account_block| {
+                                            write_block(
+                                                out,
+                                                buffer,
+                                                output_format,
+                                                with_checksum,
+                                                account_compression,
+                                                &account_template,
+                                                &header,
+                                                account_block,
+                                            )
+                                        },
+                                    ),
+                                    None => Ok(()),
+                                }
+                            })
+                            .expect("cannot write to out_combined");
+                        if let Some(cursor_path) = &cursor_path {
+                            if written {
+                                ack_cursor(&cursor, cursor_path, slot);
+                            } else {
+                                drop_cursor(&cursor, slot, &out_combined.name);
+                            }
+                        }
+                    }
+                },
+            );
         }
 
-        if let Some(out_account) = &self.out_account {
-            let mut out_account = out_account.try_clone().expect("cannot clone out_account");
-            let block_hash = block_info.block_hash.clone();
-            let parent_hash = block_info.parent_hash.clone();
-            let cursor_path = cursor_path.to_string();
-            std::thread::spawn(move || {
-                let encoded_account_block = account_block.encode_to_vec();
+        if let Some(out_block) = &self.out_block {
+            let header = header.clone();
+            let template = template.clone();
+            let cursor_path = cursor_path.map(str::to_string);
+            let cursor = self.cursor.clone();
+
+            queue_write(&self.progress, out_block, slot, tracker, move |out_block| {
+                info!(
+                    "printing block {} {} with transaction count of {}",
+                    block.slot,
+                    header.block_hash,
+                    block.transactions.len()
+                );
 
-                let base64_encoded_block = rbase64::encode(&encoded_account_block);
-                let payload = base64_encoded_block;
                 if noop {
-                    info!("printing account_block {} (noop mode)", slot);
+                    info!("printing block {} (noop mode)", slot);
                 } else {
-                    let _lock = ACC_MUTEX.lock().expect("acc_mutex lock poisoned");
-                    writeln!(out_account, "FIRE BLOCK {slot} {block_hash} {parent_slot} {parent_hash} {lib} {timestamp_nano} {payload}").expect("cannot write to out_account");
-                    write_cursor(&cursor_path, slot);
+                    let written = out_block
+                        .write_slot(diff_epoch, |out, buffer, _| {
+                            write_block(
+                                out,
+                                buffer,
+                                output_format,
+                                with_checksum,
+                                block_compression,
+                                &template,
+                                &header,
+                                &block,
+                            )
+                        })
+                        .expect("cannot write to out_block");
+                    if let Some(cursor_path) = &cursor_path {
+                        if written {
+                            ack_cursor(&cursor, cursor_path, slot);
+                        } else {
+                            drop_cursor(&cursor, slot, &out_block.name);
+                        }
+                    }
                 }
             });
-        } else {
-            write_cursor(cursor_path, slot); // must still be called twice
         }
 
-        // We are not waiting for the threads to finish, so that the plugin can be called again for the updates. The lock is only used to prevent interleaving of the output.
-        // If an error occurs while writing, the expect() will make it panic and poison the mutex.
-        // TODO: updating the cursor should be done with that knowledge (maybe wrapping the cursor in the mutex?)
+        // the account outputs of their own are written the same way as the account output
+        let queue_account_write =
+            |out_account: Arc<Destination>, mut account_block: AccountBlock| {
+                let header = header.clone();
+                let template = template.clone();
+                let cursor_path = cursor_path.map(str::to_string);
+                let cursor = self.cursor.clone();
+
+                queue_write(
+                    &self.progress,
+                    &out_account,
+                    slot,
+                    tracker,
+                    move |out_account| {
+                        if noop {
+                            info!("printing account_block {} (noop mode)", slot);
+                        } else {
+                            let written = out_account
+                                .write_slot(diff_epoch, |out, buffer, full_data| {
+                                    with_account_data(
+                                        &mut account_block,
+                                        full_data,
+                                        |account_block| {
+                                            write_block(
+                                                out,
+                                                buffer,
+                                                output_format,
+                                                with_checksum,
+                                                account_compression,
+                                                &template,
+                                                &header,
+                                                account_block,
+                                            )
+                                        },
+                                    )
+                                })
+                                .expect("cannot write to out_account");
+                            if let Some(cursor_path) = &cursor_path {
+                                if written {
+                                    ack_cursor(&cursor, cursor_path, slot);
+                                } else {
+                                    drop_cursor(&cursor, slot, &out_account.name);
+                                }
+                            }
+                        }
+                    },
+                );
+            };
+        for (out, output_block) in output_blocks {
+            queue_account_write(out, output_block);
+        }
+        if let Some(out_account) = self.out_account.as_ref().filter(|_| !skip_account_block) {
+            queue_account_write(out_account.clone(), account_block);
+        }
+
+        if let (Some(out_transaction), Some(transaction_block)) =
+            (&self.out_transaction, transaction_block)
+        {
+            let header = header.clone();
+            let template = template.clone();
+            let cursor_path = cursor_path.map(str::to_string);
+            let cursor = self.cursor.clone();
+
+            queue_write(
+                &self.progress,
+                out_transaction,
+                slot,
+                tracker,
+                move |out_transaction| {
+                    if noop {
+                        info!("printing transaction_block {} (noop mode)", slot);
+                    } else {
+                        let written = out_transaction
+                            .write_slot(diff_epoch, |out, buffer, _| {
+                                write_block(
+                                    out,
+                                    buffer,
+                                    output_format,
+                                    with_checksum,
+                                    Compression::None,
+                                    &template,
+                                    &header,
+                                    &transaction_block,
+                                )
+                            })
+                            .expect("cannot write to out_transaction");
+                        if let Some(cursor_path) = &cursor_path {
+                            if written {
+                                ack_cursor(&cursor, cursor_path, slot);
+                            } else {
+                                drop_cursor(&cursor, slot, &out_transaction.name);
+                            }
+                        }
+                    }
+                },
+            );
+        }
+
+        // We are not waiting for the writes to finish, so that the plugin can be called again for the updates. The lock is only used to prevent interleaving of the output.
+        // A slot dropped by a disconnected destination or a full queue stops the cursor before it until the restart (see Destination).
+        // If another error occurs while writing, the expect() will make it panic and poison the destination lock, with the same effect on the cursor.
         Ok(())
     }
 }
 
-// write_cursor writes the cursor the second time it is called with the same value
-// We should normally receive 1, 1, 2, 2, 3, 3, etc.
-// In case we receive 1, 1, 2, 3, 2, 3 -- we ignore a lower value, so we ignore the second '2': The cursor will be set to 1, then 3.
-// If that situation persists, the worst that can happen is that the cursor moves only every other block.
-// This would be less damageful that moving the cursor while one of the two blocks wasn't correctly written.
-fn write_cursor(cursor_file: &str, cursor: u64) {
-    let mut last = CURSOR_MUTEX.lock().expect("cursor_mutex lock poisoned");
-    if *last < cursor {
-        *last = cursor;
-        return;
+impl BlockSink for BlockPrinter {
+    fn print_init(
+        &mut self,
+        block_type: &str,
+        account_block_type: &str,
+        transaction_block_type: &str,
+    ) -> std::io::Result<()> {
+        if let Some(combined) = self.out_combined.as_mut() {
+            combined.block_template =
+                Arc::new(self.fire_block_template.with_message_type(block_type));
+            combined.account_template = Arc::new(
+                self.fire_block_template
+                    .with_message_type(account_block_type),
+            );
+        }
+        if self.noop {
+            debug!(
+                "printing init for type {}, {} and {} (noop mode)",
+                block_type, account_block_type, transaction_block_type
+            );
+            Ok(())
+        } else if self.output_format != OutputFormat::FireText {
+            // only the firecore readers expect the init line, the other formats only carry blocks
+            debug!(
+                "skipping init for type {}, {} and {} ({:?} output)",
+                block_type, account_block_type, transaction_block_type, self.output_format
+            );
+            Ok(())
+        } else {
+            let block_line = self.init_line(block_type, self.block_compression)?;
+            let account_line = self.init_line(account_block_type, self.account_compression)?;
+            let transaction_line = self.init_line(transaction_block_type, Compression::None)?;
+            if let Some(out_block) = &self.out_block {
+                out_block.write_init(vec![block_line.clone()])?;
+            }
+            if let Some(out_account) = &self.out_account {
+                out_account.write_init(vec![account_line.clone()])?;
+            }
+            if let Some(out_transaction) = &self.out_transaction {
+                out_transaction.write_init(vec![transaction_line])?;
+            }
+            for output in &self.account_outputs {
+                output.destination.write_init(vec![account_line.clone()])?;
+            }
+            if let Some(combined) = &self.out_combined {
+                combined
+                    .destination
+                    .write_init(vec![block_line, account_line])?;
+            }
+            // the idle markers start once the readers got their init line
+            if let Some(interval) = self
+                .idle_marker_interval
+                .filter(|_| self.idle_marker.is_none())
+            {
+                self.idle_marker = Some(IdleMarker::spawn(
+                    interval,
+                    self.activity.clone(),
+                    self.destinations(),
+                ));
+            }
+            Ok(())
+        }
     }
-    if *last == cursor {
-        std::fs::write(cursor_file, cursor.to_string()).expect("cannot write cursor");
+
+    // print_snapshot writes a chunk of the accounts loaded at startup to the account output, right away.
+    // It does not move the cursor: the snapshot is not a block.
+    fn print_snapshot(&mut self, slot: u64, accounts: Vec<Account>) -> std::io::Result<()> {
+        if self.out_account.is_none()
+            && self.account_outputs.is_empty()
+            && self.out_combined.is_none()
+        {
+            return Ok(());
+        }
+        if self.noop {
+            info!(
+                "printing snapshot of {} accounts at slot {} (noop mode)",
+                accounts.len(),
+                slot
+            );
+            return Ok(());
+        }
+
+        let header = BlockHeader {
+            slot,
+            block_hash: SNAPSHOT_BLOCK_HASH.to_string(),
+            parent_slot: slot,
+            parent_hash: SNAPSHOT_BLOCK_HASH.to_string(),
+            lib: slot,
+            timestamp_nano: 0,
+            block_height: None,
+        };
+        let account_block = AccountBlock {
+            slot,
+            hash: SNAPSHOT_BLOCK_HASH.to_string(),
+            parent_slot: slot,
+            parent_hash: SNAPSHOT_BLOCK_HASH.to_string(),
+            timestamp: None,
+            accounts,
+            snapshot: true,
+        };
+        for output in &self.account_outputs {
+            let output_block = output.account_block(&account_block);
+            output.destination.write(|out, buffer| {
+                write_block(
+                    out,
+                    buffer,
+                    self.output_format,
+                    self.payload_checksum,
+                    self.account_compression,
+                    &self.fire_block_template,
+                    &header,
+                    &output_block,
+                )
+            })?;
+        }
+        if let Some(out_account) = &self.out_account {
+            out_account.write(|out, buffer| {
+                write_block(
+                    out,
+                    buffer,
+                    self.output_format,
+                    self.payload_checksum,
+                    self.account_compression,
+                    &self.fire_block_template,
+                    &header,
+                    &account_block,
+                )
+            })?;
+        }
+        if let Some(combined) = &self.out_combined {
+            combined.destination.write(|out, buffer| {
+                write_block(
+                    out,
+                    buffer,
+                    self.output_format,
+                    self.payload_checksum,
+                    self.account_compression,
+                    &combined.account_template,
+                    &header,
+                    &account_block,
+                )
+            })?;
+        }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::NamedTempFile;
+    fn print(
+        &mut self,
+        block_info: &BlockInfo,
+        lib: u64,
+        block: Block,
+        account_block: AccountBlock,
+        cursor_path: &str,
+    ) -> std::io::Result<()> {
+        self.write_slot(block_info, lib, block, account_block, Some(cursor_path))
+    }
 
-    #[test]
-    fn test_write_cursor() {
-        let temp_file = NamedTempFile::new().unwrap();
-        let path = temp_file.path().to_str().unwrap().to_string();
+    // replay writes a slot that was already sent once more, the cursor does not wait for it nor move
+    fn replay(
+        &mut self,
+        block_info: &BlockInfo,
+        lib: u64,
+        block: Block,
+        account_block: AccountBlock,
+    ) -> std::io::Result<()> {
+        self.write_slot(block_info, lib, block, account_block, None)
+    }
+
+    // a placeholder has no account data, so whether its account block is diffed does not matter
+    fn print_placeholder(
+        &mut self,
+        block_info: &BlockInfo,
+        lib: u64,
+        block: Block,
+        account_block: AccountBlock,
+    ) -> std::io::Result<()> {
+        self.write_slot(block_info, lib, block, account_block, None)
+    }
+
+    // confirm_slot lets the cursor move over the written slots up to `slot`, see PrinterOptions::cursor_requires_confirmation
+    fn confirm_slot(&self, slot: u64, cursor_path: &str) {
+        confirm_cursor(&self.cursor, cursor_path, slot);
+    }
+
+    // flush_cursor persists the latest fully written slot if it was held back by cursor_write_interval_slots
+    fn flush_cursor(&self, cursor_path: &str) {
+        let mut tracker = self.cursor.lock().expect("cursor lock poisoned");
+        if let Some(cursor) = tracker.flush() {
+            write_cursor(cursor_path, &cursor);
+        }
+    }
+
+    // a reopened destination has a new reader. The slots whose diffs were made before it get their full data
+    // on that destination (see Destination::write_slot), the next ones are diffed against what it got.
+    fn diff_base_lost(&mut self) -> bool {
+        let reopens = self.reopens.load(Ordering::SeqCst);
+        if reopens == self.diff_epoch {
+            return false;
+        }
+        self.diff_epoch = reopens;
+        true
+    }
+
+    // a writer that panicked while writing poisoned the lock of its destination
+    fn outputs_poisoned(&self) -> bool {
+        self.destinations()
+            .iter()
+            .any(|destination| destination.state.is_poisoned())
+    }
+}
+
+// with_account_data gives the account block to `write` with either the diffs of its accounts or, with `full_data`,
+// their full data only, see take_diffed_data
+fn with_account_data<T>(
+    account_block: &mut AccountBlock,
+    full_data: bool,
+    write: impl FnOnce(&AccountBlock) -> T,
+) -> T {
+    if full_data {
+        drop_account_diffs(account_block);
+    }
+    let data = take_diffed_data(account_block);
+    let result = write(account_block);
+    restore_diffed_data(account_block, data);
+    result
+}
+
+fn transaction_block(block: &Block) -> TransactionBlock {
+    TransactionBlock {
+        slot: block.slot,
+        hash: block.blockhash.clone(),
+        parent_slot: block.parent_slot,
+        parent_hash: block.previous_blockhash.clone(),
+        transactions: block.transactions.clone(),
+    }
+}
+
+// CursorTracker follows the slots handed to the sinks and how many of them still have to confirm the write.
+// The cursor only moves up to the highest slot for which every lower slot was also fully written,
+// so it never advances past a block that one of the sinks did not write, even if the threads finish out of order.
+// It is only returned for writing every `write_interval` slots: on restart, at most that many slots are sent again.
+// With `require_confirmation`, written slots are also held back until the chain confirmed them.
+// Once a sink dropped a slot, the cursor never reaches it: the later slots are not tracked anymore.
+pub(crate) struct CursorTracker {
+    pending: BTreeMap<u64, PendingSlot>,
+    dropped: Option<u64>,
+    write_interval: u64,
+    require_confirmation: bool,
+    confirmed_upto: Option<u64>,
+    written: VecDeque<Cursor>,
+    latest: Option<Cursor>,
+    unwritten: u64,
+}
+
+struct PendingSlot {
+    block_hash: String,
+    remaining_sinks: usize,
+}
+
+impl Default for CursorTracker {
+    fn default() -> Self {
+        CursorTracker::new(1, false)
+    }
+}
+
+impl CursorTracker {
+    pub(crate) fn new(write_interval: u64, require_confirmation: bool) -> Self {
+        CursorTracker {
+            pending: BTreeMap::new(),
+            dropped: None,
+            write_interval: write_interval.max(1),
+            require_confirmation,
+            confirmed_upto: None,
+            written: VecDeque::new(),
+            latest: None,
+            unwritten: 0,
+        }
+    }
+
+    pub(crate) fn register(&mut self, slot: u64, block_hash: &str, sinks: usize) -> Option<Cursor> {
+        if self.dropped.is_some_and(|dropped| slot > dropped) {
+            return None;
+        }
+        self.pending.insert(
+            slot,
+            PendingSlot {
+                block_hash: block_hash.to_string(),
+                remaining_sinks: sinks,
+            },
+        );
+        self.advance()
+    }
+
+    fn ack(&mut self, slot: u64) -> Option<Cursor> {
+        match self.pending.get_mut(&slot) {
+            Some(pending) => pending.remaining_sinks = pending.remaining_sinks.saturating_sub(1),
+            None => return None,
+        }
+        self.advance()
+    }
+
+    // drop_slot keeps the cursor before a slot a sink did not write, returns false when an earlier one was dropped already
+    fn drop_slot(&mut self, slot: u64) -> bool {
+        if self.dropped.is_some_and(|dropped| dropped <= slot) {
+            return false;
+        }
+        self.dropped = Some(slot);
+        self.pending.split_off(&slot);
+        true
+    }
+
+    pub(crate) fn confirm(&mut self, slot: u64) -> Option<Cursor> {
+        if self
+            .confirmed_upto
+            .is_some_and(|confirmed| confirmed >= slot)
+        {
+            return None;
+        }
+        self.confirmed_upto = Some(slot);
+        self.advance()
+    }
+
+    pub(crate) fn flush(&mut self) -> Option<Cursor> {
+        if self.unwritten == 0 {
+            return None;
+        }
+        self.unwritten = 0;
+        self.latest.clone()
+    }
+
+    // pops all the leading slots that were written by every sink (and confirmed, when required),
+    // returns the last one if the cursor is due
+    fn advance(&mut self) -> Option<Cursor> {
+        while let Some(entry) = self.pending.first_entry() {
+            if entry.get().remaining_sinks > 0 {
+                break;
+            }
+            let (slot, pending) = entry.remove_entry();
+            self.written
+                .push_back(Cursor::new(slot, &pending.block_hash));
+        }
+        while let Some(cursor) = self.written.front() {
+            if self.require_confirmation
+                && self
+                    .confirmed_upto
+                    .is_none_or(|confirmed| cursor.slot > confirmed)
+            {
+                break;
+            }
+            self.latest = self.written.pop_front();
+            self.unwritten += 1;
+        }
+        if self.unwritten >= self.write_interval {
+            return self.flush();
+        }
+        None
+    }
+}
+
+// register_cursor must be called before the slot is handed to the sinks.
+// Without any active sink, the cursor moves right away.
+fn register_cursor(
+    tracker: &Mutex<CursorTracker>,
+    cursor_file: &str,
+    slot: u64,
+    block_hash: &str,
+    sinks: usize,
+) {
+    let mut tracker = tracker.lock().expect("cursor lock poisoned");
+    if let Some(cursor) = tracker.register(slot, block_hash, sinks) {
+        write_cursor(cursor_file, &cursor);
+    }
+}
+
+// confirm_cursor lets the cursor move up to a slot that reached the configured cursor commitment.
+fn confirm_cursor(tracker: &Mutex<CursorTracker>, cursor_file: &str, slot: u64) {
+    let mut tracker = tracker.lock().expect("cursor lock poisoned");
+    if let Some(cursor) = tracker.confirm(slot) {
+        write_cursor(cursor_file, &cursor);
+    }
+}
+
+// ack_cursor is called by each sink once it has written the slot.
+fn ack_cursor(tracker: &Mutex<CursorTracker>, cursor_file: &str, slot: u64) {
+    let mut tracker = tracker.lock().expect("cursor lock poisoned");
+    if let Some(cursor) = tracker.ack(slot) {
+        write_cursor(cursor_file, &cursor);
+    }
+}
+
+// drop_cursor is called by a sink that could not write the slot, only a restart sends it again.
+fn drop_cursor(tracker: &Mutex<CursorTracker>, slot: u64, destination: &str) {
+    let mut tracker = tracker.lock().expect("cursor lock poisoned");
+    if tracker.drop_slot(slot) {
+        error!(
+            "{} dropped slot {}, the cursor stays before it until the validator is restarted",
+            destination, slot
+        );
+    }
+}
+
+fn write_cursor(cursor_file: &str, cursor: &Cursor) {
+    cursor.write(cursor_file).expect("cannot write cursor");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::sf::solana::r#type::v1::{Account, ConfirmedTransaction};
+    use tempfile::NamedTempFile;
+
+    fn test_account_block(slot: u64, accounts: usize, data_len: usize) -> AccountBlock {
+        AccountBlock {
+            slot,
+            hash: format!("hash{}", slot),
+            parent_slot: slot - 1,
+            parent_hash: format!("hash{}", slot - 1),
+            timestamp: None,
+            accounts: (0..accounts)
+                .map(|i| Account {
+                    address: vec![i as u8; 32],
+                    owner: vec![1; 32],
+                    data: vec![i as u8; data_len],
+                    deleted: false,
+                    truncated: false,
+                    owner_changed: false,
+                    lamports: 1,
+                    rent_epoch: 0,
+                    txn_signature: vec![],
+                    write_version: i as u64,
+                    data_diff: None,
+                })
+                .collect(),
+            snapshot: false,
+        }
+    }
+
+    #[test]
+    fn test_payload_buffer_reuse() {
+        let mut buffer = PayloadBuffer::default();
+
+        let big = test_account_block(10, 16, 4096);
+        let payload = buffer.encode(&big, Compression::None).to_string();
+        assert_eq!(payload, BASE64.encode(big.encode_to_vec()));
+        let encoded_capacity = buffer.encoded.capacity();
+        let payload_capacity = buffer.payload.capacity();
+
+        // a smaller block must not carry leftovers from the previous one, nor reallocate
+        let small = test_account_block(11, 1, 8);
+        let payload = buffer.encode(&small, Compression::None).to_string();
+        assert_eq!(payload, BASE64.encode(small.encode_to_vec()));
+        let decoded = AccountBlock::decode(BASE64.decode(&payload).unwrap().as_slice()).unwrap();
+        assert_eq!(decoded, small);
+        assert_eq!(buffer.encoded.capacity(), encoded_capacity);
+        assert_eq!(buffer.payload.capacity(), payload_capacity);
+
+        let empty = AccountBlock::default();
+        assert_eq!(buffer.encode(&empty, Compression::None), "");
+    }
+
+    fn test_header(slot: u64) -> BlockHeader {
+        BlockHeader {
+            slot,
+            block_hash: format!("hash{}", slot),
+            parent_slot: slot - 1,
+            parent_hash: format!("hash{}", slot - 1),
+            lib: slot - 10,
+            timestamp_nano: 1_234_000_000_000,
+            block_height: Some(slot - 5),
+        }
+    }
+
+    #[test]
+    fn test_write_block_fire_text() {
+        let mut buffer = PayloadBuffer::default();
+        let mut out = Vec::new();
+        let account_block = test_account_block(20, 2, 16);
+
+        write_block(
+            &mut out,
+            &mut buffer,
+            OutputFormat::FireText,
+            false,
+            Compression::None,
+            &FireBlockTemplate::default(),
+            &test_header(20),
+            &account_block,
+        )
+        .unwrap();
+
+        let line = String::from_utf8(out).unwrap();
+        let expected_prefix = "FIRE BLOCK 20 hash20 19 hash19 10 1234000000000 ";
+        assert!(line.starts_with(expected_prefix));
+        assert!(line.ends_with('\n'));
+        let payload = line[expected_prefix.len()..].trim_end();
+        let decoded = AccountBlock::decode(BASE64.decode(payload).unwrap().as_slice()).unwrap();
+        assert_eq!(decoded, account_block);
+    }
+
+    #[test]
+    fn test_write_block_fire_block_template() {
+        let account_block = test_account_block(20, 2, 16);
+        let render = |template: &str, header: &BlockHeader| {
+            let mut out = Vec::new();
+            write_block(
+                &mut out,
+                &mut PayloadBuffer::default(),
+                OutputFormat::FireText,
+                false,
+                Compression::None,
+                &FireBlockTemplate::parse(template).unwrap(),
+                header,
+                &account_block,
+            )
+            .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let payload = PayloadBuffer::default()
+            .encode(&account_block, Compression::None)
+            .to_string();
+
+        assert_eq!(
+            render(
+                "FIRE BLOCK {slot} {block_hash} {parent_slot} {lib} {timestamp_nano} {payload}",
+                &test_header(20)
+            ),
+            format!("FIRE BLOCK 20 hash20 19 10 1234000000000 {}\n", payload)
+        );
+        let mut header = test_header(20);
+        assert_eq!(
+            render(
+                "FIRE BLOCK {slot} {block_hash} {block_height} {payload}",
+                &header
+            ),
+            format!("FIRE BLOCK 20 hash20 15 {}\n", payload)
+        );
+        header.block_height = None;
+        assert_eq!(
+            render(
+                "FIRE BLOCK {slot} {block_hash} {block_height} {payload}",
+                &header
+            ),
+            format!("FIRE BLOCK 20 hash20 0 {}\n", payload)
+        );
+    }
+
+    #[test]
+    fn test_write_block_block_height_header() {
+        let account_block = test_account_block(20, 2, 16);
+        let template = FireBlockTemplate::default().with_block_height();
+        let render = |header: &BlockHeader| {
+            let mut out = Vec::new();
+            write_block(
+                &mut out,
+                &mut PayloadBuffer::default(),
+                OutputFormat::FireText,
+                false,
+                Compression::None,
+                &template,
+                header,
+                &account_block,
+            )
+            .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let mut header = test_header(20);
+        assert!(render(&header).starts_with("FIRE BLOCK 20 hash20 19 hash19 10 1234000000000 15 "));
+        header.block_height = None;
+        assert!(render(&header).starts_with("FIRE BLOCK 20 hash20 19 hash19 10 1234000000000 0 "));
+        // a template that already has the height keeps it where it is
+        assert_eq!(
+            FireBlockTemplate::parse("FIRE BLOCK {block_height} {payload}")
+                .unwrap()
+                .with_block_height(),
+            FireBlockTemplate::parse("FIRE BLOCK {block_height} {payload}").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_write_block_fire_text_checksum() {
+        let mut buffer = PayloadBuffer::default();
+        let mut out = Vec::new();
+        let account_block = test_account_block(20, 2, 16);
+
+        write_block(
+            &mut out,
+            &mut buffer,
+            OutputFormat::FireText,
+            true,
+            Compression::None,
+            &FireBlockTemplate::default(),
+            &test_header(20),
+            &account_block,
+        )
+        .unwrap();
+
+        let line = String::from_utf8(out).unwrap();
+        let fields: Vec<&str> = line.trim_end().split(' ').collect();
+        assert_eq!(fields.len(), 10);
+        let (payload, checksum) = (fields[8], fields[9]);
+        assert_eq!(checksum.len(), 16);
+        assert_eq!(checksum, payload_checksum(payload));
+        assert_eq!(
+            u64::from_str_radix(checksum, 16).unwrap(),
+            xxhash_rust::xxh3::xxh3_64(payload.as_bytes())
+        );
+
+        // a payload cut short does not match anymore
+        assert_ne!(checksum, payload_checksum(&payload[..payload.len() - 4]));
+    }
+
+    #[test]
+    fn test_write_block_length_prefixed_proto() {
+        let mut buffer = PayloadBuffer::default();
+        let mut out = Vec::new();
+        let blocks = vec![test_account_block(20, 2, 16), test_account_block(21, 0, 0)];
+        for block in blocks.iter() {
+            write_block(
+                &mut out,
+                &mut buffer,
+                OutputFormat::LengthPrefixedProto,
+                false,
+                Compression::None,
+                &FireBlockTemplate::default(),
+                &test_header(block.slot),
+                block,
+            )
+            .unwrap();
+        }
+
+        let mut decoded = vec![];
+        let mut rest = out.as_slice();
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            decoded.push(AccountBlock::decode(&rest[4..4 + length]).unwrap());
+            rest = &rest[4 + length..];
+        }
+        assert_eq!(decoded, blocks);
+    }
+
+    #[cfg(feature = "json-lines")]
+    #[test]
+    fn test_write_block_json_lines() {
+        let mut buffer = PayloadBuffer::default();
+        let mut out = Vec::new();
+        let blocks = [test_account_block(20, 2, 16), test_account_block(21, 0, 0)];
+        for block in blocks.iter() {
+            write_block(
+                &mut out,
+                &mut buffer,
+                OutputFormat::JsonLines,
+                false,
+                Compression::None,
+                &FireBlockTemplate::default(),
+                &test_header(block.slot),
+                block,
+            )
+            .unwrap();
+        }
+
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for (line, block) in lines.iter().zip(blocks.iter()) {
+            let value: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(value["slot"], block.slot);
+            assert_eq!(value["lib"], block.slot - 10);
+            assert_eq!(value["payload"]["slot"], block.slot);
+            assert_eq!(
+                value["payload"]["accounts"].as_array().unwrap().len(),
+                block.accounts.len()
+            );
+        }
+    }
+
+    // slot of the cursor file, empty if it was not written
+    fn read_cursor(path: &str) -> String {
+        Cursor::from_file(path)
+            .map(|cursor| cursor.slot.to_string())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_cursor_no_sink() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap().to_string();
+        let tracker = Mutex::new(CursorTracker::default());
+
+        register_cursor(&tracker, &path, 1, "hash", 0);
+        assert_eq!(read_cursor(&path), "1");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1:hash");
+        register_cursor(&tracker, &path, 2, "hash", 0);
+        assert_eq!(read_cursor(&path), "2");
+    }
+
+    #[test]
+    fn test_cursor_one_sink() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap().to_string();
+        let tracker = Mutex::new(CursorTracker::default());
+
+        register_cursor(&tracker, &path, 1, "hash", 1);
+        assert_eq!(read_cursor(&path), "");
+        ack_cursor(&tracker, &path, 1);
+        assert_eq!(read_cursor(&path), "1");
+
+        // 3 is written before 2: the cursor must wait for 2
+        register_cursor(&tracker, &path, 2, "hash", 1);
+        register_cursor(&tracker, &path, 3, "hash", 1);
+        ack_cursor(&tracker, &path, 3);
+        assert_eq!(read_cursor(&path), "1");
+        ack_cursor(&tracker, &path, 2);
+        assert_eq!(read_cursor(&path), "3");
+    }
+
+    #[test]
+    fn test_cursor_dropped_slot() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap().to_string();
+        let tracker = Mutex::new(CursorTracker::default());
+
+        for slot in 1..=3 {
+            register_cursor(&tracker, &path, slot, "hash", 1);
+        }
+        ack_cursor(&tracker, &path, 1);
+        drop_cursor(&tracker, 2, "out_block");
+        ack_cursor(&tracker, &path, 3);
+        assert_eq!(read_cursor(&path), "1");
+
+        // the cursor never moves again, the later slots are not kept waiting for it
+        register_cursor(&tracker, &path, 4, "hash", 1);
+        ack_cursor(&tracker, &path, 4);
+        assert_eq!(read_cursor(&path), "1");
+        assert!(tracker.lock().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn test_cursor_requires_confirmation() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap().to_string();
+        let tracker = Mutex::new(CursorTracker::new(1, true));
+
+        register_cursor(&tracker, &path, 1, "hash", 1);
+        register_cursor(&tracker, &path, 2, "hash", 1);
+        ack_cursor(&tracker, &path, 1);
+        ack_cursor(&tracker, &path, 2);
+        assert_eq!(read_cursor(&path), "");
+
+        confirm_cursor(&tracker, &path, 1);
+        assert_eq!(read_cursor(&path), "1");
+
+        // confirmed before being written: moves as soon as the sink is done
+        confirm_cursor(&tracker, &path, 3);
+        assert_eq!(read_cursor(&path), "2");
+        register_cursor(&tracker, &path, 3, "hash", 1);
+        assert_eq!(read_cursor(&path), "2");
+        ack_cursor(&tracker, &path, 3);
+        assert_eq!(read_cursor(&path), "3");
+    }
+
+    #[test]
+    fn test_cursor_two_sinks() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap().to_string();
+        let tracker = Mutex::new(CursorTracker::default());
+
+        register_cursor(&tracker, &path, 1, "hash", 2);
+        ack_cursor(&tracker, &path, 1);
+        assert_eq!(read_cursor(&path), "");
+        ack_cursor(&tracker, &path, 1);
+        assert_eq!(read_cursor(&path), "1");
+
+        // 2 is only written by one sink, 3 by both: nothing moves
+        register_cursor(&tracker, &path, 2, "hash", 2);
+        register_cursor(&tracker, &path, 3, "hash", 2);
+        ack_cursor(&tracker, &path, 2);
+        ack_cursor(&tracker, &path, 3);
+        ack_cursor(&tracker, &path, 3);
+        assert_eq!(read_cursor(&path), "1");
+
+        ack_cursor(&tracker, &path, 2);
+        assert_eq!(read_cursor(&path), "3");
+
+        // an acknowledgement for an unknown slot is ignored
+        ack_cursor(&tracker, &path, 10);
+        assert_eq!(read_cursor(&path), "3");
+    }
+
+    fn wait_for_cursor(path: &str, expected: &str) {
+        for _ in 0..500 {
+            if read_cursor(path) == expected {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        panic!("cursor never reached {}", expected);
+    }
+
+    #[test]
+    fn test_placeholder_does_not_move_cursor() {
+        let cursor_file = NamedTempFile::new().unwrap();
+        let cursor_path = cursor_file.path().to_str().unwrap().to_string();
+        let block_file = NamedTempFile::new().unwrap();
+        let mut printer = BlockPrinter::new(
+            Some(block_file.reopen().unwrap()),
+            None,
+            None,
+            PrinterOptions::default(),
+        );
+        let slot = |slot: u64, block_hash: String| {
+            let block_info = BlockInfo {
+                slot,
+                parent_slot: slot - 1,
+                block_hash: block_hash.clone(),
+                ..Default::default()
+            };
+            let block = Block {
+                slot,
+                blockhash: block_hash,
+                ..Default::default()
+            };
+            (block_info, block)
+        };
+
+        let (block_info, block) = slot(20, "hash20".to_string());
+        printer
+            .print(
+                &block_info,
+                10,
+                block,
+                AccountBlock::default(),
+                &cursor_path,
+            )
+            .unwrap();
+        wait_for_cursor(&cursor_path, "20");
+        let (block_info, block) = slot(21, skipped_block_hash(21));
+        printer
+            .print_placeholder(&block_info, 10, block, AccountBlock::default())
+            .unwrap();
+        let start = Instant::now();
+        while std::fs::read_to_string(block_file.path())
+            .unwrap()
+            .lines()
+            .count()
+            < 2
+        {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(std::fs::read_to_string(&cursor_path).unwrap(), "20:hash20");
+
+        let (block_info, block) = slot(22, "hash22".to_string());
+        printer
+            .print(
+                &block_info,
+                10,
+                block,
+                AccountBlock::default(),
+                &cursor_path,
+            )
+            .unwrap();
+        wait_for_cursor(&cursor_path, "22");
+    }
+
+    #[test]
+    fn test_print_transaction_stream() {
+        let cursor_file = NamedTempFile::new().unwrap();
+        let cursor_path = cursor_file.path().to_str().unwrap().to_string();
+        let block_file = NamedTempFile::new().unwrap();
+        let transaction_file = NamedTempFile::new().unwrap();
+
+        let mut printer = BlockPrinter::new(
+            Some(block_file.reopen().unwrap()),
+            None,
+            Some(transaction_file.reopen().unwrap()),
+            PrinterOptions::default(),
+        );
+        printer
+            .print_init("Block", "AccountBlock", "TransactionBlock")
+            .unwrap();
+
+        let block_info = BlockInfo {
+            slot: 20,
+            parent_slot: 19,
+            block_hash: "hash20".to_string(),
+            parent_hash: "hash19".to_string(),
+            ..Default::default()
+        };
+        let block = Block {
+            slot: 20,
+            blockhash: "hash20".to_string(),
+            parent_slot: 19,
+            previous_blockhash: "hash19".to_string(),
+            transactions: vec![ConfirmedTransaction::default(); 3],
+            ..Default::default()
+        };
+        printer
+            .print(
+                &block_info,
+                10,
+                block,
+                AccountBlock::default(),
+                &cursor_path,
+            )
+            .unwrap();
+
+        // the cursor only moves once both the block and the transactions were written
+        wait_for_cursor(&cursor_path, "20");
+
+        let content = std::fs::read_to_string(transaction_file.path()).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines[0], "FIRE INIT 3.0 TransactionBlock");
+        let prefix = "FIRE BLOCK 20 hash20 19 hash19 10 0 ";
+        assert!(lines[1].starts_with(prefix));
+        let decoded =
+            TransactionBlock::decode(BASE64.decode(&lines[1][prefix.len()..]).unwrap().as_slice())
+                .unwrap();
+        assert_eq!(decoded.slot, 20);
+        assert_eq!(decoded.parent_hash, "hash19");
+        assert_eq!(decoded.transactions.len(), 3);
+
+        let content = std::fs::read_to_string(block_file.path()).unwrap();
+        assert!(content.lines().nth(1).unwrap().starts_with(prefix));
+    }
+
+    fn test_slot(slot: u64) -> (BlockInfo, Block) {
+        let block_info = BlockInfo {
+            slot,
+            parent_slot: slot - 1,
+            block_hash: format!("hash{}", slot),
+            parent_hash: format!("hash{}", slot - 1),
+            ..Default::default()
+        };
+        let block = Block {
+            slot,
+            blockhash: block_info.block_hash.clone(),
+            ..Default::default()
+        };
+        (block_info, block)
+    }
+
+    fn wait_for_disconnect(destination: &Destination) {
+        for _ in 0..500 {
+            if destination.lock().out.is_none() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("{} was never disconnected", destination.name);
+    }
+
+    #[test]
+    fn test_print_to_closed_reader() {
+        use std::os::fd::OwnedFd;
+
+        let cursor_file = NamedTempFile::new().unwrap();
+        let cursor_path = cursor_file.path().to_str().unwrap().to_string();
+        let (reader, writer) = std::io::pipe().unwrap();
+        let mut printer = BlockPrinter::new(
+            Some(File::from(OwnedFd::from(writer))),
+            None,
+            None,
+            PrinterOptions::default(),
+        );
+        printer
+            .print_init("Block", "AccountBlock", "TransactionBlock")
+            .unwrap();
+
+        drop(reader);
+        for slot in [20, 21] {
+            let (block_info, block) = test_slot(slot);
+            printer
+                .print(
+                    &block_info,
+                    10,
+                    block,
+                    AccountBlock::default(),
+                    &cursor_path,
+                )
+                .unwrap();
+            wait_for_disconnect(printer.out_block.as_ref().unwrap());
+        }
+
+        // the writer threads did not panic, the slots were dropped and the cursor did not move
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!printer.outputs_poisoned());
+        assert_eq!(read_cursor(&cursor_path), "");
+        // nor is it waiting for them
+        assert!(printer.cursor.lock().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn test_writer_stall_watchdog() {
+        use std::os::fd::OwnedFd;
+
+        let cursor_file = NamedTempFile::new().unwrap();
+        let cursor_path = cursor_file.path().to_str().unwrap().to_string();
+        let (mut reader, writer) = std::io::pipe().unwrap();
+        let mut printer = BlockPrinter::new(
+            Some(File::from(OwnedFd::from(writer))),
+            None,
+            None,
+            PrinterOptions {
+                writer_stall_timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+        );
+
+        // the line does not fit in the pipe, the writer is stuck until the reader reads
+        let (block_info, mut block) = test_slot(20);
+        block.previous_blockhash = "x".repeat(1 << 20);
+        printer
+            .print(
+                &block_info,
+                10,
+                block,
+                AccountBlock::default(),
+                &cursor_path,
+            )
+            .unwrap();
+        let start = Instant::now();
+        while printer.writer_stalls() == 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "no stall reported"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(read_cursor(&cursor_path), "");
+
+        std::thread::spawn(move || std::io::copy(&mut reader, &mut std::io::sink()));
+        while read_cursor(&cursor_path) != "20" {
+            assert!(start.elapsed() < Duration::from_secs(5), "never written");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // once the write finished, the watchdog is quiet again
+        let stalls = printer.writer_stalls();
+        std::thread::sleep(Duration::from_millis(500));
+        assert_eq!(printer.writer_stalls(), stalls);
+    }
+
+    #[test]
+    fn test_reopen_destination() {
+        use std::io::Read;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("blocks.fifo");
+        let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) }, 0);
+        let open_reader = || {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&path)
+                .unwrap()
+        };
+        let cursor_file = NamedTempFile::new().unwrap();
+        let cursor_path = cursor_file.path().to_str().unwrap().to_string();
+
+        let reader = open_reader();
+        let out = try_open_fifo(path.to_str().unwrap()).unwrap().unwrap();
+        let mut printer = BlockPrinter::new(Some(out), None, None, PrinterOptions::default())
+            .with_reopen(path.to_str().unwrap(), "", "", "");
+        printer
+            .print_init("Block", "AccountBlock", "TransactionBlock")
+            .unwrap();
+
+        // slot 20 waits while nobody reads, slots 21 and 22 are queued behind it
+        drop(reader);
+        for slot in 20..=22 {
+            let (block_info, block) = test_slot(slot);
+            printer
+                .print(
+                    &block_info,
+                    10,
+                    block,
+                    AccountBlock::default(),
+                    &cursor_path,
+                )
+                .unwrap();
+        }
+        // the waiting write holds the destination, the write fails right away without a reader
+        std::thread::sleep(REOPEN_MIN_BACKOFF);
+        assert_eq!(read_cursor(&cursor_path), "");
+
+        // once a new reader is attached, it gets the init line again, then the slots in order
+        let mut reader = open_reader();
+
+        let mut content = String::new();
+        for _ in 0..500 {
+            let mut buf = [0; 1024];
+            match reader.read(&mut buf) {
+                Ok(read) => content.push_str(std::str::from_utf8(&buf[..read]).unwrap()),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                Err(err) => panic!("cannot read: {}", err),
+            }
+            if content.ends_with('\n') && content.lines().count() == 4 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", content);
+        assert_eq!(lines[0], "FIRE INIT 3.0 Block");
+        assert!(lines[1].starts_with("FIRE BLOCK 20 hash20 19 hash19 10 "));
+        assert!(lines[2].starts_with("FIRE BLOCK 21 hash21 20 hash20 10 "));
+        assert!(lines[3].starts_with("FIRE BLOCK 22 hash22 21 hash21 10 "));
+
+        // nothing was dropped, the cursor moves again
+        wait_for_cursor(&cursor_path, "22");
+    }
+
+    #[test]
+    fn test_full_writer_queue() {
+        let cursor_file = NamedTempFile::new().unwrap();
+        let cursor_path = cursor_file.path().to_str().unwrap().to_string();
+        let block_file = NamedTempFile::new().unwrap();
+        let mut printer = BlockPrinter::new(
+            Some(block_file.reopen().unwrap()),
+            None,
+            None,
+            PrinterOptions::default(),
+        );
+
+        // a write to out_block that does not return: the next slots fill the queue, then they are dropped
+        // instead of blocking the printer
+        let out_block = printer.out_block.clone().unwrap();
+        let stalled = out_block.lock();
+        let first = 20;
+        let last = first + WRITER_QUEUE_SLOTS as u64 + 5;
+        for slot in first..=last {
+            let (block_info, block) = test_slot(slot);
+            printer
+                .print(
+                    &block_info,
+                    10,
+                    block,
+                    AccountBlock::default(),
+                    &cursor_path,
+                )
+                .unwrap();
+        }
+        drop(stalled);
+        for _ in 0..500 {
+            if printer.progress.lock().unwrap().queued == 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // the queued slots are written in order, the cursor stays on the last one before the first dropped slot
+        let content = std::fs::read_to_string(block_file.path()).unwrap();
+        let slots: Vec<u64> = content
+            .lines()
+            .map(|line| line.split(' ').nth(2).unwrap().parse().unwrap())
+            .collect();
+        let written = *slots.last().unwrap();
+        assert_eq!(slots, (first..=written).collect::<Vec<_>>());
+        assert!(written < last);
+        assert_eq!(read_cursor(&cursor_path), written.to_string());
+        assert!(printer.cursor.lock().unwrap().pending.is_empty());
+    }
+
+    #[test]
+    fn test_reopen_destination_account_diffs() {
+        use std::io::Read;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("accounts.fifo");
+        let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) }, 0);
+        let open_reader = || {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&path)
+                .unwrap()
+        };
+        // the accounts of the account block of each line, the init lines left out
+        let read_accounts = |reader: &mut File, count: usize| {
+            let mut content = String::new();
+            for _ in 0..500 {
+                let mut buf = [0; 4096];
+                match reader.read(&mut buf) {
+                    Ok(read) => content.push_str(std::str::from_utf8(&buf[..read]).unwrap()),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                    Err(err) => panic!("cannot read: {}", err),
+                }
+                if content.ends_with('\n') && content.matches("FIRE BLOCK").count() == count {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            content
+                .lines()
+                .filter(|line| line.starts_with("FIRE BLOCK"))
+                .map(|line| {
+                    let payload = line.rsplit(' ').next().unwrap();
+                    let decoded = BASE64.decode(payload).unwrap();
+                    AccountBlock::decode(decoded.as_slice()).unwrap().accounts
+                })
+                .collect::<Vec<_>>()
+        };
+        let diffed_block = |slot: u64| AccountBlock {
+            slot,
+            accounts: vec![Account {
+                address: vec![1; 32],
+                data: vec![7; 100],
+                data_diff: Some(crate::pb::sf::solana::r#type::v1::AccountDiff {
+                    data_len: 100,
+                    chunks: vec![],
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let cursor_file = NamedTempFile::new().unwrap();
+        let cursor_path = cursor_file.path().to_str().unwrap().to_string();
+
+        let mut reader = open_reader();
+        let out = try_open_fifo(path.to_str().unwrap()).unwrap().unwrap();
+        let mut printer = BlockPrinter::new(None, Some(out), None, PrinterOptions::default())
+            .with_reopen("", path.to_str().unwrap(), "", "");
+        printer
+            .print_init("Block", "AccountBlock", "TransactionBlock")
+            .unwrap();
+
+        // the reader got the previous slots: only the diff is sent
+        let (block_info, block) = test_slot(20);
+        printer
+            .print(&block_info, 10, block, diffed_block(20), &cursor_path)
+            .unwrap();
+        let accounts = read_accounts(&mut reader, 1);
+        assert!(accounts[0][0].data.is_empty());
+        assert!(accounts[0][0].data_diff.is_some());
+
+        // a new reader does not have the data the diff is made against: the full data is sent
+        drop(reader);
+        let (block_info, block) = test_slot(21);
+        printer
+            .print(&block_info, 10, block, diffed_block(21), &cursor_path)
+            .unwrap();
+        std::thread::sleep(REOPEN_MIN_BACKOFF);
+        let mut reader = open_reader();
+        let accounts = read_accounts(&mut reader, 1);
+        assert_eq!(accounts[0][0].data, vec![7; 100]);
+        assert_eq!(accounts[0][0].data_diff, None);
+
+        // State is told once, its next diffs are made against what the new reader got
+        assert!(printer.diff_base_lost());
+        assert!(!printer.diff_base_lost());
+        let (block_info, block) = test_slot(22);
+        printer
+            .print(&block_info, 10, block, diffed_block(22), &cursor_path)
+            .unwrap();
+        let accounts = read_accounts(&mut reader, 1);
+        assert!(accounts[0][0].data.is_empty());
+
+        // a replay always sends the full data
+        let (block_info, block) = test_slot(22);
+        printer
+            .replay(&block_info, 10, block, diffed_block(22))
+            .unwrap();
+        let accounts = read_accounts(&mut reader, 1);
+        assert_eq!(accounts[0][0].data, vec![7; 100]);
+    }
+
+    #[test]
+    fn test_account_block_compression() {
+        use std::io::Read;
+
+        let cursor_file = NamedTempFile::new().unwrap();
+        let cursor_path = cursor_file.path().to_str().unwrap().to_string();
+        let block_file = NamedTempFile::new().unwrap();
+        let account_file = NamedTempFile::new().unwrap();
+        let mut printer = BlockPrinter::new(
+            Some(block_file.reopen().unwrap()),
+            Some(account_file.reopen().unwrap()),
+            None,
+            PrinterOptions {
+                account_compression: Compression::Gzip,
+                ..Default::default()
+            },
+        );
+        printer
+            .print_init("Block", "AccountBlock", "TransactionBlock")
+            .unwrap();
+
+        let (block_info, block) = test_slot(20);
+        let account_block = test_account_block(20, 3, 1_000);
+        printer
+            .print(&block_info, 10, block, account_block.clone(), &cursor_path)
+            .unwrap();
+        wait_for_cursor(&cursor_path, "20");
+
+        let prefix = "FIRE BLOCK 20 hash20 19 hash19 10 0 ";
+        let content = std::fs::read_to_string(account_file.path()).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines[0], "FIRE INIT 3.0 AccountBlock compression=gzip");
+        let compressed = BASE64.decode(&lines[1][prefix.len()..]).unwrap();
+        assert!(compressed.len() < account_block.encoded_len());
+        let mut decoded = vec![];
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(
+            AccountBlock::decode(decoded.as_slice()).unwrap(),
+            account_block
+        );
+
+        // the block stream is not compressed
+        let content = std::fs::read_to_string(block_file.path()).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines[0], "FIRE INIT 3.0 Block");
+        let decoded =
+            Block::decode(BASE64.decode(&lines[1][prefix.len()..]).unwrap().as_slice()).unwrap();
+        assert_eq!(decoded.slot, 20);
+    }
+
+    #[test]
+    fn test_idle_marker() {
+        let cursor_file = NamedTempFile::new().unwrap();
+        let cursor_path = cursor_file.path().to_str().unwrap().to_string();
+        let block_file = NamedTempFile::new().unwrap();
+        let mut printer = BlockPrinter::new(
+            Some(block_file.reopen().unwrap()),
+            None,
+            None,
+            PrinterOptions {
+                idle_marker_interval: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+        );
+        printer
+            .print_init("Block", "AccountBlock", "TransactionBlock")
+            .unwrap();
+        let markers = |slot: u64| {
+            std::fs::read_to_string(block_file.path())
+                .unwrap()
+                .lines()
+                .filter(|line| line.starts_with(&format!("FIRE IDLE {} ", slot)))
+                .count()
+        };
+
+        // without any block, a marker is written every interval
+        std::thread::sleep(Duration::from_millis(300));
+        let idle = markers(0);
+        assert!((2..=6).contains(&idle), "{} markers", idle);
+
+        let (block_info, block) = test_slot(20);
+        printer
+            .print(
+                &block_info,
+                10,
+                block,
+                AccountBlock::default(),
+                &cursor_path,
+            )
+            .unwrap();
+        wait_for_cursor(&cursor_path, "20");
+        for _ in 0..100 {
+            if markers(20) > 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(markers(20), 1);
+
+        // the markers stop with the printer and never moved the cursor
+        drop(printer);
+        let content = std::fs::read_to_string(block_file.path()).unwrap();
+        assert_eq!(content.lines().next(), Some("FIRE INIT 3.0 Block"));
+        assert_eq!(
+            content
+                .lines()
+                .filter(|line| line.starts_with("FIRE BLOCK"))
+                .count(),
+            1
+        );
+        assert_eq!(read_cursor(&cursor_path), "20");
+    }
+
+    #[test]
+    fn test_print_combined_output() {
+        let cursor_file = NamedTempFile::new().unwrap();
+        let cursor_path = cursor_file.path().to_str().unwrap().to_string();
+        let combined_file = NamedTempFile::new().unwrap();
+
+        let mut printer = BlockPrinter::new(None, None, None, PrinterOptions::default())
+            .with_combined_output(combined_file.reopen().unwrap());
+        printer
+            .print_init("Block", "AccountBlock", "TransactionBlock")
+            .unwrap();
+
+        let block_info = BlockInfo {
+            slot: 20,
+            parent_slot: 19,
+            block_hash: "hash20".to_string(),
+            parent_hash: "hash19".to_string(),
+            ..Default::default()
+        };
+        let block = Block {
+            slot: 20,
+            blockhash: "hash20".to_string(),
+            transactions: vec![ConfirmedTransaction::default(); 3],
+            ..Default::default()
+        };
+        printer
+            .print(
+                &block_info,
+                10,
+                block,
+                test_account_block(20, 2, 4),
+                &cursor_path,
+            )
+            .unwrap();
+
+        // the combined output is a single sink, its one acknowledgement moves the cursor
+        wait_for_cursor(&cursor_path, "20");
+
+        let content = std::fs::read_to_string(combined_file.path()).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "FIRE INIT 3.0 Block");
+        assert_eq!(lines[1], "FIRE INIT 3.0 AccountBlock");
+        let prefix = "FIRE BLOCK 20 hash20 19 hash19 10 0 ";
+        let block_payload = lines[2].strip_prefix(prefix).unwrap();
+        let block_payload = block_payload.strip_prefix("Block ").unwrap();
+        let decoded = Block::decode(BASE64.decode(block_payload).unwrap().as_slice()).unwrap();
+        assert_eq!(decoded.transactions.len(), 3);
+        let account_payload = lines[3].strip_prefix(prefix).unwrap();
+        let account_payload = account_payload.strip_prefix("AccountBlock ").unwrap();
+        let decoded =
+            AccountBlock::decode(BASE64.decode(account_payload).unwrap().as_slice()).unwrap();
+        assert_eq!(decoded.slot, 20);
+        assert_eq!(decoded.accounts.len(), 2);
+    }
+
+    #[test]
+    fn test_skip_empty_account_blocks() {
+        let cursor_file = NamedTempFile::new().unwrap();
+        let cursor_path = cursor_file.path().to_str().unwrap().to_string();
+        let block_file = NamedTempFile::new().unwrap();
+        let account_file = NamedTempFile::new().unwrap();
+
+        let mut printer = BlockPrinter::new(
+            Some(block_file.reopen().unwrap()),
+            Some(account_file.reopen().unwrap()),
+            None,
+            PrinterOptions {
+                skip_empty_account_blocks: true,
+                ..Default::default()
+            },
+        );
+
+        for (slot, accounts) in [(20, 0), (21, 2), (22, 0)] {
+            let block_info = BlockInfo {
+                slot,
+                parent_slot: slot - 1,
+                block_hash: format!("hash{}", slot),
+                parent_hash: format!("hash{}", slot - 1),
+                ..Default::default()
+            };
+            let block = Block {
+                slot,
+                ..Default::default()
+            };
+            printer
+                .print(
+                    &block_info,
+                    10,
+                    block,
+                    test_account_block(slot, accounts, 4),
+                    &cursor_path,
+                )
+                .unwrap();
+            // the cursor does not wait for the skipped account blocks
+            wait_for_cursor(&cursor_path, &slot.to_string());
+        }
+
+        let content = std::fs::read_to_string(account_file.path()).unwrap();
+        let slots: Vec<&str> = content
+            .lines()
+            .map(|line| line.split(' ').nth(2).unwrap())
+            .collect();
+        assert_eq!(slots, vec!["21"]);
+        let content = std::fs::read_to_string(block_file.path()).unwrap();
+        assert_eq!(content.lines().count(), 3);
+    }
+
+    #[test]
+    fn test_account_outputs() {
+        use crate::config::AccountOutputConfig;
+        use solana_sdk::pubkey::Pubkey;
+
+        let cursor_file = NamedTempFile::new().unwrap();
+        let cursor_path = cursor_file.path().to_str().unwrap().to_string();
+        let account_file = NamedTempFile::new().unwrap();
+        let token_file = NamedTempFile::new().unwrap();
+        let program_file = NamedTempFile::new().unwrap();
+        let output = |name: &str, file: &NamedTempFile, owners: &[u8]| AccountOutput {
+            name: name.to_string(),
+            filter: AccountFilter::from_output_config(&AccountOutputConfig {
+                name: name.to_string(),
+                include_account_owners: owners
+                    .iter()
+                    .map(|owner| Pubkey::new_from_array([*owner; 32]).to_string())
+                    .collect(),
+                ..Default::default()
+            })
+            .unwrap(),
+            out: file.reopen().unwrap(),
+            path: String::new(),
+        };
+
+        let mut printer = BlockPrinter::new(
+            None,
+            Some(account_file.reopen().unwrap()),
+            None,
+            PrinterOptions {
+                output_format: OutputFormat::LengthPrefixedProto,
+                ..Default::default()
+            },
+        )
+        .with_account_outputs(vec![
+            output("tokens", &token_file, &[1]),
+            output("programs", &program_file, &[1, 2]),
+        ]);
+
+        let block_info = BlockInfo {
+            slot: 20,
+            parent_slot: 19,
+            block_hash: "hash20".to_string(),
+            parent_hash: "hash19".to_string(),
+            ..Default::default()
+        };
+        // accounts 10 and 11 are owned by 1, 20 by 2 and 30 by 3
+        let account_block = AccountBlock {
+            slot: 20,
+            accounts: [(10, 1), (11, 1), (20, 2), (30, 3)]
+                .into_iter()
+                .map(|(address, owner)| Account {
+                    address: vec![address; 32],
+                    owner: vec![owner; 32],
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        printer
+            .print(
+                &block_info,
+                10,
+                Block::default(),
+                account_block,
+                &cursor_path,
+            )
+            .unwrap();
+        // the cursor waits for every account output
+        wait_for_cursor(&cursor_path, "20");
+
+        let addresses = |file: &NamedTempFile| -> Vec<u8> {
+            let content = std::fs::read(file.path()).unwrap();
+            AccountBlock::decode(&content[4..])
+                .unwrap()
+                .accounts
+                .iter()
+                .map(|account| account.address[0])
+                .collect()
+        };
+        assert_eq!(addresses(&account_file), vec![10, 11, 20, 30]);
+        assert_eq!(addresses(&token_file), vec![10, 11]);
+        // an account matching both groups is in both outputs
+        assert_eq!(addresses(&program_file), vec![10, 11, 20]);
+    }
+
+    #[test]
+    fn test_account_outputs_independent() {
+        let cursor_file = NamedTempFile::new().unwrap();
+        let cursor_path = cursor_file.path().to_str().unwrap().to_string();
+        let account_file = NamedTempFile::new().unwrap();
+        let token_file = NamedTempFile::new().unwrap();
+        let mut printer = BlockPrinter::new(
+            None,
+            Some(account_file.reopen().unwrap()),
+            None,
+            PrinterOptions::default(),
+        )
+        .with_account_outputs(vec![AccountOutput {
+            name: "tokens".to_string(),
+            filter: AccountFilter::default(),
+            out: token_file.reopen().unwrap(),
+            path: String::new(),
+        }]);
+
+        // a write to out_account that does not return, as with a reader that stopped reading
+        let out_account = printer.out_account.clone().unwrap();
+        let stalled = out_account.lock();
+        let (block_info, block) = test_slot(20);
+        printer
+            .print(
+                &block_info,
+                10,
+                block,
+                AccountBlock::default(),
+                &cursor_path,
+            )
+            .unwrap();
+        let mut written = false;
+        for _ in 0..500 {
+            written = std::fs::metadata(token_file.path()).unwrap().len() > 0;
+            if written {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(written, "the other account output waited for out_account");
+        assert_eq!(read_cursor(&cursor_path), "");
+
+        drop(stalled);
+        wait_for_cursor(&cursor_path, "20");
+    }
+
+    #[test]
+    fn test_cursor_write_interval() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap().to_string();
+        let printer = BlockPrinter::new(
+            None,
+            None,
+            None,
+            PrinterOptions {
+                cursor_write_interval_slots: 5,
+                ..Default::default()
+            },
+        );
+
+        for slot in 1..=12 {
+            register_cursor(&printer.cursor, &path, slot, "hash", 0);
+            let expected = match slot {
+                1..=4 => "",
+                5..=9 => "5",
+                _ => "10",
+            };
+            assert_eq!(read_cursor(&path), expected, "after slot {}", slot);
+        }
 
-        // First pair - 1,1
-        write_cursor(&path, 1);
-        let content = std::fs::read_to_string(&path).unwrap_or_default();
-        assert_eq!(content, "");
-        write_cursor(&path, 1);
-        let content = std::fs::read_to_string(&path).unwrap();
-        assert_eq!(content, "1");
-
-        // Second pair - 2,3
-        write_cursor(&path, 2);
-        let content = std::fs::read_to_string(&path).unwrap();
-        assert_eq!(content, "1");
-        write_cursor(&path, 3);
-        let content = std::fs::read_to_string(&path).unwrap();
-        assert_eq!(content, "1");
-
-        // Third pair - 2,3
-        write_cursor(&path, 2);
-        let content = std::fs::read_to_string(&path).unwrap();
-        assert_eq!(content, "1");
-        write_cursor(&path, 3);
-        let content = std::fs::read_to_string(&path).unwrap();
-        assert_eq!(content, "3");
-
-        // Third pair - 4,4
-        write_cursor(&path, 4);
-        let content = std::fs::read_to_string(&path).unwrap();
-        assert_eq!(content, "3");
-        write_cursor(&path, 4);
-        let content = std::fs::read_to_string(&path).unwrap();
-        assert_eq!(content, "4");
+        // on unload, the latest slot is written even if the interval is not reached
+        printer.flush_cursor(&path);
+        assert_eq!(read_cursor(&path), "12");
+        std::fs::write(&path, "").unwrap();
+        printer.flush_cursor(&path);
+        assert_eq!(read_cursor(&path), "");
     }
 }
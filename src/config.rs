@@ -2,26 +2,570 @@ use agave_geyser_plugin_interface::geyser_plugin_interface::{
     GeyserPluginError, Result as PluginResult,
 };
 use serde::Deserialize;
-use serde_json;
+use solana_sdk::pubkey::Pubkey;
 
-use std::{fs::read_to_string, path::Path};
+use crate::block_printer::FireProtocol;
+use crate::rpc::{header_map, new_rpc_client};
+use crate::state;
+use reqwest::header::HeaderMap;
+use std::collections::HashMap;
+use std::{fmt, fs::read_to_string, path::Path, str::FromStr, time::Duration};
 
 #[derive(Deserialize, Default, Debug)]
 pub struct Config {
     //pub libpath: String,
+    #[serde(default)]
     pub local_rpc_client: RpcClientConfig,
+    #[serde(default)]
     pub remote_rpc_client: RpcClientConfig,
+    /// Use the RPC clients for the LIB, the missing blocks and the cursor check. Without them, the LIB only comes
+    /// from the rooted slots and the holes are not filled.
+    #[serde(default = "Config::default_use_rpc")]
+    pub use_rpc: bool,
     pub cursor_file: String,
+    /// Fetch the block of the cursor at startup and check that the first slot sent follows it.
+    #[serde(default)]
+    pub cursor_self_test: bool,
 
     #[serde(default)]
     pub noop: bool,
 
+    /// Deprecated, same as `commitment_trigger` set to `processed` when true.
     #[serde(default)]
     pub send_processed: bool,
+    /// Commitment at which a slot is sent, defaults to `processed` or `confirmed` following `send_processed`.
+    #[serde(default, alias = "commitment_to_emit")]
+    pub commitment_trigger: Option<Commitment>,
+    /// Commitment a sent slot needs to reach before the cursor moves over it, defaults to `commitment_trigger`.
+    #[serde(default)]
+    pub cursor_commitment: Option<Commitment>,
     #[serde(default)]
     pub log: ConfigLog,
     pub account_block_destination_file: String,
     pub block_destination_file: String,
+    #[serde(default)]
+    pub transaction_destination_file: String,
+    /// Directory where the data of the buffered account changes is kept until their slot is sent, in memory when empty.
+    #[serde(default)]
+    pub account_spill_dir: String,
+    /// Send the data of an account as a diff against the data last sent for it, when the diff is smaller.
+    #[serde(default)]
+    pub account_data_diff: bool,
+    /// Number of accounts whose last sent data is kept for account_data_diff.
+    #[serde(default = "Config::default_account_data_diff_max_accounts")]
+    pub account_data_diff_max_accounts: usize,
+    /// Bytes of last sent data kept for account_data_diff.
+    #[serde(default = "Config::default_account_data_diff_max_bytes")]
+    pub account_data_diff_max_bytes: usize,
+    /// Single output of both the blocks and the account blocks, instead of the two destination files.
+    #[serde(default, alias = "unified_output_file")]
+    pub combined_destination_file: String,
+    /// Create the destination FIFOs with mkfifo when they do not exist.
+    #[serde(default)]
+    pub create_fifo: bool,
+    /// Give up loading if no reader attached to a destination FIFO after this long, wait forever when unset.
+    #[serde(default)]
+    pub open_timeout_secs: Option<u64>,
+    /// Number of times a destination is opened again when it cannot be opened on load, none by default.
+    #[serde(default)]
+    pub open_retries: u32,
+    #[serde(default = "Config::default_open_retry_delay_ms")]
+    pub open_retry_delay_ms: u64,
+    /// Where the slots are sent, the destination files by default.
+    #[serde(default)]
+    pub sink: SinkConfig,
+    /// Reopen a destination FIFO whose reader went away once a new reader is attached, instead of dropping its slots until the restart.
+    #[serde(default)]
+    pub reopen_destinations: bool,
+
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    /// Layout of the `FIRE BLOCK` lines of the `fire_text` output format.
+    #[serde(default)]
+    pub fire_block_template: FireBlockTemplate,
+    /// Write the block height in the `FIRE BLOCK` lines, right before the payload.
+    #[serde(default)]
+    pub block_height_header: bool,
+
+    #[serde(default)]
+    pub transaction_order: TransactionOrder,
+
+    /// Only keep the accounts owned by one of these programs (base58), empty keeps everything.
+    #[serde(default)]
+    pub include_account_owners: Vec<String>,
+    /// Only keep these accounts (base58), empty keeps everything.
+    #[serde(default)]
+    pub include_account_pubkeys: Vec<String>,
+    /// How the two lists above are combined when both are set.
+    #[serde(default)]
+    pub account_filter_mode: AccountFilterMode,
+    /// More account outputs, each written with the accounts matching its own filter.
+    #[serde(default)]
+    pub account_outputs: Vec<AccountOutputConfig>,
+    /// How much of each transaction is written in the blocks.
+    #[serde(default, alias = "transaction_detail")]
+    pub block_detail: BlockDetail,
+    /// Which account keys are written in the transaction messages.
+    #[serde(default)]
+    pub account_keys_mode: AccountKeysMode,
+    /// Append the checksum of the payload to each `FIRE BLOCK` line.
+    #[serde(default)]
+    pub payload_checksum: bool,
+    /// Do not write the account blocks of the slots without any account change.
+    #[serde(default)]
+    pub skip_empty_account_blocks: bool,
+    /// Write a `FIRE IDLE` line to the outputs when no slot was written for this long, never when unset.
+    #[serde(default)]
+    pub idle_marker_interval_ms: Option<u64>,
+    /// Report the writers when no write finished for this long while some are queued, never when unset.
+    #[serde(default)]
+    pub writer_stall_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub writer_stall_action: WriterStallAction,
+    /// Version of the FIRE protocol written in the `FIRE INIT` lines instead of the current one, a 3.x version.
+    #[serde(default, alias = "fire_protocol_version")]
+    pub protocol_version: Option<String>,
+    /// Share the identical data of the pending writes of an account, each slot keeps its own write.
+    /// Data that changes between the writes is kept once per slot.
+    #[serde(default)]
+    pub compact_account_changes: bool,
+    /// Compare the hash sent for each slot with the finalized one from the local RPC when the slot is rooted.
+    #[serde(default)]
+    pub verify_blockhash_on_lib: bool,
+    /// Fetch a block missing from geyser from a remote RPC too, and keep the finalized one when the hashes differ.
+    #[serde(default)]
+    pub rpc_cross_check: bool,
+    /// Send a placeholder block, with `skipped: true`, for each slot skipped by the cluster.
+    #[serde(default)]
+    pub emit_skipped_slots: bool,
+    /// Request the rewards with the blocks fetched from the RPC and send them in the blocks.
+    #[serde(default = "Config::default_include_block_rewards")]
+    pub include_block_rewards: bool,
+    /// Slots of account changes kept before the first confirmed slot, while neither a cursor nor a first block is known.
+    #[serde(default = "Config::default_retention_slots")]
+    pub retention_slots: u64,
+    /// Slots the sent slots are remembered for, so that late notifications about them are skipped.
+    #[serde(default = "Config::default_processed_retention_slots")]
+    pub processed_retention_slots: u64,
+    /// Evict the oldest unconfirmed slots when more slots than this are buffered, unbounded when unset.
+    #[serde(default)]
+    pub max_buffered_slots: Option<usize>,
+    /// Fill the kind, message, instruction index and custom code of the transaction errors next to their bincode bytes.
+    #[serde(default = "Config::default_decode_transaction_errors")]
+    pub decode_transaction_errors: bool,
+    /// Write 0 as the `ui_amount` of the token balances, it is an f64 that loses precision: consumers must
+    /// read the exact `amount` and `decimals` (or `ui_amount_string`) instead.
+    #[serde(default)]
+    pub drop_lossy_ui_amount: bool,
+    /// Flag the account writes whose owner differs from the previous write of the account.
+    #[serde(default)]
+    pub detect_owner_changes: bool,
+    /// Skip an account update when its data did not change, set to false to get every write version.
+    #[serde(default = "Config::default_dedup_accounts")]
+    pub dedup_accounts: bool,
+    /// Keep the later of two writes of an account with the same write version in a slot, instead of the earlier one.
+    #[serde(default)]
+    pub prefer_later_equal_write_version: bool,
+    /// Hash of the account data used to skip unchanged accounts, only needs to be consistent within the process.
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
+    /// Log every change of these accounts (base58) when their slot is sent, for debugging.
+    #[serde(default, alias = "debug_watch_accounts")]
+    pub watch_accounts: Vec<String>,
+    /// Keep the signature of the transaction that wrote an account in its Account, when geyser gives it.
+    #[serde(default)]
+    pub account_txn_signature: bool,
+    /// Number of the last sent slots kept in memory so that they can be replayed, none by default.
+    #[serde(default)]
+    pub replay_buffer_slots: usize,
+    /// File polled on every rooted slot for a `FROM_SLOT TO_SLOT` replay request, removed once read.
+    #[serde(default)]
+    pub replay_control_file: String,
+    /// Number of unsent slots before the parent of a block for which the ancestors are waited for before being
+    /// fetched from the RPC, none by default.
+    #[serde(default)]
+    pub max_natural_gap: u64,
+    /// When an account write is flagged as `deleted`.
+    #[serde(default)]
+    pub deletion_detection: DeletionDetection,
+    /// Order of the accounts in an AccountBlock.
+    #[serde(default)]
+    pub account_sort: AccountSort,
+    /// Accounts sent in an AccountBlock.
+    #[serde(default)]
+    pub account_block_mode: AccountBlockMode,
+    /// Compression of the payloads of the block stream.
+    #[serde(default)]
+    pub block_compression: Compression,
+    /// Compression of the payloads of the account block streams.
+    #[serde(default)]
+    pub account_block_compression: Compression,
+    /// Encoding of the account addresses written to the logs.
+    #[serde(default)]
+    pub log_address_encoding: AddressEncoding,
+
+    /// Write the accounts loaded at startup to the account output as snapshot AccountBlocks.
+    #[serde(default)]
+    pub emit_startup_snapshot: bool,
+    /// Number of startup accounts written per snapshot AccountBlock, bounds the memory they use.
+    #[serde(default = "Config::default_startup_snapshot_chunk_accounts")]
+    pub startup_snapshot_chunk_accounts: usize,
+
+    /// Accounts with less data than this are skipped (deletions are always kept).
+    #[serde(default)]
+    pub min_account_data_bytes: Option<usize>,
+    /// Accounts with more data than this are dropped or truncated, see `oversized_accounts`.
+    #[serde(default)]
+    pub max_account_data_bytes: Option<usize>,
+    #[serde(default)]
+    pub oversized_accounts: OversizedAccountAction,
+
+    /// Timeout of each RPC request.
+    #[serde(default = "Config::default_rpc_timeout_ms")]
+    pub rpc_timeout_ms: u64,
+    /// Number of times a failed RPC call is attempted again, on each client.
+    #[serde(default = "Config::default_rpc_max_retries")]
+    pub rpc_max_retries: u32,
+    /// Wait before the first retry, doubled after each attempt.
+    #[serde(default = "Config::default_rpc_retry_backoff_ms")]
+    pub rpc_retry_backoff_ms: u64,
+
+    /// Ask the local RPC for the finalized slot every N seconds and move the LIB to it when it is higher,
+    /// in case rooted notifications are missed. Disabled when unset.
+    #[serde(default)]
+    pub lib_refresh_interval_secs: Option<u64>,
+
+    /// Stop sending blocks after this slot (or the first one after it, if it is skipped), for bounded backfills.
+    #[serde(default)]
+    pub stop_at_slot: Option<u64>,
+
+    /// Persist the cursor every N processed slots (and on unload).
+    #[serde(default = "Config::default_cursor_write_interval_slots")]
+    pub cursor_write_interval_slots: u64,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// `FIRE BLOCK ...` text lines with a base64 payload, read by firecore
+    #[default]
+    FireText,
+    /// 4-byte big-endian length followed by the raw protobuf bytes, no text header
+    LengthPrefixedProto,
+    /// one JSON object per line, for debugging only
+    #[cfg(feature = "json-lines")]
+    JsonLines,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum SinkConfig {
+    /// the destination files
+    #[default]
+    Fifo,
+    /// each block, prost-encoded and keyed by its slot, to a Kafka topic
+    #[cfg(feature = "kafka")]
+    Kafka { brokers: String, topic: String },
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Commitment {
+    /// as soon as the slot is processed by this validator
+    Processed,
+    /// once the slot is confirmed by the cluster
+    #[default]
+    Confirmed,
+}
+
+/// Header field a `{...}` placeholder of `fire_block_template` stands for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemplateField {
+    Slot,
+    BlockHash,
+    ParentSlot,
+    ParentHash,
+    Lib,
+    TimestampNano,
+    /// 0 when the block height is unknown
+    BlockHeight,
+    Payload,
+}
+
+impl FromStr for TemplateField {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Ok(match name {
+            "slot" => TemplateField::Slot,
+            "block_hash" => TemplateField::BlockHash,
+            "parent_slot" => TemplateField::ParentSlot,
+            "parent_hash" => TemplateField::ParentHash,
+            "lib" => TemplateField::Lib,
+            "timestamp_nano" => TemplateField::TimestampNano,
+            "block_height" => TemplateField::BlockHeight,
+            "payload" => TemplateField::Payload,
+            _ => return Err(format!("unknown placeholder {{{}}}", name)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplatePart {
+    Text(String),
+    Field(TemplateField),
+}
+
+/// `FIRE BLOCK` line layout, a string with `{field}` placeholders parsed once at load.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct FireBlockTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+impl FireBlockTemplate {
+    pub const DEFAULT: &'static str =
+        "FIRE BLOCK {slot} {block_hash} {parent_slot} {parent_hash} {lib} {timestamp_nano} {payload}";
+
+    pub fn parse(template: &str) -> Result<Self, String> {
+        if template.contains('\n') {
+            return Err("fire_block_template cannot contain a new line".to_string());
+        }
+        let mut parts = vec![];
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(TemplatePart::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unterminated placeholder in {:?}", template))?;
+            parts.push(TemplatePart::Field(rest[start + 1..start + end].parse()?));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Text(rest.to_string()));
+        }
+
+        let payloads = parts
+            .iter()
+            .filter(|part| **part == TemplatePart::Field(TemplateField::Payload))
+            .count();
+        if payloads != 1 {
+            return Err(format!(
+                "fire_block_template needs exactly one {{payload}}, found {} in {:?}",
+                payloads, template
+            ));
+        }
+        Ok(FireBlockTemplate { parts })
+    }
+
+    pub fn parts(&self) -> &[TemplatePart] {
+        &self.parts
+    }
+
+    /// The same template with `{message_type} ` right before the payload, to tell the lines of a combined output apart.
+    pub fn with_message_type(&self, message_type: &str) -> Self {
+        let mut parts = self.parts.clone();
+        let payload = parts
+            .iter()
+            .position(|part| *part == TemplatePart::Field(TemplateField::Payload))
+            .expect("a parsed template has a payload");
+        parts.insert(payload, TemplatePart::Text(format!("{} ", message_type)));
+        FireBlockTemplate { parts }
+    }
+
+    /// The same template with `{block_height} ` right before the payload, unless it already has it.
+    pub fn with_block_height(&self) -> Self {
+        let mut parts = self.parts.clone();
+        if !parts.contains(&TemplatePart::Field(TemplateField::BlockHeight)) {
+            let payload = parts
+                .iter()
+                .position(|part| *part == TemplatePart::Field(TemplateField::Payload))
+                .expect("a parsed template has a payload");
+            parts.splice(
+                payload..payload,
+                [
+                    TemplatePart::Field(TemplateField::BlockHeight),
+                    TemplatePart::Text(" ".to_string()),
+                ],
+            );
+        }
+        FireBlockTemplate { parts }
+    }
+}
+
+impl Default for FireBlockTemplate {
+    fn default() -> Self {
+        FireBlockTemplate::parse(FireBlockTemplate::DEFAULT).expect("default template is valid")
+    }
+}
+
+impl TryFrom<String> for FireBlockTemplate {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        FireBlockTemplate::parse(&template)
+    }
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionOrder {
+    /// Order of execution reported by geyser, the first signature breaks ties
+    #[default]
+    GeyserIndex,
+    /// First signature bytes, the geyser index breaks ties
+    Signature,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OversizedAccountAction {
+    /// the account change is not sent at all
+    #[default]
+    Drop,
+    /// the account change is sent with empty data and `truncated` set
+    Truncate,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountKeysMode {
+    /// the keys of the message only, the addresses loaded from lookup tables are left out
+    #[default]
+    StaticOnly,
+    /// the keys of the message followed by the loaded writable then readonly addresses,
+    /// the list the instruction account indexes point into
+    Full,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockDetail {
+    /// the whole transaction with its message
+    #[default]
+    Full,
+    /// the signatures and the status meta only, the message is left out
+    #[serde(alias = "signatures")]
+    SignaturesOnly,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HashAlgorithm {
+    /// fastest, but its output depends on the CPU target
+    #[default]
+    Gxhash,
+    /// xxh3, same output on every target
+    Xxhash,
+    /// slower, same output on every target
+    Blake3,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    #[default]
+    None,
+    /// the protobuf payload is gzipped before being encoded
+    Gzip,
+}
+
+impl Compression {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WriterStallAction {
+    /// log an error, again every timeout while the writers stay stalled
+    #[default]
+    Log,
+    /// log an error and exit the process, to be restarted from the cursor
+    Exit,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletionDetection {
+    /// an account without lamports is deleted
+    #[default]
+    LamportsOnly,
+    /// an account is only deleted without lamports, without data and back to the system program
+    Strict,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountSort {
+    /// account address
+    #[default]
+    Address,
+    /// owner, then address: the accounts of a program are next to each other
+    OwnerAddress,
+    /// write version of the change kept for the slot, then address
+    WriteVersion,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountBlockMode {
+    /// every account change kept for the slot
+    #[default]
+    All,
+    /// only the accounts whose owner changed, needs detect_owner_changes
+    OwnerChangedOnly,
+}
+
+impl AccountSort {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AccountSort::Address => "address",
+            AccountSort::OwnerAddress => "owner_address",
+            AccountSort::WriteVersion => "write_version",
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressEncoding {
+    /// lowercase hex of the address bytes
+    Hex,
+    /// the encoding used by the explorers and the CLI
+    #[default]
+    Base58,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountFilterMode {
+    /// keep the accounts matching the owners or the pubkeys
+    #[default]
+    Union,
+    /// keep the accounts matching both the owners and the pubkeys
+    Intersection,
+}
+
+/// An account output of its own, for the accounts of a group of owners or pubkeys.
+/// The filter applies on top of the global include_account_owners and include_account_pubkeys.
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AccountOutputConfig {
+    pub name: String,
+    pub destination_file: String,
+    #[serde(default)]
+    pub include_account_owners: Vec<String>,
+    #[serde(default)]
+    pub include_account_pubkeys: Vec<String>,
+    #[serde(default)]
+    pub account_filter_mode: AccountFilterMode,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -46,20 +590,1319 @@ impl ConfigLog {
     }
 }
 
-#[derive(Deserialize, Default, Debug)]
+#[derive(Deserialize, Default, PartialEq, Eq)]
 pub struct RpcClientConfig {
+    #[serde(default)]
     pub endpoint: String,
+    /// More endpoints, tried in order after `endpoint` (only used by remote_rpc_client).
+    #[serde(default)]
+    pub endpoints: Vec<String>,
+    /// Headers sent with every request, to every endpoint of the client.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Token sent as `Authorization: Bearer {auth_token}` with every request.
+    #[serde(default)]
+    pub auth_token: Option<String>,
+}
+
+// the auth token is not printed
+impl fmt::Debug for RpcClientConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcClientConfig")
+            .field("endpoint", &self.endpoint)
+            .field("endpoints", &self.endpoints)
+            .field("headers", &self.headers)
+            .field(
+                "auth_token",
+                &self.auth_token.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+impl RpcClientConfig {
+    /// The headers and the auth token, checked.
+    pub fn header_map(&self) -> Result<HeaderMap, String> {
+        header_map(&self.headers, self.auth_token.as_deref())
+    }
+
+    /// `endpoint` (if set) followed by `endpoints`.
+    pub fn all_endpoints(&self) -> Vec<String> {
+        std::iter::once(&self.endpoint)
+            .filter(|endpoint| !endpoint.is_empty())
+            .chain(self.endpoints.iter())
+            .cloned()
+            .collect()
+    }
 }
 
 impl Config {
-    fn load_from_str(config: &str) -> PluginResult<Self> {
-        serde_json::from_str(config).map_err(|error| GeyserPluginError::ConfigFileReadError {
-            msg: error.to_string(),
+    fn default_cursor_write_interval_slots() -> u64 {
+        1
+    }
+
+    fn default_startup_snapshot_chunk_accounts() -> usize {
+        state::STARTUP_SNAPSHOT_CHUNK_ACCOUNTS
+    }
+
+    fn default_retention_slots() -> u64 {
+        state::RETENTION_SLOTS
+    }
+
+    fn default_account_data_diff_max_accounts() -> usize {
+        100_000
+    }
+
+    fn default_account_data_diff_max_bytes() -> usize {
+        1 << 30
+    }
+
+    fn default_processed_retention_slots() -> u64 {
+        state::PROCESSED_RETENTION_SLOTS
+    }
+
+    fn default_dedup_accounts() -> bool {
+        true
+    }
+
+    fn default_include_block_rewards() -> bool {
+        true
+    }
+
+    fn default_use_rpc() -> bool {
+        true
+    }
+
+    fn default_open_retry_delay_ms() -> u64 {
+        1_000
+    }
+
+    fn default_decode_transaction_errors() -> bool {
+        true
+    }
+
+    fn default_rpc_timeout_ms() -> u64 {
+        30_000
+    }
+
+    // the calls are made under the state lock, in the geyser callbacks: no retries unless asked for
+    fn default_rpc_max_retries() -> u32 {
+        0
+    }
+
+    fn default_rpc_retry_backoff_ms() -> u64 {
+        200
+    }
+
+    /// Commitment at which the slots are sent, `send_processed` is only used when it is not set.
+    pub fn commitment_trigger(&self) -> Commitment {
+        self.commitment_trigger.unwrap_or(if self.send_processed {
+            Commitment::Processed
+        } else {
+            Commitment::Confirmed
         })
     }
 
+    /// Layout of the `FIRE BLOCK` lines, `fire_block_template` extended with the block height when asked for.
+    pub fn effective_fire_block_template(&self) -> FireBlockTemplate {
+        if self.block_height_header {
+            self.fire_block_template.with_block_height()
+        } else {
+            self.fire_block_template.clone()
+        }
+    }
+
+    /// Commitment needed for the cursor to move over a sent slot.
+    pub fn cursor_commitment(&self) -> Commitment {
+        self.cursor_commitment
+            .unwrap_or_else(|| self.commitment_trigger())
+    }
+
+    /// Capabilities announced in the `FIRE INIT` lines, one `key=value` per setting that changes the stream.
+    /// Nothing is added with the default settings, so that the init lines stay the ones the readers expect.
+    pub fn init_capabilities(&self) -> Vec<String> {
+        let mut capabilities = vec![];
+        if self.block_detail == BlockDetail::SignaturesOnly {
+            capabilities.push("detail=signatures_only".to_string());
+        }
+        if self.account_keys_mode == AccountKeysMode::Full {
+            capabilities.push("account_keys=full".to_string());
+        }
+        if self.payload_checksum {
+            capabilities.push("checksum=xxh3".to_string());
+        }
+        if self.block_height_header {
+            capabilities.push("header=block_height".to_string());
+        }
+        if self.drop_lossy_ui_amount {
+            capabilities.push("ui_amount=dropped".to_string());
+        }
+        if self.emit_skipped_slots {
+            capabilities.push("skipped_slots=placeholder".to_string());
+        }
+        if !self.include_block_rewards {
+            capabilities.push("rewards=omitted".to_string());
+        }
+        if self.account_data_diff {
+            capabilities.push("account_data=diff".to_string());
+        }
+        if !self.combined_destination_file.is_empty() {
+            capabilities.push("header=message_type".to_string());
+        }
+        if self.deletion_detection == DeletionDetection::Strict {
+            capabilities.push("deletion=strict".to_string());
+        }
+        if self.account_sort != AccountSort::Address {
+            capabilities.push(format!("account_sort={}", self.account_sort.as_str()));
+        }
+        if self.account_block_mode == AccountBlockMode::OwnerChangedOnly {
+            capabilities.push("account_block_mode=owner_changed_only".to_string());
+        }
+        if self.idle_marker_interval_ms.is_some() {
+            capabilities.push("idle_marker".to_string());
+        }
+        if self.max_natural_gap > 0 {
+            capabilities.push(format!("max_natural_gap={}", self.max_natural_gap));
+        }
+        capabilities
+    }
+
+    fn load_from_str(config: &str) -> PluginResult<Self> {
+        let config: Self = serde_json::from_str(config).map_err(|error| {
+            GeyserPluginError::ConfigFileReadError {
+                msg: error.to_string(),
+            }
+        })?;
+        config.checked()
+    }
+
+    fn load_from_toml_str(config: &str) -> PluginResult<Self> {
+        let config: Self =
+            toml::from_str(config).map_err(|error| GeyserPluginError::ConfigFileReadError {
+                msg: error.to_string(),
+            })?;
+        config.checked()
+    }
+
+    /// Expands the environment variables and checks the fields that depend on each other, whatever the file format.
+    fn checked(mut self) -> PluginResult<Self> {
+        let config = &mut self;
+        config.expand_env_vars()?;
+        config
+            .check_combined_destination()
+            .and_then(|()| config.check_compression())
+            .and_then(|()| config.check_sink())
+            .and_then(|()| config.check_account_block_mode())
+            .and_then(|()| config.check_account_data_diff())
+            .map_err(|msg| GeyserPluginError::ConfigFileReadError { msg })?;
+        for (field, rpc_client) in [
+            ("local_rpc_client", &config.local_rpc_client),
+            ("remote_rpc_client", &config.remote_rpc_client),
+        ] {
+            rpc_client
+                .header_map()
+                .map_err(|msg| GeyserPluginError::ConfigFileReadError {
+                    msg: format!("{}: {}", field, msg),
+                })?;
+        }
+        if let Some(version) = &config.protocol_version {
+            FireProtocol::parse(version)
+                .map_err(|msg| GeyserPluginError::ConfigFileReadError { msg })?;
+        }
+        Ok(self)
+    }
+
+    /// The combined output replaces the block and account block outputs, and only the FIRE lines can tell its types apart.
+    fn check_combined_destination(&self) -> Result<(), String> {
+        if self.combined_destination_file.is_empty() {
+            return Ok(());
+        }
+        if !self.block_destination_file.is_empty()
+            || !self.account_block_destination_file.is_empty()
+        {
+            return Err("combined_destination_file replaces block_destination_file and account_block_destination_file, leave them empty".to_string());
+        }
+        if self.output_format != OutputFormat::FireText {
+            return Err(format!(
+                "combined_destination_file needs the fire_text output format, not {:?}",
+                self.output_format
+            ));
+        }
+        Ok(())
+    }
+
+    /// The JSON lines carry the payload as JSON, there are no bytes to compress.
+    fn check_compression(&self) -> Result<(), String> {
+        #[cfg(feature = "json-lines")]
+        if self.output_format == OutputFormat::JsonLines
+            && (self.block_compression != Compression::None
+                || self.account_block_compression != Compression::None)
+        {
+            return Err("block_compression and account_block_compression need the fire_text or length_prefixed_proto output format".to_string());
+        }
+        Ok(())
+    }
+
+    /// The owner changes are only known when they are tracked.
+    fn check_account_block_mode(&self) -> Result<(), String> {
+        if self.account_block_mode == AccountBlockMode::OwnerChangedOnly
+            && !self.detect_owner_changes
+        {
+            return Err(
+                "account_block_mode owner_changed_only needs detect_owner_changes".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// The account diffs are made against the data last sent, a processed slot that ends up on a dead fork would be the base.
+    fn check_account_data_diff(&self) -> Result<(), String> {
+        if self.account_data_diff && self.commitment_trigger() == Commitment::Processed {
+            return Err(
+                "account_data_diff needs the confirmed commitment_trigger, the processed slots can be forked out"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// A broker sink only publishes the blocks, the destination files are not written.
+    fn check_sink(&self) -> Result<(), String> {
+        match &self.sink {
+            SinkConfig::Fifo => Ok(()),
+            #[cfg(feature = "kafka")]
+            SinkConfig::Kafka { brokers, topic } => {
+                if brokers.is_empty() || topic.is_empty() {
+                    return Err("the kafka sink needs brokers and a topic".to_string());
+                }
+                if !self.block_destination_file.is_empty()
+                    || !self.account_block_destination_file.is_empty()
+                    || !self.transaction_destination_file.is_empty()
+                    || !self.combined_destination_file.is_empty()
+                    || !self.account_outputs.is_empty()
+                {
+                    return Err("the kafka sink replaces the destination files and account_outputs, leave them empty".to_string());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Expands `$VAR` and `${VAR}` references in the path and endpoint fields.
+    fn expand_env_vars(&mut self) -> PluginResult<()> {
+        for value in [
+            &mut self.cursor_file,
+            &mut self.block_destination_file,
+            &mut self.account_block_destination_file,
+            &mut self.transaction_destination_file,
+            &mut self.combined_destination_file,
+            &mut self.account_spill_dir,
+            &mut self.replay_control_file,
+            &mut self.local_rpc_client.endpoint,
+            &mut self.remote_rpc_client.endpoint,
+        ] {
+            *value =
+                expand_env(value).map_err(|msg| GeyserPluginError::ConfigFileReadError { msg })?;
+        }
+        // the tokens are better kept out of the config file
+        for rpc_client in [&mut self.local_rpc_client, &mut self.remote_rpc_client] {
+            for value in rpc_client
+                .auth_token
+                .iter_mut()
+                .chain(rpc_client.headers.values_mut())
+            {
+                *value = expand_env(value)
+                    .map_err(|msg| GeyserPluginError::ConfigFileReadError { msg })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Names of the fields that differ from `other` but can only be applied by restarting the validator.
+    pub fn static_changes(&self, other: &Config) -> Vec<&'static str> {
+        let mut changes = vec![];
+        if self.local_rpc_client != other.local_rpc_client {
+            changes.push("local_rpc_client");
+        }
+        if self.remote_rpc_client != other.remote_rpc_client {
+            changes.push("remote_rpc_client");
+        }
+        if self.cursor_file != other.cursor_file {
+            changes.push("cursor_file");
+        }
+        if self.cursor_self_test != other.cursor_self_test {
+            changes.push("cursor_self_test");
+        }
+        if self.noop != other.noop {
+            changes.push("noop");
+        }
+        if self.account_block_destination_file != other.account_block_destination_file {
+            changes.push("account_block_destination_file");
+        }
+        if self.block_destination_file != other.block_destination_file {
+            changes.push("block_destination_file");
+        }
+        if self.transaction_destination_file != other.transaction_destination_file {
+            changes.push("transaction_destination_file");
+        }
+        if self.combined_destination_file != other.combined_destination_file {
+            changes.push("combined_destination_file");
+        }
+        if self.account_spill_dir != other.account_spill_dir {
+            changes.push("account_spill_dir");
+        }
+        if self.account_data_diff != other.account_data_diff {
+            changes.push("account_data_diff");
+        }
+        if self.account_data_diff_max_accounts != other.account_data_diff_max_accounts {
+            changes.push("account_data_diff_max_accounts");
+        }
+        if self.account_data_diff_max_bytes != other.account_data_diff_max_bytes {
+            changes.push("account_data_diff_max_bytes");
+        }
+        if self.create_fifo != other.create_fifo {
+            changes.push("create_fifo");
+        }
+        if self.reopen_destinations != other.reopen_destinations {
+            changes.push("reopen_destinations");
+        }
+        if self.sink != other.sink {
+            changes.push("sink");
+        }
+        if self.open_retries != other.open_retries {
+            changes.push("open_retries");
+        }
+        if self.open_retry_delay_ms != other.open_retry_delay_ms {
+            changes.push("open_retry_delay_ms");
+        }
+        if self.open_timeout_secs != other.open_timeout_secs {
+            changes.push("open_timeout_secs");
+        }
+        if self.output_format != other.output_format {
+            changes.push("output_format");
+        }
+        if self.fire_block_template != other.fire_block_template {
+            changes.push("fire_block_template");
+        }
+        if self.block_height_header != other.block_height_header {
+            changes.push("block_height_header");
+        }
+        if self.transaction_order != other.transaction_order {
+            changes.push("transaction_order");
+        }
+        if self.emit_startup_snapshot != other.emit_startup_snapshot {
+            changes.push("emit_startup_snapshot");
+        }
+        if self.startup_snapshot_chunk_accounts != other.startup_snapshot_chunk_accounts {
+            changes.push("startup_snapshot_chunk_accounts");
+        }
+        if self.min_account_data_bytes != other.min_account_data_bytes {
+            changes.push("min_account_data_bytes");
+        }
+        if self.max_account_data_bytes != other.max_account_data_bytes {
+            changes.push("max_account_data_bytes");
+        }
+        if self.oversized_accounts != other.oversized_accounts {
+            changes.push("oversized_accounts");
+        }
+        if self.rpc_timeout_ms != other.rpc_timeout_ms {
+            changes.push("rpc_timeout_ms");
+        }
+        if self.rpc_max_retries != other.rpc_max_retries {
+            changes.push("rpc_max_retries");
+        }
+        if self.rpc_retry_backoff_ms != other.rpc_retry_backoff_ms {
+            changes.push("rpc_retry_backoff_ms");
+        }
+        if self.cursor_write_interval_slots != other.cursor_write_interval_slots {
+            changes.push("cursor_write_interval_slots");
+        }
+        if self.skip_empty_account_blocks != other.skip_empty_account_blocks {
+            changes.push("skip_empty_account_blocks");
+        }
+        if self.payload_checksum != other.payload_checksum {
+            changes.push("payload_checksum");
+        }
+        if self.idle_marker_interval_ms != other.idle_marker_interval_ms {
+            changes.push("idle_marker_interval_ms");
+        }
+        if self.writer_stall_timeout_secs != other.writer_stall_timeout_secs {
+            changes.push("writer_stall_timeout_secs");
+        }
+        if self.writer_stall_action != other.writer_stall_action {
+            changes.push("writer_stall_action");
+        }
+        if self.protocol_version != other.protocol_version {
+            changes.push("protocol_version");
+        }
+        if self.decode_transaction_errors != other.decode_transaction_errors {
+            changes.push("decode_transaction_errors");
+        }
+        if self.drop_lossy_ui_amount != other.drop_lossy_ui_amount {
+            changes.push("drop_lossy_ui_amount");
+        }
+        if self.account_keys_mode != other.account_keys_mode {
+            changes.push("account_keys_mode");
+        }
+        if self.block_detail != other.block_detail {
+            changes.push("block_detail");
+        }
+        if self.dedup_accounts != other.dedup_accounts {
+            changes.push("dedup_accounts");
+        }
+        if self.prefer_later_equal_write_version != other.prefer_later_equal_write_version {
+            changes.push("prefer_later_equal_write_version");
+        }
+        if self.verify_blockhash_on_lib != other.verify_blockhash_on_lib {
+            changes.push("verify_blockhash_on_lib");
+        }
+        if self.rpc_cross_check != other.rpc_cross_check {
+            changes.push("rpc_cross_check");
+        }
+        if self.emit_skipped_slots != other.emit_skipped_slots {
+            changes.push("emit_skipped_slots");
+        }
+        if self.include_block_rewards != other.include_block_rewards {
+            changes.push("include_block_rewards");
+        }
+        if self.retention_slots != other.retention_slots {
+            changes.push("retention_slots");
+        }
+        if self.processed_retention_slots != other.processed_retention_slots {
+            changes.push("processed_retention_slots");
+        }
+        if self.max_buffered_slots != other.max_buffered_slots {
+            changes.push("max_buffered_slots");
+        }
+        if self.detect_owner_changes != other.detect_owner_changes {
+            changes.push("detect_owner_changes");
+        }
+        if self.account_outputs != other.account_outputs {
+            changes.push("account_outputs");
+        }
+        if self.compact_account_changes != other.compact_account_changes {
+            changes.push("compact_account_changes");
+        }
+        if self.watch_accounts != other.watch_accounts {
+            changes.push("watch_accounts");
+        }
+        if self.account_txn_signature != other.account_txn_signature {
+            changes.push("account_txn_signature");
+        }
+        if self.replay_buffer_slots != other.replay_buffer_slots {
+            changes.push("replay_buffer_slots");
+        }
+        if self.max_natural_gap != other.max_natural_gap {
+            changes.push("max_natural_gap");
+        }
+        if self.deletion_detection != other.deletion_detection {
+            changes.push("deletion_detection");
+        }
+        if self.account_sort != other.account_sort {
+            changes.push("account_sort");
+        }
+        if self.account_block_mode != other.account_block_mode {
+            changes.push("account_block_mode");
+        }
+        if self.block_compression != other.block_compression {
+            changes.push("block_compression");
+        }
+        if self.account_block_compression != other.account_block_compression {
+            changes.push("account_block_compression");
+        }
+        if self.log_address_encoding != other.log_address_encoding {
+            changes.push("log_address_encoding");
+        }
+        if self.stop_at_slot != other.stop_at_slot {
+            changes.push("stop_at_slot");
+        }
+        if self.lib_refresh_interval_secs != other.lib_refresh_interval_secs {
+            changes.push("lib_refresh_interval_secs");
+        }
+        if self.use_rpc != other.use_rpc {
+            changes.push("use_rpc");
+        }
+        if self.cursor_commitment() != other.cursor_commitment() {
+            changes.push("cursor_commitment");
+        }
+        changes
+    }
+
+    /// Loads a TOML file when its extension is `.toml`, a JSON file otherwise.
     pub fn load_from_file<P: AsRef<Path>>(file: P) -> PluginResult<Self> {
+        let is_toml = file
+            .as_ref()
+            .extension()
+            .is_some_and(|extension| extension == "toml");
         let config = read_to_string(file).map_err(GeyserPluginError::ConfigFileOpenError)?;
-        Self::load_from_str(&config)
+        if is_toml {
+            Self::load_from_toml_str(&config)
+        } else {
+            Self::load_from_str(&config)
+        }
+    }
+
+    /// Loads the config file and lists everything that would make `on_load` fail or misbehave.
+    pub fn check_file<P: AsRef<Path>>(file: P) -> Vec<ConfigProblem> {
+        match Self::load_from_file(file) {
+            Ok(config) => config.validate(),
+            Err(err) => vec![ConfigProblem::new("config", err.to_string())],
+        }
+    }
+
+    /// Checks the required fields, the destination directories and the pubkey lists,
+    /// and asks every RPC endpoint for its health. An empty list means the config looks usable.
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        let mut problems = vec![];
+
+        if self.local_rpc_client.endpoint.is_empty() {
+            problems.push(ConfigProblem::new(
+                "local_rpc_client",
+                "endpoint is required".to_string(),
+            ));
+        }
+        if self.remote_rpc_client.all_endpoints().is_empty() {
+            problems.push(ConfigProblem::new(
+                "remote_rpc_client",
+                "at least one endpoint is required".to_string(),
+            ));
+        }
+        if self.cursor_file.is_empty() {
+            problems.push(ConfigProblem::new("cursor_file", "is required".to_string()));
+        }
+
+        for (field, path) in [
+            ("cursor_file", &self.cursor_file),
+            ("block_destination_file", &self.block_destination_file),
+            (
+                "account_block_destination_file",
+                &self.account_block_destination_file,
+            ),
+            (
+                "transaction_destination_file",
+                &self.transaction_destination_file,
+            ),
+            ("combined_destination_file", &self.combined_destination_file),
+        ] {
+            let parent = Path::new(path)
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty());
+            if let Some(parent) = parent {
+                if !parent.is_dir() {
+                    problems.push(ConfigProblem::new(
+                        field,
+                        format!("directory {} does not exist", parent.display()),
+                    ));
+                }
+            }
+        }
+
+        for (field, values) in [
+            ("include_account_owners", &self.include_account_owners),
+            ("include_account_pubkeys", &self.include_account_pubkeys),
+            ("watch_accounts", &self.watch_accounts),
+        ] {
+            for value in values {
+                if let Err(err) = Pubkey::from_str(value) {
+                    problems.push(ConfigProblem::new(
+                        field,
+                        format!("invalid pubkey {:?}: {}", value, err),
+                    ));
+                }
+            }
+        }
+
+        for output in &self.account_outputs {
+            if output.destination_file.is_empty() {
+                problems.push(ConfigProblem::new(
+                    "account_outputs",
+                    format!("{}: destination_file is required", output.name),
+                ));
+            }
+            let parent = Path::new(&output.destination_file)
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty());
+            if let Some(parent) = parent.filter(|parent| !parent.is_dir()) {
+                problems.push(ConfigProblem::new(
+                    "account_outputs",
+                    format!(
+                        "{}: directory {} does not exist",
+                        output.name,
+                        parent.display()
+                    ),
+                ));
+            }
+            for value in output
+                .include_account_owners
+                .iter()
+                .chain(&output.include_account_pubkeys)
+            {
+                if let Err(err) = Pubkey::from_str(value) {
+                    problems.push(ConfigProblem::new(
+                        "account_outputs",
+                        format!("{}: invalid pubkey {:?}: {}", output.name, value, err),
+                    ));
+                }
+            }
+        }
+
+        let timeout = Duration::from_millis(self.rpc_timeout_ms);
+        let endpoints = std::iter::once((
+            "local_rpc_client",
+            &self.local_rpc_client,
+            self.local_rpc_client.endpoint.clone(),
+        ))
+        .filter(|(_, _, endpoint)| !endpoint.is_empty())
+        .chain(
+            self.remote_rpc_client
+                .all_endpoints()
+                .into_iter()
+                .map(|endpoint| ("remote_rpc_client", &self.remote_rpc_client, endpoint)),
+        );
+        // without rpc, the endpoints are never used
+        for (field, rpc_client, endpoint) in endpoints.filter(|_| self.use_rpc) {
+            let headers = rpc_client.header_map().unwrap_or_default();
+            if let Err(err) = new_rpc_client(endpoint.clone(), timeout, &headers).get_health() {
+                problems.push(ConfigProblem::new(
+                    field,
+                    format!("{} is not healthy: {}", endpoint, err),
+                ));
+            }
+        }
+
+        problems
+    }
+}
+
+/// One problem found by `Config::validate`, attached to the config field that causes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigProblem {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl ConfigProblem {
+    fn new(field: &'static str, message: String) -> Self {
+        ConfigProblem { field, message }
+    }
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+fn expand_env(value: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+
+        let braced = chars.peek() == Some(&'{');
+        if braced {
+            chars.next();
+        }
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                name.push(c);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        if braced && chars.next() != Some('}') {
+            return Err(format!("unterminated variable reference in {:?}", value));
+        }
+        if name.is_empty() {
+            if braced {
+                return Err(format!("empty variable reference in {:?}", value));
+            }
+            // a lone '$' is kept as is
+            out.push('$');
+            continue;
+        }
+
+        let resolved = std::env::var(&name).map_err(|_| {
+            format!(
+                "environment variable {} referenced in {:?} is not set",
+                name, value
+            )
+        })?;
+        out.push_str(&resolved);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    async fn healthy_rpc() -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({"jsonrpc": "2.0", "result": "ok", "id": 1})),
+            )
+            .mount(&server)
+            .await;
+        server
+    }
+
+    // config with both endpoints on `rpc` and every file in `dir`, `extra` is appended to the fields
+    fn config_for(rpc: &str, dir: &TempDir, extra: &str) -> Config {
+        Config::load_from_str(&format!(
+            r#"{{
+                "local_rpc_client": {{"endpoint": "{rpc}"}},
+                "remote_rpc_client": {{"endpoint": "{rpc}"}},
+                "cursor_file": "{dir}/cursor",
+                "account_block_destination_file": "{dir}/accounts.fifo",
+                "block_destination_file": "{dir}/blocks.fifo"{extra}
+            }}"#,
+            rpc = rpc,
+            dir = dir.path().display(),
+            extra = extra
+        ))
+        .unwrap()
+    }
+
+    fn fields(problems: &[ConfigProblem]) -> Vec<&'static str> {
+        problems.iter().map(|problem| problem.field).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_validate_ok() {
+        let server = healthy_rpc().await;
+        let dir = TempDir::new().unwrap();
+        let config = config_for(&server.uri(), &dir, "");
+        assert_eq!(config.validate(), vec![]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_validate_missing_fields() {
+        let server = healthy_rpc().await;
+        let config = Config::load_from_str(&format!(
+            r#"{{
+                "local_rpc_client": {{"endpoint": ""}},
+                "remote_rpc_client": {{"endpoints": ["{}"]}},
+                "cursor_file": "",
+                "account_block_destination_file": "",
+                "block_destination_file": ""
+            }}"#,
+            server.uri()
+        ))
+        .unwrap();
+        assert_eq!(
+            fields(&config.validate()),
+            vec!["local_rpc_client", "cursor_file"]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_validate_missing_directory_and_bad_pubkeys() {
+        let server = healthy_rpc().await;
+        let dir = TempDir::new().unwrap();
+        let config = config_for(
+            &server.uri(),
+            &dir,
+            &format!(
+                r#", "transaction_destination_file": "{}/missing/trx.fifo",
+                    "include_account_owners": ["not-a-pubkey"],
+                    "watch_accounts": ["11111111111111111111111111111111"]"#,
+                dir.path().display()
+            ),
+        );
+        let problems = config.validate();
+        assert_eq!(
+            fields(&problems),
+            vec!["transaction_destination_file", "include_account_owners"]
+        );
+        assert!(problems[0].message.contains("missing"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_validate_unhealthy_rpc() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "jsonrpc": "2.0",
+                "error": {"code": -32005, "message": "Node is behind by 42 slots"},
+                "id": 1
+            })))
+            .mount(&server)
+            .await;
+        let dir = TempDir::new().unwrap();
+        let config = config_for(&server.uri(), &dir, "");
+        assert_eq!(
+            fields(&config.validate()),
+            vec!["local_rpc_client", "remote_rpc_client"]
+        );
+    }
+
+    #[test]
+    fn test_check_file_unreadable() {
+        let dir = TempDir::new().unwrap();
+        let problems = Config::check_file(dir.path().join("missing.json"));
+        assert_eq!(fields(&problems), vec!["config"]);
+    }
+
+    const CONFIG: &str = r#"{
+        "local_rpc_client": {"endpoint": "http://localhost:8899"},
+        "remote_rpc_client": {"endpoint": "$FGP_TEST_REMOTE"},
+        "cursor_file": "${FGP_TEST_DATA}/cursor",
+        "account_block_destination_file": "$FGP_TEST_DATA/accounts.fifo",
+        "block_destination_file": ""
+    }"#;
+
+    #[test]
+    fn test_load_expands_env_vars() {
+        std::env::set_var("FGP_TEST_DATA", "/var/firehose");
+        std::env::set_var("FGP_TEST_REMOTE", "https://rpc.example.com");
+
+        let config = Config::load_from_str(CONFIG).unwrap();
+        assert_eq!(config.cursor_file, "/var/firehose/cursor");
+        assert_eq!(
+            config.account_block_destination_file,
+            "/var/firehose/accounts.fifo"
+        );
+        assert_eq!(config.remote_rpc_client.endpoint, "https://rpc.example.com");
+        assert_eq!(config.local_rpc_client.endpoint, "http://localhost:8899");
+        assert_eq!(config.block_destination_file, "");
+    }
+
+    #[test]
+    fn test_commitment_backward_compat() {
+        let load = |extra: &str| {
+            Config::load_from_str(&format!(
+                r#"{{
+                    "local_rpc_client": {{"endpoint": ""}},
+                    "remote_rpc_client": {{"endpoint": ""}},
+                    "cursor_file": "",
+                    "account_block_destination_file": "",
+                    "block_destination_file": ""{}
+                }}"#,
+                extra
+            ))
+            .unwrap()
+        };
+
+        let config = load("");
+        assert_eq!(config.commitment_trigger(), Commitment::Confirmed);
+        assert_eq!(config.cursor_commitment(), Commitment::Confirmed);
+
+        let config = load(r#", "send_processed": true"#);
+        assert_eq!(config.commitment_trigger(), Commitment::Processed);
+        assert_eq!(config.cursor_commitment(), Commitment::Processed);
+
+        let config = load(r#", "send_processed": true, "cursor_commitment": "confirmed""#);
+        assert_eq!(config.commitment_trigger(), Commitment::Processed);
+        assert_eq!(config.cursor_commitment(), Commitment::Confirmed);
+
+        // the explicit trigger wins over the old flag
+        let config = load(r#", "send_processed": true, "commitment_trigger": "confirmed""#);
+        assert_eq!(config.commitment_trigger(), Commitment::Confirmed);
+        assert_eq!(config.cursor_commitment(), Commitment::Confirmed);
+
+        let config = load(r#", "commitment_to_emit": "processed""#);
+        assert_eq!(config.commitment_trigger(), Commitment::Processed);
+    }
+
+    #[test]
+    fn test_fire_block_template() {
+        assert_eq!(
+            FireBlockTemplate::parse("FIRE BLOCK {slot} {payload}")
+                .unwrap()
+                .parts(),
+            [
+                TemplatePart::Text("FIRE BLOCK ".to_string()),
+                TemplatePart::Field(TemplateField::Slot),
+                TemplatePart::Text(" ".to_string()),
+                TemplatePart::Field(TemplateField::Payload),
+            ]
+        );
+        for invalid in [
+            "FIRE BLOCK {slot}",
+            "FIRE BLOCK {payload} {payload}",
+            "FIRE BLOCK {height} {payload}",
+            "FIRE BLOCK {slot {payload}",
+            "FIRE BLOCK {slot",
+        ] {
+            assert!(
+                FireBlockTemplate::parse(invalid).is_err(),
+                "{} should be rejected",
+                invalid
+            );
+        }
+
+        let load = |extra: &str| {
+            Config::load_from_str(&format!(
+                r#"{{
+                    "local_rpc_client": {{"endpoint": ""}},
+                    "remote_rpc_client": {{"endpoint": ""}},
+                    "cursor_file": "",
+                    "account_block_destination_file": "",
+                    "block_destination_file": ""{}
+                }}"#,
+                extra
+            ))
+        };
+        assert_eq!(
+            load("").unwrap().fire_block_template,
+            FireBlockTemplate::parse(FireBlockTemplate::DEFAULT).unwrap()
+        );
+        assert!(load(r#", "fire_block_template": "FIRE BLOCK {slot}""#).is_err());
+    }
+
+    #[test]
+    fn test_account_block_mode() {
+        let load = |fields: &str| {
+            Config::load_from_str(&format!(
+                r#"{{
+                    "local_rpc_client": {{"endpoint": ""}},
+                    "remote_rpc_client": {{"endpoint": ""}},
+                    "cursor_file": "",
+                    "account_block_destination_file": "",
+                    "block_destination_file": ""{}
+                }}"#,
+                fields
+            ))
+        };
+        assert_eq!(load("").unwrap().account_block_mode, AccountBlockMode::All);
+        let config =
+            load(r#", "account_block_mode": "owner_changed_only", "detect_owner_changes": true"#)
+                .unwrap();
+        assert_eq!(
+            config.account_block_mode,
+            AccountBlockMode::OwnerChangedOnly
+        );
+        assert!(config
+            .init_capabilities()
+            .contains(&"account_block_mode=owner_changed_only".to_string()));
+        let err = load(r#", "account_block_mode": "owner_changed_only""#).unwrap_err();
+        assert!(err.to_string().contains("needs detect_owner_changes"));
+    }
+
+    #[test]
+    fn test_account_data_diff_commitment() {
+        let load = |fields: &str| {
+            Config::load_from_str(&format!(
+                r#"{{
+                    "local_rpc_client": {{"endpoint": ""}},
+                    "remote_rpc_client": {{"endpoint": ""}},
+                    "cursor_file": "",
+                    "account_block_destination_file": "",
+                    "block_destination_file": "",
+                    "account_data_diff": true{}
+                }}"#,
+                fields
+            ))
+        };
+        assert!(load("").unwrap().account_data_diff);
+        for fields in [
+            r#", "send_processed": true"#,
+            r#", "commitment_trigger": "processed""#,
+        ] {
+            let err = load(fields).unwrap_err();
+            assert!(err
+                .to_string()
+                .contains("needs the confirmed commitment_trigger"));
+        }
+    }
+
+    #[cfg(feature = "kafka")]
+    #[test]
+    fn test_kafka_sink() {
+        let load = |block_destination_file: &str, sink: &str| {
+            Config::load_from_str(&format!(
+                r#"{{
+                    "local_rpc_client": {{"endpoint": ""}},
+                    "remote_rpc_client": {{"endpoint": ""}},
+                    "cursor_file": "",
+                    "account_block_destination_file": "",
+                    "block_destination_file": "{}",
+                    "sink": {}
+                }}"#,
+                block_destination_file, sink
+            ))
+        };
+        let kafka = r#"{"type": "kafka", "brokers": "localhost:9092", "topic": "blocks"}"#;
+        assert_eq!(
+            load("", kafka).unwrap().sink,
+            SinkConfig::Kafka {
+                brokers: "localhost:9092".to_string(),
+                topic: "blocks".to_string()
+            }
+        );
+        assert_eq!(
+            load("blocks.fifo", r#"{"type": "fifo"}"#).unwrap().sink,
+            SinkConfig::Fifo
+        );
+        let err = load("blocks.fifo", kafka).unwrap_err();
+        assert!(err.to_string().contains("replaces the destination files"));
+        let err = load("", r#"{"type": "kafka", "brokers": "", "topic": "blocks"}"#).unwrap_err();
+        assert!(err.to_string().contains("needs brokers and a topic"));
+    }
+
+    #[test]
+    fn test_load_toml() {
+        let dir = TempDir::new().unwrap();
+        let json_path = dir.path().join("config.json");
+        let toml_path = dir.path().join("config.toml");
+        std::fs::write(
+            &json_path,
+            r#"{
+                "libpath": "libfirehose_geyser_plugin.so",
+                "local_rpc_client": {"endpoint": "http://localhost:8899", "headers": {"x-api-key": "key"}},
+                "remote_rpc_client": {"endpoints": ["http://remote-1", "http://remote-2"]},
+                "cursor_file": "/data/cursor",
+                "account_block_destination_file": "/data/accounts.fifo",
+                "block_destination_file": "/data/blocks.fifo",
+                "cursor_write_interval_slots": 10,
+                "commitment_trigger": "processed",
+                "idle_marker_interval_ms": 5000,
+                "log": {"level": "debug"},
+                "account_outputs": [
+                    {"name": "tokens", "destination_file": "/data/tokens.fifo", "include_account_owners": ["TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA"]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            &toml_path,
+            r#"
+                libpath = "libfirehose_geyser_plugin.so"
+                cursor_file = "/data/cursor"
+                account_block_destination_file = "/data/accounts.fifo"
+                block_destination_file = "/data/blocks.fifo"
+                cursor_write_interval_slots = 10
+                commitment_trigger = "processed"
+                idle_marker_interval_ms = 5000
+
+                [local_rpc_client]
+                endpoint = "http://localhost:8899"
+                headers = { x-api-key = "key" }
+
+                [remote_rpc_client]
+                endpoints = ["http://remote-1", "http://remote-2"]
+
+                [log]
+                level = "debug"
+
+                [[account_outputs]]
+                name = "tokens"
+                destination_file = "/data/tokens.fifo"
+                include_account_owners = ["TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA"]
+            "#,
+        )
+        .unwrap();
+
+        let from_json = Config::load_from_file(&json_path).unwrap();
+        let from_toml = Config::load_from_file(&toml_path).unwrap();
+        assert_eq!(format!("{:?}", from_toml), format!("{:?}", from_json));
+        assert_eq!(from_toml.cursor_write_interval_slots, 10);
+        assert_eq!(from_toml.account_outputs[0].name, "tokens");
+
+        // a TOML file goes through the same checks, with the same errors
+        std::fs::write(&toml_path, "cursor_file = 1").unwrap();
+        assert!(matches!(
+            Config::load_from_file(&toml_path),
+            Err(GeyserPluginError::ConfigFileReadError { .. })
+        ));
+        std::fs::write(
+            &toml_path,
+            r#"
+                cursor_file = ""
+                account_block_destination_file = ""
+                block_destination_file = ""
+                protocol_version = "4.0"
+            "#,
+        )
+        .unwrap();
+        let err = Config::load_from_file(&toml_path).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("unsupported FIRE protocol version"));
+    }
+
+    #[test]
+    fn test_protocol_version() {
+        let load = |version: &str| {
+            Config::load_from_str(&format!(
+                r#"{{
+                    "local_rpc_client": {{"endpoint": ""}},
+                    "remote_rpc_client": {{"endpoint": ""}},
+                    "cursor_file": "",
+                    "account_block_destination_file": "",
+                    "block_destination_file": "",
+                    "fire_protocol_version": "{}"
+                }}"#,
+                version
+            ))
+        };
+        assert_eq!(
+            load("3.1").unwrap().protocol_version,
+            Some("3.1".to_string())
+        );
+        for version in ["4.0", "3", "3.", "3.x", "three"] {
+            let err = load(version).unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains("unsupported FIRE protocol version"),
+                "{}: {}",
+                version,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_combined_destination_file() {
+        let load = |destinations: &str| {
+            Config::load_from_str(&format!(
+                r#"{{
+                    "local_rpc_client": {{"endpoint": ""}},
+                    "remote_rpc_client": {{"endpoint": ""}},
+                    "cursor_file": "",
+                    {}
+                }}"#,
+                destinations
+            ))
+        };
+        let config = load(
+            r#""account_block_destination_file": "", "block_destination_file": "",
+               "combined_destination_file": "/tmp/combined.fifo""#,
+        )
+        .unwrap();
+        assert!(config
+            .init_capabilities()
+            .contains(&"header=message_type".to_string()));
+        let config = load(
+            r#""account_block_destination_file": "", "block_destination_file": "",
+               "unified_output_file": "/tmp/combined.fifo""#,
+        )
+        .unwrap();
+        assert_eq!(config.combined_destination_file, "/tmp/combined.fifo");
+
+        // the combined output replaces the other two
+        assert!(load(
+            r#""account_block_destination_file": "", "block_destination_file": "/tmp/blocks.fifo",
+               "combined_destination_file": "/tmp/combined.fifo""#,
+        )
+        .is_err());
+        assert!(load(
+            r#""account_block_destination_file": "", "block_destination_file": "",
+               "combined_destination_file": "/tmp/combined.fifo",
+               "output_format": "length_prefixed_proto""#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_debug_watch_accounts_alias() {
+        let config = Config::load_from_str(
+            r#"{
+                "local_rpc_client": {"endpoint": "http://localhost:8899"},
+                "remote_rpc_client": {"endpoint": "http://a"},
+                "cursor_file": "",
+                "account_block_destination_file": "",
+                "block_destination_file": "",
+                "debug_watch_accounts": ["11111111111111111111111111111111"]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.watch_accounts,
+            vec!["11111111111111111111111111111111"]
+        );
+    }
+
+    #[test]
+    fn test_transaction_detail_alias() {
+        let config = Config::load_from_str(
+            r#"{
+                "local_rpc_client": {"endpoint": "http://localhost:8899"},
+                "remote_rpc_client": {"endpoint": "http://a"},
+                "cursor_file": "",
+                "account_block_destination_file": "",
+                "block_destination_file": "",
+                "transaction_detail": "signatures"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.block_detail, BlockDetail::SignaturesOnly);
+    }
+
+    #[test]
+    fn test_remote_endpoints() {
+        let config = Config::load_from_str(
+            r#"{
+                "local_rpc_client": {"endpoint": "http://localhost:8899"},
+                "remote_rpc_client": {"endpoint": "http://a", "endpoints": ["http://b", "http://c"]},
+                "cursor_file": "",
+                "account_block_destination_file": "",
+                "block_destination_file": ""
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.remote_rpc_client.all_endpoints(),
+            vec!["http://a", "http://b", "http://c"]
+        );
+
+        let only_list = RpcClientConfig {
+            endpoint: String::new(),
+            endpoints: vec!["http://b".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(only_list.all_endpoints(), vec!["http://b"]);
+    }
+
+    #[test]
+    fn test_rpc_headers() {
+        std::env::set_var("FGP_TEST_RPC_TOKEN", "secret");
+        let load = |headers: &str| {
+            Config::load_from_str(&format!(
+                r#"{{
+                    "local_rpc_client": {{"endpoint": "", "auth_token": "${{FGP_TEST_RPC_TOKEN}}"}},
+                    "remote_rpc_client": {{"endpoint": "", "headers": {}}},
+                    "cursor_file": "",
+                    "account_block_destination_file": "",
+                    "block_destination_file": ""
+                }}"#,
+                headers
+            ))
+        };
+        let config = load(r#"{"x-api-key": "key"}"#).unwrap();
+        assert_eq!(
+            config.local_rpc_client.auth_token.as_deref(),
+            Some("secret")
+        );
+        assert!(!format!("{:?}", config.local_rpc_client).contains("secret"));
+        let headers = config.remote_rpc_client.header_map().unwrap();
+        assert_eq!(headers["x-api-key"], "key");
+
+        let err = load(r#"{"bad name": "key"}"#).unwrap_err();
+        assert!(err.to_string().contains("remote_rpc_client"), "{}", err);
+    }
+
+    #[test]
+    fn test_expand_env_unknown_var() {
+        let err = expand_env("${FGP_TEST_NOT_SET_ANYWHERE}/cursor").unwrap_err();
+        assert!(err.contains("FGP_TEST_NOT_SET_ANYWHERE"), "{}", err);
+        assert!(expand_env("${FGP_TEST_DATA").is_err());
+    }
+
+    #[test]
+    fn test_expand_env_literal_dollar() {
+        assert_eq!(expand_env("/tmp/$/cursor").unwrap(), "/tmp/$/cursor");
+        assert_eq!(expand_env("/tmp/cursor").unwrap(), "/tmp/cursor");
     }
 }
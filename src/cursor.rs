@@ -0,0 +1,300 @@
+use crate::rpc::is_slot_skipped;
+use log::{error, info, warn};
+use solana_rpc_client::rpc_client::RpcClient;
+use solana_rpc_client_api::config::RpcBlockConfig;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_transaction_status::TransactionDetails;
+use std::fmt;
+
+// only the block hash is needed to check the cursor
+const CURSOR_CHECK_RPC_BLOCK_CONFIG: RpcBlockConfig = RpcBlockConfig {
+    encoding: None,
+    transaction_details: Some(TransactionDetails::None),
+    rewards: Some(false),
+    commitment: Some(CommitmentConfig::confirmed()),
+    max_supported_transaction_version: Some(0),
+};
+
+// Cursor is the last slot fully written to the outputs, stored as `{slot}:{block_hash}`.
+// Files written by older versions only contain the slot, they are still accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    pub slot: u64,
+    pub block_hash: Option<String>,
+}
+
+impl Cursor {
+    pub fn new(slot: u64, block_hash: &str) -> Self {
+        Cursor {
+            slot,
+            block_hash: Some(block_hash.to_string()),
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (slot, block_hash) = match value.split_once(':') {
+            Some((slot, block_hash)) if !block_hash.is_empty() => {
+                (slot, Some(block_hash.to_string()))
+            }
+            Some(_) => return None,
+            None => (value, None),
+        };
+        Some(Cursor {
+            slot: slot.parse().ok()?,
+            block_hash,
+        })
+    }
+
+    pub fn from_file(cursor_file: &str) -> Option<Self> {
+        std::fs::read_to_string(cursor_file)
+            .ok()
+            .and_then(|cursor| Cursor::parse(&cursor))
+    }
+
+    // write goes through a temporary file and a rename, so that a crash never leaves a partial cursor
+    pub fn write(&self, cursor_file: &str) -> std::io::Result<()> {
+        let tmp_file = format!("{}.tmp", cursor_file);
+        std::fs::write(&tmp_file, self.to_string())?;
+        std::fs::rename(&tmp_file, cursor_file)
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.block_hash {
+            Some(block_hash) => write!(f, "{}:{}", self.slot, block_hash),
+            None => write!(f, "{}", self.slot),
+        }
+    }
+}
+
+// verify_cursor compares the block hash of the cursor with the one the rpc clients have for its slot.
+// On a mismatch the block was reorged away: the cursor is dropped and the plugin starts again from the LIB.
+// If no client can give the block, the cursor is kept as it is. The block found by self_test_cursor is
+// reused instead of being fetched again.
+pub fn verify_cursor(
+    cursor: Cursor,
+    rpc_clients: &[&RpcClient],
+    self_test: Option<CursorBlock>,
+) -> Option<u64> {
+    let Some(expected_hash) = cursor.block_hash.as_deref() else {
+        return Some(cursor.slot);
+    };
+
+    let block_hash = match self_test {
+        Some(CursorBlock::Found { block_hash }) => Some(block_hash),
+        Some(CursorBlock::Missing | CursorBlock::Unknown) => None,
+        None => fetch_block_hash(cursor.slot, rpc_clients),
+    };
+    match block_hash {
+        Some(block_hash) if block_hash == expected_hash => {
+            info!("cursor {} matches the chain", cursor);
+            Some(cursor.slot)
+        }
+        Some(block_hash) => {
+            warn!(
+                "cursor {} does not match block hash {} of slot {}, ignoring it and starting from the LIB",
+                cursor, block_hash, cursor.slot
+            );
+            None
+        }
+        None => {
+            warn!("cursor {} could not be verified, using it anyway", cursor);
+            Some(cursor.slot)
+        }
+    }
+}
+
+// fetch_block_hash returns the block hash of the slot from the first rpc client that has it
+fn fetch_block_hash(slot: u64, rpc_clients: &[&RpcClient]) -> Option<String> {
+    for rpc_client in rpc_clients {
+        match rpc_client.get_block_with_config(slot, CURSOR_CHECK_RPC_BLOCK_CONFIG) {
+            Ok(block) => return Some(block.blockhash),
+            Err(err) => warn!("cannot get block {} to verify the cursor: {}", slot, err),
+        }
+    }
+    None
+}
+
+// CursorBlock is what the rpc clients know of the slot of the cursor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CursorBlock {
+    Found { block_hash: String },
+    // the slot was skipped, a cursor never points at it unless it was written by another chain
+    Missing,
+    // no client could tell
+    Unknown,
+}
+
+// self_test_cursor fetches the block of the cursor and logs what the plugin resumes from. It does not change
+// the cursor, verify_cursor decides whether it is used.
+pub fn self_test_cursor(cursor: &Cursor, rpc_clients: &[&RpcClient]) -> CursorBlock {
+    for rpc_client in rpc_clients {
+        match rpc_client.get_block_with_config(cursor.slot, CURSOR_CHECK_RPC_BLOCK_CONFIG) {
+            Ok(block) => {
+                info!(
+                    "cursor self-test: slot {} has block hash {} on chain, the first slot emitted will be the first child of {} above the LIB",
+                    cursor.slot, block.blockhash, cursor.slot
+                );
+                return CursorBlock::Found {
+                    block_hash: block.blockhash,
+                };
+            }
+            Err(err) if is_slot_skipped(&err) => {
+                error!(
+                    "cursor self-test: slot {} of cursor {} does not exist on chain, the cursor file does not belong to this cluster or was corrupted",
+                    cursor.slot, cursor
+                );
+                return CursorBlock::Missing;
+            }
+            Err(err) => warn!(
+                "cursor self-test: cannot get block {}: {}",
+                cursor.slot, err
+            ),
+        }
+    }
+    warn!(
+        "cursor self-test: no rpc client could get block {}",
+        cursor.slot
+    );
+    CursorBlock::Unknown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Cursor::parse("123"),
+            Some(Cursor {
+                slot: 123,
+                block_hash: None
+            })
+        );
+        assert_eq!(Cursor::parse("123:abc\n"), Some(Cursor::new(123, "abc")));
+        assert_eq!(Cursor::parse("123:"), None);
+        assert_eq!(Cursor::parse("abc"), None);
+        assert_eq!(Cursor::parse(""), None);
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("cursor").to_str().unwrap().to_string();
+
+        Cursor::new(123, "abc").write(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "123:abc");
+        assert_eq!(Cursor::from_file(&path), Some(Cursor::new(123, "abc")));
+
+        // plain slot, as written by older versions
+        std::fs::write(&path, "456").unwrap();
+        assert_eq!(Cursor::from_file(&path).unwrap().slot, 456);
+    }
+
+    async fn rpc_with_block_hash(block_hash: &str) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "jsonrpc": "2.0",
+                "result": {
+                    "blockhash": block_hash,
+                    "previousBlockhash": "parent",
+                    "parentSlot": 122,
+                    "blockTime": 1234,
+                    "blockHeight": 100,
+                },
+                "id": 1
+            })))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_verify_cursor() {
+        let server = rpc_with_block_hash("abc").await;
+        let rpc_client = RpcClient::new(server.uri());
+
+        assert_eq!(
+            verify_cursor(Cursor::new(123, "abc"), &[&rpc_client], None),
+            Some(123)
+        );
+        assert_eq!(
+            verify_cursor(Cursor::new(123, "reorged"), &[&rpc_client], None),
+            None
+        );
+        assert_eq!(
+            verify_cursor(Cursor::parse("123").unwrap(), &[], None),
+            Some(123)
+        );
+
+        // the block of the self-test is used without asking the clients again
+        let found = CursorBlock::Found {
+            block_hash: "abc".to_string(),
+        };
+        assert_eq!(
+            verify_cursor(Cursor::new(123, "abc"), &[], Some(found.clone())),
+            Some(123)
+        );
+        assert_eq!(
+            verify_cursor(Cursor::new(123, "reorged"), &[], Some(found)),
+            None
+        );
+        assert_eq!(
+            verify_cursor(
+                Cursor::new(123, "reorged"),
+                &[&rpc_client],
+                Some(CursorBlock::Unknown)
+            ),
+            Some(123)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_self_test_cursor() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("cursor").to_str().unwrap().to_string();
+        std::fs::write(&path, "123:abc").unwrap();
+        let cursor = Cursor::from_file(&path).unwrap();
+
+        let server = rpc_with_block_hash("abc").await;
+        let rpc_client = RpcClient::new(server.uri());
+        assert_eq!(
+            self_test_cursor(&cursor, &[&rpc_client]),
+            CursorBlock::Found {
+                block_hash: "abc".to_string()
+            }
+        );
+
+        let skipped = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "jsonrpc": "2.0",
+                "error": {
+                    "code": -32007,
+                    "message": "Slot 123 was skipped, or missing due to ledger jump to recent snapshot"
+                },
+                "id": 1
+            })))
+            .mount(&skipped)
+            .await;
+        let skipped_client = RpcClient::new(skipped.uri());
+        assert_eq!(
+            self_test_cursor(&cursor, &[&skipped_client]),
+            CursorBlock::Missing
+        );
+
+        // a client that cannot answer is not enough to call the slot missing
+        let unreachable = RpcClient::new("http://127.0.0.1:1".to_string());
+        assert_eq!(
+            self_test_cursor(&cursor, &[&unreachable]),
+            CursorBlock::Unknown
+        );
+    }
+}
@@ -0,0 +1,328 @@
+use crate::pb::sf::solana::r#type::v1::{Account, AccountBlock, AccountDiff, AccountDiffChunk};
+use prost::Message;
+use std::collections::{HashMap, VecDeque};
+
+// equal bytes between two changed ranges are sent with them when there are fewer than this, a chunk costs
+// its offset and its length on top of its bytes
+const MERGE_GAP: usize = 16;
+
+// AccountDataCache keeps the data last sent for each account, so that the next data of the account can be sent
+// as a diff against it. It is bounded by a number of accounts and a total of data bytes, the accounts cached
+// first are forgotten first: their next data is sent in full again.
+pub struct AccountDataCache {
+    max_accounts: usize,
+    max_bytes: usize,
+    // data of each account, with the generation it was cached at
+    data: HashMap<Vec<u8>, (Vec<u8>, u64)>,
+    // caching order, an entry whose generation is not the one of its account in `data` is stale and skipped
+    order: VecDeque<(Vec<u8>, u64)>,
+    generation: u64,
+    bytes: usize,
+}
+
+impl AccountDataCache {
+    pub fn new(max_accounts: usize, max_bytes: usize) -> Self {
+        AccountDataCache {
+            max_accounts,
+            max_bytes,
+            data: HashMap::new(),
+            order: VecDeque::new(),
+            generation: 0,
+            bytes: 0,
+        }
+    }
+
+    // diff_account sets the diff of a sent account against the data last sent for it, when the diff is smaller.
+    // The data is kept: the sink sends one of them, see take_diffed_data. A deleted account, and one seen for the
+    // first time, gets no diff.
+    pub fn diff_account(&mut self, account: &mut Account) {
+        if account.truncated {
+            // the consumers do not know the data, there is nothing to diff against next time
+            self.remove(&account.address);
+            return;
+        }
+        let previous = self.data.get(&account.address);
+        if let Some((previous, _)) = previous.filter(|_| !account.deleted) {
+            let diff = diff_data(previous, &account.data);
+            if diff.encoded_len() < account.data.len() {
+                account.data_diff = Some(diff);
+            }
+        }
+        self.insert(account.address.clone(), account.data.clone());
+    }
+
+    // clear forgets all the data, when the consumers may not have it: the next data of every account is sent in full
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.order.clear();
+        self.bytes = 0;
+    }
+
+    fn insert(&mut self, address: Vec<u8>, data: Vec<u8>) {
+        if data.len() > self.max_bytes {
+            self.remove(&address);
+            return;
+        }
+        self.bytes += data.len();
+        match self.data.get_mut(&address) {
+            // an account cached again keeps its place
+            Some((previous, _)) => self.bytes -= std::mem::replace(previous, data).len(),
+            None => {
+                self.generation += 1;
+                self.order.push_back((address.clone(), self.generation));
+                self.data.insert(address, (data, self.generation));
+            }
+        }
+        while self.data.len() > self.max_accounts || self.bytes > self.max_bytes {
+            let Some((oldest, generation)) = self.order.pop_front() else {
+                break;
+            };
+            if self.is_current(&oldest, generation) {
+                self.remove(&oldest);
+            }
+        }
+        // the stale entries of the accounts removed then cached again are dropped once they are the majority
+        if self.order.len() > 2 * self.data.len() {
+            let order = std::mem::take(&mut self.order);
+            self.order = order
+                .into_iter()
+                .filter(|(address, generation)| self.is_current(address, *generation))
+                .collect();
+        }
+    }
+
+    fn is_current(&self, address: &[u8], generation: u64) -> bool {
+        self.data
+            .get(address)
+            .is_some_and(|(_, current)| *current == generation)
+    }
+
+    fn remove(&mut self, address: &[u8]) {
+        if let Some((previous, _)) = self.data.remove(address) {
+            self.bytes -= previous.len();
+        }
+    }
+}
+
+// The accounts handed to the sinks carry both their data and, when it is smaller, their diff (see
+// AccountDataCache::diff_account). The diff is sent to a reader that got the previous slots, the full data to
+// any other one: a replay, a reader attached to a reopened destination.
+
+// take_diffed_data empties the data of the accounts that have a diff, so that only the diff is sent.
+// The data is given back by restore_diffed_data.
+pub fn take_diffed_data(account_block: &mut AccountBlock) -> Vec<Vec<u8>> {
+    account_block
+        .accounts
+        .iter_mut()
+        .filter(|account| account.data_diff.is_some())
+        .map(|account| std::mem::take(&mut account.data))
+        .collect()
+}
+
+pub fn restore_diffed_data(account_block: &mut AccountBlock, data: Vec<Vec<u8>>) {
+    let accounts = account_block
+        .accounts
+        .iter_mut()
+        .filter(|account| account.data_diff.is_some());
+    for (account, data) in accounts.zip(data) {
+        account.data = data;
+    }
+}
+
+// drop_account_diffs leaves the accounts with their full data only
+pub fn drop_account_diffs(account_block: &mut AccountBlock) {
+    for account in account_block.accounts.iter_mut() {
+        account.data_diff = None;
+    }
+}
+
+// diff_data returns the chunks of `data` that differ from `previous`, the bytes past the end of `previous` included
+pub fn diff_data(previous: &[u8], data: &[u8]) -> AccountDiff {
+    let differs = |i: usize| i >= previous.len() || previous[i] != data[i];
+    let mut chunks = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        if !differs(i) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let mut end = i + 1;
+        let mut next = end;
+        while next < data.len() && next - end < MERGE_GAP {
+            if differs(next) {
+                end = next + 1;
+            }
+            next += 1;
+        }
+        chunks.push(AccountDiffChunk {
+            offset: start as u64,
+            data: data[start..end].to_vec(),
+        });
+        i = end;
+    }
+    AccountDiff {
+        data_len: data.len() as u64,
+        chunks,
+    }
+}
+
+// apply_account_diff rebuilds the data of an account from the data last received for it, as a reader of the
+// account blocks does. None when a chunk does not fit in the data.
+pub fn apply_account_diff(previous: &[u8], diff: &AccountDiff) -> Option<Vec<u8>> {
+    let mut data = previous.to_vec();
+    data.resize(usize::try_from(diff.data_len).ok()?, 0);
+    for chunk in &diff.chunks {
+        let start = usize::try_from(chunk.offset).ok()?;
+        let end = start.checked_add(chunk.data.len())?;
+        data.get_mut(start..end)?.copy_from_slice(&chunk.data);
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(address: u8, data: Vec<u8>) -> Account {
+        Account {
+            address: vec![address; 32],
+            data,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_diff_data() {
+        let previous: Vec<u8> = (0..=255).collect();
+        let mut data = previous.clone();
+        data[10] = 0;
+        data[12] = 0;
+        data[200] = 0;
+        let diff = diff_data(&previous, &data);
+        // the close changes are one chunk
+        assert_eq!(
+            diff.chunks
+                .iter()
+                .map(|chunk| (chunk.offset, chunk.data.len()))
+                .collect::<Vec<_>>(),
+            vec![(10, 3), (200, 1)]
+        );
+        assert_eq!(apply_account_diff(&previous, &diff), Some(data));
+
+        let grown = [previous.clone(), vec![1, 2, 3]].concat();
+        let diff = diff_data(&previous, &grown);
+        assert_eq!(diff.chunks.len(), 1);
+        assert_eq!(apply_account_diff(&previous, &diff), Some(grown));
+
+        let shrunk = previous[..100].to_vec();
+        let diff = diff_data(&previous, &shrunk);
+        assert!(diff.chunks.is_empty());
+        assert_eq!(apply_account_diff(&previous, &diff), Some(shrunk));
+
+        let out_of_range = AccountDiff {
+            data_len: 4,
+            chunks: vec![AccountDiffChunk {
+                offset: 3,
+                data: vec![1, 2],
+            }],
+        };
+        assert_eq!(apply_account_diff(&previous, &out_of_range), None);
+    }
+
+    #[test]
+    fn test_diff_account() {
+        let mut cache = AccountDataCache::new(10, 1 << 20);
+        let previous = vec![7; 10_000];
+
+        // first sight, the data is sent in full
+        let mut first = account(1, previous.clone());
+        cache.diff_account(&mut first);
+        assert_eq!(first.data, previous);
+        assert_eq!(first.data_diff, None);
+
+        let mut data = previous.clone();
+        data[5_000] = 8;
+        let mut changed = account(1, data.clone());
+        let full_len = changed.encoded_len();
+        cache.diff_account(&mut changed);
+        // the data is kept next to the diff, the sink sends one of them
+        let mut block = AccountBlock {
+            accounts: vec![changed],
+            ..Default::default()
+        };
+        let taken = take_diffed_data(&mut block);
+        assert_eq!(taken, vec![data.clone()]);
+        assert!(block.accounts[0].encoded_len() < full_len / 100);
+        assert_eq!(
+            apply_account_diff(&previous, block.accounts[0].data_diff.as_ref().unwrap()),
+            Some(data.clone())
+        );
+        restore_diffed_data(&mut block, taken);
+        assert_eq!(block.accounts[0].data, data);
+        drop_account_diffs(&mut block);
+        assert_eq!(block.accounts[0].data_diff, None);
+
+        // a deletion is sent in full, and the next write diffs against it
+        let mut deleted = account(1, vec![]);
+        deleted.deleted = true;
+        cache.diff_account(&mut deleted);
+        assert_eq!(deleted.data_diff, None);
+        let mut recreated = account(1, data.clone());
+        cache.diff_account(&mut recreated);
+        assert_eq!(recreated.data, data);
+        assert_eq!(recreated.data_diff, None);
+
+        // a change as big as the data is not worth a diff
+        let mut rewritten = account(1, vec![9; 10_000]);
+        cache.diff_account(&mut rewritten);
+        assert_eq!(rewritten.data_diff, None);
+    }
+
+    #[test]
+    fn test_account_data_cache_bounds() {
+        let mut cache = AccountDataCache::new(2, 250);
+        for address in 1..=3 {
+            cache.diff_account(&mut account(address, vec![0; 100]));
+        }
+        // the first account was forgotten, its next data is sent in full
+        assert_eq!(cache.data.len(), 2);
+        let mut first = account(1, vec![1; 100]);
+        cache.diff_account(&mut first);
+        assert_eq!(first.data_diff, None);
+
+        // data bigger than the whole cache is never kept
+        cache.diff_account(&mut account(4, vec![0; 300]));
+        let mut big = account(4, vec![0; 300]);
+        cache.diff_account(&mut big);
+        assert_eq!(big.data_diff, None);
+        assert!(cache.bytes <= 250);
+    }
+
+    #[test]
+    fn test_account_data_cache_cached_again() {
+        let mut cache = AccountDataCache::new(2, 1 << 20);
+        cache.diff_account(&mut account(1, vec![0; 100]));
+        cache.diff_account(&mut account(2, vec![0; 100]));
+        // the first account is forgotten, then cached again: it is now the newest
+        let mut truncated = account(1, vec![]);
+        truncated.truncated = true;
+        cache.diff_account(&mut truncated);
+        cache.diff_account(&mut account(1, vec![1; 100]));
+        cache.diff_account(&mut account(3, vec![0; 100]));
+
+        let mut first = account(1, vec![1; 100]);
+        cache.diff_account(&mut first);
+        assert!(first.data_diff.is_some());
+        let mut second = account(2, vec![0; 100]);
+        cache.diff_account(&mut second);
+        assert_eq!(second.data_diff, None);
+
+        // the caching order does not grow with the accounts cached again
+        for _ in 0..100 {
+            cache.diff_account(&mut truncated);
+            cache.diff_account(&mut account(1, vec![1; 100]));
+        }
+        assert!(cache.order.len() <= 2 * cache.data.len());
+    }
+}
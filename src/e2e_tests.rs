@@ -0,0 +1,331 @@
+// End-to-end tests: a Plugin built around an in-memory State is driven through the GeyserPlugin callbacks
+// the way the validator does, with a fake RPC for the LIB, and the FIRE BLOCK lines written to temp files,
+// or the slots collected by a MemorySink, are checked.
+use crate::block_printer::{BlockPrinter, PrinterOptions};
+use crate::pb::sf::solana::r#type::v1::{AccountBlock, Block};
+use crate::plugins::Plugin;
+use crate::sink::{BlockSink, MemorySink};
+use crate::state::{State, StateOptions};
+use agave_geyser_plugin_interface::geyser_plugin_interface::{
+    GeyserPlugin, ReplicaAccountInfoV3, ReplicaAccountInfoVersions, ReplicaBlockInfoV4,
+    ReplicaBlockInfoVersions, ReplicaTransactionInfoV2, ReplicaTransactionInfoVersions, SlotStatus,
+};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use prost::Message;
+use solana_rpc_client::rpc_client::RpcClient;
+use solana_sdk::signature::{Keypair, Signer};
+use solana_sdk::system_instruction;
+use solana_sdk::transaction::{SanitizedTransaction, Transaction};
+use solana_transaction_status::{RewardsAndNumPartitions, TransactionStatusMeta};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tempfile::{NamedTempFile, TempDir};
+use wiremock::matchers::method;
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+const LIB: u64 = 100;
+
+struct Harness {
+    plugin: Plugin,
+    cursor_path: String,
+    // every account write gets a higher write version, like on a validator
+    write_version: AtomicU64,
+    _dir: TempDir,
+    // the local RPC only answers the LIB requests
+    _rpc: MockServer,
+}
+
+// block and account outputs of a Harness writing FIRE BLOCK lines
+struct Outputs {
+    blocks: NamedTempFile,
+    accounts: NamedTempFile,
+}
+
+impl Harness {
+    async fn with_files() -> (Self, Outputs) {
+        let blocks = NamedTempFile::new().unwrap();
+        let accounts = NamedTempFile::new().unwrap();
+        let printer = BlockPrinter::new(
+            Some(blocks.reopen().unwrap()),
+            Some(accounts.reopen().unwrap()),
+            None,
+            PrinterOptions::default(),
+        );
+        (Harness::new(printer).await, Outputs { blocks, accounts })
+    }
+
+    async fn new(sink: impl BlockSink + 'static) -> Self {
+        let rpc = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({"jsonrpc": "2.0", "result": LIB, "id": 1})),
+            )
+            .mount(&rpc)
+            .await;
+
+        let dir = TempDir::new().unwrap();
+        let cursor_path = dir.path().join("cursor").to_str().unwrap().to_string();
+        let state = State::new(
+            Some(RpcClient::new(rpc.uri())),
+            vec![],
+            None,
+            cursor_path.clone(),
+            sink,
+            StateOptions::default(),
+        );
+
+        Harness {
+            plugin: Plugin::builder().state(state).build(),
+            cursor_path,
+            write_version: AtomicU64::new(1),
+            _dir: dir,
+            _rpc: rpc,
+        }
+    }
+
+    fn account(&self, slot: u64, address: u8, data: &[u8]) {
+        let info = ReplicaAccountInfoV3 {
+            pubkey: &[address; 32],
+            lamports: 1,
+            owner: &[9; 32],
+            executable: false,
+            rent_epoch: 0,
+            data,
+            write_version: self.write_version.fetch_add(1, Ordering::Relaxed),
+            txn: None,
+        };
+        self.plugin
+            .update_account(ReplicaAccountInfoVersions::V0_0_3(&info), slot, false)
+            .unwrap();
+    }
+
+    fn transaction(&self, slot: u64, index: usize, lamports: u64) {
+        let payer = Keypair::new();
+        let instruction =
+            system_instruction::transfer(&payer.pubkey(), &Keypair::new().pubkey(), lamports);
+        let transaction =
+            SanitizedTransaction::from_transaction_for_tests(Transaction::new_signed_with_payer(
+                &[instruction],
+                Some(&payer.pubkey()),
+                &[&payer],
+                Default::default(),
+            ));
+        let meta = TransactionStatusMeta {
+            fee: 5000,
+            ..TransactionStatusMeta::default()
+        };
+        let info = ReplicaTransactionInfoV2 {
+            signature: transaction.signature(),
+            is_vote: false,
+            transaction: &transaction,
+            transaction_status_meta: &meta,
+            index,
+        };
+        self.plugin
+            .notify_transaction(ReplicaTransactionInfoVersions::V0_0_2(&info), slot)
+            .unwrap();
+    }
+
+    fn block_metadata(&self, slot: u64, transaction_count: u64) {
+        let rewards = RewardsAndNumPartitions {
+            rewards: vec![],
+            num_partitions: None,
+        };
+        let block_hash = format!("hash{}", slot);
+        let parent_hash = format!("hash{}", slot - 1);
+        let info = ReplicaBlockInfoV4 {
+            parent_slot: slot - 1,
+            parent_blockhash: &parent_hash,
+            slot,
+            blockhash: &block_hash,
+            rewards: &rewards,
+            block_time: Some(1_700_000_000 + slot as i64),
+            block_height: Some(slot - 10),
+            executed_transaction_count: transaction_count,
+            entry_count: 1,
+        };
+        self.plugin
+            .notify_block_metadata(ReplicaBlockInfoVersions::V0_0_4(&info))
+            .unwrap();
+    }
+
+    fn slot_status(&self, slot: u64, status: SlotStatus) {
+        self.plugin
+            .update_slot_status(slot, Some(slot - 1), status)
+            .unwrap();
+    }
+
+    // the outputs are written by their own threads, the cursor moves once both of them wrote the slot
+    fn wait_for_cursor(&self, expected: &str) {
+        let start = Instant::now();
+        while std::fs::read_to_string(&self.cursor_path).unwrap_or_default() != expected {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "cursor never reached {}",
+                expected
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+// FIRE BLOCK lines of `path`, split into their header fields and decoded payload
+fn fire_blocks<M: Message + Default>(path: &Path) -> Vec<(Vec<String>, M)> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let fields: Vec<String> = line.split(' ').map(str::to_string).collect();
+            assert_eq!(fields[..2], ["FIRE", "BLOCK"]);
+            assert_eq!(fields.len(), 9);
+            let payload = BASE64.decode(&fields[8]).unwrap();
+            (
+                fields[2..8].to_vec(),
+                M::decode(payload.as_slice()).unwrap(),
+            )
+        })
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_plugin_emits_confirmed_slots() {
+    let (harness, outputs) = Harness::with_files().await;
+
+    // slot 101: the transactions arrive out of order, the account before the metadata
+    harness.account(101, 1, &[1, 1]);
+    harness.account(101, 2, &[2, 2]);
+    harness.transaction(101, 1, 20);
+    harness.transaction(101, 0, 10);
+    harness.block_metadata(101, 2);
+    harness.slot_status(101, SlotStatus::Processed);
+    harness.slot_status(101, SlotStatus::Confirmed);
+    harness.wait_for_cursor("101:hash101");
+
+    // slot 102: confirmed before its metadata is received, account 2 is written twice
+    harness.account(102, 1, &[1, 1]);
+    harness.account(102, 2, &[3]);
+    harness.account(102, 2, &[4]);
+    harness.transaction(102, 0, 30);
+    harness.slot_status(102, SlotStatus::Confirmed);
+    harness.block_metadata(102, 1);
+    harness.wait_for_cursor("102:hash102");
+
+    let blocks: Vec<(Vec<String>, Block)> = fire_blocks(outputs.blocks.path());
+    assert_eq!(blocks.len(), 2);
+
+    let (header, block) = &blocks[0];
+    assert_eq!(
+        header[..5],
+        ["101", "hash101", "100", "hash100", &LIB.to_string()]
+    );
+    assert_eq!(header[5], (1_700_000_101u64 * 1_000_000_000).to_string());
+    assert_eq!(block.slot, 101);
+    assert_eq!(block.block_height.as_ref().unwrap().block_height, 91);
+    assert_eq!(block.expected_transaction_count, 2);
+    assert_eq!(block.transactions.len(), 2);
+    let fees: Vec<u64> = block
+        .transactions
+        .iter()
+        .map(|trx| trx.meta.as_ref().unwrap().fee)
+        .collect();
+    assert_eq!(fees, vec![5000, 5000]);
+    let instruction_data: Vec<Vec<u8>> = block
+        .transactions
+        .iter()
+        .map(|trx| {
+            trx.transaction
+                .as_ref()
+                .unwrap()
+                .message
+                .as_ref()
+                .unwrap()
+                .instructions[0]
+                .data
+                .clone()
+        })
+        .collect();
+    // transfer instructions: the amount follows the 4-byte discriminant, geyser index 0 comes first
+    assert_eq!(instruction_data[0][4..12], 10u64.to_le_bytes());
+    assert_eq!(instruction_data[1][4..12], 20u64.to_le_bytes());
+
+    let (header, block) = &blocks[1];
+    assert_eq!(header[..4], ["102", "hash102", "101", "hash101"]);
+    assert_eq!(block.previous_blockhash, "hash101");
+    assert_eq!(block.transactions.len(), 1);
+
+    let account_blocks: Vec<(Vec<String>, AccountBlock)> = fire_blocks(outputs.accounts.path());
+    assert_eq!(account_blocks.len(), 2);
+    let addresses = |block: &AccountBlock| -> Vec<u8> {
+        block
+            .accounts
+            .iter()
+            .map(|account| account.address[0])
+            .collect()
+    };
+    assert_eq!(account_blocks[0].1.slot, 101);
+    assert_eq!(addresses(&account_blocks[0].1), vec![1, 2]);
+    assert_eq!(account_blocks[1].1.slot, 102);
+    assert_eq!(addresses(&account_blocks[1].1), vec![1, 2]);
+    assert_eq!(account_blocks[1].1.accounts[1].data, vec![4]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_plugin_waits_for_all_transactions() {
+    let (harness, outputs) = Harness::with_files().await;
+
+    harness.transaction(101, 0, 10);
+    harness.block_metadata(101, 2);
+    harness.slot_status(101, SlotStatus::Confirmed);
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(std::fs::read_to_string(outputs.blocks.path()).unwrap(), "");
+
+    // the missing transaction completes the slot, it is sent right away without waiting for
+    // the next slot status or metadata
+    harness.transaction(101, 1, 20);
+    harness.wait_for_cursor("101:hash101");
+
+    harness.transaction(102, 0, 30);
+    harness.block_metadata(102, 1);
+    harness.slot_status(102, SlotStatus::Confirmed);
+    harness.wait_for_cursor("102:hash102");
+
+    let blocks: Vec<(Vec<String>, Block)> = fire_blocks(outputs.blocks.path());
+    let slots: Vec<u64> = blocks.iter().map(|(_, block)| block.slot).collect();
+    assert_eq!(slots, vec![101, 102]);
+    assert_eq!(blocks[0].1.transactions.len(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_plugin_emits_to_memory_sink() {
+    let sink = MemorySink::default();
+    let harness = Harness::new(sink.clone()).await;
+
+    harness.account(101, 1, &[1]);
+    harness.transaction(101, 0, 10);
+    harness.block_metadata(101, 1);
+    harness.slot_status(101, SlotStatus::Confirmed);
+    // not confirmed yet
+    harness.account(102, 2, &[2]);
+    harness.transaction(102, 0, 20);
+    harness.block_metadata(102, 1);
+
+    // the sink is called while the notification is handled, nothing has to be waited for
+    let slots = sink.slots();
+    assert_eq!(slots.len(), 1);
+    assert_eq!(slots[0].lib, LIB);
+    assert!(!slots[0].replayed);
+    assert_eq!(slots[0].block.slot, 101);
+    assert_eq!(slots[0].block.blockhash, "hash101");
+    assert_eq!(slots[0].block.transactions.len(), 1);
+    assert_eq!(slots[0].account_block.slot, 101);
+    assert_eq!(slots[0].account_block.accounts.len(), 1);
+    assert_eq!(slots[0].account_block.accounts[0].data, vec![1]);
+
+    harness.slot_status(102, SlotStatus::Confirmed);
+    let slots: Vec<u64> = sink.slots().iter().map(|sent| sent.block.slot).collect();
+    assert_eq!(slots, vec![101, 102]);
+    assert!(!Path::new(&harness.cursor_path).exists());
+}
@@ -0,0 +1,280 @@
+use agave_geyser_plugin_interface::geyser_plugin_interface::{
+    GeyserPluginError, Result as PluginResult,
+};
+use log::{info, warn};
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const OPEN_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+const OPEN_WAITING_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+fn config_error(msg: String) -> GeyserPluginError {
+    GeyserPluginError::ConfigFileReadError { msg }
+}
+
+// prepare_fifo makes sure that the destination is a FIFO we can write to.
+// A missing path is created with mkfifo when create_fifo is set, anything else is reported as a config error.
+pub fn prepare_fifo(path: &str, create_fifo: bool) -> PluginResult<()> {
+    let c_path = CString::new(path)
+        .map_err(|_| config_error(format!("invalid destination path {}", path)))?;
+
+    if !Path::new(path).exists() {
+        if !create_fifo {
+            return Err(config_error(format!(
+                "destination {} does not exist, create it with 'mkfifo {}' or set create_fifo",
+                path, path
+            )));
+        }
+        info!("creating FIFO {}", path);
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) } != 0 {
+            return Err(config_error(format!(
+                "cannot create FIFO {}: {}",
+                path,
+                std::io::Error::last_os_error()
+            )));
+        }
+    }
+
+    let metadata = std::fs::metadata(path)
+        .map_err(|e| config_error(format!("cannot stat destination {}: {}", path, e)))?;
+    if !metadata.file_type().is_fifo() {
+        return Err(config_error(format!(
+            "destination {} is not a FIFO, create it with 'mkfifo {}'",
+            path, path
+        )));
+    }
+    if unsafe { libc::access(c_path.as_ptr(), libc::W_OK) } != 0 {
+        return Err(config_error(format!(
+            "destination FIFO {} is not writable: {}",
+            path,
+            std::io::Error::last_os_error()
+        )));
+    }
+    Ok(())
+}
+
+// try_open_fifo opens the FIFO if a reader is attached to it, it returns None without waiting otherwise.
+// The FIFO is opened in non-blocking mode, which fails with ENXIO as long as there is no reader.
+// Once opened, the file is switched back to blocking writes.
+pub fn try_open_fifo(path: &str) -> std::io::Result<Option<File>> {
+    match OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+    {
+        Ok(file) => {
+            let fd = file.as_raw_fd();
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(Some(file))
+        }
+        Err(err) if err.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// open_fifo waits for a reader to attach to the FIFO, retrying try_open_fifo so that we can log
+// and give up after `open_timeout` (None waits forever).
+fn open_fifo(path: &str, open_timeout: Option<Duration>) -> std::io::Result<File> {
+    let start = Instant::now();
+    let mut last_log: Option<Instant> = None;
+    loop {
+        match try_open_fifo(path)? {
+            Some(file) => return Ok(file),
+            None => {
+                if open_timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("no reader attached after {:?}", start.elapsed()),
+                    ));
+                }
+                if last_log.is_none_or(|last| last.elapsed() >= OPEN_WAITING_LOG_INTERVAL) {
+                    info!("waiting for reader on {}", path);
+                    last_log = Some(Instant::now());
+                }
+                std::thread::sleep(OPEN_RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+// open_destination opens the destination FIFO for writing, or returns None when no path is configured.
+// It waits until a reader is attached to the other end, for at most `open_timeout` if set.
+pub fn open_destination(
+    path: &str,
+    create_fifo: bool,
+    open_timeout: Option<Duration>,
+) -> PluginResult<Option<File>> {
+    if path.is_empty() {
+        return Ok(None);
+    }
+    prepare_fifo(path, create_fifo)?;
+    let file = open_fifo(path, open_timeout)
+        .map_err(|e| config_error(format!("cannot open FIFO {}: {}", path, e)))?;
+    Ok(Some(file))
+}
+
+// OpenRetry is how many more times a destination is opened after a failure, `delay` apart
+#[derive(Default, Clone, Copy, Debug)]
+pub struct OpenRetry {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+// open_destination_with_retry tries open_destination again after a failure, for example when the reader
+// recreates its FIFO while the plugin loads. The last error is returned once the retries are exhausted.
+pub fn open_destination_with_retry(
+    path: &str,
+    create_fifo: bool,
+    open_timeout: Option<Duration>,
+    retry: OpenRetry,
+    is_reload: bool,
+) -> PluginResult<Option<File>> {
+    let mut attempt = 0;
+    loop {
+        match open_destination(path, create_fifo, open_timeout) {
+            Ok(file) => return Ok(file),
+            Err(err) if attempt < retry.retries => {
+                attempt += 1;
+                warn!(
+                    "cannot open destination {} on {}: {}, retrying in {:?} ({}/{})",
+                    path,
+                    if is_reload { "reload" } else { "load" },
+                    err,
+                    retry.delay,
+                    attempt,
+                    retry.retries
+                );
+                std::thread::sleep(retry.delay);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn mkfifo(path: &str) {
+        let c_path = CString::new(path).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) }, 0);
+    }
+
+    #[test]
+    fn test_prepare_fifo_missing() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("blocks.fifo");
+        let path = path.to_str().unwrap();
+
+        let err = prepare_fifo(path, false).unwrap_err();
+        assert!(err.to_string().contains("does not exist"));
+        assert!(!Path::new(path).exists());
+
+        prepare_fifo(path, true).unwrap();
+        assert!(std::fs::metadata(path).unwrap().file_type().is_fifo());
+    }
+
+    #[test]
+    fn test_prepare_fifo_not_a_fifo() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("blocks.txt");
+        std::fs::write(&path, "").unwrap();
+
+        let err = prepare_fifo(path.to_str().unwrap(), true).unwrap_err();
+        assert!(err.to_string().contains("is not a FIFO"));
+
+        let err = prepare_fifo(dir.path().to_str().unwrap(), false).unwrap_err();
+        assert!(err.to_string().contains("is not a FIFO"));
+    }
+
+    #[test]
+    fn test_prepare_fifo_valid() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("blocks.fifo");
+        let path = path.to_str().unwrap();
+        mkfifo(path);
+
+        prepare_fifo(path, false).unwrap();
+        prepare_fifo(path, true).unwrap();
+    }
+
+    #[test]
+    fn test_open_destination_empty_path() {
+        assert!(open_destination("", false, None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_open_destination_delayed_reader() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("blocks.fifo");
+        let path = path.to_str().unwrap().to_string();
+        mkfifo(&path);
+
+        let reader_path = path.clone();
+        let reader = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            std::fs::read_to_string(reader_path).unwrap()
+        });
+
+        let mut file = open_destination(&path, false, Some(Duration::from_secs(10)))
+            .unwrap()
+            .unwrap();
+        let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+        assert_eq!(flags & libc::O_NONBLOCK, 0);
+
+        use std::io::Write;
+        writeln!(file, "FIRE INIT").unwrap();
+        drop(file);
+        assert_eq!(reader.join().unwrap(), "FIRE INIT\n");
+    }
+
+    #[test]
+    fn test_open_destination_with_retry() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("blocks.fifo");
+        let path = path.to_str().unwrap().to_string();
+        let retry = OpenRetry {
+            retries: 20,
+            delay: Duration::from_millis(50),
+        };
+
+        assert!(
+            open_destination_with_retry(&path, false, None, OpenRetry::default(), false).is_err()
+        );
+
+        // the reader creates its FIFO a bit later
+        let reader_path = path.clone();
+        let reader = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            mkfifo(&reader_path);
+            std::fs::read_to_string(reader_path).unwrap()
+        });
+        let mut file = open_destination_with_retry(&path, false, None, retry, true)
+            .unwrap()
+            .unwrap();
+        use std::io::Write;
+        writeln!(file, "FIRE INIT").unwrap();
+        drop(file);
+        assert_eq!(reader.join().unwrap(), "FIRE INIT\n");
+    }
+
+    #[test]
+    fn test_open_destination_timeout() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("blocks.fifo");
+        let path = path.to_str().unwrap();
+        mkfifo(path);
+
+        let err = open_destination(path, false, Some(Duration::from_millis(200))).unwrap_err();
+        assert!(err.to_string().contains("no reader attached"), "{}", err);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::config::{AccountFilterMode, AccountOutputConfig, Config};
+use agave_geyser_plugin_interface::geyser_plugin_interface::{
+    GeyserPluginError, Result as PluginResult,
+};
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashSet;
+use std::str::FromStr;
+
+// AccountFilter decides which account changes are kept, from the include_account_owners
+// and include_account_pubkeys lists of the config. Empty lists do not filter anything.
+#[derive(Default, Debug)]
+pub struct AccountFilter {
+    owners: HashSet<Pubkey>,
+    pubkeys: HashSet<Pubkey>,
+    mode: AccountFilterMode,
+}
+
+pub fn parse_pubkeys(field: &str, values: &[String]) -> PluginResult<HashSet<Pubkey>> {
+    values
+        .iter()
+        .map(|value| {
+            Pubkey::from_str(value).map_err(|err| GeyserPluginError::ConfigFileReadError {
+                msg: format!("invalid pubkey {:?} in {}: {}", value, field, err),
+            })
+        })
+        .collect()
+}
+
+fn contains(set: &HashSet<Pubkey>, key: &[u8]) -> bool {
+    Pubkey::try_from(key)
+        .map(|key| set.contains(&key))
+        .unwrap_or(false)
+}
+
+impl AccountFilter {
+    pub fn from_config(config: &Config) -> PluginResult<Self> {
+        Ok(AccountFilter {
+            owners: parse_pubkeys("include_account_owners", &config.include_account_owners)?,
+            pubkeys: parse_pubkeys("include_account_pubkeys", &config.include_account_pubkeys)?,
+            mode: config.account_filter_mode,
+        })
+    }
+
+    pub fn from_output_config(output: &AccountOutputConfig) -> PluginResult<Self> {
+        let field = |name: &str| format!("account_outputs {:?} {}", output.name, name);
+        Ok(AccountFilter {
+            owners: parse_pubkeys(
+                &field("include_account_owners"),
+                &output.include_account_owners,
+            )?,
+            pubkeys: parse_pubkeys(
+                &field("include_account_pubkeys"),
+                &output.include_account_pubkeys,
+            )?,
+            mode: output.account_filter_mode,
+        })
+    }
+
+    pub fn matches(&self, pubkey: &[u8], owner: &[u8]) -> bool {
+        match (self.owners.is_empty(), self.pubkeys.is_empty()) {
+            (true, true) => true,
+            (false, true) => contains(&self.owners, owner),
+            (true, false) => contains(&self.pubkeys, pubkey),
+            (false, false) => match self.mode {
+                AccountFilterMode::Union => {
+                    contains(&self.owners, owner) || contains(&self.pubkeys, pubkey)
+                }
+                AccountFilterMode::Intersection => {
+                    contains(&self.owners, owner) && contains(&self.pubkeys, pubkey)
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: Pubkey = Pubkey::new_from_array([1; 32]);
+    const OTHER_OWNER: Pubkey = Pubkey::new_from_array([2; 32]);
+    const POOL: Pubkey = Pubkey::new_from_array([3; 32]);
+    const OTHER_ACCOUNT: Pubkey = Pubkey::new_from_array([4; 32]);
+
+    fn filter(owners: &[Pubkey], pubkeys: &[Pubkey], mode: AccountFilterMode) -> AccountFilter {
+        let config = Config {
+            include_account_owners: owners.iter().map(|key| key.to_string()).collect(),
+            include_account_pubkeys: pubkeys.iter().map(|key| key.to_string()).collect(),
+            account_filter_mode: mode,
+            ..Config::default()
+        };
+        AccountFilter::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn test_no_filter() {
+        let filter = filter(&[], &[], AccountFilterMode::default());
+        assert!(filter.matches(OTHER_ACCOUNT.as_ref(), OTHER_OWNER.as_ref()));
+    }
+
+    #[test]
+    fn test_pubkey_only() {
+        let filter = filter(&[], &[POOL], AccountFilterMode::default());
+        assert!(filter.matches(POOL.as_ref(), OTHER_OWNER.as_ref()));
+        assert!(!filter.matches(OTHER_ACCOUNT.as_ref(), OWNER.as_ref()));
+    }
+
+    #[test]
+    fn test_owner_only() {
+        let filter = filter(&[OWNER], &[], AccountFilterMode::default());
+        assert!(filter.matches(OTHER_ACCOUNT.as_ref(), OWNER.as_ref()));
+        assert!(!filter.matches(POOL.as_ref(), OTHER_OWNER.as_ref()));
+    }
+
+    #[test]
+    fn test_combined() {
+        let union = filter(&[OWNER], &[POOL], AccountFilterMode::Union);
+        assert!(union.matches(POOL.as_ref(), OTHER_OWNER.as_ref()));
+        assert!(union.matches(OTHER_ACCOUNT.as_ref(), OWNER.as_ref()));
+        assert!(union.matches(POOL.as_ref(), OWNER.as_ref()));
+        assert!(!union.matches(OTHER_ACCOUNT.as_ref(), OTHER_OWNER.as_ref()));
+
+        let intersection = filter(&[OWNER], &[POOL], AccountFilterMode::Intersection);
+        assert!(intersection.matches(POOL.as_ref(), OWNER.as_ref()));
+        assert!(!intersection.matches(POOL.as_ref(), OTHER_OWNER.as_ref()));
+        assert!(!intersection.matches(OTHER_ACCOUNT.as_ref(), OWNER.as_ref()));
+    }
+
+    #[test]
+    fn test_invalid_pubkey() {
+        let config = Config {
+            include_account_pubkeys: vec!["not-a-pubkey".to_string()],
+            ..Config::default()
+        };
+        let err = AccountFilter::from_config(&config).unwrap_err();
+        assert!(
+            err.to_string().contains("include_account_pubkeys"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use crate::block_printer::CursorTracker;
+use crate::cursor::Cursor;
+use crate::pb::sf::solana::r#type::v1::{Account, AccountBlock, Block};
+use crate::sink::BlockSink;
+use crate::state::BlockInfo;
+use log::{debug, error, info};
+use prost::Message;
+use rdkafka::config::ClientConfig;
+use rdkafka::error::{KafkaError, KafkaResult, RDKafkaErrorCode};
+use rdkafka::producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext};
+use rdkafka::util::Timeout;
+use rdkafka::ClientContext;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// how long the producer is polled before sending again when its queue is full
+const QUEUE_FULL_POLL: Duration = Duration::from_millis(100);
+
+// DeliveryContext keeps the error of the last delivery that failed, until it is taken by the sink
+#[derive(Default)]
+struct DeliveryContext {
+    error: Mutex<Option<KafkaError>>,
+}
+
+impl ClientContext for DeliveryContext {}
+
+impl ProducerContext for DeliveryContext {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, delivery_result: &DeliveryResult<'_>, _: Self::DeliveryOpaque) {
+        if let Err((err, _)) = delivery_result {
+            *self.error.lock().expect("delivery lock poisoned") = Some(err.clone());
+        }
+    }
+}
+
+// KafkaSink publishes each block, prost-encoded and keyed by its slot in decimal, to a Kafka topic.
+// A block is sent once the previous one was acknowledged by the brokers: a full producer queue or a slow
+// broker blocks the processing, the order of the slots is kept, and the cursor only moves over acknowledged blocks.
+// The account blocks, the transactions and the startup snapshot are not published.
+pub struct KafkaSink {
+    producer: BaseProducer<DeliveryContext>,
+    topic: String,
+    cursor: Mutex<CursorTracker>,
+}
+
+impl KafkaSink {
+    pub fn new(brokers: &str, topic: &str) -> KafkaResult<Self> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .create_with_context(DeliveryContext::default())?;
+        info!(
+            "publishing the blocks to kafka topic {} on {}",
+            topic, brokers
+        );
+        Ok(KafkaSink {
+            producer,
+            topic: topic.to_string(),
+            cursor: Mutex::new(CursorTracker::default()),
+        })
+    }
+
+    // with_cursor writes the cursor every `write_interval_slots` acknowledged slots, and only over the slots
+    // confirmed with confirm_slot when `requires_confirmation`, like the BlockPrinter does
+    pub fn with_cursor(mut self, write_interval_slots: u64, requires_confirmation: bool) -> Self {
+        self.cursor = Mutex::new(CursorTracker::new(
+            write_interval_slots,
+            requires_confirmation,
+        ));
+        self
+    }
+
+    fn write_cursor(&self, cursor: Option<Cursor>, cursor_path: &str) -> std::io::Result<()> {
+        match cursor {
+            Some(cursor) => cursor.write(cursor_path),
+            None => Ok(()),
+        }
+    }
+
+    fn publish(&self, slot: u64, payload: &[u8]) -> KafkaResult<()> {
+        let key = slot.to_string();
+        let mut record = BaseRecord::to(&self.topic).key(&key).payload(payload);
+        loop {
+            match self.producer.send(record) {
+                Ok(()) => break,
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), rejected)) => {
+                    debug!("kafka producer queue full, waiting to send slot {}", slot);
+                    self.producer.poll(QUEUE_FULL_POLL);
+                    record = rejected;
+                }
+                Err((err, _)) => return Err(err),
+            }
+        }
+        // flush serves the delivery callback, the message either reached the brokers or failed by now
+        self.producer.flush(Timeout::Never)?;
+        match self
+            .producer
+            .context()
+            .error
+            .lock()
+            .expect("delivery lock poisoned")
+            .take()
+        {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+impl BlockSink for KafkaSink {
+    fn print_init(&mut self, _: &str, _: &str, _: &str) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn print_snapshot(&mut self, slot: u64, accounts: Vec<Account>) -> std::io::Result<()> {
+        debug!(
+            "not publishing the snapshot of {} accounts at slot {} to kafka",
+            accounts.len(),
+            slot
+        );
+        Ok(())
+    }
+
+    fn print(
+        &mut self,
+        block_info: &BlockInfo,
+        _lib: u64,
+        block: Block,
+        _account_block: AccountBlock,
+        cursor_path: &str,
+    ) -> std::io::Result<()> {
+        self.publish(block_info.slot, &block.encode_to_vec())
+            .map_err(std::io::Error::other)?;
+        let cursor = self.cursor.lock().expect("cursor lock poisoned").register(
+            block_info.slot,
+            &block_info.block_hash,
+            0,
+        );
+        self.write_cursor(cursor, cursor_path)
+    }
+
+    fn replay(
+        &mut self,
+        block_info: &BlockInfo,
+        _lib: u64,
+        block: Block,
+        _account_block: AccountBlock,
+    ) -> std::io::Result<()> {
+        self.publish(block_info.slot, &block.encode_to_vec())
+            .map_err(std::io::Error::other)
+    }
+
+    fn print_placeholder(
+        &mut self,
+        block_info: &BlockInfo,
+        lib: u64,
+        block: Block,
+        account_block: AccountBlock,
+    ) -> std::io::Result<()> {
+        self.replay(block_info, lib, block, account_block)
+    }
+
+    fn confirm_slot(&self, slot: u64, cursor_path: &str) {
+        let cursor = self
+            .cursor
+            .lock()
+            .expect("cursor lock poisoned")
+            .confirm(slot);
+        if let Err(err) = self.write_cursor(cursor, cursor_path) {
+            error!("cannot write cursor after slot {}: {}", slot, err);
+        }
+    }
+
+    fn flush_cursor(&self, cursor_path: &str) {
+        let cursor = self.cursor.lock().expect("cursor lock poisoned").flush();
+        if let Err(err) = self.write_cursor(cursor, cursor_path) {
+            error!("cannot write cursor: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::consumer::{BaseConsumer, Consumer};
+    use rdkafka::mocking::MockCluster;
+    use rdkafka::Message as _;
+
+    fn test_slot(slot: u64) -> (BlockInfo, Block) {
+        let block_info = BlockInfo {
+            slot,
+            parent_slot: slot - 1,
+            block_hash: format!("hash{}", slot),
+            parent_hash: format!("hash{}", slot - 1),
+            ..Default::default()
+        };
+        let block = Block {
+            slot,
+            parent_slot: slot - 1,
+            blockhash: block_info.block_hash.clone(),
+            previous_blockhash: block_info.parent_hash.clone(),
+            ..Default::default()
+        };
+        (block_info, block)
+    }
+
+    #[test]
+    fn test_kafka_sink() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("blocks", 1, 1).unwrap();
+        let dir = tempfile::TempDir::new().unwrap();
+        let cursor_path = dir.path().join("cursor").to_str().unwrap().to_string();
+
+        let mut sink = KafkaSink::new(&cluster.bootstrap_servers(), "blocks").unwrap();
+        for slot in [101, 102] {
+            let (block_info, block) = test_slot(slot);
+            sink.print(
+                &block_info,
+                100,
+                block,
+                AccountBlock::default(),
+                &cursor_path,
+            )
+            .unwrap();
+            // the cursor only moves once the block was acknowledged
+            assert_eq!(
+                std::fs::read_to_string(&cursor_path).unwrap(),
+                format!("{}:hash{}", slot, slot)
+            );
+        }
+        let (block_info, block) = test_slot(101);
+        sink.replay(&block_info, 100, block, AccountBlock::default())
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&cursor_path).unwrap(),
+            "102:hash102"
+        );
+
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .set("group.id", "test")
+            .set("auto.offset.reset", "earliest")
+            .create()
+            .unwrap();
+        consumer.subscribe(&["blocks"]).unwrap();
+        let mut published = vec![];
+        let start = std::time::Instant::now();
+        while published.len() < 3 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            if let Some(message) = consumer.poll(Duration::from_millis(100)) {
+                let message = message.unwrap();
+                let key = String::from_utf8(message.key().unwrap().to_vec()).unwrap();
+                let block = Block::decode(message.payload().unwrap()).unwrap();
+                published.push((key, block.slot, block.blockhash));
+            }
+        }
+        assert_eq!(
+            published,
+            vec![
+                ("101".to_string(), 101, "hash101".to_string()),
+                ("102".to_string(), 102, "hash102".to_string()),
+                ("101".to_string(), 101, "hash101".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_kafka_sink_cursor_write_interval() {
+        let cluster = MockCluster::new(1).unwrap();
+        let dir = tempfile::TempDir::new().unwrap();
+        let cursor_path = dir.path().join("cursor").to_str().unwrap().to_string();
+        let read_cursor = || std::fs::read_to_string(&cursor_path).unwrap_or_default();
+
+        let mut sink = KafkaSink::new(&cluster.bootstrap_servers(), "blocks")
+            .unwrap()
+            .with_cursor(3, false);
+        for slot in 101..=107 {
+            let (block_info, block) = test_slot(slot);
+            sink.print(
+                &block_info,
+                100,
+                block,
+                AccountBlock::default(),
+                &cursor_path,
+            )
+            .unwrap();
+            let expected = match slot {
+                101..=102 => "",
+                103..=105 => "103:hash103",
+                _ => "106:hash106",
+            };
+            assert_eq!(read_cursor(), expected, "after slot {}", slot);
+        }
+        sink.flush_cursor(&cursor_path);
+        assert_eq!(read_cursor(), "107:hash107");
+    }
+}
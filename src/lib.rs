@@ -1,6 +1,31 @@
+// solana_rpc_client_api's ClientError is big, it is passed around as the rpc client returns it
+#![allow(clippy::result_large_err)]
+
 mod block_printer;
-mod config;
+pub mod config;
+mod cursor;
+mod diff;
+#[cfg(test)]
+mod e2e_tests;
+mod fifo;
+mod filters;
+#[cfg(feature = "kafka")]
+mod kafka;
+#[allow(dead_code)]
 mod pb;
 mod plugins;
+mod rpc;
+mod sink;
+mod spill;
 mod state;
 mod utils;
+
+pub use block_printer::{BlockPrinter, PrinterOptions};
+pub use diff::apply_account_diff;
+pub use plugins::{Plugin, PluginBuilder};
+pub use sink::{BlockSink, MemorySink, PrintedSlot, PrintedSnapshot};
+pub use state::{DedupStats, State, StateOptions};
+
+// mimalloc used to be installed transitively by rbase64, keep it as the global allocator
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
@@ -1,2 +1,19 @@
 // @generated
+// This file is @generated by prost-build.
+/// TransactionBlock carries the transactions of a slot without the rest of the block
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TransactionBlock {
+    #[prost(uint64, tag="1")]
+    pub slot: u64,
+    #[prost(string, tag="2")]
+    pub hash: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub parent_slot: u64,
+    #[prost(string, tag="4")]
+    pub parent_hash: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="5")]
+    pub transactions: ::prost::alloc::vec::Vec<super::sf::solana::r#type::v1::ConfirmedTransaction>,
+}
 // @@protoc_insertion_point(module)
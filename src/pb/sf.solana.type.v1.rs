@@ -2,6 +2,7 @@
 // This file is @generated by prost-build.
 /// This Block is backwards compatible with solana.storage.ConfirmedBlock.ConfirmedBlock from
 /// the Solana Labs repositories.
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Block {
@@ -19,10 +20,22 @@ pub struct Block {
     pub block_time: ::core::option::Option<UnixTimestamp>,
     #[prost(message, optional, tag="7")]
     pub block_height: ::core::option::Option<BlockHeight>,
+    #[prost(message, optional, tag="8")]
+    pub num_partitions: ::core::option::Option<NumPartitions>,
     /// StreamingFast additions
     #[prost(uint64, tag="20")]
     pub slot: u64,
+    /// placeholder for a slot skipped by the cluster, it has no content
+    #[prost(bool, tag="21")]
+    pub skipped: bool,
+    /// number of transactions announced by the block metadata, `transactions` falls short of it when some were not received
+    #[prost(uint64, tag="22")]
+    pub expected_transaction_count: u64,
+    /// the block was produced after the plugin started, false for the blocks sent while catching up from the cursor
+    #[prost(bool, tag="23")]
+    pub live: bool,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ConfirmedTransaction {
@@ -31,6 +44,7 @@ pub struct ConfirmedTransaction {
     #[prost(message, optional, tag="2")]
     pub meta: ::core::option::Option<TransactionStatusMeta>,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Transaction {
@@ -39,6 +53,7 @@ pub struct Transaction {
     #[prost(message, optional, tag="2")]
     pub message: ::core::option::Option<Message>,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Message {
@@ -57,6 +72,7 @@ pub struct Message {
     #[prost(message, repeated, tag="6")]
     pub address_table_lookups: ::prost::alloc::vec::Vec<MessageAddressTableLookup>,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, Copy, PartialEq, ::prost::Message)]
 pub struct MessageHeader {
@@ -67,6 +83,7 @@ pub struct MessageHeader {
     #[prost(uint32, tag="3")]
     pub num_readonly_unsigned_accounts: u32,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct MessageAddressTableLookup {
@@ -77,6 +94,7 @@ pub struct MessageAddressTableLookup {
     #[prost(bytes="vec", tag="3")]
     pub readonly_indexes: ::prost::alloc::vec::Vec<u8>,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct TransactionStatusMeta {
@@ -116,12 +134,26 @@ pub struct TransactionStatusMeta {
     #[prost(uint64, optional, tag="16")]
     pub compute_units_consumed: ::core::option::Option<u64>,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct TransactionError {
     #[prost(bytes="vec", tag="1")]
     pub err: ::prost::alloc::vec::Vec<u8>,
+    /// name of the error variant, ex: `InstructionError`
+    #[prost(string, tag="2")]
+    pub kind: ::prost::alloc::string::String,
+    /// human readable description of the error
+    #[prost(string, tag="3")]
+    pub message: ::prost::alloc::string::String,
+    /// index of the failing instruction, for the instruction errors
+    #[prost(uint32, optional, tag="4")]
+    pub instruction_index: ::core::option::Option<u32>,
+    /// code of the `Custom` program errors
+    #[prost(uint32, optional, tag="5")]
+    pub custom_error_code: ::core::option::Option<u32>,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct InnerInstructions {
@@ -130,6 +162,7 @@ pub struct InnerInstructions {
     #[prost(message, repeated, tag="2")]
     pub instructions: ::prost::alloc::vec::Vec<InnerInstruction>,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct InnerInstruction {
@@ -145,6 +178,7 @@ pub struct InnerInstruction {
     #[prost(uint32, optional, tag="4")]
     pub stack_height: ::core::option::Option<u32>,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CompiledInstruction {
@@ -155,6 +189,7 @@ pub struct CompiledInstruction {
     #[prost(bytes="vec", tag="3")]
     pub data: ::prost::alloc::vec::Vec<u8>,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct TokenBalance {
@@ -169,9 +204,11 @@ pub struct TokenBalance {
     #[prost(string, tag="5")]
     pub program_id: ::prost::alloc::string::String,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct UiTokenAmount {
+    /// amount / 10^decimals as an f64, not exact for big amounts: `amount` is the exact value
     #[prost(double, tag="1")]
     pub ui_amount: f64,
     #[prost(uint32, tag="2")]
@@ -181,6 +218,7 @@ pub struct UiTokenAmount {
     #[prost(string, tag="4")]
     pub ui_amount_string: ::prost::alloc::string::String,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ReturnData {
@@ -189,6 +227,7 @@ pub struct ReturnData {
     #[prost(bytes="vec", tag="2")]
     pub data: ::prost::alloc::vec::Vec<u8>,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Reward {
@@ -203,24 +242,35 @@ pub struct Reward {
     #[prost(string, tag="5")]
     pub commission: ::prost::alloc::string::String,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Rewards {
     #[prost(message, repeated, tag="1")]
     pub rewards: ::prost::alloc::vec::Vec<Reward>,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, Copy, PartialEq, ::prost::Message)]
 pub struct UnixTimestamp {
     #[prost(int64, tag="1")]
     pub timestamp: i64,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct NumPartitions {
+    #[prost(uint64, tag="1")]
+    pub num_partitions: u64,
+}
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, Copy, PartialEq, ::prost::Message)]
 pub struct BlockHeight {
     #[prost(uint64, tag="1")]
     pub block_height: u64,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
 #[repr(i32)]
 pub enum RewardType {
@@ -256,6 +306,7 @@ impl RewardType {
         }
     }
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct AccountBlock {
@@ -268,10 +319,15 @@ pub struct AccountBlock {
     #[prost(string, tag="4")]
     pub parent_hash: ::prost::alloc::string::String,
     #[prost(message, optional, tag="6")]
+    #[cfg_attr(feature = "json-lines", serde(serialize_with = "crate::block_printer::serialize_timestamp"))]
     pub timestamp: ::core::option::Option<::prost_types::Timestamp>,
     #[prost(message, repeated, tag="7")]
     pub accounts: ::prost::alloc::vec::Vec<Account>,
+    /// accounts loaded from the snapshot at startup, not changes made by the block
+    #[prost(bool, tag="8")]
+    pub snapshot: bool,
 }
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Account {
@@ -283,5 +339,44 @@ pub struct Account {
     pub data: ::prost::alloc::vec::Vec<u8>,
     #[prost(bool, tag="7")]
     pub deleted: bool,
+    /// data was emptied because it was bigger than the configured maximum
+    #[prost(bool, tag="8")]
+    pub truncated: bool,
+    /// the owner is not the one of the previous write of the account
+    #[prost(bool, tag="9")]
+    pub owner_changed: bool,
+    #[prost(uint64, tag="10")]
+    pub lamports: u64,
+    #[prost(uint64, tag="11")]
+    pub rent_epoch: u64,
+    /// signature of the transaction that wrote the account, empty when geyser does not give it
+    #[prost(bytes="vec", tag="12")]
+    pub txn_signature: ::prost::alloc::vec::Vec<u8>,
+    /// write version given by geyser, orders the writes of an account across slots
+    #[prost(uint64, tag="13")]
+    pub write_version: u64,
+    /// set instead of data when the data is sent as a diff against the data last sent for the account
+    #[prost(message, optional, tag="14")]
+    pub data_diff: ::core::option::Option<AccountDiff>,
+}
+/// AccountDiff gives the data of an account from the data last sent for it: the data is resized to data_len,
+/// then each chunk overwrites the bytes at its offset
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AccountDiff {
+    #[prost(uint64, tag="1")]
+    pub data_len: u64,
+    #[prost(message, repeated, tag="2")]
+    pub chunks: ::prost::alloc::vec::Vec<AccountDiffChunk>,
+}
+#[cfg_attr(feature = "json-lines", derive(serde::Serialize))]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AccountDiffChunk {
+    #[prost(uint64, tag="1")]
+    pub offset: u64,
+    #[prost(bytes="vec", tag="2")]
+    pub data: ::prost::alloc::vec::Vec<u8>,
 }
 // @@protoc_insertion_point(module)
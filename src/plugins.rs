@@ -2,12 +2,18 @@ use agave_geyser_plugin_interface::geyser_plugin_interface::{
     ReplicaTransactionInfoV2, SlotStatus,
 };
 use {
-    crate::{config::Config as PluginConfig, state::BlockInfo, state::State},
+    crate::{
+        config::{
+            AccountKeysMode, AddressEncoding, BlockDetail, Commitment, Config as PluginConfig,
+            HashAlgorithm, RpcClientConfig, SinkConfig,
+        },
+        state::BlockInfo,
+        state::State,
+    },
     agave_geyser_plugin_interface::geyser_plugin_interface::{
-        GeyserPlugin, ReplicaAccountInfoVersions, ReplicaBlockInfoVersions,
+        GeyserPlugin, GeyserPluginError, ReplicaAccountInfoVersions, ReplicaBlockInfoVersions,
         ReplicaEntryInfoVersions, ReplicaTransactionInfoVersions, Result as PluginResult,
     },
-    gxhash::gxhash64,
     std::{concat, env, sync::RwLock},
 };
 
@@ -17,23 +23,28 @@ use crate::pb::sf::solana::r#type::v1::{
     Transaction, TransactionError, TransactionStatusMeta, UiTokenAmount,
 };
 
-use crate::state::{ACC_MUTEX, BLOCK_MUTEX};
-use crate::utils::convert_sol_timestamp;
+use crate::diff::AccountDataCache;
+use crate::spill::SpillFiles;
+use crate::state::{ProcessError, StateOptions};
+use crate::utils::{convert_sol_timestamp, encode_address, hash_account};
 use env_logger::Target;
-use log::{debug, info, LevelFilter};
+use log::{debug, error, info, warn, LevelFilter};
 use solana_rpc_client::rpc_client::RpcClient;
 
-use crate::block_printer::BlockPrinter;
+use crate::block_printer::{AccountOutput, BlockPrinter, PrinterOptions, FIRE_PROTOCOL_VERSION};
+use crate::cursor::{self_test_cursor, verify_cursor, Cursor};
+use crate::fifo::{open_destination_with_retry, OpenRetry};
+use crate::filters::{parse_pubkeys, AccountFilter};
+use crate::rpc::{new_rpc_client, LibRefresher, RetryPolicy};
+use crate::sink::BlockSink;
 
 use solana_sdk::hash::Hash;
 use solana_sdk::message::v0::LoadedAddresses;
 use solana_sdk::message::AccountKeys;
 use solana_sdk::transaction_context::TransactionReturnData;
 use std::fmt;
-use std::fs::OpenOptions;
 use std::str::FromStr;
-
-const SEED: i64 = 76;
+use std::time::Duration;
 
 #[derive(Clone)]
 pub struct ConfirmTransactionWithIndex {
@@ -43,10 +54,18 @@ pub struct ConfirmTransactionWithIndex {
 
 pub struct Plugin {
     state: Option<RwLock<State>>,
-    send_processed: bool,
+    commitment_trigger: Commitment,
+    cursor_commitment: Commitment,
     trace: bool,
     with_block: bool,
     with_account: bool,
+    with_transaction: bool,
+    config: Option<PluginConfig>,
+    account_filter: AccountFilter,
+    hash_algorithm: HashAlgorithm,
+    log_address_encoding: AddressEncoding,
+    transaction_options: TransactionOptions,
+    lib_refresher: Option<LibRefresher>,
 }
 
 impl fmt::Debug for Plugin {
@@ -55,32 +74,288 @@ impl fmt::Debug for Plugin {
     }
 }
 
-fn cursor_from_file(cursor_file: &str) -> Option<u64> {
-    match std::fs::read_to_string(cursor_file) {
-        Ok(cursor) => {
-            let cursor = cursor.trim().parse::<u64>().ok();
-            cursor
-        }
-        Err(_) => None,
-    }
+// PluginBuilder creates a Plugin without going through on_load, the state is normally created there
+// from the config file. Embedders and tests can hand over their own State instead.
+pub struct PluginBuilder {
+    state: Option<State>,
+    commitment_trigger: Commitment,
+    trace: bool,
+    with_block: bool,
+    with_account: bool,
+    with_transaction: bool,
 }
 
-impl Plugin {
-    pub fn new(send_processed: bool, trace: bool) -> Self {
-        Plugin {
+impl Default for PluginBuilder {
+    fn default() -> Self {
+        PluginBuilder {
             state: None,
-            send_processed,
-            trace,
+            commitment_trigger: Commitment::Confirmed,
+            trace: false,
             with_account: true, // in case account_data_notifications_enabled gets called before on_load
             with_block: true, // in case transaction_notifications_enabled gets called before on_load
+            with_transaction: true,
+        }
+    }
+}
+
+impl PluginBuilder {
+    pub fn state(mut self, state: State) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    pub fn send_processed(mut self, send_processed: bool) -> Self {
+        self.commitment_trigger = if send_processed {
+            Commitment::Processed
+        } else {
+            Commitment::Confirmed
+        };
+        self
+    }
+
+    pub fn commitment_trigger(mut self, commitment_trigger: Commitment) -> Self {
+        self.commitment_trigger = commitment_trigger;
+        self
+    }
+
+    pub fn trace(mut self, trace: bool) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    pub fn with_block(mut self, with_block: bool) -> Self {
+        self.with_block = with_block;
+        self
+    }
+
+    pub fn with_account(mut self, with_account: bool) -> Self {
+        self.with_account = with_account;
+        self
+    }
+
+    pub fn with_transaction(mut self, with_transaction: bool) -> Self {
+        self.with_transaction = with_transaction;
+        self
+    }
+
+    pub fn build(self) -> Plugin {
+        Plugin {
+            state: self.state.map(RwLock::new),
+            commitment_trigger: self.commitment_trigger,
+            cursor_commitment: self.commitment_trigger,
+            trace: self.trace,
+            with_account: self.with_account,
+            with_block: self.with_block,
+            with_transaction: self.with_transaction,
+            config: None,
+            account_filter: AccountFilter::default(),
+            hash_algorithm: HashAlgorithm::default(),
+            log_address_encoding: AddressEncoding::default(),
+            transaction_options: TransactionOptions::default(),
+            lib_refresher: None,
         }
     }
+}
+
+impl Plugin {
+    pub fn builder() -> PluginBuilder {
+        PluginBuilder::default()
+    }
+
+    // lag is State::lag, None before the plugin is loaded
+    pub fn lag(&self) -> Option<u64> {
+        self.state
+            .as_ref()?
+            .read()
+            .expect("cannot get state for lag (poisoned)")
+            .lag()
+    }
+
+    // cursor_self_test_passed is State::cursor_self_test_passed, None before the plugin is loaded
+    pub fn cursor_self_test_passed(&self) -> Option<bool> {
+        self.state
+            .as_ref()?
+            .read()
+            .expect("cannot get state for cursor self-test (poisoned)")
+            .cursor_self_test_passed()
+    }
+
+    // outputs_poisoned is State::outputs_poisoned, false before the plugin is loaded
+    fn outputs_poisoned(&self) -> bool {
+        self.state.as_ref().is_some_and(|state| {
+            state
+                .read()
+                .expect("cannot get state for outputs (poisoned)")
+                .outputs_poisoned()
+        })
+    }
+
+    // encode_sample_block is a self-test of the serialization path, to check a build without a validator:
+    // a synthetic block with one transfer is converted like the geyser notifications, written by a BlockPrinter
+    // to a temporary file and read back. The FIRE BLOCK payload must decode to the same block, its protobuf bytes
+    // are returned.
+    pub fn encode_sample_block() -> std::io::Result<Vec<u8>> {
+        use base64::Engine;
+        use prost::Message as _;
+        use solana_sdk::signer::{keypair::Keypair, Signer};
+
+        let slot = 100;
+        let payer = Keypair::new();
+        let transaction = solana_sdk::transaction::SanitizedTransaction::from_transaction_for_tests(
+            solana_sdk::transaction::Transaction::new_signed_with_payer(
+                &[solana_sdk::system_instruction::transfer(
+                    &payer.pubkey(),
+                    &solana_sdk::pubkey::Pubkey::new_unique(),
+                    10,
+                )],
+                Some(&payer.pubkey()),
+                &[&payer],
+                Hash::default(),
+            ),
+        );
+        let meta = solana_transaction_status::TransactionStatusMeta {
+            fee: 5000,
+            pre_balances: vec![1_000_000, 0, 1],
+            post_balances: vec![994_990, 10, 1],
+            log_messages: Some(vec![
+                "Program 11111111111111111111111111111111 success".to_string()
+            ]),
+            ..Default::default()
+        };
+        let info = ReplicaTransactionInfoV2 {
+            signature: transaction.signature(),
+            is_vote: false,
+            transaction: &transaction,
+            transaction_status_meta: &meta,
+            index: 0,
+        };
+        let block_info = BlockInfo {
+            slot,
+            parent_slot: slot - 1,
+            block_hash: Hash::new_unique().to_string(),
+            parent_hash: Hash::new_unique().to_string(),
+            timestamp: convert_sol_timestamp(1_700_000_000),
+            height: Some(slot - 10),
+            transaction_count: 1,
+            ..Default::default()
+        };
+        let block = crate::state::compose_and_purge_block(
+            slot,
+            &block_info,
+            vec![ConfirmTransactionWithIndex {
+                index: 0,
+                transaction: to_confirm_transaction(&info, TransactionOptions::default()),
+            }],
+            true,
+        );
+
+        static SAMPLES: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let dir = env::temp_dir().join(format!(
+            "firehose-geyser-sample-{}-{}",
+            std::process::id(),
+            SAMPLES.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir)?;
+        let result = (|| {
+            let block_path = dir.join("blocks");
+            let cursor_path = dir.join("cursor").to_string_lossy().to_string();
+            let mut printer = BlockPrinter::new(
+                Some(std::fs::File::create(&block_path)?),
+                None,
+                None,
+                PrinterOptions::default(),
+            );
+            printer.print(
+                &block_info,
+                slot - 1,
+                block.clone(),
+                Default::default(),
+                &cursor_path,
+            )?;
+
+            // the block is written by its own thread, the cursor moves once it is done
+            let start = std::time::Instant::now();
+            while !std::path::Path::new(&cursor_path).exists() {
+                if start.elapsed() > Duration::from_secs(5) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "the sample block was not written",
+                    ));
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+
+            let invalid =
+                |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+            let content = std::fs::read_to_string(&block_path)?;
+            let payload = content
+                .lines()
+                .find(|line| line.starts_with("FIRE BLOCK "))
+                .and_then(|line| line.split(' ').nth(8))
+                .ok_or_else(|| invalid(format!("no FIRE BLOCK line in {:?}", content)))?;
+            let encoded = base64::engine::general_purpose::STANDARD
+                .decode(payload)
+                .map_err(|err| invalid(format!("cannot decode the base64 payload: {}", err)))?;
+            let decoded = crate::pb::sf::solana::r#type::v1::Block::decode(encoded.as_slice())
+                .map_err(|err| invalid(format!("cannot decode the block: {}", err)))?;
+            if decoded != block {
+                return Err(invalid(
+                    "the decoded block differs from the one written".to_string(),
+                ));
+            }
+            Ok(encoded)
+        })();
+        let _ = std::fs::remove_dir_all(&dir);
+        result
+    }
     const VOTE111111111111111111111111111111111111111: [u8; 32] = [
         0x07, 0x61, 0x48, 0x1d, 0x35, 0x74, 0x74, 0xbb, 0x7c, 0x4d, 0x76, 0x24, 0xeb, 0xd3, 0xbd,
         0xb3, 0xd8, 0x35, 0x5e, 0x73, 0xd1, 0x10, 0x43, 0xfc, 0x0d, 0xa3, 0x53, 0x80, 0x00, 0x00,
         0x00, 0x00,
     ];
 
+    // apply_reloaded_config keeps the running state and outputs, only the settings that can change
+    // at runtime are applied, the others are reported as ignored.
+    fn apply_reloaded_config(
+        &mut self,
+        plugin_config: PluginConfig,
+        filter_level: LevelFilter,
+    ) -> PluginResult<()> {
+        self.account_filter = AccountFilter::from_config(&plugin_config)?;
+        self.trace = filter_level == LevelFilter::Trace;
+        self.commitment_trigger = plugin_config.commitment_trigger();
+        self.set_hash_algorithm(plugin_config.hash_algorithm);
+
+        if let Some(previous) = self.config.as_ref() {
+            let ignored = previous.static_changes(&plugin_config);
+            if !ignored.is_empty() {
+                warn!(
+                    "ignoring changes to {} on reload, restart the validator to apply them",
+                    ignored.join(", ")
+                );
+            }
+        }
+        info!(
+            "config reloaded: log level {}, commitment_trigger {:?}",
+            filter_level, self.commitment_trigger
+        );
+        self.config = Some(plugin_config);
+        Ok(())
+    }
+
+    // the hashes already cached were computed with the previous algorithm: each account is sent once more
+    // on its next change, after that the change detection works as before
+    fn set_hash_algorithm(&mut self, hash_algorithm: HashAlgorithm) {
+        if hash_algorithm != self.hash_algorithm {
+            info!(
+                "account data hash algorithm switched from {:?} to {:?}",
+                self.hash_algorithm, hash_algorithm
+            );
+            self.hash_algorithm = hash_algorithm;
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn set_account(
         &self,
         slot: u64,
@@ -88,12 +363,17 @@ impl Plugin {
         data: &[u8],
         owner: &[u8],
         write_version: u64,
-        deleted: bool,
+        lamports: u64,
+        rent_epoch: u64,
+        txn_signature: Option<&[u8]>,
         is_startup: bool,
     ) {
         if owner == Self::VOTE111111111111111111111111111111111111111 {
             return;
         }
+        if !self.account_filter.matches(pub_key, owner) {
+            return;
+        }
 
         let mut lock_state = self
             .state
@@ -106,16 +386,15 @@ impl Plugin {
             return;
         }
 
-        let data_hash = if data.len() == 0 {
-            0
-        } else {
-            gxhash64(data, SEED)
-        };
+        let data_hash = hash_account(self.hash_algorithm, data, owner, lamports);
 
         if self.trace {
             debug!(
-                "slot: {}, pub_key: {:?}, owner: {:?}, write_version: {}, deleted: {}, data_hash: {}, is_startup: {}",
-                slot, hex::encode(pub_key), hex::encode(owner), write_version, deleted, data_hash, is_startup
+                "slot: {}, pub_key: {}, owner: {}, write_version: {}, lamports: {}, data_hash: {}, is_startup: {}",
+                slot,
+                encode_address(self.log_address_encoding, pub_key),
+                encode_address(self.log_address_encoding, owner),
+                write_version, lamports, data_hash, is_startup
             );
         }
 
@@ -125,7 +404,9 @@ impl Plugin {
             data,
             owner,
             write_version,
-            deleted,
+            lamports,
+            rent_epoch,
+            txn_signature,
             is_startup,
             data_hash,
             self.trace,
@@ -138,7 +419,7 @@ impl GeyserPlugin for Plugin {
         concat!(env!("CARGO_PKG_NAME"), "-", env!("CARGO_PKG_VERSION"))
     }
 
-    fn on_load(&mut self, config_file: &str, _is_reload: bool) -> PluginResult<()> {
+    fn on_load(&mut self, config_file: &str, is_reload: bool) -> PluginResult<()> {
         let plugin_config = PluginConfig::load_from_file(config_file)?;
 
         let filter_level =
@@ -148,50 +429,120 @@ impl GeyserPlugin for Plugin {
             self.trace = true;
         }
 
-        env_logger::Builder::new()
-            .filter_level(filter_level)
+        // a reload may load a fresh copy of the library, whose log statics have no logger yet: the logger is
+        // installed on every load, and the error of an already installed one is ignored. The max level is set
+        // either way so that log.level can be updated without a restart.
+        let _ = env_logger::Builder::new()
+            .filter_level(LevelFilter::Trace)
             .format_timestamp_nanos()
             .target(Target::Stdout)
-            .init();
+            .try_init();
+        log::set_max_level(filter_level);
 
-        debug!("on load");
+        debug!("on load (reload: {})", is_reload);
 
-        let local_rpc_client = RpcClient::new(plugin_config.local_rpc_client.endpoint);
-        let remote_rpc_client = RpcClient::new(plugin_config.remote_rpc_client.endpoint);
-        let cursor = cursor_from_file(&plugin_config.cursor_file);
-        self.send_processed = plugin_config.send_processed;
+        if is_reload && self.state.is_some() {
+            return self.apply_reloaded_config(plugin_config, filter_level);
+        }
 
-        let blk_file = match plugin_config.block_destination_file.as_str() {
-            "" => {
-                self.with_block = false;
-                None
-            }
-            _ => {
-                self.with_block = true;
-                Some(
-                    OpenOptions::new()
-                        .write(true)
-                        .open(plugin_config.block_destination_file)
-                        .expect("Failed to open FIFO for blocks"),
-                )
-            }
+        let rpc_timeout = Duration::from_millis(plugin_config.rpc_timeout_ms);
+        let rpc_headers = |rpc_client: &RpcClientConfig| {
+            rpc_client
+                .header_map()
+                .map_err(|msg| GeyserPluginError::ConfigFileReadError { msg })
         };
-
-        let acc_blk_file = match plugin_config.account_block_destination_file.as_str() {
-            "" => {
-                self.with_account = false;
-                None
-            }
-            _ => {
-                self.with_account = true;
-                Some(
-                    OpenOptions::new()
-                        .write(true)
-                        .open(plugin_config.account_block_destination_file)
-                        .expect("Failed to open FIFO for account_blocks"),
-                )
-            }
+        let local_rpc_headers = rpc_headers(&plugin_config.local_rpc_client)?;
+        let remote_rpc_headers = rpc_headers(&plugin_config.remote_rpc_client)?;
+        let local_rpc_client = plugin_config.use_rpc.then(|| {
+            new_rpc_client(
+                plugin_config.local_rpc_client.endpoint.clone(),
+                rpc_timeout,
+                &local_rpc_headers,
+            )
+        });
+        let remote_rpc_clients: Vec<RpcClient> = plugin_config
+            .remote_rpc_client
+            .all_endpoints()
+            .into_iter()
+            .filter(|_| plugin_config.use_rpc)
+            .map(|endpoint| new_rpc_client(endpoint, rpc_timeout, &remote_rpc_headers))
+            .collect();
+        if !plugin_config.use_rpc {
+            info!("rpc disabled: the lib comes from the rooted slots and the holes are not filled");
+        }
+        let cursor = Cursor::from_file(&plugin_config.cursor_file).and_then(|cursor| {
+            let rpc_clients: Vec<&RpcClient> = local_rpc_client
+                .iter()
+                .chain(remote_rpc_clients.iter())
+                .collect();
+            let self_test = plugin_config
+                .cursor_self_test
+                .then(|| self_test_cursor(&cursor, &rpc_clients));
+            verify_cursor(cursor, &rpc_clients, self_test)
+        });
+        self.account_filter = AccountFilter::from_config(&plugin_config)?;
+        self.commitment_trigger = plugin_config.commitment_trigger();
+        self.cursor_commitment = plugin_config.cursor_commitment();
+        self.transaction_options = TransactionOptions::from_config(&plugin_config);
+        self.set_hash_algorithm(plugin_config.hash_algorithm);
+        self.log_address_encoding = plugin_config.log_address_encoding;
+
+        // a reload only gets here when the first load failed, a running plugin keeps its outputs
+        let open_retry = OpenRetry {
+            retries: plugin_config.open_retries,
+            delay: Duration::from_millis(plugin_config.open_retry_delay_ms),
         };
+        let open = |path: &str| {
+            open_destination_with_retry(
+                path,
+                plugin_config.create_fifo,
+                plugin_config.open_timeout_secs.map(Duration::from_secs),
+                open_retry,
+                is_reload,
+            )
+        };
+        let blk_file = open(&plugin_config.block_destination_file)?;
+        self.with_block = blk_file.is_some();
+        let acc_blk_file = open(&plugin_config.account_block_destination_file)?;
+        let mut account_outputs = vec![];
+        for output in &plugin_config.account_outputs {
+            let out = open(&output.destination_file)?.ok_or_else(|| {
+                GeyserPluginError::ConfigFileReadError {
+                    msg: format!("account output {:?} has no destination_file", output.name),
+                }
+            })?;
+            info!(
+                "writing the accounts of {:?} to {}",
+                output.name, output.destination_file
+            );
+            account_outputs.push(AccountOutput {
+                name: output.name.clone(),
+                filter: AccountFilter::from_output_config(output)?,
+                out,
+                path: output.destination_file.clone(),
+            });
+        }
+        let combined_file = open(&plugin_config.combined_destination_file)?;
+        if combined_file.is_some() {
+            info!(
+                "writing blocks and accountBlocks to {}",
+                plugin_config.combined_destination_file
+            );
+            self.with_block = true;
+        }
+        self.with_account =
+            acc_blk_file.is_some() || !account_outputs.is_empty() || combined_file.is_some();
+        let trx_file = open(&plugin_config.transaction_destination_file)?;
+        self.with_transaction = trx_file.is_some();
+        if plugin_config.sink != SinkConfig::Fifo {
+            info!("sending blocks to {:?}", plugin_config.sink);
+            self.with_block = true;
+        }
+
+        if self.with_transaction {
+            info!("processing transactionBlocks...");
+        }
+
         if self.with_account && self.with_block {
             info!("processing blocks and accountBlocks...");
         } else if self.with_account {
@@ -202,25 +553,154 @@ impl GeyserPlugin for Plugin {
             info!("no processing enabled...");
         }
 
-        let mut printer = BlockPrinter::new(blk_file, acc_blk_file, plugin_config.noop);
-        printer
-            .print_init("sf.solana.type.v1.Block", "sf.solana.type.v1.AccountBlock")
-            .expect("Failed to print init");
-
-        self.state = Some(RwLock::new(State::new(
+        if !plugin_config.use_rpc && plugin_config.lib_refresh_interval_secs.is_some() {
+            warn!("lib_refresh_interval_secs is ignored, the rpc is disabled");
+        }
+        self.lib_refresher = plugin_config
+            .lib_refresh_interval_secs
+            .filter(|_| plugin_config.use_rpc)
+            .map(|interval| {
+                info!("refreshing the lib from the local rpc every {}s", interval);
+                LibRefresher::spawn(
+                    new_rpc_client(
+                        plugin_config.local_rpc_client.endpoint.clone(),
+                        rpc_timeout,
+                        &local_rpc_headers,
+                    ),
+                    Duration::from_secs(interval.max(1)),
+                )
+            });
+
+        let mut sink: Box<dyn BlockSink> = match &plugin_config.sink {
+            SinkConfig::Fifo => {
+                let mut printer = BlockPrinter::new(
+                    blk_file,
+                    acc_blk_file,
+                    trx_file,
+                    printer_options(&plugin_config),
+                )
+                .with_account_outputs(account_outputs);
+                if let Some(combined_file) = combined_file {
+                    printer = printer.with_combined_output(combined_file);
+                }
+                if plugin_config.reopen_destinations {
+                    printer = printer.with_reopen(
+                        &plugin_config.block_destination_file,
+                        &plugin_config.account_block_destination_file,
+                        &plugin_config.transaction_destination_file,
+                        &plugin_config.combined_destination_file,
+                    );
+                }
+                Box::new(printer)
+            }
+            #[cfg(feature = "kafka")]
+            SinkConfig::Kafka { brokers, topic } => {
+                let options = printer_options(&plugin_config);
+                let sink = crate::kafka::KafkaSink::new(brokers, topic).map_err(|err| {
+                    GeyserPluginError::ConfigFileReadError {
+                        msg: format!("cannot create the kafka producer: {}", err),
+                    }
+                })?;
+                Box::new(sink.with_cursor(
+                    options.cursor_write_interval_slots,
+                    options.cursor_requires_confirmation,
+                ))
+            }
+        };
+        sink.print_init(
+            "sf.solana.type.v1.Block",
+            "sf.solana.type.v1.AccountBlock",
+            "account_plugins.TransactionBlock",
+        )
+        .expect("Failed to print init");
+
+        let mut state = State::new(
             local_rpc_client,
-            remote_rpc_client,
+            remote_rpc_clients,
             cursor,
-            plugin_config.cursor_file,
-            printer,
-        )));
+            plugin_config.cursor_file.clone(),
+            sink,
+            StateOptions {
+                transaction_order: plugin_config.transaction_order,
+                min_account_data_bytes: plugin_config.min_account_data_bytes,
+                max_account_data_bytes: plugin_config.max_account_data_bytes,
+                oversized_accounts: plugin_config.oversized_accounts,
+                rpc_retry: RetryPolicy {
+                    max_retries: plugin_config.rpc_max_retries,
+                    backoff: Duration::from_millis(plugin_config.rpc_retry_backoff_ms),
+                },
+                emit_startup_snapshot: plugin_config.emit_startup_snapshot,
+                startup_snapshot_chunk_accounts: Some(
+                    plugin_config.startup_snapshot_chunk_accounts,
+                ),
+                watch_accounts: parse_pubkeys("watch_accounts", &plugin_config.watch_accounts)?,
+                log_address_encoding: plugin_config.log_address_encoding,
+                account_sort: plugin_config.account_sort,
+                account_block_mode: plugin_config.account_block_mode,
+                deletion_detection: plugin_config.deletion_detection,
+                account_txn_signature: plugin_config.account_txn_signature,
+                replay_buffer_slots: plugin_config.replay_buffer_slots,
+                max_natural_gap: plugin_config.max_natural_gap,
+                compact_account_changes: plugin_config.compact_account_changes,
+                max_buffered_slots: plugin_config.max_buffered_slots,
+                without_transactions: !self.transaction_notifications_enabled(),
+                detect_owner_changes: plugin_config.detect_owner_changes,
+                stop_at_slot: plugin_config.stop_at_slot,
+                emit_skipped_slots: plugin_config.emit_skipped_slots,
+                verify_blockhash_on_lib: plugin_config.verify_blockhash_on_lib,
+                rpc_cross_check: plugin_config.rpc_cross_check,
+                retention_slots: Some(plugin_config.retention_slots),
+                processed_retention_slots: Some(plugin_config.processed_retention_slots),
+                refreshed_lib: self.lib_refresher.as_ref().map(LibRefresher::latest),
+                disable_account_dedup: !plugin_config.dedup_accounts,
+                without_block_rewards: !plugin_config.include_block_rewards,
+                cursor_self_test: plugin_config.cursor_self_test,
+                prefer_later_equal_write_version: plugin_config.prefer_later_equal_write_version,
+            },
+        );
+        if !plugin_config.account_spill_dir.is_empty() {
+            let spill_files = SpillFiles::new(&plugin_config.account_spill_dir).map_err(|err| {
+                GeyserPluginError::ConfigFileReadError {
+                    msg: format!(
+                        "cannot use account_spill_dir {}: {}",
+                        plugin_config.account_spill_dir, err
+                    ),
+                }
+            })?;
+            info!(
+                "spilling the buffered account data to {}",
+                plugin_config.account_spill_dir
+            );
+            state = state.with_spill_files(spill_files);
+        }
+        if plugin_config.account_data_diff {
+            info!(
+                "sending the account data as diffs, against the data of up to {} accounts and {} bytes",
+                plugin_config.account_data_diff_max_accounts,
+                plugin_config.account_data_diff_max_bytes
+            );
+            state = state.with_account_data_diff(AccountDataCache::new(
+                plugin_config.account_data_diff_max_accounts,
+                plugin_config.account_data_diff_max_bytes,
+            ));
+        }
+        self.state = Some(RwLock::new(state));
 
         info!("cursor: {:?}", cursor);
+        self.config = Some(plugin_config);
 
         Ok(())
     }
 
-    fn on_unload(&mut self) {}
+    fn on_unload(&mut self) {
+        self.lib_refresher = None;
+        if let Some(state) = self.state.as_ref() {
+            state
+                .read()
+                .expect("cannot get state while flushing cursor (poisoned)")
+                .flush_cursor();
+        }
+    }
 
     fn update_account(
         &self,
@@ -239,7 +719,9 @@ impl GeyserPlugin for Plugin {
                     account.data,
                     account.owner,
                     account.write_version,
-                    account.lamports == 0,
+                    account.lamports,
+                    account.rent_epoch,
+                    None,
                     is_startup,
                 );
             }
@@ -251,7 +733,9 @@ impl GeyserPlugin for Plugin {
                     account.data,
                     account.owner,
                     account.write_version,
-                    account.lamports == 0,
+                    account.lamports,
+                    account.rent_epoch,
+                    account.txn_signature.map(|signature| signature.as_ref()),
                     is_startup,
                 );
             }
@@ -263,7 +747,9 @@ impl GeyserPlugin for Plugin {
                     account.data,
                     account.owner,
                     account.write_version,
-                    account.lamports == 0,
+                    account.lamports,
+                    account.rent_epoch,
+                    account.txn.map(|txn| txn.signature().as_ref()),
                     is_startup,
                 );
             }
@@ -273,15 +759,17 @@ impl GeyserPlugin for Plugin {
     }
 
     fn notify_end_of_startup(&self) -> PluginResult<()> {
+        let mut lock_state = self
+            .state
+            .as_ref()
+            .expect("cannot get state at end of startup (state is None)")
+            .write()
+            .expect("cannot get state at end of startup (poisoned)");
         info!(
             "preloaded account data hash count: {}",
-            self.state
-                .as_ref()
-                .expect("cannot get state while getting hash count (state is None)")
-                .read()
-                .expect("cannot get state while getting hash count (poisoned)")
-                .get_hash_count()
+            lock_state.get_hash_count()
         );
+        lock_state.end_of_startup();
         info!("end of startup");
         Ok(())
     }
@@ -292,12 +780,12 @@ impl GeyserPlugin for Plugin {
         _parent: Option<u64>,
         status: SlotStatus,
     ) -> PluginResult<()> {
-        if ACC_MUTEX.is_poisoned() || BLOCK_MUTEX.is_poisoned() {
-            panic!("poisoned mutex")
+        if self.outputs_poisoned() {
+            return Err(runtime_error(ProcessError::MutexPoisoned));
         }
         match status {
-            SlotStatus::Processed => match self.send_processed {
-                true => {
+            SlotStatus::Processed => match self.commitment_trigger {
+                Commitment::Processed => {
                     debug!(
                         "slot processed {} (parent: {}) acting as confirmed",
                         slot,
@@ -310,13 +798,9 @@ impl GeyserPlugin for Plugin {
                         .write()
                         .expect("cannot get RW lock for update_slot_status (poisoned)");
                     lock_state.set_confirmed_slot(slot);
-                    if lock_state.is_ready(slot) {
-                        if lock_state.process_upto(slot).is_err() {
-                            panic!("poisoned mutex")
-                        }
-                    }
+                    process_if_ready(&mut lock_state, slot)?;
                 }
-                false => {
+                Commitment::Confirmed => {
                     debug!(
                         "slot processed {} (parent: {}) (noop)",
                         slot,
@@ -326,41 +810,52 @@ impl GeyserPlugin for Plugin {
             },
             SlotStatus::Rooted => {
                 debug!("slot rooted {}", slot);
-                self.state
+                let mut lock_state = self
+                    .state
                     .as_ref()
                     .expect("cannot get RW lock for set_lib (state is None)")
                     .write()
-                    .expect("cannot get RW lock for set_lib (poisoned)")
-                    .set_lib(slot);
-            }
-            SlotStatus::Confirmed => match self.send_processed {
-                true => {
-                    debug!(
-                        "slot confirmed {} (parent: {}) (noop)",
-                        slot,
-                        _parent.unwrap_or_default()
-                    );
+                    .expect("cannot get RW lock for set_lib (poisoned)");
+                lock_state.set_lib(slot);
+                // a replay asked through the control file is done once per rooted slot at most
+                if let Some(control_file) = self
+                    .config
+                    .as_ref()
+                    .map(|config| config.replay_control_file.as_str())
+                    .filter(|control_file| !control_file.is_empty())
+                {
+                    if let Some((from_slot, to_slot)) = take_replay_request(control_file) {
+                        if let Err(err) = lock_state.replay(from_slot, to_slot) {
+                            error!("cannot replay slots {} to {}: {}", from_slot, to_slot, err);
+                            return Err(GeyserPluginError::Custom(Box::new(err)));
+                        }
+                    }
                 }
-                false => {
-                    debug!(
-                        "slot confirmed {} (parent: {})",
-                        slot,
-                        _parent.unwrap_or_default()
-                    );
+            }
+            SlotStatus::Confirmed => {
+                let trigger = self.commitment_trigger == Commitment::Confirmed;
+                let confirm_cursor = self.cursor_commitment == Commitment::Confirmed;
+                debug!(
+                    "slot confirmed {} (parent: {})",
+                    slot,
+                    _parent.unwrap_or_default()
+                );
+                if trigger || confirm_cursor {
                     let mut lock_state = self
                         .state
                         .as_ref()
                         .expect("cannot get RW lock for set_confirmed_slot (state is None)")
                         .write()
                         .expect("cannot get RW lock for set_confirmed_slot (poisoned)");
-                    lock_state.set_confirmed_slot(slot);
-                    if lock_state.is_ready(slot) {
-                        if lock_state.process_upto(slot).is_err() {
-                            panic!("poisoned mutex")
-                        }
+                    if trigger {
+                        lock_state.set_confirmed_slot(slot);
+                        process_if_ready(&mut lock_state, slot)?;
+                    }
+                    if confirm_cursor {
+                        lock_state.confirm_cursor(slot);
                     }
                 }
-            },
+            }
         }
 
         Ok(())
@@ -371,7 +866,7 @@ impl GeyserPlugin for Plugin {
         transaction: ReplicaTransactionInfoVersions<'_>,
         slot: u64,
     ) -> PluginResult<()> {
-        if !self.with_block {
+        if !self.with_block && !self.with_transaction {
             return Ok(());
         }
 
@@ -382,7 +877,7 @@ impl GeyserPlugin for Plugin {
             ReplicaTransactionInfoVersions::V0_0_2(info) => info,
         };
 
-        let compiled_transaction = to_confirm_transaction(&transaction);
+        let compiled_transaction = to_confirm_transaction(transaction, self.transaction_options);
         let tx = ConfirmTransactionWithIndex {
             index: transaction.index,
             transaction: compiled_transaction,
@@ -396,13 +891,8 @@ impl GeyserPlugin for Plugin {
             .expect("cannot get RW lock for notify_transaction (poisoned)");
 
         lock_state.set_transaction(slot, tx);
-        if lock_state.is_ready(slot) {
-            if lock_state.process_upto(slot).is_err() {
-                panic!("poisoned mutex")
-            }
-        }
-
-        Ok(())
+        // the last transaction of a confirmed slot makes it ready, it is sent now rather than on the next slot status
+        process_if_ready(&mut lock_state, slot)
     }
 
     fn notify_entry(&self, _entry: ReplicaEntryInfoVersions) -> PluginResult<()> {
@@ -410,15 +900,154 @@ impl GeyserPlugin for Plugin {
     }
 
     fn notify_block_metadata(&self, block_info: ReplicaBlockInfoVersions<'_>) -> PluginResult<()> {
-        if ACC_MUTEX.is_poisoned() || BLOCK_MUTEX.is_poisoned() {
-            panic!("poisoned mutex")
+        if self.outputs_poisoned() {
+            return Err(runtime_error(ProcessError::MutexPoisoned));
+        }
+
+        let (block_info, block_time) = block_info_from_replica(block_info);
+        let slot = block_info.slot;
+
+        let mut lock_state = self
+            .state
+            .as_ref()
+            .expect("state is None while updating slot status")
+            .write()
+            .expect("rw mutex poisoned while updating slot status");
+
+        if block_time.is_none() {
+            lock_state.set_block_time_missing(slot);
         }
+        lock_state.set_block_info(block_info);
 
-        let block_info = match block_info {
-            ReplicaBlockInfoVersions::V0_0_1(_) => {
-                panic!("V0_0_1 not supported");
+        // if we get block_info for block 25, but we have 'confirmed blocks' 20 to 24, we'll fetch their block_info from RPC, which is a bit costly but prevents being stuck forever. This happens in rare cases, mostly upon startup
+        for slot in lock_state.ordered_confirmed_slots_upto(slot) {
+            // a failed fetch is logged and retried when the slot is processed
+            if !lock_state.has_block_info(slot) && lock_state.cache_block_from_rpc(slot).is_err() {
+                break;
             }
-            ReplicaBlockInfoVersions::V0_0_2(blockinfo) => BlockInfo {
+        }
+
+        process_if_ready(&mut lock_state, slot)
+    }
+
+    fn account_data_notifications_enabled(&self) -> bool {
+        self.with_account
+    }
+
+    // only the block and transaction outputs use the transactions, without them the validator does not send any
+    fn transaction_notifications_enabled(&self) -> bool {
+        self.with_block || self.with_transaction
+    }
+
+    fn entry_notifications_enabled(&self) -> bool {
+        false
+    }
+}
+
+fn printer_options(plugin_config: &PluginConfig) -> PrinterOptions {
+    PrinterOptions {
+        noop: plugin_config.noop,
+        output_format: plugin_config.output_format,
+        fire_block_template: plugin_config.effective_fire_block_template(),
+        cursor_write_interval_slots: plugin_config.cursor_write_interval_slots,
+        cursor_requires_confirmation: plugin_config.cursor_commitment() == Commitment::Confirmed,
+        protocol_version: plugin_config
+            .protocol_version
+            .clone()
+            .unwrap_or_else(|| FIRE_PROTOCOL_VERSION.to_string()),
+        init_capabilities: plugin_config.init_capabilities(),
+        payload_checksum: plugin_config.payload_checksum,
+        skip_empty_account_blocks: plugin_config.skip_empty_account_blocks,
+        block_compression: plugin_config.block_compression,
+        account_compression: plugin_config.account_block_compression,
+        idle_marker_interval: plugin_config
+            .idle_marker_interval_ms
+            .map(|interval| Duration::from_millis(interval.max(1))),
+        writer_stall_timeout: plugin_config
+            .writer_stall_timeout_secs
+            .map(|timeout| Duration::from_secs(timeout.max(1))),
+        writer_stall_action: plugin_config.writer_stall_action,
+    }
+}
+
+// take_replay_request reads and removes the replay control file, it holds `FROM_SLOT TO_SLOT`.
+// None when there is no file or it cannot be parsed.
+fn take_replay_request(control_file: &str) -> Option<(u64, u64)> {
+    let request = std::fs::read_to_string(control_file).ok()?;
+    if let Err(err) = std::fs::remove_file(control_file) {
+        warn!(
+            "cannot remove replay control file {}: {}",
+            control_file, err
+        );
+    }
+    let slots: Vec<u64> = request
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .unwrap_or_default();
+    match slots[..] {
+        [from_slot, to_slot] if from_slot <= to_slot => {
+            info!("replay of slots {} to {} requested", from_slot, to_slot);
+            Some((from_slot, to_slot))
+        }
+        _ => {
+            warn!(
+                "ignoring replay request {:?} from {}, expecting 'FROM_SLOT TO_SLOT'",
+                request.trim(),
+                control_file
+            );
+            None
+        }
+    }
+}
+
+// process_if_ready sends the slot, and the confirmed slots before it, once everything was received for it.
+// A missing block info is only logged, it is asked again on the next call.
+fn process_if_ready(state: &mut State, slot: u64) -> PluginResult<()> {
+    if !state.is_ready(slot) {
+        return Ok(());
+    }
+    match state.process_upto(slot) {
+        Ok(()) => Ok(()),
+        Err(ProcessError::MissingBlockInfo(missing)) => {
+            info!(
+                "processing up to slot {} stopped, waiting for block info of slot {}",
+                slot, missing
+            );
+            Ok(())
+        }
+        Err(ProcessError::RpcUnavailable { slot: missing, .. }) => {
+            warn!(
+                "processing up to slot {} stopped, no rpc could give block info of slot {}",
+                slot, missing
+            );
+            Ok(())
+        }
+        Err(
+            err @ (ProcessError::PrinterFailed { .. }
+            | ProcessError::SpillFailed { .. }
+            | ProcessError::MutexPoisoned),
+        ) => {
+            error!("processing up to slot {} failed: {}", slot, err);
+            Err(runtime_error(err))
+        }
+    }
+}
+
+// runtime_error hands a processing error over to the validator
+fn runtime_error(err: ProcessError) -> GeyserPluginError {
+    GeyserPluginError::Custom(Box::new(err))
+}
+
+// block_info_from_replica converts the block metadata from geyser, the block time is also returned as received
+// so that the caller knows whether it has to be fetched elsewhere.
+fn block_info_from_replica(block_info: ReplicaBlockInfoVersions<'_>) -> (BlockInfo, Option<i64>) {
+    match block_info {
+        ReplicaBlockInfoVersions::V0_0_1(_) => {
+            panic!("V0_0_1 not supported");
+        }
+        ReplicaBlockInfoVersions::V0_0_2(blockinfo) => (
+            BlockInfo {
                 block_hash: blockinfo.blockhash.to_string(),
                 parent_hash: blockinfo.parent_blockhash.to_string(),
                 parent_slot: blockinfo.parent_slot,
@@ -426,10 +1055,14 @@ impl GeyserPlugin for Plugin {
                 height: blockinfo.block_height,
                 timestamp: convert_sol_timestamp(blockinfo.block_time.unwrap_or_default()),
                 rewards: to_block_rewards_from_vec(blockinfo.rewards),
+                num_reward_partitions: None,
                 transaction_count: blockinfo.executed_transaction_count,
             },
+            blockinfo.block_time,
+        ),
 
-            ReplicaBlockInfoVersions::V0_0_3(blockinfo) => BlockInfo {
+        ReplicaBlockInfoVersions::V0_0_3(blockinfo) => (
+            BlockInfo {
                 block_hash: blockinfo.blockhash.to_string(),
                 parent_hash: blockinfo.parent_blockhash.to_string(),
                 parent_slot: blockinfo.parent_slot,
@@ -437,10 +1070,14 @@ impl GeyserPlugin for Plugin {
                 height: blockinfo.block_height,
                 timestamp: convert_sol_timestamp(blockinfo.block_time.unwrap_or_default()),
                 rewards: to_block_rewards_from_vec(blockinfo.rewards),
+                num_reward_partitions: None,
                 transaction_count: blockinfo.executed_transaction_count,
             },
+            blockinfo.block_time,
+        ),
 
-            ReplicaBlockInfoVersions::V0_0_4(blockinfo) => BlockInfo {
+        ReplicaBlockInfoVersions::V0_0_4(blockinfo) => (
+            BlockInfo {
                 block_hash: blockinfo.blockhash.to_string(),
                 parent_hash: blockinfo.parent_blockhash.to_string(),
                 parent_slot: blockinfo.parent_slot,
@@ -448,46 +1085,11 @@ impl GeyserPlugin for Plugin {
                 height: blockinfo.block_height,
                 timestamp: convert_sol_timestamp(blockinfo.block_time.unwrap_or_default()),
                 rewards: to_block_rewards(&Some(blockinfo.rewards.rewards.clone())),
+                num_reward_partitions: blockinfo.rewards.num_partitions,
                 transaction_count: blockinfo.executed_transaction_count,
             },
-        };
-        let slot = block_info.slot;
-
-        let mut lock_state = self
-            .state
-            .as_ref()
-            .expect("state is None while updating slot status")
-            .write()
-            .expect("rw mutex poisoned while updating slot status");
-
-        lock_state.set_block_info(block_info);
-
-        // if we get block_info for block 25, but we have 'confirmed blocks' 20 to 24, we'll fetch their block_info from RPC, which is a bit costly but prevents being stuck forever. This happens in rare cases, mostly upon startup
-        for slot in lock_state.ordered_confirmed_slots_upto(slot) {
-            if !lock_state.has_block_info(slot) {
-                lock_state.cache_block_from_rpc(slot);
-            }
-        }
-
-        if lock_state.is_ready(slot) {
-            if lock_state.process_upto(slot).is_err() {
-                panic!("poisoned mutex")
-            }
-        }
-
-        Ok(())
-    }
-
-    fn account_data_notifications_enabled(&self) -> bool {
-        self.with_account
-    }
-
-    fn transaction_notifications_enabled(&self) -> bool {
-        true
-    }
-
-    fn entry_notifications_enabled(&self) -> bool {
-        false
+            blockinfo.block_time,
+        ),
     }
 }
 
@@ -541,33 +1143,78 @@ pub fn to_block_rewards(rewards: &Option<solana_transaction_status::Rewards>) ->
 ///
 /// This function returns the Plugin pointer as trait GeyserPlugin.
 pub unsafe extern "C" fn _create_plugin() -> *mut dyn GeyserPlugin {
-    let plugin = Plugin::new(false, false);
+    let plugin = Plugin::builder().build();
     let plugin: Box<dyn GeyserPlugin> = Box::new(plugin);
     Box::into_raw(plugin)
 }
 
-fn to_confirm_transaction(tx: &'_ ReplicaTransactionInfoV2<'_>) -> ConfirmedTransaction {
+// TransactionOptions selects what is kept of each transaction when it is converted
+#[derive(Clone, Copy)]
+struct TransactionOptions {
+    block_detail: BlockDetail,
+    decode_errors: bool,
+    account_keys_mode: AccountKeysMode,
+    drop_lossy_ui_amount: bool,
+}
+
+impl Default for TransactionOptions {
+    fn default() -> Self {
+        TransactionOptions {
+            block_detail: BlockDetail::default(),
+            decode_errors: true,
+            account_keys_mode: AccountKeysMode::default(),
+            drop_lossy_ui_amount: false,
+        }
+    }
+}
+
+impl TransactionOptions {
+    fn from_config(plugin_config: &PluginConfig) -> Self {
+        TransactionOptions {
+            block_detail: plugin_config.block_detail,
+            decode_errors: plugin_config.decode_transaction_errors,
+            account_keys_mode: plugin_config.account_keys_mode,
+            drop_lossy_ui_amount: plugin_config.drop_lossy_ui_amount,
+        }
+    }
+}
+
+fn to_confirm_transaction(
+    tx: &'_ ReplicaTransactionInfoV2<'_>,
+    options: TransactionOptions,
+) -> ConfirmedTransaction {
     ConfirmedTransaction {
         transaction: Some(to_transaction(
             tx.transaction,
             &tx.transaction_status_meta.loaded_addresses,
+            options,
+        )),
+        meta: Some(to_transaction_meta_status(
+            tx.transaction_status_meta,
+            options,
         )),
-        meta: Some(to_transaction_meta_status(tx.transaction_status_meta)),
     }
 }
 
 fn to_transaction_meta_status(
     status: &solana_transaction_status::TransactionStatusMeta,
+    options: TransactionOptions,
 ) -> TransactionStatusMeta {
     TransactionStatusMeta {
-        err: to_transaction_err(status),
+        err: to_transaction_err(status, options.decode_errors),
         fee: status.fee,
         pre_balances: status.pre_balances.to_vec(),
         post_balances: status.post_balances.to_vec(),
         inner_instructions: to_inner_instructions(&status.inner_instructions),
         log_messages: to_log_messages(&status.log_messages),
-        pre_token_balances: to_token_balances(&status.pre_token_balances),
-        post_token_balances: to_token_balances(&status.post_token_balances),
+        pre_token_balances: to_token_balances(
+            &status.pre_token_balances,
+            options.drop_lossy_ui_amount,
+        ),
+        post_token_balances: to_token_balances(
+            &status.post_token_balances,
+            options.drop_lossy_ui_amount,
+        ),
         rewards: to_rewards(&status.rewards),
         loaded_writable_addresses: status
             .loaded_addresses
@@ -586,8 +1233,10 @@ fn to_transaction_meta_status(
     }
 }
 
+// to_token_balances keeps the exact `amount` string, the f64 `ui_amount` is zeroed when drop_lossy_ui_amount is set
 fn to_token_balances(
     balances: &Option<Vec<solana_transaction_status::TransactionTokenBalance>>,
+    drop_lossy_ui_amount: bool,
 ) -> Vec<TokenBalance> {
     balances
         .as_ref()
@@ -600,7 +1249,11 @@ fn to_token_balances(
                     owner: balance.owner.clone(),
                     program_id: balance.program_id.clone(),
                     ui_token_amount: Some(UiTokenAmount {
-                        ui_amount: balance.ui_token_amount.ui_amount.unwrap_or_default(),
+                        ui_amount: if drop_lossy_ui_amount {
+                            0.0
+                        } else {
+                            balance.ui_token_amount.ui_amount.unwrap_or_default()
+                        },
                         decimals: balance.ui_token_amount.decimals as u32,
                         amount: balance.ui_token_amount.amount.clone(),
                         ui_amount_string: balance.ui_token_amount.ui_amount_string.clone(),
@@ -608,7 +1261,7 @@ fn to_token_balances(
                 })
                 .collect()
         })
-        .unwrap_or_else(Vec::new)
+        .unwrap_or_default()
 }
 
 fn to_log_messages(logs: &Option<Vec<String>>) -> Vec<String> {
@@ -618,19 +1271,53 @@ fn to_log_messages(logs: &Option<Vec<String>>) -> Vec<String> {
     }
 }
 
+// to_transaction_err always keeps the bincode bytes of the error, with `decode` the other fields
+// are filled so that consumers do not need bincode to tell what failed
 fn to_transaction_err(
     status: &solana_transaction_status::TransactionStatusMeta,
+    decode: bool,
 ) -> Option<TransactionError> {
+    use solana_sdk::instruction::InstructionError;
+    use solana_sdk::transaction::TransactionError as SolanaTransactionError;
+
     match &status.status {
         Ok(_) => None,
         Err(e) => {
             let bytes = bincode::serialize(e).expect("error serializing TransactionError");
-            let err = TransactionError { err: bytes };
-            Some(err)
+            if !decode {
+                return Some(TransactionError {
+                    err: bytes,
+                    ..Default::default()
+                });
+            }
+            let (instruction_index, custom_error_code) = match e {
+                SolanaTransactionError::InstructionError(index, InstructionError::Custom(code)) => {
+                    (Some(*index as u32), Some(*code))
+                }
+                SolanaTransactionError::InstructionError(index, _) => (Some(*index as u32), None),
+                _ => (None, None),
+            };
+            Some(TransactionError {
+                err: bytes,
+                kind: transaction_error_kind(e),
+                message: e.to_string(),
+                instruction_index,
+                custom_error_code,
+            })
         }
     }
 }
 
+// transaction_error_kind is the name of the error variant, without its fields
+fn transaction_error_kind(err: &solana_sdk::transaction::TransactionError) -> String {
+    let debug = format!("{:?}", err);
+    debug
+        .split(['(', ' ', '{'])
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
 fn to_inner_instructions(
     inner_instructions: &Option<Vec<solana_transaction_status::InnerInstructions>>,
 ) -> Vec<InnerInstructions> {
@@ -671,7 +1358,7 @@ fn to_rewards(rewards: &Option<solana_transaction_status::Rewards>) -> Vec<Rewar
                 })
                 .collect()
         })
-        .unwrap_or_else(Vec::new)
+        .unwrap_or_default()
 }
 
 fn to_pb_reward_type(reward_type: Option<solana_transaction_status::RewardType>) -> RewardType {
@@ -685,32 +1372,39 @@ fn to_pb_reward_type(reward_type: Option<solana_transaction_status::RewardType>)
 }
 
 fn to_return_data(d: &Option<TransactionReturnData>) -> Option<ReturnData> {
-    match d {
-        Some(d) => Some(ReturnData {
-            program_id: d.program_id.to_bytes().to_vec(),
-            data: d.data.to_vec(),
-        }),
-        None => None,
-    }
+    d.as_ref().map(|d| ReturnData {
+        program_id: d.program_id.to_bytes().to_vec(),
+        data: d.data.to_vec(),
+    })
 }
 
 fn to_transaction(
     tx: &solana_sdk::transaction::SanitizedTransaction,
     loaded_addresses: &LoadedAddresses,
+    options: TransactionOptions,
 ) -> Transaction {
     Transaction {
         signatures: to_signature(tx.signatures()),
-        message: Some(to_message(tx.message(), loaded_addresses)),
+        // same as TransactionDetails::Signatures on the RPC: the message is most of the payload
+        message: match options.block_detail {
+            BlockDetail::Full => Some(to_message(
+                tx.message(),
+                loaded_addresses,
+                options.account_keys_mode,
+            )),
+            BlockDetail::SignaturesOnly => None,
+        },
     }
 }
 
 fn to_message(
     msg: &solana_sdk::message::SanitizedMessage,
     loaded_addresses: &LoadedAddresses,
+    account_keys_mode: AccountKeysMode,
 ) -> Message {
     Message {
         header: Some(to_header(msg.header())),
-        account_keys: to_account_keys(msg.account_keys(), loaded_addresses),
+        account_keys: to_account_keys(msg.account_keys(), loaded_addresses, account_keys_mode),
         recent_blockhash: to_recent_block_hash(msg.recent_blockhash()),
         instructions: to_compiled_instructions(msg.instructions()),
         versioned: msg.legacy_message().is_none(),
@@ -748,7 +1442,17 @@ fn to_recent_block_hash(h: &Hash) -> Vec<u8> {
     h.as_ref().to_vec()
 }
 
-fn to_account_keys(keys: AccountKeys, loaded_addresses: &LoadedAddresses) -> Vec<Vec<u8>> {
+// The account keys of a sanitized message already list the static keys followed by the loaded
+// writable then readonly addresses, the order the runtime resolves the instruction account indexes in.
+fn to_account_keys(
+    keys: AccountKeys,
+    loaded_addresses: &LoadedAddresses,
+    account_keys_mode: AccountKeysMode,
+) -> Vec<Vec<u8>> {
+    if account_keys_mode == AccountKeysMode::Full {
+        return keys.iter().map(|key| key.to_bytes().to_vec()).collect();
+    }
+
     // Create a HashSet of all loaded addresses (address lookup table)
     let lookup_keys: std::collections::HashSet<_> = loaded_addresses
         .writable
@@ -776,3 +1480,812 @@ fn to_signature(signatures: &[solana_sdk::signature::Signature]) -> Vec<Vec<u8>>
         .map(|signature| signature.as_ref().to_vec())
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn write_config(dir: &TempDir, level: &str, send_processed: bool, cursor: &str) -> String {
+        let path = dir.path().join("config.json");
+        let config = format!(
+            r#"{{
+                "local_rpc_client": {{"endpoint": "http://test.local"}},
+                "remote_rpc_client": {{"endpoint": "http://test.remote"}},
+                "cursor_file": "{}",
+                "send_processed": {},
+                "log": {{"level": "{}"}},
+                "account_block_destination_file": "",
+                "block_destination_file": ""
+            }}"#,
+            dir.path().join(cursor).display(),
+            send_processed,
+            level
+        );
+        std::fs::write(&path, config).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_on_load_reload() {
+        let dir = TempDir::new().unwrap();
+        let mut plugin = Plugin::builder().build();
+
+        let config_file = write_config(&dir, "info", false, "cursor");
+        plugin.on_load(&config_file, false).unwrap();
+        assert!(plugin.state.is_some());
+        assert_eq!(plugin.commitment_trigger, Commitment::Confirmed);
+
+        // the logger already installed does not fail the reload, and the state is kept
+        let config_file = write_config(&dir, "trace", true, "other_cursor");
+        plugin.on_load(&config_file, true).unwrap();
+        assert!(plugin.state.is_some());
+        assert_eq!(plugin.commitment_trigger, Commitment::Processed);
+        // the cursor commitment needs a restart
+        assert_eq!(plugin.cursor_commitment, Commitment::Confirmed);
+        assert!(plugin.trace);
+        assert_eq!(log::max_level(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn test_reload_keeps_outputs() {
+        let dir = TempDir::new().unwrap();
+        let mut plugin = Plugin::builder().build();
+        plugin
+            .on_load(&write_config(&dir, "info", false, "cursor"), false)
+            .unwrap();
+        assert!(plugin.state.is_some());
+
+        // a destination that cannot be opened does not fail the reload, the running outputs are kept
+        let config_file = dir.path().join("reload.json");
+        std::fs::write(
+            &config_file,
+            format!(
+                r#"{{
+                    "local_rpc_client": {{"endpoint": "http://test.local"}},
+                    "remote_rpc_client": {{"endpoint": "http://test.remote"}},
+                    "cursor_file": "{}",
+                    "account_block_destination_file": "",
+                    "block_destination_file": "{}",
+                    "open_retries": 1,
+                    "open_retry_delay_ms": 10
+                }}"#,
+                dir.path().join("cursor").display(),
+                dir.path().join("missing.fifo").display()
+            ),
+        )
+        .unwrap();
+        plugin.on_load(config_file.to_str().unwrap(), true).unwrap();
+        assert!(plugin.state.is_some());
+        assert!(!plugin.with_block);
+
+        // without a running plugin, the reload opens them and fails once the retries are exhausted
+        let mut plugin = Plugin::builder().build();
+        assert!(plugin.on_load(config_file.to_str().unwrap(), true).is_err());
+        assert!(plugin.state.is_none());
+    }
+
+    #[test]
+    fn test_block_info_from_replica_num_partitions() {
+        let rewards = solana_transaction_status::RewardsAndNumPartitions {
+            rewards: vec![],
+            num_partitions: Some(4),
+        };
+        let replica = agave_geyser_plugin_interface::geyser_plugin_interface::ReplicaBlockInfoV4 {
+            parent_slot: 9,
+            parent_blockhash: "hash9",
+            slot: 10,
+            blockhash: "hash10",
+            rewards: &rewards,
+            block_time: Some(1234),
+            block_height: Some(8),
+            executed_transaction_count: 3,
+            entry_count: 1,
+        };
+
+        let (block_info, block_time) =
+            block_info_from_replica(ReplicaBlockInfoVersions::V0_0_4(&replica));
+        assert_eq!(block_info.num_reward_partitions, Some(4));
+        assert_eq!(block_info.slot, 10);
+        assert_eq!(block_time, Some(1234));
+
+        let block = crate::state::compose_and_purge_block(10, &block_info, vec![], true);
+        assert_eq!(block.num_partitions.unwrap().num_partitions, 4);
+    }
+
+    // plugin_with_commitment builds a plugin without outputs, slot 10 has its block info and transaction
+    fn plugin_with_commitment(
+        trigger: Commitment,
+        cursor_commitment: Commitment,
+        cursor_path: &str,
+    ) -> Plugin {
+        let mut state = State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![],
+            None,
+            cursor_path.to_string(),
+            BlockPrinter::new(
+                None,
+                None,
+                None,
+                PrinterOptions {
+                    cursor_requires_confirmation: cursor_commitment == Commitment::Confirmed,
+                    ..PrinterOptions::default()
+                },
+            ),
+            StateOptions::default(),
+        );
+        state.set_lib(1);
+        state.set_block_info(BlockInfo {
+            slot: 10,
+            parent_slot: 9,
+            block_hash: "hash10".to_string(),
+            parent_hash: "hash9".to_string(),
+            timestamp: prost_types::Timestamp::default(),
+            height: None,
+            rewards: vec![],
+            num_reward_partitions: None,
+            transaction_count: 1,
+        });
+        state.set_transaction(
+            10,
+            ConfirmTransactionWithIndex {
+                index: 0,
+                transaction: ConfirmedTransaction::default(),
+            },
+        );
+
+        let mut plugin = Plugin::builder()
+            .state(state)
+            .commitment_trigger(trigger)
+            .build();
+        plugin.cursor_commitment = cursor_commitment;
+        plugin
+    }
+
+    // returns (processed, cursor written) after each status of slot 10
+    fn slot_status_outcome(
+        trigger: Commitment,
+        cursor_commitment: Commitment,
+    ) -> Vec<(bool, Option<String>)> {
+        let dir = TempDir::new().unwrap();
+        let cursor_path = dir.path().join("cursor");
+        let cursor_path = cursor_path.to_str().unwrap();
+        let plugin = plugin_with_commitment(trigger, cursor_commitment, cursor_path);
+
+        [SlotStatus::Processed, SlotStatus::Confirmed]
+            .into_iter()
+            .map(|status| {
+                plugin.update_slot_status(10, Some(9), status).unwrap();
+                let processed = plugin
+                    .state
+                    .as_ref()
+                    .unwrap()
+                    .read()
+                    .unwrap()
+                    .buffered_stats()
+                    .transactions
+                    == 0;
+                let cursor = std::fs::read_to_string(cursor_path).ok();
+                (processed, cursor)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_update_slot_status_processed_trigger_processed_cursor() {
+        let cursor = Some("10:hash10".to_string());
+        assert_eq!(
+            slot_status_outcome(Commitment::Processed, Commitment::Processed),
+            vec![(true, cursor.clone()), (true, cursor)]
+        );
+    }
+
+    #[test]
+    fn test_update_slot_status_processed_trigger_confirmed_cursor() {
+        assert_eq!(
+            slot_status_outcome(Commitment::Processed, Commitment::Confirmed),
+            vec![(true, None), (true, Some("10:hash10".to_string()))]
+        );
+    }
+
+    #[test]
+    fn test_update_slot_status_confirmed_trigger_confirmed_cursor() {
+        assert_eq!(
+            slot_status_outcome(Commitment::Confirmed, Commitment::Confirmed),
+            vec![(false, None), (true, Some("10:hash10".to_string()))]
+        );
+    }
+
+    #[test]
+    fn test_update_slot_status_confirmed_trigger_processed_cursor() {
+        // the cursor cannot move before the slot is sent, it moves as soon as it is written
+        assert_eq!(
+            slot_status_outcome(Commitment::Confirmed, Commitment::Processed),
+            vec![(false, None), (true, Some("10:hash10".to_string()))]
+        );
+    }
+
+    #[test]
+    fn test_update_slot_status_print_error() {
+        let sink = crate::sink::MemorySink::default();
+        sink.fail_prints();
+        let mut state = State::new(
+            None,
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            sink,
+            StateOptions {
+                without_transactions: true,
+                ..StateOptions::default()
+            },
+        );
+        state.set_lib(1);
+        state.set_block_info(BlockInfo {
+            slot: 10,
+            parent_slot: 9,
+            block_hash: "hash10".to_string(),
+            parent_hash: "hash9".to_string(),
+            timestamp: prost_types::Timestamp::default(),
+            height: None,
+            rewards: vec![],
+            num_reward_partitions: None,
+            transaction_count: 0,
+        });
+        let plugin = Plugin::builder()
+            .state(state)
+            .commitment_trigger(Commitment::Processed)
+            .build();
+
+        // the error is handed over to the validator instead of panicking
+        match plugin.update_slot_status(10, Some(9), SlotStatus::Processed) {
+            Err(GeyserPluginError::Custom(err)) => match err.downcast_ref::<ProcessError>() {
+                Some(ProcessError::PrinterFailed { slot, .. }) => assert_eq!(*slot, 10),
+                other => panic!("expected PrinterFailed, got {:?}", other),
+            },
+            other => panic!("expected a custom error, got {:?}", other),
+        }
+    }
+
+    fn in_memory_state() -> State {
+        State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
+        )
+    }
+
+    fn update_test_account(plugin: &Plugin, slot: u64, data: &[u8]) {
+        let account =
+            agave_geyser_plugin_interface::geyser_plugin_interface::ReplicaAccountInfoV3 {
+                pubkey: &[1; 32],
+                lamports: 1,
+                owner: &[2; 32],
+                executable: false,
+                rent_epoch: 0,
+                data,
+                write_version: slot,
+                txn: None,
+            };
+        plugin
+            .update_account(ReplicaAccountInfoVersions::V0_0_3(&account), slot, false)
+            .unwrap();
+    }
+
+    fn dedup_hits(plugin: &Plugin) -> u64 {
+        plugin
+            .state
+            .as_ref()
+            .unwrap()
+            .read()
+            .unwrap()
+            .dedup_stats()
+            .dedup_hits
+    }
+
+    fn buffered_account_slots(plugin: &Plugin) -> usize {
+        plugin
+            .state
+            .as_ref()
+            .unwrap()
+            .read()
+            .unwrap()
+            .buffered_stats()
+            .block_account_changes
+    }
+
+    #[test]
+    fn test_take_replay_request() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let control_file = dir.path().join("replay");
+        let control_file = control_file.to_str().unwrap();
+        assert_eq!(take_replay_request(control_file), None);
+
+        std::fs::write(control_file, "100 120\n").unwrap();
+        assert_eq!(take_replay_request(control_file), Some((100, 120)));
+        assert!(!std::path::Path::new(control_file).exists());
+
+        for invalid in ["100", "120 100", "a b", "1 2 3"] {
+            std::fs::write(control_file, invalid).unwrap();
+            assert_eq!(take_replay_request(control_file), None, "{}", invalid);
+            assert!(!std::path::Path::new(control_file).exists());
+        }
+    }
+
+    #[test]
+    fn test_lag_without_state() {
+        assert_eq!(Plugin::builder().build().lag(), None);
+        assert_eq!(
+            Plugin::builder().state(in_memory_state()).build().lag(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_builder_update_account() {
+        let plugin = Plugin::builder().state(in_memory_state()).build();
+        update_test_account(&plugin, 100, &[1, 2, 3]);
+        assert_eq!(buffered_account_slots(&plugin), 1);
+        update_test_account(&plugin, 101, &[4]);
+        assert_eq!(buffered_account_slots(&plugin), 2);
+    }
+
+    #[test]
+    fn test_dedup_owner_and_lamports() {
+        let plugin = Plugin::builder().state(in_memory_state()).build();
+        let write = |write_version: u64, owner: &[u8], lamports: u64| {
+            let account =
+                agave_geyser_plugin_interface::geyser_plugin_interface::ReplicaAccountInfoV3 {
+                    pubkey: &[1; 32],
+                    lamports,
+                    owner,
+                    executable: false,
+                    rent_epoch: 0,
+                    data: &[1, 2, 3],
+                    write_version,
+                    txn: None,
+                };
+            plugin
+                .update_account(ReplicaAccountInfoVersions::V0_0_3(&account), 100, false)
+                .unwrap();
+        };
+
+        write(1, &[2; 32], 10);
+        write(2, &[2; 32], 10);
+        assert_eq!(dedup_hits(&plugin), 1);
+        // same data, new owner
+        write(3, &[3; 32], 10);
+        assert_eq!(dedup_hits(&plugin), 1);
+        // same data, lamport transfer
+        write(4, &[3; 32], 20);
+        assert_eq!(dedup_hits(&plugin), 1);
+        write(5, &[3; 32], 20);
+        assert_eq!(dedup_hits(&plugin), 2);
+    }
+
+    fn state_with_account_txn_signature() -> State {
+        State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions {
+                account_txn_signature: true,
+                ..StateOptions::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_update_account_txn_signature_v3() {
+        let payer = solana_sdk::signature::Keypair::new();
+        let instruction = solana_sdk::system_instruction::transfer(
+            &solana_sdk::signer::Signer::pubkey(&payer),
+            &solana_sdk::pubkey::Pubkey::new_unique(),
+            10,
+        );
+        let transaction = solana_sdk::transaction::SanitizedTransaction::from_transaction_for_tests(
+            solana_sdk::transaction::Transaction::new_signed_with_payer(
+                &[instruction],
+                Some(&solana_sdk::signer::Signer::pubkey(&payer)),
+                &[&payer],
+                Hash::default(),
+            ),
+        );
+        for (state, expected) in [
+            (in_memory_state(), vec![]),
+            (
+                state_with_account_txn_signature(),
+                transaction.signature().as_ref().to_vec(),
+            ),
+        ] {
+            let plugin = Plugin::builder().state(state).build();
+            let account =
+                agave_geyser_plugin_interface::geyser_plugin_interface::ReplicaAccountInfoV3 {
+                    pubkey: &[1; 32],
+                    lamports: 1,
+                    owner: &[2; 32],
+                    executable: false,
+                    rent_epoch: 0,
+                    data: &[1, 2, 3],
+                    write_version: 1,
+                    txn: Some(&transaction),
+                };
+            plugin
+                .update_account(ReplicaAccountInfoVersions::V0_0_3(&account), 100, false)
+                .unwrap();
+
+            let state = plugin.state.as_ref().unwrap().read().unwrap();
+            let changes = state.get_account_changes(100).unwrap();
+            assert_eq!(changes[&vec![1; 32]].account.txn_signature, expected);
+        }
+    }
+
+    #[test]
+    fn test_update_account_balance_and_signature() {
+        let plugin = Plugin::builder()
+            .state(state_with_account_txn_signature())
+            .build();
+        let signature = solana_sdk::signature::Signature::new_unique();
+        let account =
+            agave_geyser_plugin_interface::geyser_plugin_interface::ReplicaAccountInfoV2 {
+                pubkey: &[1; 32],
+                lamports: 42,
+                owner: &[2; 32],
+                executable: false,
+                rent_epoch: 7,
+                data: &[1, 2, 3],
+                write_version: 1,
+                txn_signature: Some(&signature),
+            };
+        plugin
+            .update_account(ReplicaAccountInfoVersions::V0_0_2(&account), 100, false)
+            .unwrap();
+        // a drained account
+        let account =
+            agave_geyser_plugin_interface::geyser_plugin_interface::ReplicaAccountInfoV3 {
+                pubkey: &[3; 32],
+                lamports: 0,
+                owner: &[2; 32],
+                executable: false,
+                rent_epoch: 8,
+                data: &[],
+                write_version: 2,
+                txn: None,
+            };
+        plugin
+            .update_account(ReplicaAccountInfoVersions::V0_0_3(&account), 100, false)
+            .unwrap();
+
+        let state = plugin.state.as_ref().unwrap().read().unwrap();
+        let changes = state.get_account_changes(100).unwrap();
+        let written = &changes[&vec![1; 32]].account;
+        assert_eq!(written.lamports, 42);
+        assert_eq!(written.rent_epoch, 7);
+        assert_eq!(written.txn_signature, signature.as_ref());
+        assert!(!written.deleted);
+        let drained = &changes[&vec![3; 32]].account;
+        assert_eq!(drained.lamports, 0);
+        assert_eq!(drained.rent_epoch, 8);
+        assert!(drained.txn_signature.is_empty());
+        assert!(drained.deleted);
+    }
+
+    #[test]
+    fn test_builder_without_account() {
+        let plugin = Plugin::builder()
+            .state(in_memory_state())
+            .with_account(false)
+            .build();
+        update_test_account(&plugin, 100, &[1, 2, 3]);
+        assert_eq!(buffered_account_slots(&plugin), 0);
+        assert!(!plugin.account_data_notifications_enabled());
+    }
+
+    #[test]
+    fn test_encode_sample_block() {
+        use prost::Message as _;
+
+        let encoded = Plugin::encode_sample_block().unwrap();
+        let block = crate::pb::sf::solana::r#type::v1::Block::decode(encoded.as_slice()).unwrap();
+        assert_eq!(block.slot, 100);
+        assert_eq!(block.transactions.len(), 1);
+        assert_eq!(block.transactions[0].meta.as_ref().unwrap().fee, 5000);
+    }
+
+    #[test]
+    fn test_notifications_enabled() {
+        for (with_block, with_account, with_transaction, accounts, transactions) in [
+            (true, true, true, true, true),
+            (true, false, false, false, true),
+            (false, true, false, true, false),
+            (false, false, true, false, true),
+            (false, true, true, true, true),
+            (false, false, false, false, false),
+        ] {
+            let plugin = Plugin::builder()
+                .with_block(with_block)
+                .with_account(with_account)
+                .with_transaction(with_transaction)
+                .build();
+            let outputs = (with_block, with_account, with_transaction);
+            assert_eq!(
+                plugin.account_data_notifications_enabled(),
+                accounts,
+                "{:?}",
+                outputs
+            );
+            assert_eq!(
+                plugin.transaction_notifications_enabled(),
+                transactions,
+                "{:?}",
+                outputs
+            );
+        }
+    }
+
+    #[test]
+    fn test_to_confirm_transaction_block_detail() {
+        let payer = solana_sdk::signature::Keypair::new();
+        let instruction = solana_sdk::system_instruction::transfer(
+            &solana_sdk::signer::Signer::pubkey(&payer),
+            &solana_sdk::pubkey::Pubkey::new_unique(),
+            10,
+        );
+        let transaction = solana_sdk::transaction::SanitizedTransaction::from_transaction_for_tests(
+            solana_sdk::transaction::Transaction::new_signed_with_payer(
+                &[instruction],
+                Some(&solana_sdk::signer::Signer::pubkey(&payer)),
+                &[&payer],
+                Hash::default(),
+            ),
+        );
+        let meta = solana_transaction_status::TransactionStatusMeta {
+            fee: 5000,
+            ..Default::default()
+        };
+        let info = ReplicaTransactionInfoV2 {
+            signature: transaction.signature(),
+            is_vote: false,
+            transaction: &transaction,
+            transaction_status_meta: &meta,
+            index: 0,
+        };
+
+        let full = to_confirm_transaction(&info, TransactionOptions::default());
+        let full_transaction = full.transaction.as_ref().unwrap();
+        assert_eq!(
+            full_transaction
+                .message
+                .as_ref()
+                .unwrap()
+                .instructions
+                .len(),
+            1
+        );
+
+        let light = to_confirm_transaction(
+            &info,
+            TransactionOptions {
+                block_detail: BlockDetail::SignaturesOnly,
+                ..Default::default()
+            },
+        );
+        let light_transaction = light.transaction.as_ref().unwrap();
+        assert_eq!(light_transaction.signatures, full_transaction.signatures);
+        assert_eq!(
+            light_transaction.signatures,
+            vec![transaction.signature().as_ref().to_vec()]
+        );
+        assert!(light_transaction.message.is_none());
+        assert_eq!(light.meta, full.meta);
+        assert!(prost::Message::encoded_len(&light) < prost::Message::encoded_len(&full));
+    }
+
+    #[test]
+    fn test_to_account_keys_full() {
+        use solana_sdk::instruction::CompiledInstruction as SolanaCompiledInstruction;
+        use solana_sdk::message::{v0, SanitizedMessage};
+        use solana_sdk::pubkey::Pubkey;
+
+        let static_keys = vec![Pubkey::new_unique(), Pubkey::new_unique()];
+        let loaded_addresses = LoadedAddresses {
+            writable: vec![Pubkey::new_unique()],
+            readonly: vec![Pubkey::new_unique(), Pubkey::new_unique()],
+        };
+        let message = v0::Message {
+            header: solana_sdk::message::MessageHeader {
+                num_required_signatures: 1,
+                num_readonly_signed_accounts: 0,
+                num_readonly_unsigned_accounts: 1,
+            },
+            account_keys: static_keys.clone(),
+            // indexes 2 and 4 point into the loaded writable and readonly addresses
+            instructions: vec![SolanaCompiledInstruction::new_from_raw_parts(
+                1,
+                vec![],
+                vec![0, 2, 4],
+            )],
+            ..v0::Message::default()
+        };
+        let message = SanitizedMessage::V0(v0::LoadedMessage::new(
+            message,
+            loaded_addresses.clone(),
+            &Default::default(),
+        ));
+
+        let keys = |mode| to_account_keys(message.account_keys(), &loaded_addresses, mode);
+        let bytes = |key: &Pubkey| key.to_bytes().to_vec();
+
+        assert_eq!(
+            keys(AccountKeysMode::StaticOnly),
+            static_keys.iter().map(bytes).collect::<Vec<_>>()
+        );
+
+        let full = keys(AccountKeysMode::Full);
+        assert_eq!(
+            full,
+            vec![
+                bytes(&static_keys[0]),
+                bytes(&static_keys[1]),
+                bytes(&loaded_addresses.writable[0]),
+                bytes(&loaded_addresses.readonly[0]),
+                bytes(&loaded_addresses.readonly[1]),
+            ]
+        );
+        // the instruction indexes resolve the same way as in the runtime
+        for &index in &message.instructions()[0].accounts {
+            assert_eq!(
+                full[index as usize],
+                bytes(message.account_keys().get(index as usize).unwrap())
+            );
+        }
+    }
+
+    fn init_lines(config: &str) -> String {
+        let dir = TempDir::new().unwrap();
+        let config_path = dir.path().join("config.json");
+        std::fs::write(&config_path, config).unwrap();
+        let plugin_config = PluginConfig::load_from_file(&config_path).unwrap();
+
+        let out = tempfile::NamedTempFile::new().unwrap();
+        let mut printer = BlockPrinter::new(
+            Some(out.reopen().unwrap()),
+            None,
+            None,
+            printer_options(&plugin_config),
+        );
+        printer
+            .print_init("sf.solana.type.v1.Block", "unused", "unused")
+            .unwrap();
+        std::fs::read_to_string(out.path()).unwrap()
+    }
+
+    #[test]
+    fn test_init_line_follows_config() {
+        let base = r#""local_rpc_client": {"endpoint": ""},
+            "remote_rpc_client": {"endpoint": ""},
+            "cursor_file": "",
+            "account_block_destination_file": "",
+            "block_destination_file": """#;
+
+        assert_eq!(
+            init_lines(&format!("{{{}}}", base)),
+            "FIRE INIT 3.0 sf.solana.type.v1.Block\n"
+        );
+        assert_eq!(
+            init_lines(&format!(
+                r#"{{{}, "block_detail": "signatures_only", "payload_checksum": true, "protocol_version": "3.1"}}"#,
+                base
+            )),
+            "FIRE INIT 3.1 sf.solana.type.v1.Block detail=signatures_only checksum=xxh3\n"
+        );
+        assert_eq!(
+            init_lines(&format!(r#"{{{}, "fire_protocol_version": "3.2"}}"#, base)),
+            "FIRE INIT 3.2 sf.solana.type.v1.Block\n"
+        );
+        assert_eq!(
+            init_lines(&format!(r#"{{{}, "block_height_header": true}}"#, base)),
+            "FIRE INIT 3.0 sf.solana.type.v1.Block header=block_height\n"
+        );
+    }
+
+    #[test]
+    fn test_to_token_balances() {
+        // 2^53 + 1 base units do not fit exactly in an f64
+        let amount = (1u64 << 53) + 1;
+        let balances = Some(vec![solana_transaction_status::TransactionTokenBalance {
+            account_index: 1,
+            mint: "mint".to_string(),
+            // the type is not exported, it is built the way the rpc serializes it
+            ui_token_amount: serde_json::from_value(serde_json::json!({
+                "uiAmount": amount as f64,
+                "decimals": 0,
+                "amount": amount.to_string(),
+                "uiAmountString": amount.to_string(),
+            }))
+            .unwrap(),
+            owner: "owner".to_string(),
+            program_id: "program".to_string(),
+        }]);
+
+        let converted = to_token_balances(&balances, false);
+        let ui_token_amount = converted[0].ui_token_amount.as_ref().unwrap();
+        assert_eq!(ui_token_amount.amount, amount.to_string());
+        assert_eq!(ui_token_amount.ui_amount_string, amount.to_string());
+        assert_ne!(ui_token_amount.ui_amount as u64, amount);
+
+        let converted = to_token_balances(&balances, true);
+        let ui_token_amount = converted[0].ui_token_amount.as_ref().unwrap();
+        assert_eq!(ui_token_amount.ui_amount, 0.0);
+        assert_eq!(ui_token_amount.amount, amount.to_string());
+        assert_eq!(ui_token_amount.decimals, 0);
+    }
+
+    #[test]
+    fn test_to_transaction_err() {
+        use solana_sdk::instruction::InstructionError;
+        use solana_sdk::transaction::TransactionError as SolanaTransactionError;
+
+        let error = SolanaTransactionError::InstructionError(2, InstructionError::Custom(6001));
+        let meta = solana_transaction_status::TransactionStatusMeta {
+            status: Err(error.clone()),
+            ..Default::default()
+        };
+
+        let err = to_transaction_err(&meta, true).unwrap();
+        assert_eq!(err.err, bincode::serialize(&error).unwrap());
+        assert_eq!(err.kind, "InstructionError");
+        assert_eq!(
+            err.message,
+            "Error processing Instruction 2: custom program error: 0x1771"
+        );
+        assert_eq!(err.instruction_index, Some(2));
+        assert_eq!(err.custom_error_code, Some(6001));
+
+        // without decoding, only the bincode bytes are kept
+        let err = to_transaction_err(&meta, false).unwrap();
+        assert_eq!(
+            err,
+            TransactionError {
+                err: bincode::serialize(&error).unwrap(),
+                ..Default::default()
+            }
+        );
+
+        let meta = solana_transaction_status::TransactionStatusMeta {
+            status: Err(SolanaTransactionError::InstructionError(
+                0,
+                InstructionError::InsufficientFunds,
+            )),
+            ..Default::default()
+        };
+        let err = to_transaction_err(&meta, true).unwrap();
+        assert_eq!(err.kind, "InstructionError");
+        assert_eq!(err.instruction_index, Some(0));
+        assert_eq!(err.custom_error_code, None);
+
+        let meta = solana_transaction_status::TransactionStatusMeta {
+            status: Err(SolanaTransactionError::InsufficientFundsForRent { account_index: 1 }),
+            ..Default::default()
+        };
+        let err = to_transaction_err(&meta, true).unwrap();
+        assert_eq!(err.kind, "InsufficientFundsForRent");
+        assert_eq!(err.instruction_index, None);
+
+        let meta = solana_transaction_status::TransactionStatusMeta {
+            status: Err(SolanaTransactionError::AccountInUse),
+            ..Default::default()
+        };
+        assert_eq!(
+            to_transaction_err(&meta, true).unwrap().kind,
+            "AccountInUse"
+        );
+        assert!(to_transaction_err(&Default::default(), true).is_none());
+    }
+}
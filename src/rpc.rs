@@ -0,0 +1,347 @@
+use log::{debug, warn};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION};
+use solana_rpc_client::http_sender::HttpSender;
+use solana_rpc_client::rpc_client::{RpcClient, RpcClientConfig};
+use solana_rpc_client_api::client_error::{Error as ClientError, ErrorKind};
+use solana_rpc_client_api::custom_error::{
+    JSON_RPC_SERVER_ERROR_BLOCK_CLEANED_UP, JSON_RPC_SERVER_ERROR_BLOCK_NOT_AVAILABLE,
+    JSON_RPC_SERVER_ERROR_LONG_TERM_STORAGE_SLOT_SKIPPED, JSON_RPC_SERVER_ERROR_SLOT_SKIPPED,
+    JSON_RPC_SERVER_ERROR_TRANSACTION_HISTORY_NOT_AVAILABLE,
+};
+use solana_rpc_client_api::request::RpcError;
+use solana_sdk::commitment_config::CommitmentConfig;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+// RetryPolicy controls how many times an RPC call is attempted again after an error,
+// the wait between attempts doubles every time, starting at `backoff`.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub backoff: Duration,
+}
+
+// new_rpc_client builds a client whose requests fail after `timeout` instead of waiting forever on a hung endpoint.
+// `headers` are sent with every request, on top of the ones of the solana client.
+pub fn new_rpc_client(endpoint: String, timeout: Duration, headers: &HeaderMap) -> RpcClient {
+    let mut default_headers = HttpSender::default_headers();
+    default_headers.extend(headers.clone());
+    let client = reqwest::Client::builder()
+        .default_headers(default_headers)
+        .timeout(timeout)
+        .pool_idle_timeout(timeout)
+        .build()
+        .expect("build rpc client");
+    RpcClient::new_sender(
+        HttpSender::new_with_client(endpoint, client),
+        RpcClientConfig::with_commitment(CommitmentConfig::default()),
+    )
+}
+
+// header_map checks the configured headers, an auth token is sent as a bearer `Authorization` header
+pub fn header_map(
+    headers: &HashMap<String, String>,
+    auth_token: Option<&str>,
+) -> Result<HeaderMap, String> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|err| format!("invalid rpc header name {:?}: {}", name, err))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|err| format!("invalid value of rpc header {}: {}", name, err))?;
+        map.insert(name, value);
+    }
+    if let Some(token) = auth_token {
+        let mut value = HeaderValue::from_str(&format!("Bearer {}", token))
+            .map_err(|err| format!("invalid rpc auth_token: {}", err))?;
+        value.set_sensitive(true);
+        map.insert(AUTHORIZATION, value);
+    }
+    Ok(map)
+}
+
+// is_slot_skipped tells whether the rpc answered that there is no block at the slot
+pub(crate) fn is_slot_skipped(err: &ClientError) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::RpcError(RpcError::RpcResponseError { code, .. })
+            if *code == JSON_RPC_SERVER_ERROR_SLOT_SKIPPED
+                || *code == JSON_RPC_SERVER_ERROR_LONG_TERM_STORAGE_SLOT_SKIPPED
+    )
+}
+
+// a skipped slot stays skipped, and a node that does not have a block (cleaned up, not in its ledger or long-term
+// storage, no transaction history) does not get it a few hundred milliseconds later: there is no point in asking
+// again, the retries are left to the other client.
+pub(crate) fn is_retryable(err: &ClientError) -> bool {
+    !matches!(
+        err.kind(),
+        ErrorKind::RpcError(RpcError::RpcResponseError { code, .. })
+            if *code == JSON_RPC_SERVER_ERROR_BLOCK_CLEANED_UP
+                || *code == JSON_RPC_SERVER_ERROR_BLOCK_NOT_AVAILABLE
+                || *code == JSON_RPC_SERVER_ERROR_TRANSACTION_HISTORY_NOT_AVAILABLE
+    ) && !is_slot_skipped(err)
+}
+
+pub fn with_retries<T>(
+    policy: RetryPolicy,
+    description: &str,
+    mut call: impl FnMut() -> Result<T, ClientError>,
+) -> Result<T, ClientError> {
+    let mut backoff = policy.backoff;
+    let mut attempt = 0;
+    loop {
+        match call() {
+            Ok(value) => return Ok(value),
+            Err(err) if attempt < policy.max_retries && is_retryable(&err) => {
+                attempt += 1;
+                warn!(
+                    "{} failed: {}, retrying in {:?} ({}/{})",
+                    description, err, backoff, attempt, policy.max_retries
+                );
+                std::thread::sleep(backoff);
+                backoff = backoff.saturating_mul(2);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// LibRefresher asks the RPC for the finalized slot every `interval` from its own thread, in case the validator
+// misses rooted notifications. The highest value seen is published in `latest` (0 until the first answer),
+// the state picks it up when it processes slots. The thread stops when the refresher is dropped.
+pub struct LibRefresher {
+    latest: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl LibRefresher {
+    pub fn spawn(rpc_client: RpcClient, interval: Duration) -> Self {
+        let latest = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let latest = latest.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let mut next = Instant::now();
+                while !stop.load(Ordering::Relaxed) {
+                    if Instant::now() >= next {
+                        match rpc_client.get_slot_with_commitment(CommitmentConfig::finalized()) {
+                            Ok(lib) => {
+                                debug!("finalized slot refreshed from rpc: {}", lib);
+                                latest.fetch_max(lib, Ordering::Relaxed);
+                            }
+                            Err(err) => {
+                                warn!("cannot refresh the finalized slot from rpc: {}", err)
+                            }
+                        }
+                        next = Instant::now() + interval;
+                    }
+                    // short sleeps so that dropping the refresher does not wait for a whole interval
+                    std::thread::sleep(interval.min(Duration::from_millis(100)));
+                }
+            })
+        };
+        LibRefresher {
+            latest,
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn latest(&self) -> Arc<AtomicU64> {
+        self.latest.clone()
+    }
+}
+
+impl Drop for LibRefresher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            backoff: Duration::from_millis(1),
+        }
+    }
+
+    fn transport_error() -> ClientError {
+        ClientError::from(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            "connection refused",
+        ))
+    }
+
+    fn response_error(code: i64) -> ClientError {
+        ClientError::from(RpcError::RpcResponseError {
+            code,
+            message: format!("error {} for slot 10", code),
+            data: solana_rpc_client_api::request::RpcResponseErrorData::Empty,
+        })
+    }
+
+    #[test]
+    fn test_with_retries() {
+        let mut calls = 0;
+        let result = with_retries(policy(3), "test", || {
+            calls += 1;
+            if calls <= 2 {
+                Err(transport_error())
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut calls = 0;
+        let result: Result<(), _> = with_retries(policy(2), "test", || {
+            calls += 1;
+            Err(transport_error())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn test_with_retries_skipped_slot() {
+        for code in [
+            JSON_RPC_SERVER_ERROR_SLOT_SKIPPED,
+            JSON_RPC_SERVER_ERROR_LONG_TERM_STORAGE_SLOT_SKIPPED,
+            JSON_RPC_SERVER_ERROR_BLOCK_CLEANED_UP,
+            JSON_RPC_SERVER_ERROR_BLOCK_NOT_AVAILABLE,
+            JSON_RPC_SERVER_ERROR_TRANSACTION_HISTORY_NOT_AVAILABLE,
+        ] {
+            let mut calls = 0;
+            let result: Result<(), _> = with_retries(policy(3), "test", || {
+                calls += 1;
+                Err(response_error(code))
+            });
+            assert!(result.is_err());
+            assert_eq!(calls, 1, "error {}", code);
+        }
+
+        // the other rpc errors may be transient
+        let mut calls = 0;
+        let result: Result<(), _> = with_retries(policy(3), "test", || {
+            calls += 1;
+            Err(response_error(-32603))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 4);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_new_rpc_client_timeout() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({"jsonrpc": "2.0", "result": 100, "id": 1}))
+                    .set_delay(Duration::from_secs(5)),
+            )
+            .mount(&server)
+            .await;
+
+        let rpc_client =
+            new_rpc_client(server.uri(), Duration::from_millis(100), &HeaderMap::new());
+        let start = std::time::Instant::now();
+        assert!(rpc_client.get_slot().is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_new_rpc_client_headers() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("authorization", "Bearer secret"))
+            .and(header("x-api-key", "key"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({"jsonrpc": "2.0", "result": 100, "id": 1})),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&server)
+            .await;
+
+        let headers = header_map(
+            &HashMap::from([("X-Api-Key".to_string(), "key".to_string())]),
+            Some("secret"),
+        )
+        .unwrap();
+        let rpc_client = new_rpc_client(server.uri(), Duration::from_secs(5), &headers);
+        assert_eq!(rpc_client.get_slot().unwrap(), 100);
+
+        let rpc_client = new_rpc_client(server.uri(), Duration::from_secs(5), &HeaderMap::new());
+        assert!(rpc_client.get_slot().is_err());
+
+        assert!(header_map(
+            &HashMap::from([("bad name".to_string(), "x".to_string())]),
+            None
+        )
+        .is_err());
+        assert!(header_map(&HashMap::new(), Some("line\nbreak")).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_lib_refresher_advances() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({"jsonrpc": "2.0", "result": 100, "id": 1})),
+            )
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        // the finalized slot goes back once, the published value does not
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({"jsonrpc": "2.0", "result": 90, "id": 1})),
+            )
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({"jsonrpc": "2.0", "result": 150, "id": 1})),
+            )
+            .mount(&server)
+            .await;
+
+        let refresher =
+            LibRefresher::spawn(RpcClient::new(server.uri()), Duration::from_millis(10));
+        let latest = refresher.latest();
+        let start = Instant::now();
+        let mut seen = vec![];
+        while latest.load(Ordering::Relaxed) != 150 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            let value = latest.load(Ordering::Relaxed);
+            if seen.last() != Some(&value) {
+                seen.push(value);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(seen.iter().all(|value| *value == 0 || *value == 100));
+        drop(refresher);
+    }
+}
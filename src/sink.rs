@@ -0,0 +1,242 @@
+use crate::diff::{drop_account_diffs, take_diffed_data};
+use crate::pb::sf::solana::r#type::v1::{Account, AccountBlock, Block};
+use crate::state::BlockInfo;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+// BlockSink is where State sends the slots, in order, once they are ready. BlockPrinter writes them to the FIRE
+// outputs, another sink can publish them elsewhere. The sink owns the cursor: it is written at cursor_path once
+// the slot is safely out, a sink without a durable output does not write it.
+pub trait BlockSink: Send + Sync {
+    // print_init is called once, before any slot, with the message types of the block, account and transaction streams
+    fn print_init(
+        &mut self,
+        block_type: &str,
+        account_block_type: &str,
+        transaction_block_type: &str,
+    ) -> std::io::Result<()>;
+
+    // print_snapshot sends a chunk of the accounts loaded at startup, it is not a block and does not move the cursor
+    fn print_snapshot(&mut self, slot: u64, accounts: Vec<Account>) -> std::io::Result<()>;
+
+    fn print(
+        &mut self,
+        block_info: &BlockInfo,
+        lib: u64,
+        block: Block,
+        account_block: AccountBlock,
+        cursor_path: &str,
+    ) -> std::io::Result<()>;
+
+    // replay sends a slot that was already sent once more, without moving the cursor
+    fn replay(
+        &mut self,
+        block_info: &BlockInfo,
+        lib: u64,
+        block: Block,
+        account_block: AccountBlock,
+    ) -> std::io::Result<()>;
+
+    // print_placeholder sends the placeholder of a slot skipped by the cluster. The cursor never points at it:
+    // there is no block to verify it against on a restart.
+    fn print_placeholder(
+        &mut self,
+        block_info: &BlockInfo,
+        lib: u64,
+        block: Block,
+        account_block: AccountBlock,
+    ) -> std::io::Result<()>;
+
+    // confirm_slot lets the cursor move over the sent slots up to `slot`, for a sink that waits for the confirmation
+    fn confirm_slot(&self, _slot: u64, _cursor_path: &str) {}
+
+    // flush_cursor persists the cursor of the last sent slot, for a sink that does not write it after every slot
+    fn flush_cursor(&self, _cursor_path: &str) {}
+
+    // diff_base_lost tells whether a reader may have lost the data the account diffs are made against since the
+    // last call, a new reader of a reopened destination for instance: the next diffs must not be made against it.
+    fn diff_base_lost(&mut self) -> bool {
+        false
+    }
+
+    // outputs_poisoned tells whether a writer panicked while holding an output, the sink cannot write anymore
+    fn outputs_poisoned(&self) -> bool {
+        false
+    }
+}
+
+// the sink chosen by the config is only known when the plugin is loaded
+impl BlockSink for Box<dyn BlockSink> {
+    fn print_init(
+        &mut self,
+        block_type: &str,
+        account_block_type: &str,
+        transaction_block_type: &str,
+    ) -> std::io::Result<()> {
+        (**self).print_init(block_type, account_block_type, transaction_block_type)
+    }
+
+    fn print_snapshot(&mut self, slot: u64, accounts: Vec<Account>) -> std::io::Result<()> {
+        (**self).print_snapshot(slot, accounts)
+    }
+
+    fn print(
+        &mut self,
+        block_info: &BlockInfo,
+        lib: u64,
+        block: Block,
+        account_block: AccountBlock,
+        cursor_path: &str,
+    ) -> std::io::Result<()> {
+        (**self).print(block_info, lib, block, account_block, cursor_path)
+    }
+
+    fn replay(
+        &mut self,
+        block_info: &BlockInfo,
+        lib: u64,
+        block: Block,
+        account_block: AccountBlock,
+    ) -> std::io::Result<()> {
+        (**self).replay(block_info, lib, block, account_block)
+    }
+
+    fn print_placeholder(
+        &mut self,
+        block_info: &BlockInfo,
+        lib: u64,
+        block: Block,
+        account_block: AccountBlock,
+    ) -> std::io::Result<()> {
+        (**self).print_placeholder(block_info, lib, block, account_block)
+    }
+
+    fn confirm_slot(&self, slot: u64, cursor_path: &str) {
+        (**self).confirm_slot(slot, cursor_path)
+    }
+
+    fn flush_cursor(&self, cursor_path: &str) {
+        (**self).flush_cursor(cursor_path)
+    }
+
+    fn diff_base_lost(&mut self) -> bool {
+        (**self).diff_base_lost()
+    }
+
+    fn outputs_poisoned(&self) -> bool {
+        (**self).outputs_poisoned()
+    }
+}
+
+// slot and accounts of a chunk of the startup snapshot
+pub type PrintedSnapshot = (u64, Vec<Account>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrintedSlot {
+    pub lib: u64,
+    pub block: Block,
+    pub account_block: AccountBlock,
+    pub replayed: bool,
+}
+
+// MemorySink keeps everything it is sent in memory, for the tests that check the emitted blocks. Its clones share
+// the same slots, so that a clone can be kept to read them once the sink was handed to State. It writes no cursor.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    slots: Arc<Mutex<Vec<PrintedSlot>>>,
+    snapshots: Arc<Mutex<Vec<PrintedSnapshot>>>,
+    diff_base_lost: Arc<AtomicBool>,
+    fail_prints: Arc<AtomicBool>,
+}
+
+impl MemorySink {
+    pub fn slots(&self) -> Vec<PrintedSlot> {
+        self.slots.lock().expect("sink lock poisoned").clone()
+    }
+
+    pub fn snapshots(&self) -> Vec<PrintedSnapshot> {
+        self.snapshots.lock().expect("sink lock poisoned").clone()
+    }
+
+    // lose_diff_base makes the sink report that its reader lost the data the account diffs are made against
+    pub fn lose_diff_base(&self) {
+        self.diff_base_lost.store(true, Ordering::Relaxed);
+    }
+
+    // fail_prints makes the next slots fail to print, like an output that cannot be written
+    pub fn fail_prints(&self) {
+        self.fail_prints.store(true, Ordering::Relaxed);
+    }
+
+    fn push(&self, lib: u64, block: Block, account_block: AccountBlock, replayed: bool) {
+        self.slots
+            .lock()
+            .expect("sink lock poisoned")
+            .push(PrintedSlot {
+                lib,
+                block,
+                account_block,
+                replayed,
+            });
+    }
+}
+
+impl BlockSink for MemorySink {
+    fn print_init(&mut self, _: &str, _: &str, _: &str) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn print_snapshot(&mut self, slot: u64, accounts: Vec<Account>) -> std::io::Result<()> {
+        self.snapshots
+            .lock()
+            .expect("sink lock poisoned")
+            .push((slot, accounts));
+        Ok(())
+    }
+
+    fn print(
+        &mut self,
+        _block_info: &BlockInfo,
+        lib: u64,
+        block: Block,
+        mut account_block: AccountBlock,
+        _cursor_path: &str,
+    ) -> std::io::Result<()> {
+        if self.fail_prints.load(Ordering::Relaxed) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "broken pipe",
+            ));
+        }
+        take_diffed_data(&mut account_block);
+        self.push(lib, block, account_block, false);
+        Ok(())
+    }
+
+    fn replay(
+        &mut self,
+        _block_info: &BlockInfo,
+        lib: u64,
+        block: Block,
+        mut account_block: AccountBlock,
+    ) -> std::io::Result<()> {
+        drop_account_diffs(&mut account_block);
+        self.push(lib, block, account_block, true);
+        Ok(())
+    }
+
+    fn print_placeholder(
+        &mut self,
+        _block_info: &BlockInfo,
+        lib: u64,
+        block: Block,
+        account_block: AccountBlock,
+    ) -> std::io::Result<()> {
+        self.push(lib, block, account_block, false);
+        Ok(())
+    }
+
+    fn diff_base_lost(&mut self) -> bool {
+        self.diff_base_lost.swap(false, Ordering::Relaxed)
+    }
+}
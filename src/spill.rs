@@ -0,0 +1,116 @@
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+// SpilledData is where the data of a buffered account change was written in the file of its slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpilledData {
+    pub offset: u64,
+    pub len: usize,
+}
+
+// SpillFiles keeps the data of the buffered account changes on disk, in one append-only file per slot,
+// so that only the account metadata stays in memory until the slot is sent. A write replaced by a newer
+// one in the same slot stays in the file, the whole file is removed with its slot.
+pub struct SpillFiles {
+    dir: PathBuf,
+    files: HashMap<u64, (File, u64)>,
+}
+
+impl SpillFiles {
+    // new creates the directory if needed, the files left by a previous run are removed
+    pub fn new(dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "spill")
+            {
+                info!("removing stale spill file {}", path.display());
+                std::fs::remove_file(&path)?;
+            }
+        }
+        Ok(SpillFiles {
+            dir,
+            files: HashMap::new(),
+        })
+    }
+
+    fn path(&self, slot: u64) -> PathBuf {
+        self.dir.join(format!("{}.spill", slot))
+    }
+
+    pub fn write(&mut self, slot: u64, data: &[u8]) -> std::io::Result<SpilledData> {
+        if !self.files.contains_key(&slot) {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(self.path(slot))?;
+            self.files.insert(slot, (file, 0));
+        }
+        let (file, end) = self.files.get_mut(&slot).expect("inserted above");
+        file.write_all(data)?;
+        let spilled = SpilledData {
+            offset: *end,
+            len: data.len(),
+        };
+        *end += data.len() as u64;
+        Ok(spilled)
+    }
+
+    pub fn read(&self, slot: u64, spilled: SpilledData) -> std::io::Result<Vec<u8>> {
+        let (file, _) = self.files.get(&slot).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no spill file for slot {}", slot),
+            )
+        })?;
+        let mut data = vec![0; spilled.len];
+        file.read_exact_at(&mut data, spilled.offset)?;
+        Ok(data)
+    }
+
+    pub fn remove(&mut self, slot: u64) {
+        if self.files.remove(&slot).is_some() {
+            if let Err(err) = std::fs::remove_file(self.path(slot)) {
+                warn!("cannot remove spill file of slot {}: {}", slot, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spill_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(dir.path().join("7.spill"), b"stale").unwrap();
+        std::fs::write(dir.path().join("other"), b"kept").unwrap();
+
+        let mut spill_files = SpillFiles::new(dir.path()).unwrap();
+        assert!(!dir.path().join("7.spill").exists());
+        assert!(dir.path().join("other").exists());
+
+        let first = spill_files.write(10, &[1, 2, 3]).unwrap();
+        let second = spill_files.write(10, &[4, 5]).unwrap();
+        let other_slot = spill_files.write(11, &[6]).unwrap();
+        assert_eq!(second, SpilledData { offset: 3, len: 2 });
+        assert_eq!(spill_files.read(10, second).unwrap(), vec![4, 5]);
+        assert_eq!(spill_files.read(10, first).unwrap(), vec![1, 2, 3]);
+        assert_eq!(spill_files.read(11, other_slot).unwrap(), vec![6]);
+
+        spill_files.remove(10);
+        assert!(!dir.path().join("10.spill").exists());
+        assert!(spill_files.read(10, first).is_err());
+        assert!(dir.path().join("11.spill").exists());
+    }
+}
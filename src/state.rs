@@ -1,11 +1,18 @@
-use crate::block_printer::BlockPrinter;
+use crate::block_printer::skipped_block_hash;
+use crate::config::{
+    AccountBlockMode, AccountSort, AddressEncoding, DeletionDetection, OversizedAccountAction,
+    TransactionOrder,
+};
 use crate::pb;
-use crate::utils::{convert_sol_timestamp, create_account_block};
-use lazy_static::lazy_static;
-use pb::sf::solana::r#type::v1::Account;
+use crate::utils::{convert_sol_timestamp, create_account_block, encode_address};
+use pb::sf::solana::r#type::v1::{Account, AccountBlock};
 use prost_types::Timestamp;
 use solana_rpc_client::rpc_client::RpcClient;
-use std::collections::HashMap;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::system_program;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 type BlockAccountChanges = HashMap<u64, AccountChanges>;
 pub type AccountChanges = HashMap<Vec<u8>, AccountWithWriteVersion>;
@@ -15,23 +22,37 @@ pub type Transactions = HashMap<u64, Vec<ConfirmTransactionWithIndex>>;
 type ProcessedSlot = HashMap<u64, bool>;
 
 type BlockInfoMap = HashMap<u64, BlockInfo>;
-type ConfirmedSlotsMap = HashMap<u64, bool>;
-use crate::pb::sf::solana::r#type::v1::{Block, BlockHeight, Reward, UnixTimestamp};
+// None when no rpc client had the block time, it is not asked again
+type BlockTimes = HashMap<u64, Option<i64>>;
+// ordered so that the slots to send up to a bound are a range query
+type ConfirmedSlots = BTreeSet<u64>;
+use crate::diff::AccountDataCache;
+use crate::pb::sf::solana::r#type::v1::{Block, BlockHeight, NumPartitions, Reward, UnixTimestamp};
 use crate::plugins::{to_block_rewards, ConfirmTransactionWithIndex};
+use crate::rpc::{is_retryable, with_retries, RetryPolicy};
+use crate::sink::BlockSink;
+use crate::spill::{SpillFiles, SpilledData};
 use log::{debug, error, info, warn};
 use solana_rpc_client_api::config::RpcBlockConfig;
 use solana_sdk::commitment_config::CommitmentConfig;
-use solana_transaction_status::TransactionDetails;
+use solana_transaction_status::{TransactionDetails, UiConfirmedBlock};
+
+// SentSlot is a slot as it was sent, kept for State::replay
+struct SentSlot {
+    block_info: BlockInfo,
+    lib: u64,
+    block: Block,
+    account_block: AccountBlock,
+}
 
 pub struct AccountWithWriteVersion {
     pub account: Account,
     pub write_version: u64,
-}
-
-lazy_static! {
-    pub static ref BLOCK_MUTEX: std::sync::Mutex<()> = std::sync::Mutex::new(());
-    pub static ref ACC_MUTEX: std::sync::Mutex<()> = std::sync::Mutex::new(());
-    pub static ref CURSOR_MUTEX: std::sync::Mutex<u64> = std::sync::Mutex::new(0);
+    // the data is in the spill file of the slot until the slot is sent, account.data is empty meanwhile
+    pub spilled: Option<SpilledData>,
+    // the data shared with the other pending writes of the account holding the same data, account.data is empty
+    // meanwhile. It is put back in the account when the slot is sent.
+    pub shared: Option<Arc<Vec<u8>>>,
 }
 
 #[derive(Default, Clone)]
@@ -43,6 +64,7 @@ pub struct BlockInfo {
     pub timestamp: Timestamp,
     pub height: Option<u64>,
     pub rewards: Vec<Reward>,
+    pub num_reward_partitions: Option<u64>,
     pub transaction_count: u64,
 }
 
@@ -54,6 +76,161 @@ const DEFAULT_RPC_BLOCK_CONFIG: RpcBlockConfig = RpcBlockConfig {
     max_supported_transaction_version: Some(0),
 };
 
+// only the hash of the finalized block is needed to verify a sent or fetched one
+const VERIFY_RPC_BLOCK_CONFIG: RpcBlockConfig = RpcBlockConfig {
+    encoding: None,
+    transaction_details: Some(TransactionDetails::None),
+    rewards: Some(false),
+    commitment: Some(CommitmentConfig::finalized()),
+    max_supported_transaction_version: Some(0),
+};
+
+const MAX_CONCURRENT_RPC_FETCHES: usize = 8;
+
+// default number of startup accounts written per snapshot AccountBlock, to bound the memory they use
+pub(crate) const STARTUP_SNAPSHOT_CHUNK_ACCOUNTS: usize = 10_000;
+
+// default number of slots kept before the first confirmed slot, while neither a cursor nor a first block is known
+pub(crate) const RETENTION_SLOTS: u64 = 32;
+
+// default number of slots the sent slots are remembered for, to skip the late notifications about them
+pub(crate) const PROCESSED_RETENTION_SLOTS: u64 = 100;
+
+// a finalized slot from the RPC further than this below the first block meta received is considered stale
+const MAX_RPC_LIB_DISTANCE: u64 = 1_000;
+
+// log the buffered_stats every N processed slots
+const BUFFERED_STATS_LOG_INTERVAL: u64 = 100;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BufferedStats {
+    pub block_account_changes: usize,
+    pub block_infos: usize,
+    pub transactions: usize,
+    pub confirmed_slots: usize,
+    pub account_data_bytes: usize,
+    // slots dropped since startup because of max_buffered_slots
+    pub evicted_slots: u64,
+}
+
+// DedupStats counts the account updates received after startup and how many were skipped
+// because their data did not change or because a newer write of the same slot was already kept.
+// `emitted` are the updates kept for their slot, a later write of the account in the slot still replaces them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DedupStats {
+    pub account_updates: u64,
+    pub dedup_hits: u64,
+    pub older_write_version: u64,
+    pub emitted: u64,
+}
+
+impl DedupStats {
+    pub fn hit_ratio(&self) -> f64 {
+        if self.account_updates == 0 {
+            return 0.0;
+        }
+        self.dedup_hits as f64 / self.account_updates as f64
+    }
+}
+
+#[derive(Debug)]
+pub enum ProcessError {
+    /// The printer could not hand the block over to the outputs.
+    PrinterFailed { slot: u64, source: std::io::Error },
+    /// A writer thread panicked while holding its output mutex, the outputs cannot be trusted anymore.
+    MutexPoisoned,
+    /// A confirmed slot has no block info, not even from the RPC. The slots after it are still sent
+    /// if they do not need it, it is retried on the next call.
+    MissingBlockInfo(u64),
+    /// The account data of the slot could not be read back from its spill file.
+    SpillFailed { slot: u64, source: std::io::Error },
+    /// No rpc client answered for the block info of a confirmed slot. Like MissingBlockInfo, it is
+    /// fetched again on the next call.
+    RpcUnavailable { slot: u64, error: String },
+}
+
+impl std::fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProcessError::PrinterFailed { slot, source } => {
+                write!(f, "failed to print block {}: {}", slot, source)
+            }
+            ProcessError::MutexPoisoned => write!(f, "poisoned mutex"),
+            ProcessError::MissingBlockInfo(slot) => write!(f, "no block info for slot {}", slot),
+            ProcessError::SpillFailed { slot, source } => {
+                write!(
+                    f,
+                    "failed to read the spilled accounts of slot {}: {}",
+                    slot, source
+                )
+            }
+            ProcessError::RpcUnavailable { slot, error } => {
+                write!(f, "no rpc could give block {}: {}", slot, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProcessError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProcessError::PrinterFailed { source, .. } => Some(source),
+            ProcessError::SpillFailed { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct StateOptions {
+    pub transaction_order: TransactionOrder,
+    pub min_account_data_bytes: Option<usize>,
+    pub max_account_data_bytes: Option<usize>,
+    pub oversized_accounts: OversizedAccountAction,
+    pub rpc_retry: RetryPolicy,
+    pub emit_startup_snapshot: bool,
+    // STARTUP_SNAPSHOT_CHUNK_ACCOUNTS when unset
+    pub startup_snapshot_chunk_accounts: Option<usize>,
+    pub watch_accounts: HashSet<Pubkey>,
+    pub log_address_encoding: AddressEncoding,
+    pub account_sort: AccountSort,
+    pub account_block_mode: AccountBlockMode,
+    pub deletion_detection: DeletionDetection,
+    // the signature of the transaction that wrote an account is kept in its Account, it adds 64 bytes to each
+    pub account_txn_signature: bool,
+    // number of the last sent slots kept in memory, as sent, so that they can be replayed
+    pub replay_buffer_slots: usize,
+    // a block whose parent is at most this many slots after the last sent block waits for its ancestors from geyser
+    // instead of fetching them from the rpc
+    pub max_natural_gap: u64,
+    // the pending writes of an account with identical data share it, see State::shared_account_data
+    pub compact_account_changes: bool,
+    pub disable_account_dedup: bool,
+    // a write with the same write_version as the buffered one replaces it, instead of being dropped
+    pub prefer_later_equal_write_version: bool,
+    // finalized slot published by a LibRefresher, 0 until known
+    pub refreshed_lib: Option<Arc<AtomicU64>>,
+    pub max_buffered_slots: Option<usize>,
+    // no output needs the transactions, they are not received: a slot does not wait for them to be sent
+    pub without_transactions: bool,
+    pub detect_owner_changes: bool,
+    // nothing is sent after the first slot at or above it
+    pub stop_at_slot: Option<u64>,
+    // RETENTION_SLOTS when unset
+    pub retention_slots: Option<u64>,
+    // PROCESSED_RETENTION_SLOTS when unset
+    pub processed_retention_slots: Option<u64>,
+    pub emit_skipped_slots: bool,
+    // the hash of each sent slot is compared with the finalized one from the rpc once the slot is rooted
+    pub verify_blockhash_on_lib: bool,
+    // a block fetched from the local rpc is also fetched from a remote one, on different hashes the finalized one is kept
+    pub rpc_cross_check: bool,
+    // the rewards are neither requested from the rpc nor sent in the blocks
+    pub without_block_rewards: bool,
+    // the first sent slot is checked against the cursor, see check_first_sent_slot
+    pub cursor_self_test: bool,
+}
+
 pub struct State {
     initialized: bool, // passed the first received blockmeta
 
@@ -61,185 +238,639 @@ pub struct State {
     first_block_to_process: Option<u64>,
 
     last_sent_block: Option<u64>,
+    // None until a block is sent, the cursor only has the slot
+    last_sent_block_hash: Option<String>,
 
     cursor: Option<u64>,
     lib: Option<u64>,
+    // the LIB from the RPC was stale, the first rooted slot is used instead
+    rpc_lib_deferred: bool,
 
     block_account_changes: BlockAccountChanges,
     account_data_hash: AccountDataHash,
+    // owner of the last write of each account, as an index in `owners`, only used with detect_owner_changes
+    detect_owner_changes: bool,
+    account_owners: HashMap<Vec<u8>, u32>,
+    owners: HashMap<Vec<u8>, u32>,
 
     block_infos: BlockInfoMap,
-    confirmed_slots: ConfirmedSlotsMap,
+    block_times: BlockTimes,
+    // slots whose block info came without a block time, it is fetched from rpc when the slot is sent
+    missing_block_times: HashSet<u64>,
+    confirmed_slots: ConfirmedSlots,
 
     transactions: Transactions,
     processed_slots: ProcessedSlot,
 
     local_rpc_client: Option<RpcClient>,
-    remote_rpc_client: Option<RpcClient>,
+    remote_rpc_clients: Vec<RpcClient>,
+    // index of the remote client that answered last, it is tried first
+    healthy_remote: usize,
     cursor_path: String,
-    block_printer: BlockPrinter,
+    sink: Box<dyn BlockSink>,
+    transaction_order: TransactionOrder,
+    min_account_data_bytes: Option<usize>,
+    max_account_data_bytes: Option<usize>,
+    oversized_accounts: OversizedAccountAction,
+    rpc_retry: RetryPolicy,
+    watch_accounts: HashSet<Pubkey>,
+    log_address_encoding: AddressEncoding,
+    account_sort: AccountSort,
+    account_block_mode: AccountBlockMode,
+    deletion_detection: DeletionDetection,
+    account_txn_signature: bool,
+    replay_buffer_slots: usize,
+    max_natural_gap: u64,
+    sent_slots: VecDeque<SentSlot>,
+    // pending slot holding the latest write of each account, only used with compact_account_changes
+    // to share the data of the writes that did not change it
+    compact_account_changes: bool,
+    account_latest_slot: HashMap<Vec<u8>, u64>,
+    dedup_accounts: bool,
+    prefer_later_equal_write_version: bool,
+    dedup_stats: DedupStats,
+    refreshed_lib: Option<Arc<AtomicU64>>,
+    max_buffered_slots: Option<usize>,
+    evicted_slots: u64,
+    without_transactions: bool,
+    stop_at_slot: Option<u64>,
+    stopped: bool,
+    processed_count: u64,
+
+    // accounts received at startup not written yet, None when emit_startup_snapshot is off
+    startup_snapshot: Option<Vec<Account>>,
+    startup_snapshot_slot: u64,
+    startup_snapshot_chunk: usize,
+    retention_slots: u64,
+    processed_retention_slots: u64,
+    emit_skipped_slots: bool,
+    // hashes of the sent slots not rooted yet, None when verify_blockhash_on_lib is off
+    sent_block_hashes: Option<BTreeMap<u64, String>>,
+    blockhash_mismatches: u64,
+    transaction_index_gaps: u64,
+    rpc_block_failures: u64,
+    rpc_cross_check: bool,
+    without_block_rewards: bool,
+    cursor_self_test: bool,
+    // whether the first sent slot followed the cursor, None until it is sent or without cursor_self_test
+    cursor_self_test_passed: Option<bool>,
+    spill_files: Option<SpillFiles>,
+    // data last sent for each account, None when the account data is always sent in full
+    account_data_cache: Option<AccountDataCache>,
 }
 
 impl State {
+    // without a local rpc client (and then without remote ones), the LIB only comes from the rooted slots,
+    // and missing blocks are not fetched
     pub fn new(
-        local_rpc_client: RpcClient,
-        remote_rpc_client: RpcClient,
+        local_rpc_client: Option<RpcClient>,
+        remote_rpc_clients: Vec<RpcClient>,
         cursor: Option<u64>,
         cursor_path: String,
-        block_printer: BlockPrinter,
+        sink: impl BlockSink + 'static,
+        options: StateOptions,
     ) -> Self {
         State {
             cursor,
             first_block_to_process: None,
             first_received_blockmeta: None,
             lib: None,
+            rpc_lib_deferred: false,
             initialized: false,
 
             block_account_changes: HashMap::new(),
             account_data_hash: HashMap::new(),
+            detect_owner_changes: options.detect_owner_changes,
+            account_owners: HashMap::new(),
+            owners: HashMap::new(),
             block_infos: HashMap::new(),
-            confirmed_slots: HashMap::new(),
+            block_times: HashMap::new(),
+            missing_block_times: HashSet::new(),
+            confirmed_slots: BTreeSet::new(),
             last_sent_block: None,
+            last_sent_block_hash: None,
 
             transactions: HashMap::new(),
             processed_slots: HashMap::new(),
 
-            local_rpc_client: Some(local_rpc_client),
-            remote_rpc_client: Some(remote_rpc_client),
+            local_rpc_client,
+            remote_rpc_clients,
+            healthy_remote: 0,
             cursor_path,
-            block_printer,
+            sink: Box::new(sink),
+            transaction_order: options.transaction_order,
+            min_account_data_bytes: options.min_account_data_bytes,
+            max_account_data_bytes: options.max_account_data_bytes,
+            oversized_accounts: options.oversized_accounts,
+            rpc_retry: options.rpc_retry,
+            watch_accounts: options.watch_accounts,
+            log_address_encoding: options.log_address_encoding,
+            account_sort: options.account_sort,
+            account_block_mode: options.account_block_mode,
+            deletion_detection: options.deletion_detection,
+            account_txn_signature: options.account_txn_signature,
+            replay_buffer_slots: options.replay_buffer_slots,
+            max_natural_gap: options.max_natural_gap,
+            sent_slots: VecDeque::new(),
+            compact_account_changes: options.compact_account_changes,
+            account_latest_slot: HashMap::new(),
+            dedup_accounts: !options.disable_account_dedup,
+            prefer_later_equal_write_version: options.prefer_later_equal_write_version,
+            dedup_stats: DedupStats::default(),
+            refreshed_lib: options.refreshed_lib,
+            max_buffered_slots: options.max_buffered_slots,
+            evicted_slots: 0,
+            without_transactions: options.without_transactions,
+            stop_at_slot: options.stop_at_slot,
+            stopped: false,
+            processed_count: 0,
+            startup_snapshot: options.emit_startup_snapshot.then(Vec::new),
+            startup_snapshot_slot: 0,
+            startup_snapshot_chunk: options
+                .startup_snapshot_chunk_accounts
+                .unwrap_or(STARTUP_SNAPSHOT_CHUNK_ACCOUNTS)
+                .max(1),
+            retention_slots: options.retention_slots.unwrap_or(RETENTION_SLOTS),
+            processed_retention_slots: options
+                .processed_retention_slots
+                .unwrap_or(PROCESSED_RETENTION_SLOTS),
+            emit_skipped_slots: options.emit_skipped_slots,
+            sent_block_hashes: options.verify_blockhash_on_lib.then(BTreeMap::new),
+            blockhash_mismatches: 0,
+            transaction_index_gaps: 0,
+            rpc_block_failures: 0,
+            rpc_cross_check: options.rpc_cross_check,
+            without_block_rewards: options.without_block_rewards,
+            cursor_self_test: options.cursor_self_test,
+            cursor_self_test_passed: None,
+            spill_files: None,
+            account_data_cache: None,
         }
     }
 
-    fn set_last_finalized_block_from_rpc(&mut self) {
+    // set_last_finalized_block_from_rpc gets the LIB from the local RPC when the first block meta `reference_slot` arrives.
+    // A LIB too far behind that slot comes from a stale RPC: it is not used, and nothing is asked to the RPC anymore,
+    // the first rooted slot notified by the validator decides instead (see set_lib).
+    fn set_last_finalized_block_from_rpc(&mut self, reference_slot: u64) {
+        if self.rpc_lib_deferred {
+            return;
+        }
+        let Some(local_rpc_client) = self.local_rpc_client.as_ref() else {
+            debug!("no rpc client, waiting for a rooted slot to get the lib");
+            return;
+        };
         let commitment_config = CommitmentConfig::finalized();
-        match self
-            .local_rpc_client
-            .as_ref()
-            .expect("local_rpc_client not set")
-            .get_slot_with_commitment(commitment_config)
-        {
+        match with_retries(self.rpc_retry, "getting lib from rpc client", || {
+            local_rpc_client.get_slot_with_commitment(commitment_config)
+        }) {
+            Ok(lib_num) if reference_slot.saturating_sub(lib_num) > MAX_RPC_LIB_DISTANCE => {
+                warn!(
+                    "ignoring lib {} from rpc client, it is more than {} slots behind slot {}: waiting for a rooted slot",
+                    lib_num, MAX_RPC_LIB_DISTANCE, reference_slot
+                );
+                self.rpc_lib_deferred = true;
+            }
             Ok(lib_num) => {
                 info!("Block lib received from rpc client: {}", lib_num);
-                self.lib = Some(lib_num);
-                if let Some(cursor) = self.cursor {
-                    if lib_num > cursor {
-                        info!(
-                            "ignoring cursor {} because LIB {} is greater",
-                            cursor, lib_num
-                        );
-                        self.cursor = None;
-                        self.first_block_to_process = None; // it would have been set by the cursor, we get rid of it too
-                    }
-                }
+                self.set_first_lib(lib_num);
             }
             Err(e) => {
-                println!("Error getting lib num from rpc client: {}", e);
+                error!("Error getting lib num from rpc client: {}", e);
             }
         }
     }
 
+    // set_lib only moves the lib forward: the rooted statuses can arrive out of order,
+    // and a lib going back would hold back process_upto again for the slots in between
     pub fn set_lib(&mut self, slot: u64) {
-        self.lib = Some(slot);
+        self.verify_rooted_blockhash(slot);
+        match self.lib {
+            None => self.set_first_lib(slot),
+            Some(lib) if slot < lib => {
+                warn!("ignoring rooted slot {} notified after lib {}", slot, lib);
+            }
+            Some(_) => self.lib = Some(slot),
+        }
+    }
+
+    // verify_rooted_blockhash compares the hash sent for the rooted slot with the finalized one from the rpc,
+    // a mismatch means a block of a fork that was abandoned was sent. The older sent slots are forgotten.
+    fn verify_rooted_blockhash(&mut self, rooted: u64) {
+        let Some(sent_block_hashes) = self.sent_block_hashes.as_mut() else {
+            return;
+        };
+        let newer = sent_block_hashes.split_off(&(rooted + 1));
+        let sent_hash = std::mem::replace(sent_block_hashes, newer).remove(&rooted);
+        let (Some(sent_hash), Some(local_rpc_client)) = (sent_hash, self.local_rpc_client.as_ref())
+        else {
+            return;
+        };
+
+        let description = format!("fetching finalized block {}", rooted);
+        match with_retries(self.rpc_retry, &description, || {
+            local_rpc_client.get_block_with_config(rooted, VERIFY_RPC_BLOCK_CONFIG)
+        }) {
+            Ok(block) if block.blockhash == sent_hash => {
+                debug!(
+                    "sent block hash of rooted slot {} matches the chain",
+                    rooted
+                );
+            }
+            Ok(block) => {
+                self.blockhash_mismatches += 1;
+                error!(
+                    "sent block hash {} of rooted slot {} does not match the finalized hash {} ({} mismatches so far)",
+                    sent_hash, rooted, block.blockhash, self.blockhash_mismatches
+                );
+            }
+            Err(err) => warn!(
+                "cannot get finalized block {} to verify the sent hash: {}",
+                rooted, err
+            ),
+        }
+    }
+
+    // number of rooted slots whose sent hash was not the finalized one, see verify_rooted_blockhash
+    pub fn blockhash_mismatches(&self) -> u64 {
+        self.blockhash_mismatches
+    }
+
+    // transaction_index_gaps is the number of sent blocks whose transaction indices were not 0..n
+    pub fn transaction_index_gaps(&self) -> u64 {
+        self.transaction_index_gaps
+    }
+
+    // rpc_block_failures is the number of missing blocks that neither the local nor the remote rpc clients could give
+    pub fn rpc_block_failures(&self) -> u64 {
+        self.rpc_block_failures
+    }
+
+    // the first known LIB decides whether the cursor is still usable
+    fn set_first_lib(&mut self, lib: u64) {
+        self.lib = Some(lib);
+        if let Some(cursor) = self.cursor {
+            if lib > cursor {
+                info!("ignoring cursor {} because LIB {} is greater", cursor, lib);
+                self.cursor = None;
+                // it would have been set by the cursor, start from the first block meta instead (None if not received yet,
+                // set_block_info will set it)
+                self.first_block_to_process = self.first_received_blockmeta;
+            }
+        }
+    }
+
+    // apply_refreshed_lib moves the LIB to the finalized slot refreshed from the RPC, if it is higher
+    fn apply_refreshed_lib(&mut self) {
+        let Some(refreshed) = self.refreshed_lib.as_ref() else {
+            return;
+        };
+        let refreshed = refreshed.load(Ordering::Relaxed);
+        if refreshed == 0 {
+            return;
+        }
+        match self.lib {
+            None => self.set_first_lib(refreshed),
+            Some(lib) if refreshed > lib => {
+                debug!("lib moved from {} to {} by the rpc refresh", lib, refreshed);
+                self.lib = Some(refreshed);
+            }
+            Some(_) => {}
+        }
     }
 
     fn get_lib(&self) -> Option<u64> {
         self.lib
     }
 
-    fn get_account_changes(&self, slot: u64) -> Option<&AccountChanges> {
+    pub(crate) fn get_account_changes(&self, slot: u64) -> Option<&AccountChanges> {
         self.block_account_changes.get(&slot)
     }
 
-    pub fn cache_block_from_rpc(&mut self, slot: u64) {
-        match self
-            .local_rpc_client
-            .as_ref()
-            .expect("local_rpc_client not set")
-            .get_block_with_config(slot, DEFAULT_RPC_BLOCK_CONFIG)
-        {
+    // with_spill_files keeps the data of the buffered account changes in files until their slot is sent
+    pub fn with_spill_files(mut self, spill_files: SpillFiles) -> Self {
+        self.spill_files = Some(spill_files);
+        self
+    }
+
+    // with_account_data_diff sends the data of the accounts as a diff against the data last sent for them, when smaller
+    pub fn with_account_data_diff(mut self, cache: AccountDataCache) -> Self {
+        self.account_data_cache = Some(cache);
+        self
+    }
+
+    fn remove_spill_file(&mut self, slot: u64) {
+        if let Some(spill_files) = self.spill_files.as_mut() {
+            spill_files.remove(slot);
+        }
+    }
+
+    fn rpc_block_config(&self) -> RpcBlockConfig {
+        RpcBlockConfig {
+            rewards: Some(!self.without_block_rewards),
+            ..DEFAULT_RPC_BLOCK_CONFIG
+        }
+    }
+
+    pub fn cache_block_from_rpc(&mut self, slot: u64) -> Result<(), ProcessError> {
+        let Some(local_rpc_client) = self.local_rpc_client.as_ref() else {
+            warn!("no rpc client to fetch the missing block of slot {}", slot);
+            return Err(ProcessError::MissingBlockInfo(slot));
+        };
+        let description = format!("fetching block {}", slot);
+        let config = self.rpc_block_config();
+        match with_retries(self.rpc_retry, &description, || {
+            local_rpc_client.get_block_with_config(slot, config)
+        }) {
             Ok(block) => {
                 debug!("Block Info fetched locally for slot {}", slot);
-                self.set_block_info(BlockInfo {
-                    timestamp: convert_sol_timestamp(block.block_time.unwrap_or_default()),
-                    parent_slot: block.parent_slot.clone(),
-                    slot,
-                    block_hash: block.blockhash.clone(),
-                    parent_hash: block.previous_blockhash.clone(),
-                    height: block.block_height,
-                    rewards: to_block_rewards(&block.rewards),
-                    transaction_count: block.transactions.unwrap_or_default().len() as u64,
-                })
-            }
-            Err(_err) => {
-                match self
-                    .remote_rpc_client
-                    .as_ref()
-                    .expect("remote_rpc_client not set")
-                    .get_block_with_config(slot, DEFAULT_RPC_BLOCK_CONFIG)
-                {
-                    Ok(block) => {
+                let block = if self.rpc_cross_check {
+                    self.cross_check_block(slot, block)
+                } else {
+                    block
+                };
+                self.set_block_info(block_info_from_rpc(slot, block));
+                Ok(())
+            }
+            Err(local_err) => {
+                // the rpc is unavailable when no client answered, not when they answered that the block is missing
+                let mut unavailable = is_retryable(&local_err);
+                let mut remote_errors = vec![];
+                for index in self.remote_order() {
+                    let remote_rpc_client = &self.remote_rpc_clients[index];
+                    match with_retries(self.rpc_retry, &description, || {
+                        remote_rpc_client.get_block_with_config(slot, config)
+                    }) {
+                        Ok(block) => {
+                            debug!("Block Info fetched remotely for slot {}", slot);
+                            self.healthy_remote = index;
+                            self.set_block_info(block_info_from_rpc(slot, block));
+                            return Ok(());
+                        }
+                        Err(err) => {
+                            unavailable &= is_retryable(&err);
+                            remote_errors.push(format!("#{}: {}", index, err));
+                        }
+                    }
+                }
+                // the slot stays confirmed without its block info: process_upto asks for it again on its next run
+                self.rpc_block_failures += 1;
+                let error = format!(
+                    "local: {}, remote: {}",
+                    local_err,
+                    if remote_errors.is_empty() {
+                        "no remote rpc client".to_string()
+                    } else {
+                        remote_errors.join(", ")
+                    }
+                );
+                error!(
+                    "cannot fetch block {} from any rpc, it will be fetched again ({} failed fetches so far): {}",
+                    slot, self.rpc_block_failures, error
+                );
+                if unavailable {
+                    Err(ProcessError::RpcUnavailable { slot, error })
+                } else {
+                    Err(ProcessError::MissingBlockInfo(slot))
+                }
+            }
+        }
+    }
+
+    // cross_check_block fetches the block from a remote client too, when both hashes differ one of the endpoints
+    // is on an abandoned fork: the block with the finalized hash is kept, the local one when it is not finalized yet.
+    fn cross_check_block(&mut self, slot: u64, local_block: UiConfirmedBlock) -> UiConfirmedBlock {
+        let description = format!("fetching block {}", slot);
+        let config = self.rpc_block_config();
+        let mut remote_block = None;
+        for index in self.remote_order() {
+            let remote_rpc_client = &self.remote_rpc_clients[index];
+            if let Ok(block) = with_retries(self.rpc_retry, &description, || {
+                remote_rpc_client.get_block_with_config(slot, config)
+            }) {
+                self.healthy_remote = index;
+                remote_block = Some(block);
+                break;
+            }
+        }
+        let Some(remote_block) = remote_block else {
+            debug!("no remote rpc answered to cross check block {}", slot);
+            return local_block;
+        };
+        if remote_block.blockhash == local_block.blockhash {
+            return local_block;
+        }
+
+        let finalized_hash = self
+            .local_rpc_client
+            .iter()
+            .chain(
+                self.remote_order()
+                    .map(|index| &self.remote_rpc_clients[index]),
+            )
+            .find_map(|rpc_client| {
+                rpc_client
+                    .get_block_with_config(slot, VERIFY_RPC_BLOCK_CONFIG)
+                    .ok()
+                    .map(|block| block.blockhash)
+            });
+        match finalized_hash {
+            Some(finalized_hash) if finalized_hash == remote_block.blockhash => {
+                warn!(
+                    "local rpc gave block hash {} for slot {}, using the finalized hash {} of the remote rpc",
+                    local_block.blockhash, slot, finalized_hash
+                );
+                remote_block
+            }
+            Some(finalized_hash) if finalized_hash == local_block.blockhash => {
+                warn!(
+                    "remote rpc gave block hash {} for slot {}, using the finalized hash {} of the local rpc",
+                    remote_block.blockhash, slot, finalized_hash
+                );
+                local_block
+            }
+            finalized_hash => {
+                warn!(
+                    "local and remote rpc gave block hashes {} and {} for slot {} (finalized: {:?}), using the local one",
+                    local_block.blockhash, remote_block.blockhash, slot, finalized_hash
+                );
+                local_block
+            }
+        }
+    }
+
+    // remote_order gives the indexes of the remote clients, starting with the one that answered last
+    fn remote_order(&self) -> impl Iterator<Item = usize> {
+        let count = self.remote_rpc_clients.len();
+        let first = self.healthy_remote;
+        (0..count).map(move |offset| (first + offset) % count)
+    }
+
+    // set_block_time_missing records that geyser did not give the block time of a slot. It is not fetched in the
+    // geyser callback: at the tip the rpc does not have it yet, it is fetched when the slot is sent.
+    pub fn set_block_time_missing(&mut self, slot: u64) {
+        self.missing_block_times.insert(slot);
+    }
+
+    // fill_missing_block_time sets the timestamp of a slot about to be sent when geyser did not give it
+    fn fill_missing_block_time(&mut self, slot: u64) {
+        if !self.missing_block_times.remove(&slot) {
+            return;
+        }
+        if let Some(block_time) = self.block_time_from_rpc(slot) {
+            if let Some(block_info) = self.block_infos.get_mut(&slot) {
+                block_info.timestamp = convert_sol_timestamp(block_time);
+            }
+        }
+    }
+
+    // block_time_from_rpc is used when geyser does not give the block time of a slot,
+    // the result, a failure included, is cached until the slot is purged.
+    pub fn block_time_from_rpc(&mut self, slot: u64) -> Option<i64> {
+        if let Some(block_time) = self.block_times.get(&slot) {
+            return *block_time;
+        }
+
+        let local_rpc_client = self.local_rpc_client.as_ref()?;
+        let block_time = match local_rpc_client.get_block_time(slot) {
+            Ok(block_time) => block_time,
+            Err(local_err) => {
+                let mut found = None;
+                for index in self.remote_order() {
+                    match self.remote_rpc_clients[index].get_block_time(slot) {
+                        Ok(block_time) => {
+                            self.healthy_remote = index;
+                            found = Some(block_time);
+                            break;
+                        }
+                        Err(remote_err) => debug!(
+                            "cannot get block time of slot {} from remote {}: {}",
+                            slot, index, remote_err
+                        ),
+                    }
+                }
+                match found {
+                    Some(block_time) => block_time,
+                    None => {
+                        warn!(
+                            "cannot get block time of slot {} (local: {}, remotes failed too), its timestamp will be 0",
+                            slot, local_err
+                        );
+                        self.block_times.insert(slot, None);
+                        return None;
+                    }
+                }
+            }
+        };
+        debug!(
+            "block time of slot {} fetched from rpc: {}",
+            slot, block_time
+        );
+        self.block_times.insert(slot, Some(block_time));
+        Some(block_time)
+    }
+
+    // cache_blocks_from_rpc does the same as cache_block_from_rpc for many slots at once,
+    // fetching them concurrently instead of doing one round trip after the other.
+    fn cache_blocks_from_rpc(&mut self, slots: &[u64]) {
+        let Some(local_rpc_client) = self.local_rpc_client.as_ref() else {
+            warn!(
+                "no rpc client to fetch the {} missing slots of the hole, it cannot be filled",
+                slots.len()
+            );
+            return;
+        };
+        let config = self.rpc_block_config();
+        let mut not_found_locally = vec![];
+        for (slot, block) in fetch_blocks_from_rpc(local_rpc_client, slots, config) {
+            match block {
+                Some(block) => {
+                    debug!("Block Info fetched locally for slot {}", slot);
+                    self.set_block_info(block_info_from_rpc(slot, block))
+                }
+                None => not_found_locally.push(slot),
+            }
+        }
+        if not_found_locally.is_empty() {
+            return;
+        }
+
+        let mut missing = not_found_locally;
+        for index in self.remote_order().collect::<Vec<_>>() {
+            if missing.is_empty() {
+                break;
+            }
+            let fetched = fetch_blocks_from_rpc(&self.remote_rpc_clients[index], &missing, config);
+            missing = vec![];
+            for (slot, block) in fetched {
+                match block {
+                    Some(block) => {
                         debug!("Block Info fetched remotely for slot {}", slot);
-                        self.set_block_info(BlockInfo {
-                            timestamp: convert_sol_timestamp(block.block_time.unwrap_or_default()),
-                            parent_slot: block.parent_slot.clone(),
-                            slot: slot,
-                            block_hash: block.blockhash.clone(),
-                            parent_hash: block.previous_blockhash.clone(),
-                            height: block.block_height,
-                            rewards: to_block_rewards(&block.rewards),
-                            transaction_count: block.transactions.unwrap_or_default().len() as u64,
-                        })
+                        self.healthy_remote = index;
+                        self.set_block_info(block_info_from_rpc(slot, block))
                     }
-                    Err(_err) => return,
+                    None => missing.push(slot),
                 }
             }
         }
     }
 
     pub fn ordered_confirmed_slots_upto(&self, slot: u64) -> Vec<u64> {
-        // Collect all keys from confirmed_slots that are less than the given slot
-        let mut slots: Vec<u64> = self
-            .confirmed_slots
-            .keys()
-            .cloned()
-            .filter(|&x| x <= slot)
-            .collect();
-        slots.sort();
-        slots
+        self.confirmed_slots.range(..=slot).copied().collect()
     }
 
     fn add_missing_slots_to_confirmed_slots(&mut self, last_sent: u64, parent_slot: u64) -> bool {
         let mut i = parent_slot;
+        let mut fetched_from_rpc = false;
         while i > last_sent {
             match self.block_infos.get(&i) {
                 Some(bi) => {
-                    if self.confirmed_slots.insert(i, true).is_none() {
+                    if self.confirmed_slots.insert(i) {
                         info!("added missing slot {} to confirmed_slots", i);
                     };
                     i = bi.parent_slot;
                 }
+                None if !fetched_from_rpc => {
+                    // we only learn the parent of a slot once we have it, so we fetch every unknown slot of the hole at once.
+                    // skipped slots will simply not be found, the walk through the parents below decides which ones belong to the chain.
+                    let missing: Vec<u64> = (last_sent + 1..=i)
+                        .filter(|slot| !self.block_infos.contains_key(slot))
+                        .collect();
+                    debug!(
+                        "fetching {} missing slots between {} and {} from rpc",
+                        missing.len(),
+                        last_sent,
+                        i
+                    );
+                    self.cache_blocks_from_rpc(&missing);
+                    fetched_from_rpc = true;
+                }
                 None => {
-                    self.cache_block_from_rpc(i);
-                    match self.block_infos.get(&i) {
-                        Some(bi) => {
-                            if self.confirmed_slots.insert(i, true).is_none() {
-                                info!("added missing slot {} to confirmed_slots", i);
-                            };
-                            i = bi.parent_slot;
-                        }
-                        None => {
-                            warn!("Failed to get block info for slot {} while adding missing slots to confirmed_slots", i);
-                            return false;
-                        }
-                    }
+                    warn!("Failed to get block info for slot {} while adding missing slots to confirmed_slots", i);
+                    return false;
                 }
             }
         }
-        return i == last_sent;
+        i == last_sent
+    }
+
+    // unknown_ancestor walks the parents of the slot down to the last sent block, and returns the first one without
+    // block info. None when the whole chain is known, or when it goes below the last sent block (another fork).
+    fn unknown_ancestor(&self, last_sent: u64, slot: u64) -> Option<u64> {
+        let mut i = slot;
+        while i > last_sent {
+            match self.block_infos.get(&i) {
+                Some(bi) => i = bi.parent_slot,
+                None => return Some(i),
+            }
+        }
+        None
     }
 
     pub fn should_skip_slot(&self, slot: u64) -> bool {
+        // after stop_at_slot nothing is buffered anymore
+        if self.stopped {
+            return true;
+        }
         if self.initialized {
             return false;
         }
@@ -252,7 +883,7 @@ impl State {
         if let Some(cursor) = self.cursor {
             return slot <= cursor;
         }
-        return false;
+        false
     }
 
     pub fn set_confirmed_slot(&mut self, slot: u64) {
@@ -261,31 +892,33 @@ impl State {
             return;
         }
         if let Some(cursor) = self.cursor {
-            if self.first_block_to_process.is_none() {
-                if slot >= cursor {
-                    self.first_block_to_process = Some(slot);
-                    debug!("deleting blocks up to: {}", slot - 1);
-                    self.purge_blocks_up_to(slot - 1);
+            if self.first_block_to_process.is_none() && slot >= cursor {
+                self.first_block_to_process = Some(slot);
+                // nothing comes before slot 0
+                if let Some(upto) = slot.checked_sub(1) {
+                    debug!("deleting blocks up to: {}", upto);
+                    self.purge_blocks_up_to(upto);
                 }
             }
         }
-        self.confirmed_slots.insert(slot, true);
+        self.confirmed_slots.insert(slot);
     }
 
     pub fn has_block_info(&self, slot: u64) -> bool {
-        return self.block_infos.get(&slot).is_some();
+        self.block_infos.contains_key(&slot)
     }
 
     pub fn is_ready(&self, slot: u64) -> bool {
-        if self.confirmed_slots.get(&slot).is_none() {
+        if !self.confirmed_slots.contains(&slot) {
             return false;
         }
         match self.block_infos.get(&slot) {
-            None => return false,
+            None => false,
+            Some(_) if self.without_transactions => true,
             Some(blk) => {
                 if let Some(trxs) = self.transactions.get(&slot) {
                     if blk.transaction_count == trxs.len() as u64 {
-                        return true;
+                        true
                     } else {
                         debug!(
                             "slot {} has {} transactions, but {} were received, waiting for more",
@@ -294,15 +927,15 @@ impl State {
                             trxs.len()
                         );
                         {
-                            return false;
+                            false
                         }
-                    };
+                    }
                 } else {
                     debug!(
                         "slot {} has no transactions, but is confirmed, waiting for transactions",
                         slot
                     );
-                    return false;
+                    false
                 }
             }
         }
@@ -311,24 +944,30 @@ impl State {
     pub fn set_block_info(&mut self, block_info: BlockInfo) {
         let slot = block_info.slot;
         if self.lib.is_none() {
-            self.set_last_finalized_block_from_rpc();
+            self.set_last_finalized_block_from_rpc(slot);
         }
         if self.first_received_blockmeta.is_none() {
             self.first_received_blockmeta = Some(slot);
             if self.cursor.is_none() {
                 debug!("setting first_block_to_process to: {}", slot);
                 self.first_block_to_process = Some(slot);
-                debug!("deleting blocks up to: {}", slot - 1);
-                self.purge_blocks_up_to(slot - 1);
+                // nothing comes before slot 0
+                if let Some(upto) = slot.checked_sub(1) {
+                    debug!("deleting blocks up to: {}", upto);
+                    self.purge_blocks_up_to(upto);
+                }
             }
         }
         debug!(
             "setting block info for slot {}, hash {}",
             slot, block_info.block_hash
         );
-        self.block_infos.insert(slot, block_info);
+        if self.block_infos.insert(slot, block_info).is_none() {
+            self.enforce_buffer_cap(slot);
+        }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn set_account(
         &mut self,
         slot: u64,
@@ -336,13 +975,36 @@ impl State {
         data: &[u8],
         owner: &[u8],
         write_version: u64,
-        deleted: bool,
+        lamports: u64,
+        rent_epoch: u64,
+        txn_signature: Option<&[u8]>,
         is_startup: bool,
         data_hash: u64,
         trace: bool,
     ) {
+        let deleted = is_deleted(self.deletion_detection, lamports, data, owner);
         if is_startup {
             self.account_data_hash.insert(pub_key.to_vec(), data_hash);
+            self.record_owner(pub_key, owner);
+            if let Some(snapshot) = self.startup_snapshot.as_mut() {
+                snapshot.push(Account {
+                    address: pub_key.to_vec(),
+                    data: data.to_vec(),
+                    owner: owner.to_vec(),
+                    deleted,
+                    truncated: false,
+                    owner_changed: false,
+                    lamports,
+                    rent_epoch,
+                    txn_signature: vec![],
+                    write_version,
+                    data_diff: None,
+                });
+                self.startup_snapshot_slot = self.startup_snapshot_slot.max(slot);
+                if snapshot.len() >= self.startup_snapshot_chunk {
+                    self.print_startup_snapshot();
+                }
+            }
             return;
         }
 
@@ -350,62 +1012,213 @@ impl State {
             debug!("account data for slot {}", slot);
             if self.cursor.is_none() && self.first_block_to_process.is_none() {
                 // without cursor or first_block_to_process, we only keep a few blocks in here... this happens right after is_startup but before we get a confirmed slot
-                debug!("initializing: deleting blocks up to: {}", slot - 1);
-                self.purge_blocks_up_to(slot - 32);
+                // near genesis there is nothing old enough to purge
+                if let Some(upto) = slot.checked_sub(self.retention_slots) {
+                    debug!("initializing: deleting blocks up to: {}", upto);
+                    self.purge_blocks_up_to(upto);
+                }
             }
+            self.block_account_changes
+                .insert(slot, AccountChanges::default());
+            self.enforce_buffer_cap(slot);
         }
 
-        let slot_entries = self
-            .block_account_changes
-            .entry(slot)
-            .or_insert_with(HashMap::new);
+        self.dedup_stats.account_updates += 1;
+        let address_encoding = self.log_address_encoding;
+        let owner_changed = self.owner_changed(pub_key, owner);
+        let slot_entries = self.block_account_changes.entry(slot).or_default();
 
         let address = pub_key.to_vec();
+        // an owner change of an earlier write in the same slot is kept when the write is replaced
+        let mut earlier_owner_changed = false;
         if let Some(prev) = slot_entries.get(&address) {
+            earlier_owner_changed = prev.account.owner_changed;
             if prev.write_version > write_version {
                 if trace {
                     debug!(
-                        "skipping slot because older version: {}, pub_key: {:?}, owner: {:?}, write_version: {}, prev_write_version: {}, deleted: {}, data_hash: {}",
-                        slot, hex::encode(pub_key), hex::encode(owner), write_version, prev.write_version, deleted, data_hash
+                        "skipping slot because older version: {}, pub_key: {}, owner: {}, write_version: {}, prev_write_version: {}, deleted: {}, data_hash: {}",
+                        slot, encode_address(address_encoding, pub_key), encode_address(address_encoding, owner), write_version, prev.write_version, deleted, data_hash
                     );
                 }
+                self.dedup_stats.older_write_version += 1;
                 return; // skipping older write_versions
             }
+            // equal write versions are seen on some validator builds after a restart, the first record is kept by default
+            if prev.write_version == write_version {
+                warn!(
+                    "account {} written twice with write_version {} in slot {}, keeping the {} record",
+                    encode_address(address_encoding, pub_key),
+                    write_version,
+                    slot,
+                    if self.prefer_later_equal_write_version { "later" } else { "earlier" }
+                );
+                if !self.prefer_later_equal_write_version {
+                    return;
+                }
+            }
             // skip if the data is the same and the account is not deleted
-            if !deleted {
+            if !deleted && self.dedup_accounts && !owner_changed {
                 if let Some(h) = self.account_data_hash.get(&address) {
                     if *h == data_hash {
+                        self.dedup_stats.dedup_hits += 1;
                         return; // skipping same data
                     }
                 }
             }
         }
 
+        // Accounts skipped because of their size do not set account_data_hash, so it is not poisoned by data the
+        // consumers never saw. Deletions always go through.
+        // A truncated account was sent, its hash is the one of the original data.
+        if !deleted {
+            let too_small = self
+                .min_account_data_bytes
+                .is_some_and(|min| data.len() < min);
+            let too_big = self
+                .max_account_data_bytes
+                .is_some_and(|max| data.len() > max)
+                && self.oversized_accounts == OversizedAccountAction::Drop;
+            if too_small || too_big {
+                if trace {
+                    debug!(
+                        "skipping account because of its size slot: {}, pub_key: {}, data_len: {}",
+                        slot,
+                        encode_address(address_encoding, pub_key),
+                        data.len()
+                    );
+                }
+                // an older version of this account in the same slot would not be the final state anymore. Its hash
+                // replaced the one of the data sent before, which is not known anymore: the next write is not deduped.
+                if slot_entries.remove(&address).is_some() {
+                    self.account_data_hash.remove(&address);
+                }
+                return;
+            }
+        }
+        let truncated = !deleted
+            && self
+                .max_account_data_bytes
+                .is_some_and(|max| data.len() > max)
+            && self.oversized_accounts == OversizedAccountAction::Truncate;
+
         let pb_account = Account {
             address: pub_key.to_vec(),
-            data: data.to_vec(),
+            data: if truncated { vec![] } else { data.to_vec() },
             owner: owner.to_vec(),
             deleted,
+            truncated,
+            owner_changed: owner_changed || earlier_owner_changed,
+            lamports,
+            rent_epoch,
+            txn_signature: txn_signature
+                .filter(|_| self.account_txn_signature)
+                .map(<[u8]>::to_vec)
+                .unwrap_or_default(),
+            write_version,
+            data_diff: None,
         };
 
-        let awv = AccountWithWriteVersion {
+        let mut awv = AccountWithWriteVersion {
             account: pb_account,
             write_version,
+            spilled: None,
+            shared: None,
         };
+        if self.compact_account_changes {
+            awv.shared = self.shared_account_data(pub_key, &awv.account.data);
+            if awv.shared.is_some() {
+                awv.account.data = vec![];
+            }
+        }
+        if let Some(spill_files) = self.spill_files.as_mut() {
+            if !awv.account.data.is_empty() {
+                match spill_files.write(slot, &awv.account.data) {
+                    Ok(spilled) => {
+                        awv.account.data = vec![];
+                        awv.spilled = Some(spilled);
+                    }
+                    Err(err) => warn!(
+                        "cannot spill account data of slot {}, keeping it in memory: {}",
+                        slot, err
+                    ),
+                }
+            }
+        }
 
         if trace {
             debug!(
-                "inserting slot: {}, pub_key: {:?}, owner: {:?}, write_version: {}, deleted: {}, data_hash: {}",
-                slot, hex::encode(pub_key), hex::encode(owner), write_version, deleted, data_hash
+                "inserting slot: {}, pub_key: {}, owner: {}, write_version: {}, deleted: {}, data_hash: {}",
+                slot, encode_address(address_encoding, pub_key), encode_address(address_encoding, owner), write_version, deleted, data_hash
             );
         }
 
         self.account_data_hash.insert(pub_key.to_vec(), data_hash);
-        slot_entries.insert(address, awv);
+        self.block_account_changes
+            .entry(slot)
+            .or_default()
+            .insert(address, awv);
+        self.dedup_stats.emitted += 1;
+        self.record_owner(pub_key, owner);
+        if self.compact_account_changes {
+            self.account_latest_slot.insert(pub_key.to_vec(), slot);
+        }
+    }
+
+    // owner_changed tells whether `owner` differs from the owner of the last write of the account
+    fn owner_changed(&self, pub_key: &[u8], owner: &[u8]) -> bool {
+        if !self.detect_owner_changes {
+            return false;
+        }
+        match self.account_owners.get(pub_key) {
+            None => false,
+            Some(previous) => self.owners.get(owner) != Some(previous),
+        }
+    }
+
+    // record_owner keeps the owner of the last write of the account. The owners are stored once,
+    // each account only keeps the index of its owner.
+    fn record_owner(&mut self, pub_key: &[u8], owner: &[u8]) {
+        if !self.detect_owner_changes {
+            return;
+        }
+        let next = self.owners.len() as u32;
+        let owner = *self.owners.entry(owner.to_vec()).or_insert(next);
+        match self.account_owners.get_mut(pub_key) {
+            Some(previous) => *previous = owner,
+            None => {
+                self.account_owners.insert(pub_key.to_vec(), owner);
+            }
+        }
+    }
+
+    // shared_account_data returns the data of the latest pending write of the account when it is the same, so
+    // both writes hold a single copy. Every slot keeps its own write and gets the data back when it is sent.
+    // The data of a spilled write is not compared.
+    fn shared_account_data(&mut self, pub_key: &[u8], data: &[u8]) -> Option<Arc<Vec<u8>>> {
+        if data.is_empty() {
+            return None;
+        }
+        let latest = *self.account_latest_slot.get(pub_key)?;
+        let change = self
+            .block_account_changes
+            .get_mut(&latest)?
+            .get_mut(pub_key)?;
+        if let Some(shared) = &change.shared {
+            return (shared.as_slice() == data).then(|| shared.clone());
+        }
+        if change.spilled.is_some() || change.account.data != data {
+            return None;
+        }
+        let shared = Arc::new(std::mem::take(&mut change.account.data));
+        change.shared = Some(shared.clone());
+        Some(shared)
     }
 
     pub fn set_transaction(&mut self, slot: u64, transaction: ConfirmTransactionWithIndex) {
-        if self.processed_slots.get(&slot).is_some() {
+        if self.stopped {
+            return;
+        }
+        if self.processed_slots.contains_key(&slot) {
             error!(
                 "slot {} already processed should not receive transaction for it",
                 slot
@@ -416,41 +1229,119 @@ impl State {
             txs.push(transaction);
         } else {
             debug!("inserting first transaction for slot {}", slot);
-            let mut txs = Vec::new();
-            txs.push(transaction);
-            self.transactions.insert(slot, txs);
+            self.transactions.insert(slot, vec![transaction]);
+            self.enforce_buffer_cap(slot);
         }
     }
 
-    fn purge_blocks_up_to(&mut self, upto: u64) {
-        let blocks = self
+    // enforce_buffer_cap evicts the oldest buffered slots when there are more than max_buffered_slots,
+    // so that slots that are never confirmed (dead forks, a stuck validator) cannot grow the buffers without bound.
+    // The confirmed slots are never evicted, nor the slots between the last sent block and the highest confirmed one:
+    // they may still be needed to fill a hole (see add_missing_slots_to_confirmed_slots). `current` is the slot
+    // being written, it is kept too.
+    fn enforce_buffer_cap(&mut self, current: u64) {
+        let Some(max) = self.max_buffered_slots else {
+            return;
+        };
+        let slots: BTreeSet<u64> = self
             .block_account_changes
             .keys()
-            .cloned()
-            .collect::<Vec<u64>>();
-        for block in blocks {
-            if block > upto {
-                continue;
-            }
-            self.block_account_changes.remove(&block);
-            self.block_infos.remove(&block);
+            .chain(self.transactions.keys())
+            .chain(self.block_infos.keys())
+            .copied()
+            .collect();
+        if slots.len() <= max {
+            return;
         }
+        let excess = slots.len() - max;
 
-        let slots = self.confirmed_slots.keys().cloned().collect::<Vec<u64>>();
-        for slot in slots {
-            if slot <= upto {
-                debug!("purging confirmed slot {}", slot);
-                self.confirmed_slots.remove(&slot);
-                if upto > 100 {
-                    let processed_slot_remove = upto - 100;
-                    self.processed_slots.remove(&processed_slot_remove);
-                }
-            }
-        }
+        let last_sent = self.last_sent_block.unwrap_or_default();
+        let highest_confirmed = self.confirmed_slots.last().copied();
+        let evicted: Vec<u64> = slots
+            .into_iter()
+            .filter(|slot| {
+                *slot != current
+                    && !self.confirmed_slots.contains(slot)
+                    && !highest_confirmed.is_some_and(|upto| *slot > last_sent && *slot <= upto)
+            })
+            .take(excess)
+            .collect();
+        for slot in &evicted {
+            warn!(
+                "more than {} slots buffered, evicting unconfirmed slot {}: its accounts and transactions are lost",
+                max, slot
+            );
+            self.evict_slot(*slot);
+        }
+        if evicted.len() < excess {
+            warn!(
+                "more than {} slots buffered but only {} could be evicted, the others are confirmed and waiting to be sent",
+                max,
+                evicted.len()
+            );
+        }
+    }
+
+    fn evict_slot(&mut self, slot: u64) {
+        self.remove_spill_file(slot);
+        if let Some(changes) = self.block_account_changes.remove(&slot) {
+            for address in changes.keys() {
+                if self.account_latest_slot.get(address) == Some(&slot) {
+                    self.account_latest_slot.remove(address);
+                }
+            }
+        }
+        self.transactions.remove(&slot);
+        self.block_infos.remove(&slot);
+        self.block_times.remove(&slot);
+        self.missing_block_times.remove(&slot);
+        self.evicted_slots += 1;
+    }
+
+    fn purge_blocks_up_to(&mut self, upto: u64) {
+        let blocks = self
+            .block_account_changes
+            .keys()
+            .cloned()
+            .collect::<Vec<u64>>();
+        for block in blocks {
+            if block > upto {
+                continue;
+            }
+            self.remove_spill_file(block);
+            if let Some(changes) = self.block_account_changes.remove(&block) {
+                if self.compact_account_changes {
+                    for address in changes.keys() {
+                        if self.account_latest_slot.get(address) == Some(&block) {
+                            self.account_latest_slot.remove(address);
+                        }
+                    }
+                }
+            }
+            self.block_infos.remove(&block);
+        }
+        self.block_times.retain(|slot, _| *slot > upto);
+        self.missing_block_times.retain(|slot| *slot > upto);
+
+        let slots: Vec<u64> = self.confirmed_slots.range(..=upto).copied().collect();
+        for slot in &slots {
+            debug!("purging confirmed slot {}", slot);
+            self.confirmed_slots.remove(slot);
+        }
+        if !slots.is_empty() {
+            if let Some(oldest) = upto.checked_sub(self.processed_retention_slots) {
+                self.processed_slots.retain(|slot, _| *slot > oldest);
+            }
+        }
     }
 
-    pub fn process_upto(&mut self, slot: u64) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn process_upto(&mut self, slot: u64) -> Result<(), ProcessError> {
         debug!("processing upto slot {}", slot);
+        if self.stopped {
+            debug!("stop_at_slot was reached, not processing slot {}", slot);
+            return Ok(());
+        }
+        self.apply_refreshed_lib();
         let first_block_to_process = match self.first_block_to_process {
             Some(slot) => slot,
             None => {
@@ -486,6 +1377,8 @@ impl State {
             self.initialized = true;
         }
 
+        // the first slot without block info, with the error of its fetch
+        let mut missing_block_info: Option<(u64, ProcessError)> = None;
         for slot in self.ordered_confirmed_slots_upto(slot) {
             if slot < first_block_to_process {
                 debug!(
@@ -495,16 +1388,47 @@ impl State {
                 continue;
             }
 
+            let mut fetch_error = None;
+            if !self.block_infos.contains_key(&slot) {
+                info!(
+                    "No block info for slot {} in process_upto, fetching it from rpc",
+                    slot
+                );
+                fetch_error = self.cache_block_from_rpc(slot).err();
+            }
+            self.fill_missing_block_time(slot);
+            // a later slot that does not descend from this one can still be sent, one that does
+            // stops on the parent check below
             let block_info = match self.block_infos.get(&slot) {
                 None => {
-                    info!("No block info for slot {} in process_upto", slot);
-                    return Ok(());
+                    info!(
+                        "No block info for slot {} in process_upto, skipping it",
+                        slot
+                    );
+                    missing_block_info.get_or_insert((
+                        slot,
+                        fetch_error.unwrap_or(ProcessError::MissingBlockInfo(slot)),
+                    ));
+                    continue;
                 }
                 Some(bi) => bi,
             };
 
             if let Some(last_sent_block) = self.last_sent_block {
-                if last_sent_block < block_info.parent_slot {
+                // the slots skipped by the cluster are not a hole, the parent of the block is the last sent one
+                // a block is never sent before its parent. Within max_natural_gap, an ancestor geyser did not
+                // give yet is waited for rather than fetched from the rpc.
+                let gap = block_info.parent_slot.saturating_sub(last_sent_block);
+                let unknown_ancestor = self.unknown_ancestor(last_sent_block, slot);
+                if let Some(ancestor) = unknown_ancestor.filter(|_| gap <= self.max_natural_gap) {
+                    info!(
+                        "slot {} waits for its ancestor {}, the last sent block is {} (max_natural_gap: {})",
+                        slot, ancestor, last_sent_block, self.max_natural_gap
+                    );
+                    missing_block_info
+                        .get_or_insert((ancestor, ProcessError::MissingBlockInfo(ancestor)));
+                    break;
+                } else if last_sent_block < block_info.parent_slot {
                     warn!(
                             "last sent block {} is not the parent of slot {}. Expecting {}. (This is a very rare case that would create a hole). Manually adding missing slots to 'confirmed_slots', they will be sent on next loop",
                             last_sent_block,
@@ -520,45 +1444,503 @@ impl State {
                 }
             }
 
+            // the slots between the parent and this block were skipped by the cluster: the parent link says so,
+            // a slot this validator missed is a hole above, filled with the real block from the rpc
+            if self.emit_skipped_slots {
+                if let Some(last_sent_block) = self.last_sent_block {
+                    // the parent was sent, each placeholder is chained to the block sent just before it
+                    let mut parent_slot = last_sent_block;
+                    let mut parent_hash = if last_sent_block == block_info.parent_slot {
+                        block_info.parent_hash.clone()
+                    } else {
+                        self.last_sent_block_hash.clone().unwrap_or_default()
+                    };
+                    for skipped in last_sent_block + 1..slot {
+                        let skipped_info =
+                            skipped_slot_info(skipped, parent_slot, parent_hash, block_info);
+                        let mut block =
+                            compose_and_purge_block(skipped, &skipped_info, vec![], false);
+                        block.skipped = true;
+                        block.live = skipped >= first_received_blockmeta;
+                        let acc_block = create_account_block(
+                            &AccountChanges::default(),
+                            &skipped_info,
+                            &self.watch_accounts,
+                            self.log_address_encoding,
+                            self.account_sort,
+                            self.account_block_mode,
+                        );
+                        debug!("sending placeholder of skipped slot {}", skipped);
+                        if let Err(err) =
+                            self.sink
+                                .print_placeholder(&skipped_info, lib, block, acc_block)
+                        {
+                            return Err(ProcessError::PrinterFailed {
+                                slot: skipped,
+                                source: err,
+                            });
+                        }
+                        parent_slot = skipped;
+                        parent_hash = skipped_info.block_hash;
+                    }
+                }
+            }
+
+            if let Err(err) = unspill_account_changes(
+                &mut self.spill_files,
+                &mut self.block_account_changes,
+                slot,
+            ) {
+                return Err(ProcessError::SpillFailed { slot, source: err });
+            }
             let account_changes = self.get_account_changes(slot);
-            let acc_block = create_account_block(
+            let mut acc_block = create_account_block(
                 account_changes.unwrap_or(&AccountChanges::default()),
-                &block_info,
+                block_info,
+                &self.watch_accounts,
+                self.log_address_encoding,
+                self.account_sort,
+                self.account_block_mode,
             );
+            // the diffs are made when the slot is sent: the consumers only know the data of the sent slots
+            if let Some(cache) = self.account_data_cache.as_mut() {
+                if self.sink.diff_base_lost() {
+                    info!("a reader may not have the data last sent, the accounts of slot {} are not diffed", slot);
+                    cache.clear();
+                }
+                for account in acc_block.accounts.iter_mut() {
+                    cache.diff_account(account);
+                }
+            }
+
+            let mut transactions_with_index = self.transactions.remove(&slot).unwrap_or_default();
+
+            let (missing, duplicated) = index_gaps(&transactions_with_index);
+            if !missing.is_empty() || !duplicated.is_empty() {
+                self.transaction_index_gaps += 1;
+                warn!(
+                    "slot {} has {} transactions whose indices are not contiguous, missing {:?}, duplicated {:?} ({} blocks with gaps so far)",
+                    slot,
+                    transactions_with_index.len(),
+                    missing,
+                    duplicated,
+                    self.transaction_index_gaps
+                );
+            }
 
-            let mut transactions_with_index =
-                self.transactions.remove(&slot).unwrap_or_else(|| vec![]);
+            sort_transactions(&mut transactions_with_index, self.transaction_order);
+
+            let mut block = compose_and_purge_block(
+                slot,
+                block_info,
+                transactions_with_index,
+                !self.without_block_rewards,
+            );
+            // the blocks before the first block meta geyser gave were produced before the plugin started
+            block.live = slot >= first_received_blockmeta;
 
-            transactions_with_index.sort_by_key(|ti| ti.index);
+            let sent_slot = (self.replay_buffer_slots > 0).then(|| SentSlot {
+                block_info: block_info.clone(),
+                lib,
+                block: block.clone(),
+                account_block: acc_block.clone(),
+            });
 
-            let block = compose_and_purge_block(slot, &block_info, transactions_with_index);
+            if self.cursor_self_test && self.last_sent_block.is_none() {
+                self.cursor_self_test_passed =
+                    Some(check_first_sent_slot(self.cursor, self.lib, block_info));
+            }
 
-            let printer = &mut self.block_printer;
-            let result = printer.print(&block_info, lib, block, acc_block, &self.cursor_path);
-            if !result.is_ok() {
+            let printer = &mut self.sink;
+            if let Err(err) = printer.print(block_info, lib, block, acc_block, &self.cursor_path) {
                 info!("Error printing block at {}", slot);
-                return Err("Error printing block".into());
+                return Err(ProcessError::PrinterFailed { slot, source: err });
+            }
+            if let Some(sent_slot) = sent_slot {
+                if self.sent_slots.len() >= self.replay_buffer_slots {
+                    self.sent_slots.pop_front();
+                }
+                self.sent_slots.push_back(sent_slot);
             }
             self.last_sent_block = Some(block_info.slot);
+            self.last_sent_block_hash = Some(block_info.block_hash.clone());
+            if let Some(sent_block_hashes) = self.sent_block_hashes.as_mut() {
+                sent_block_hashes.insert(slot, block_info.block_hash.clone());
+            }
             self.purge_blocks_up_to(slot);
             self.processed_slots.insert(slot, true);
 
-            if BLOCK_MUTEX.is_poisoned() || ACC_MUTEX.is_poisoned() {
-                return Err("mutex poisoned".into());
+            if self.stop_at_slot.is_some_and(|stop| slot >= stop) {
+                info!(
+                    "stop_at_slot {} reached with slot {}, nothing more will be sent",
+                    self.stop_at_slot.unwrap_or_default(),
+                    slot
+                );
+                self.stopped = true;
+                self.sink.flush_cursor(&self.cursor_path);
+                return Ok(());
+            }
+
+            self.processed_count += 1;
+            if self
+                .processed_count
+                .is_multiple_of(BUFFERED_STATS_LOG_INTERVAL)
+            {
+                let stats = self.buffered_stats();
+                info!(
+                    "buffered at slot {}: block_account_changes: {}, block_infos: {}, transactions: {}, confirmed_slots: {}, account_data_bytes: {}, evicted_slots: {}",
+                    slot,
+                    stats.block_account_changes,
+                    stats.block_infos,
+                    stats.transactions,
+                    stats.confirmed_slots,
+                    stats.account_data_bytes,
+                    stats.evicted_slots
+                );
+                let dedup = self.dedup_stats;
+                info!(
+                    "account dedup: {} of {} updates skipped ({:.1}%), {} older write versions skipped, {} emitted",
+                    dedup.dedup_hits,
+                    dedup.account_updates,
+                    dedup.hit_ratio() * 100.0,
+                    dedup.older_write_version,
+                    dedup.emitted
+                );
+            }
+
+            if self.sink.outputs_poisoned() {
+                return Err(ProcessError::MutexPoisoned);
             }
         }
-        return Ok(());
+
+        // the missing slot was purged if a later slot was sent without it: that slot does not descend from it,
+        // the ones that do wait for it
+        match missing_block_info {
+            Some((missing, err)) if self.confirmed_slots.contains(&missing) => Err(err),
+            Some((missing, _)) if missing < self.last_sent_block.unwrap_or_default() => {
+                warn!(
+                    "slot {} was dropped without its block info, the blocks sent after it are on another fork",
+                    missing
+                );
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // replay writes the sent slots from `from_slot` to `to_slot` once more, as they were sent, without moving the cursor.
+    // Only the last replay_buffer_slots sent slots are kept, the others cannot be replayed. Returns the number of slots written.
+    pub fn replay(&mut self, from_slot: u64, to_slot: u64) -> std::io::Result<usize> {
+        let oldest = self.sent_slots.front().map(|sent| sent.block_info.slot);
+        let newest = self.sent_slots.back().map(|sent| sent.block_info.slot);
+        match oldest {
+            None => warn!(
+                "cannot replay slots {} to {}: no sent slot is kept (replay_buffer_slots: {})",
+                from_slot, to_slot, self.replay_buffer_slots
+            ),
+            Some(oldest) if from_slot < oldest => warn!(
+                "cannot replay slots {} to {}: they are not kept anymore",
+                from_slot,
+                (oldest - 1).min(to_slot)
+            ),
+            _ => {}
+        }
+        if let Some(newest) = newest.filter(|newest| to_slot > *newest) {
+            warn!(
+                "cannot replay slots {} to {}: they were not sent yet",
+                (newest + 1).max(from_slot),
+                to_slot
+            );
+        }
+
+        let mut replayed = 0;
+        for sent in self
+            .sent_slots
+            .iter()
+            .filter(|sent| (from_slot..=to_slot).contains(&sent.block_info.slot))
+        {
+            self.sink.replay(
+                &sent.block_info,
+                sent.lib,
+                sent.block.clone(),
+                sent.account_block.clone(),
+            )?;
+            replayed += 1;
+        }
+        // the replayed slots carry the full data of their accounts, the readers now hold it instead of the data last sent
+        if replayed > 0 {
+            if let Some(cache) = self.account_data_cache.as_mut() {
+                cache.clear();
+            }
+        }
+        info!(
+            "replayed {} slots between {} and {}",
+            replayed, from_slot, to_slot
+        );
+        Ok(replayed)
+    }
+
+    // print_startup_snapshot writes the startup accounts buffered so far
+    fn print_startup_snapshot(&mut self) {
+        let Some(snapshot) = self.startup_snapshot.as_mut() else {
+            return;
+        };
+        if snapshot.is_empty() {
+            return;
+        }
+        let accounts = std::mem::take(snapshot);
+        let count = accounts.len();
+        match self
+            .sink
+            .print_snapshot(self.startup_snapshot_slot, accounts)
+        {
+            Ok(()) => debug!(
+                "printed {} startup accounts at slot {}",
+                count, self.startup_snapshot_slot
+            ),
+            Err(err) => error!(
+                "cannot print {} startup accounts at slot {}: {}",
+                count, self.startup_snapshot_slot, err
+            ),
+        }
+    }
+
+    // end_of_startup writes the remaining startup accounts, no more are buffered afterwards
+    pub fn end_of_startup(&mut self) {
+        self.print_startup_snapshot();
+        self.startup_snapshot = None;
+    }
+
+    // confirm_cursor lets the cursor move over the written slots up to `slot`, once it is confirmed
+    pub fn confirm_cursor(&self, slot: u64) {
+        self.sink.confirm_slot(slot, &self.cursor_path);
+    }
+
+    pub fn flush_cursor(&self) {
+        self.sink.flush_cursor(&self.cursor_path);
     }
 
     pub fn get_hash_count(&self) -> usize {
         self.account_data_hash.len()
     }
+
+    // number of slots waiting in each buffer, to see where the pipeline backs up
+    pub fn dedup_stats(&self) -> DedupStats {
+        self.dedup_stats
+    }
+
+    pub fn buffered_stats(&self) -> BufferedStats {
+        BufferedStats {
+            block_account_changes: self.block_account_changes.len(),
+            block_infos: self.block_infos.len(),
+            transactions: self.transactions.len(),
+            confirmed_slots: self.confirmed_slots.len(),
+            account_data_bytes: self.account_data_bytes(),
+            evicted_slots: self.evicted_slots,
+        }
+    }
+
+    // account_data_bytes is the account data held in memory, the data shared by several writes is counted once
+    fn account_data_bytes(&self) -> usize {
+        let mut shared = HashSet::new();
+        self.block_account_changes
+            .values()
+            .flat_map(|changes| changes.values())
+            .map(|change| match &change.shared {
+                Some(data) if shared.insert(Arc::as_ptr(data)) => data.len(),
+                Some(_) => 0,
+                None => change.account.data.len(),
+            })
+            .sum()
+    }
+
+    // cursor_self_test_passed tells whether the first slot sent after the restart followed the cursor,
+    // None until it is sent or without cursor_self_test
+    pub fn cursor_self_test_passed(&self) -> Option<bool> {
+        self.cursor_self_test_passed
+    }
+
+    // outputs_poisoned tells whether a writer of the sink panicked, see BlockSink::outputs_poisoned
+    pub fn outputs_poisoned(&self) -> bool {
+        self.sink.outputs_poisoned()
+    }
+
+    // lag is the number of slots the last sent block is behind the lib, 0 when it is ahead of it.
+    // None until both are known.
+    pub fn lag(&self) -> Option<u64> {
+        Some(self.lib?.saturating_sub(self.last_sent_block?))
+    }
+}
+
+fn block_info_from_rpc(slot: u64, block: UiConfirmedBlock) -> BlockInfo {
+    BlockInfo {
+        timestamp: convert_sol_timestamp(block.block_time.unwrap_or_default()),
+        parent_slot: block.parent_slot,
+        slot,
+        block_hash: block.blockhash,
+        parent_hash: block.previous_blockhash,
+        height: block.block_height,
+        rewards: to_block_rewards(&block.rewards),
+        num_reward_partitions: block.num_reward_partitions,
+        transaction_count: block.transactions.unwrap_or_default().len() as u64,
+    }
+}
+
+// is_deleted tells whether an account write removes the account. An account can be left without lamports
+// for a moment with its data still there, Strict only trusts a write that also cleared the data and the owner.
+fn is_deleted(detection: DeletionDetection, lamports: u64, data: &[u8], owner: &[u8]) -> bool {
+    match detection {
+        DeletionDetection::LamportsOnly => lamports == 0,
+        DeletionDetection::Strict => {
+            lamports == 0 && data.is_empty() && owner == system_program::ID.as_ref()
+        }
+    }
+}
+
+// unspill_account_changes reads the spilled data of the slot back into its account changes, and removes its file
+fn unspill_account_changes(
+    spill_files: &mut Option<SpillFiles>,
+    block_account_changes: &mut BlockAccountChanges,
+    slot: u64,
+) -> std::io::Result<()> {
+    let Some(spill_files) = spill_files.as_mut() else {
+        return Ok(());
+    };
+    if let Some(changes) = block_account_changes.get_mut(&slot) {
+        for change in changes.values_mut() {
+            if let Some(spilled) = change.spilled {
+                change.account.data = spill_files.read(slot, spilled)?;
+                change.spilled = None;
+            }
+        }
+    }
+    spill_files.remove(slot);
+    Ok(())
+}
+
+// fetch_blocks_from_rpc runs the getBlock requests concurrently (at most MAX_CONCURRENT_RPC_FETCHES at a time)
+// on the runtime of the rpc client. Slots that could not be fetched are returned with None.
+fn fetch_blocks_from_rpc(
+    rpc_client: &RpcClient,
+    slots: &[u64],
+    config: RpcBlockConfig,
+) -> Vec<(u64, Option<UiConfirmedBlock>)> {
+    let runtime = rpc_client.runtime();
+    let mut results = Vec::with_capacity(slots.len());
+    for chunk in slots.chunks(MAX_CONCURRENT_RPC_FETCHES) {
+        let handles: Vec<_> = chunk
+            .iter()
+            .map(|&slot| {
+                let client = rpc_client.get_inner_client().clone();
+                let handle = runtime
+                    .spawn(async move { client.get_block_with_config(slot, config).await.ok() });
+                (slot, handle)
+            })
+            .collect();
+
+        tokio::task::block_in_place(|| {
+            runtime.block_on(async {
+                for (slot, handle) in handles {
+                    results.push((slot, handle.await.ok().flatten()));
+                }
+            })
+        });
+    }
+    results
+}
+
+fn first_signature(transaction: &ConfirmTransactionWithIndex) -> &[u8] {
+    transaction
+        .transaction
+        .transaction
+        .as_ref()
+        .and_then(|trx| trx.signatures.first())
+        .map(|sig| sig.as_slice())
+        .unwrap_or_default()
+}
+
+// sort_transactions gives a deterministic order, even when geyser reports the same index twice
+fn sort_transactions(transactions: &mut [ConfirmTransactionWithIndex], order: TransactionOrder) {
+    match order {
+        TransactionOrder::GeyserIndex => transactions
+            .sort_by(|a, b| (a.index, first_signature(a)).cmp(&(b.index, first_signature(b)))),
+        TransactionOrder::Signature => transactions
+            .sort_by(|a, b| (first_signature(a), a.index).cmp(&(first_signature(b), b.index))),
+    }
+}
+
+// check_first_sent_slot logs whether the first slot sent after a restart resumes from the cursor: it must be its child,
+// unless the cursor was dropped for being behind the LIB. Returns false when the slot does not follow the cursor.
+fn check_first_sent_slot(cursor: Option<u64>, lib: Option<u64>, block_info: &BlockInfo) -> bool {
+    match cursor {
+        Some(cursor) if block_info.parent_slot == cursor => {
+            info!(
+                "cursor self-test passed: the first slot sent {} follows cursor {}",
+                block_info.slot, cursor
+            );
+            true
+        }
+        Some(cursor) => {
+            error!(
+                "cursor self-test FAILED: the first slot sent {} has parent {}, it does not follow cursor {}",
+                block_info.slot, block_info.parent_slot, cursor
+            );
+            false
+        }
+        None => {
+            info!(
+                "cursor self-test: no usable cursor, the first slot sent is {} with LIB {:?}",
+                block_info.slot, lib
+            );
+            true
+        }
+    }
+}
+
+// index_gaps returns the indices of 0..n that no transaction has and the ones reported more than once,
+// both empty when the n transactions of a block have the indices 0..n
+fn index_gaps(transactions: &[ConfirmTransactionWithIndex]) -> (Vec<usize>, Vec<usize>) {
+    let mut counts: BTreeMap<usize, usize> = BTreeMap::new();
+    for ti in transactions {
+        *counts.entry(ti.index).or_default() += 1;
+    }
+    let missing = (0..transactions.len())
+        .filter(|index| !counts.contains_key(index))
+        .collect();
+    let duplicated = counts
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(index, _)| index)
+        .collect();
+    (missing, duplicated)
+}
+
+// skipped_slot_info is the block info of the placeholder of a skipped slot before `next`: its parent is the block
+// sent just before it, it has the time of the next block and a hash of its own, see skipped_block_hash
+fn skipped_slot_info(
+    slot: u64,
+    parent_slot: u64,
+    parent_hash: String,
+    next: &BlockInfo,
+) -> BlockInfo {
+    BlockInfo {
+        slot,
+        parent_slot,
+        block_hash: skipped_block_hash(slot),
+        parent_hash,
+        timestamp: next.timestamp.clone(),
+        height: None,
+        rewards: vec![],
+        num_reward_partitions: None,
+        transaction_count: 0,
+    }
 }
 
-fn compose_and_purge_block(
+pub fn compose_and_purge_block(
     slot: u64,
     block_info: &BlockInfo,
     transactions_with_index: Vec<ConfirmTransactionWithIndex>,
+    with_rewards: bool,
 ) -> Block {
     Block {
         previous_blockhash: block_info.parent_hash.clone(),
@@ -568,26 +1950,72 @@ fn compose_and_purge_block(
             .into_iter()
             .map(|ti| ti.transaction)
             .collect(),
-        rewards: block_info.rewards.clone(), //todo: clone?????
+        rewards: if with_rewards {
+            block_info.rewards.clone() //todo: clone?????
+        } else {
+            vec![]
+        },
         block_time: Some(UnixTimestamp {
             timestamp: block_info.timestamp.seconds,
         }),
         parent_slot: block_info.parent_slot,
-        block_height: match block_info.height {
-            Some(height) => Some(BlockHeight {
-                block_height: height,
-            }),
-            None => None,
-        },
+        block_height: block_info.height.map(|height| BlockHeight {
+            block_height: height,
+        }),
+        num_partitions: block_info
+            .num_reward_partitions
+            .map(|num_partitions| NumPartitions { num_partitions }),
+        skipped: false,
+        expected_transaction_count: block_info.transaction_count,
+        // process_upto knows whether the block was produced before the start
+        live: false,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::block_printer::{BlockPrinter, PrinterOptions};
+    use crate::pb::sf::solana::r#type::v1::AccountBlock;
+    use crate::pb::sf::solana::r#type::v1::{ConfirmedTransaction, Transaction};
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use prost::Message;
+
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+    // answers getBlock for the known slots (slot -> parent_slot), every other slot is reported as skipped
+    struct GetBlockResponder {
+        parents: HashMap<u64, u64>,
+    }
 
-    use wiremock::matchers::{method, path};
-    use wiremock::{Mock, MockServer, ResponseTemplate};
+    impl Respond for GetBlockResponder {
+        fn respond(&self, request: &Request) -> ResponseTemplate {
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            let id = body["id"].clone();
+            let slot = body["params"][0].as_u64().unwrap();
+            match self.parents.get(&slot) {
+                Some(parent) => ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "result": {
+                        "blockhash": format!("hash{}", slot),
+                        "previousBlockhash": format!("hash{}", parent),
+                        "parentSlot": parent,
+                        "blockTime": 1234,
+                        "blockHeight": slot,
+                        "rewards": [],
+                    },
+                    "id": id
+                })),
+                None => ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "error": {"code": -32007, "message": format!("Slot {} was skipped", slot)},
+                    "id": id
+                })),
+            }
+        }
+    }
 
     fn test_block_info(slot: u64, parent_slot: u64) -> BlockInfo {
         BlockInfo {
@@ -601,6 +2029,7 @@ mod tests {
             parent_hash: "parent1".to_string(),
             height: Some(100),
             rewards: vec![],
+            num_reward_partitions: None,
             transaction_count: 0,
         }
     }
@@ -623,11 +2052,12 @@ mod tests {
         // Initialize state with no lib and no first_received_blockmeta
 
         let mut state = State::new(
-            RpcClient::new(test_url.clone()),
-            RpcClient::new(test_url.clone()),
+            Some(RpcClient::new(test_url.clone())),
+            vec![RpcClient::new(test_url.clone())],
             None,
             "test_cursor_file".to_string(),
-            BlockPrinter::new(None, None, false),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
         );
 
         // Test case 1: No lib set yet
@@ -640,11 +2070,12 @@ mod tests {
 
         // Test case 2: With cursor set, lib is before cursor
         let mut state_with_cursor = State::new(
-            RpcClient::new(test_url.clone()),
-            RpcClient::new(test_url.clone()),
+            Some(RpcClient::new(test_url.clone())),
+            vec![RpcClient::new(test_url.clone())],
             Some(110),
             "test_cursor_file".to_string(),
-            BlockPrinter::new(None, None, false),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
         );
 
         state_with_cursor.set_block_info(block_info.clone());
@@ -654,11 +2085,12 @@ mod tests {
 
         // Test case 3: With cursor set, lib is greater than cursor which will get cancelled
         let mut state_with_cursor = State::new(
-            RpcClient::new(test_url.clone()),
-            RpcClient::new(test_url.clone()),
+            Some(RpcClient::new(test_url.clone())),
+            vec![RpcClient::new(test_url.clone())],
             Some(90),
             "test_cursor_file".to_string(),
-            BlockPrinter::new(None, None, false),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
         );
 
         state_with_cursor.set_block_info(block_info.clone());
@@ -677,11 +2109,12 @@ mod tests {
     #[test]
     fn test_add_missing_slots_to_confirmed_slots() {
         let mut state = State::new(
-            RpcClient::new("http://test.local"),
-            RpcClient::new("http://test.remote"),
+            Some(RpcClient::new("http://test.local")),
+            vec![RpcClient::new("http://test.remote")],
             None,
             "test_cursor.txt".to_string(),
-            BlockPrinter::new(None, None, false),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
         );
 
         // Setup initial state
@@ -697,10 +2130,2164 @@ mod tests {
         let result = state.add_missing_slots_to_confirmed_slots(state.last_sent_block.unwrap(), 6);
         assert!(result);
 
-        assert!(state.confirmed_slots.get(&1).is_none()); // was already sent
+        assert!(!state.confirmed_slots.contains(&1)); // was already sent
+
+        assert!(state.confirmed_slots.contains(&2));
+        assert!(state.confirmed_slots.contains(&4));
+        assert!(state.confirmed_slots.contains(&6));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_add_missing_slots_to_confirmed_slots_from_rpc() {
+        let local_server = MockServer::start().await;
+        let remote_server = MockServer::start().await;
+
+        // slot 4 is skipped, the local node only knows about slot 3 and 6, 5 comes from the remote
+        Mock::given(method("POST"))
+            .respond_with(GetBlockResponder {
+                parents: HashMap::from([(3, 2), (6, 5)]),
+            })
+            .mount(&local_server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(GetBlockResponder {
+                parents: HashMap::from([(5, 3)]),
+            })
+            .mount(&remote_server)
+            .await;
+
+        let mut state = State::new(
+            Some(RpcClient::new(local_server.uri())),
+            vec![RpcClient::new(remote_server.uri())],
+            None,
+            "test_cursor.txt".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
+        );
+        state.initialized = true;
+        state.lib = Some(1);
+        state.first_received_blockmeta = Some(2);
+        state.last_sent_block = Some(2);
+        state.block_infos.insert(2, test_block_info(2, 1));
+        state.block_infos.insert(7, test_block_info(7, 6));
+
+        // assume we receive confirmed_slot 7 with parent_slot 6
+        let result = state.add_missing_slots_to_confirmed_slots(2, 6);
+        assert!(result);
+
+        assert_eq!(state.ordered_confirmed_slots_upto(7), vec![3, 5, 6]);
+        assert_eq!(state.block_infos.get(&5).unwrap().parent_slot, 3);
+        assert_eq!(state.block_infos.get(&6).unwrap().block_hash, "hash6");
+        assert!(!state.block_infos.contains_key(&4));
+
+        // every slot of the hole was requested once locally, only the missing ones remotely
+        assert_eq!(local_server.received_requests().await.unwrap().len(), 4);
+        assert_eq!(remote_server.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_add_missing_slots_to_confirmed_slots_broken_chain() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(GetBlockResponder {
+                parents: HashMap::from([(6, 4)]),
+            })
+            .mount(&mock_server)
+            .await;
+
+        let mut state = State::new(
+            Some(RpcClient::new(mock_server.uri())),
+            vec![RpcClient::new(mock_server.uri())],
+            None,
+            "test_cursor.txt".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
+        );
+        state.initialized = true;
+        state.lib = Some(1);
+        state.first_received_blockmeta = Some(2);
+        state.block_infos.insert(2, test_block_info(2, 1));
+
+        // slot 4 cannot be found anywhere: the chain cannot be linked back to 2
+        assert!(!state.add_missing_slots_to_confirmed_slots(2, 6));
+    }
+
+    // the server and the directory of the cursor must outlive the state
+    async fn state_for_missing_block_info(
+        parents: HashMap<u64, u64>,
+    ) -> (State, MockServer, tempfile::TempDir) {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(GetBlockResponder { parents })
+            .mount(&server)
+            .await;
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut state = State::new(
+            Some(RpcClient::new(server.uri())),
+            vec![],
+            None,
+            dir.path().join("cursor").to_str().unwrap().to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
+        );
+        state.lib = Some(1);
+        state.first_received_blockmeta = Some(2);
+        state.first_block_to_process = Some(2);
+        state.last_sent_block = Some(1);
+        (state, server, dir)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_process_upto_missing_block_info_from_rpc() {
+        // the hole at slot 2 is filled by the rpc
+        let (mut state, _server, _dir) =
+            state_for_missing_block_info(HashMap::from([(2, 1)])).await;
+        state.confirmed_slots.insert(2);
+        state.confirmed_slots.insert(3);
+        state.block_infos.insert(3, test_block_info(3, 2));
+
+        assert!(state.process_upto(3).is_ok());
+        assert_eq!(state.last_sent_block, Some(3));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_max_natural_gap() {
+        let (mut state, server, _dir) =
+            state_for_missing_block_info(HashMap::from([(6, 5), (7, 6), (8, 7)])).await;
+        state.max_natural_gap = 1;
+
+        // slot 2 was skipped by the cluster, the parent of slot 3 is the last sent block
+        state.confirmed_slots.insert(3);
+        state.block_infos.insert(3, test_block_info(3, 1));
+        assert!(state.process_upto(3).is_ok());
+        assert_eq!(state.last_sent_block, Some(3));
+
+        // slot 4 was not received yet: within the tolerance slot 5 waits for it instead of fetching it
+        state.confirmed_slots.insert(5);
+        state.block_infos.insert(5, test_block_info(5, 4));
+        assert!(state.process_upto(5).is_ok());
+        assert_eq!(state.last_sent_block, Some(3));
+        assert!(server.received_requests().await.unwrap().is_empty());
+
+        // its block info arrives, both are sent in order without asking the rpc
+        state.block_infos.insert(4, test_block_info(4, 3));
+        assert!(state.process_upto(5).is_ok());
+        assert_eq!(state.last_sent_block, Some(3));
+        assert!(state.process_upto(5).is_ok());
+        assert_eq!(state.last_sent_block, Some(5));
+        assert!(server.received_requests().await.unwrap().is_empty());
+
+        // slots 6 to 8 are a genuine hole, filled from the rpc before slot 9 is sent
+        state.confirmed_slots.insert(9);
+        state.block_infos.insert(9, test_block_info(9, 8));
+        assert!(state.process_upto(9).is_ok());
+        assert_eq!(state.last_sent_block, Some(5));
+        assert!(state.process_upto(9).is_ok());
+        assert_eq!(state.last_sent_block, Some(9));
+        assert!(!server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_max_natural_gap_late_block_info() {
+        let sink = crate::sink::MemorySink::default();
+        let (state, _server, _dir) = state_for_missing_block_info(HashMap::new()).await;
+        let mut state = State {
+            sink: Box::new(sink.clone()),
+            max_natural_gap: 5,
+            emit_skipped_slots: true,
+            ..state
+        };
+        // slot 2 is confirmed but its block info comes late, the rpc does not have it yet
+        state.confirmed_slots.insert(2);
+        state.confirmed_slots.insert(3);
+        state.block_infos.insert(3, test_block_info(3, 2));
+        match state.process_upto(3) {
+            Err(ProcessError::MissingBlockInfo(slot)) => assert_eq!(slot, 2),
+            other => panic!("expected MissingBlockInfo, got {:?}", other),
+        }
+        assert!(sink.slots().is_empty());
+
+        state.block_infos.insert(2, test_block_info(2, 1));
+        assert!(state.process_upto(3).is_ok());
+        let sent: Vec<(u64, u64, bool)> = sink
+            .slots()
+            .iter()
+            .map(|sent| (sent.block.slot, sent.block.parent_slot, sent.block.skipped))
+            .collect();
+        assert_eq!(sent, vec![(2, 1, false), (3, 2, false)]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_process_upto_missing_block_info() {
+        let (mut state, _server, _dir) = state_for_missing_block_info(HashMap::new()).await;
+        state.confirmed_slots.insert(2);
+        state.confirmed_slots.insert(3);
+        state.block_infos.insert(3, test_block_info(3, 2));
+
+        // slot 3 descends from the missing slot 2, it cannot be sent
+        match state.process_upto(3) {
+            Err(ProcessError::MissingBlockInfo(slot)) => assert_eq!(slot, 2),
+            other => panic!("expected MissingBlockInfo, got {:?}", other),
+        }
+        assert_eq!(state.last_sent_block, Some(1));
+
+        // once the block info arrives, both slots go through
+        state.block_infos.insert(2, test_block_info(2, 1));
+        assert!(state.process_upto(3).is_ok());
+        assert_eq!(state.last_sent_block, Some(3));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_process_upto_missing_block_info_not_in_chain() {
+        let (mut state, _server, _dir) = state_for_missing_block_info(HashMap::new()).await;
+        state.confirmed_slots.insert(2);
+        state.confirmed_slots.insert(3);
+        // slot 3 does not descend from slot 2, it is not stuck behind it
+        state.block_infos.insert(3, test_block_info(3, 1));
+
+        assert!(state.process_upto(3).is_ok());
+        assert_eq!(state.last_sent_block, Some(3));
+        assert!(state.ordered_confirmed_slots_upto(3).is_empty());
+    }
+
+    fn state_with_retention(
+        retention_slots: Option<u64>,
+        processed_retention_slots: Option<u64>,
+    ) -> State {
+        State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions {
+                retention_slots,
+                processed_retention_slots,
+                ..StateOptions::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_retention_slots() {
+        // slots near genesis, below the retention window
+        let mut state = state_with_retention(None, None);
+        for slot in 0..10 {
+            set_test_account(&mut state, slot, 1, &[1]);
+        }
+        assert_eq!(state.buffered_stats().block_account_changes, 10);
+        for slot in 10..40 {
+            set_test_account(&mut state, slot, 1, &[1]);
+        }
+        // 39 - 32 = 7 and below are dropped
+        assert_eq!(state.buffered_stats().block_account_changes, 32);
+        assert!(state.get_account_changes(7).is_none());
+        assert!(state.get_account_changes(8).is_some());
+
+        let mut state = state_with_retention(Some(4), None);
+        for slot in 1..=10 {
+            set_test_account(&mut state, slot, 1, &[1]);
+        }
+        let mut kept: Vec<u64> = state.block_account_changes.keys().copied().collect();
+        kept.sort();
+        assert_eq!(kept, vec![7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn test_genesis_slot() {
+        let mut state = state_with_retention(None, None);
+        set_test_account(&mut state, 0, 1, &[1]);
+        state.set_block_info(test_block_info(0, 0));
+        state.set_confirmed_slot(0);
+        // slot 0 is the first block, nothing of it is purged
+        assert_eq!(state.first_block_to_process, Some(0));
+        assert!(state.get_account_changes(0).is_some());
+        assert!(state.has_block_info(0));
+        assert_eq!(state.ordered_confirmed_slots_upto(0), vec![0]);
+
+        set_test_account(&mut state, 1, 1, &[2]);
+        state.set_block_info(test_block_info(1, 0));
+        state.set_confirmed_slot(1);
+        assert_eq!(state.ordered_confirmed_slots_upto(1), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_processed_retention_slots() {
+        let mut state = state_with_retention(None, Some(10));
+        for slot in 1..=200 {
+            state.processed_slots.insert(slot, true);
+        }
+        // nothing to forget yet near genesis
+        state.confirmed_slots.insert(5);
+        state.purge_blocks_up_to(5);
+        assert_eq!(state.processed_slots.len(), 200);
+
+        state.confirmed_slots.insert(150);
+        state.purge_blocks_up_to(150);
+        assert_eq!(state.processed_slots.len(), 60);
+        assert!(!state.processed_slots.contains_key(&140));
+        assert!(state.processed_slots.contains_key(&141));
+    }
+
+    #[test]
+    fn test_ordered_confirmed_slots_upto() {
+        let mut state = State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
+        );
+        // confirmed out of order, some of them twice
+        let slots: Vec<u64> = (0..500).map(|i| 1_000 + (i * 7_919) % 300).collect();
+        for slot in &slots {
+            state.confirmed_slots.insert(*slot);
+        }
+
+        for upto in [0, 1_000, 1_001, 1_150, 1_299, 1_300, 5_000] {
+            // what the slots were before they were kept ordered: every slot up to the bound, sorted
+            let mut expected: Vec<u64> = slots.iter().copied().filter(|&x| x <= upto).collect();
+            expected.sort();
+            expected.dedup();
+            assert_eq!(state.ordered_confirmed_slots_upto(upto), expected);
+        }
+    }
+
+    #[test]
+    fn test_process_without_rpc() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let block_file = tempfile::NamedTempFile::new().unwrap();
+        let mut state = State::new(
+            None,
+            vec![],
+            None,
+            dir.path().join("cursor").to_str().unwrap().to_string(),
+            BlockPrinter::new(
+                Some(block_file.reopen().unwrap()),
+                None,
+                None,
+                PrinterOptions::default(),
+            ),
+            StateOptions::default(),
+        );
+
+        state.set_block_info(test_block_info(100, 99));
+        state.set_confirmed_slot(100);
+        // nothing is sent until a rooted slot gives the lib
+        assert!(state.process_upto(100).is_ok());
+        assert_eq!(state.last_sent_block, None);
+        state.set_lib(98);
+        assert!(state.process_upto(100).is_ok());
+        assert_eq!(state.last_sent_block, Some(100));
+
+        // the hole before slot 103 cannot be filled, the slot waits
+        state.set_block_info(test_block_info(103, 102));
+        state.set_confirmed_slot(103);
+        assert!(state.process_upto(103).is_ok());
+        assert_eq!(state.last_sent_block, Some(100));
+        assert_eq!(state.block_time_from_rpc(103), None);
+
+        // until the missing slots are received
+        state.set_block_info(test_block_info(101, 100));
+        state.set_block_info(test_block_info(102, 101));
+        state.set_confirmed_slot(101);
+        state.set_confirmed_slot(102);
+        assert!(state.process_upto(103).is_ok());
+        assert_eq!(state.last_sent_block, Some(103));
+
+        // the writer threads must be done before the cursor directory is removed
+        let cursor_path = dir.path().join("cursor");
+        let start = std::time::Instant::now();
+        while std::fs::read_to_string(&cursor_path).unwrap_or_default() != "103:hash1" {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5));
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    // answers getBlock with the confirmed hash, or the finalized one when it is asked (an error when not finalized)
+    struct ForkResponder {
+        confirmed_hash: &'static str,
+        finalized_hash: Option<&'static str>,
+    }
+
+    impl Respond for ForkResponder {
+        fn respond(&self, request: &Request) -> ResponseTemplate {
+            let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+            let id = body["id"].clone();
+            let block_hash = match body["params"][1]["commitment"].as_str() {
+                Some("finalized") => self.finalized_hash,
+                _ => Some(self.confirmed_hash),
+            };
+            match block_hash {
+                Some(block_hash) => ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "result": {
+                        "blockhash": block_hash,
+                        "previousBlockhash": "parent",
+                        "parentSlot": 9,
+                        "blockTime": 1234,
+                        "blockHeight": 10,
+                        "rewards": [],
+                    },
+                    "id": id
+                })),
+                None => ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "error": {"code": -32004, "message": "Block not available for slot 10"},
+                    "id": id
+                })),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rpc_cross_check() {
+        // the local rpc is on an abandoned fork
+        let local_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ForkResponder {
+                confirmed_hash: "forked",
+                finalized_hash: None,
+            })
+            .mount(&local_server)
+            .await;
+        let remote_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ForkResponder {
+                confirmed_hash: "canonical",
+                finalized_hash: Some("canonical"),
+            })
+            .mount(&remote_server)
+            .await;
+
+        for (rpc_cross_check, expected_hash) in [(false, "forked"), (true, "canonical")] {
+            let mut state = State::new(
+                Some(RpcClient::new(local_server.uri())),
+                vec![RpcClient::new(remote_server.uri())],
+                None,
+                "test_cursor_file".to_string(),
+                BlockPrinter::new(None, None, None, PrinterOptions::default()),
+                StateOptions {
+                    rpc_cross_check,
+                    ..StateOptions::default()
+                },
+            );
+            assert!(state.cache_block_from_rpc(10).is_ok());
+            assert_eq!(state.block_infos[&10].block_hash, expected_hash);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_verify_blockhash_on_lib() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "jsonrpc": "2.0",
+                "result": {
+                    "blockhash": "finalized",
+                    "previousBlockhash": "parent",
+                    "parentSlot": 9,
+                    "blockTime": 1234,
+                    "blockHeight": 10,
+                },
+                "id": 1
+            })))
+            .mount(&server)
+            .await;
+        let mut state = State::new(
+            Some(RpcClient::new(server.uri())),
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions {
+                verify_blockhash_on_lib: true,
+                ..StateOptions::default()
+            },
+        );
+        state.lib = Some(1);
+        let sent_block_hashes = state.sent_block_hashes.as_mut().unwrap();
+        sent_block_hashes.insert(10, "finalized".to_string());
+        sent_block_hashes.insert(11, "forked".to_string());
+        sent_block_hashes.insert(12, "next".to_string());
+
+        state.set_lib(10);
+        assert_eq!(state.blockhash_mismatches(), 0);
+        state.set_lib(11);
+        assert_eq!(state.blockhash_mismatches(), 1);
+        // a rooted slot that was not sent is not verified
+        state.set_lib(11);
+        assert_eq!(state.blockhash_mismatches(), 1);
+        assert_eq!(
+            state
+                .sent_block_hashes
+                .as_ref()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            vec![&12]
+        );
+    }
+
+    #[test]
+    fn test_replay() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let block_file = tempfile::NamedTempFile::new().unwrap();
+        let cursor_path = dir.path().join("cursor");
+        let mut state = State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![],
+            None,
+            cursor_path.to_str().unwrap().to_string(),
+            BlockPrinter::new(
+                Some(block_file.reopen().unwrap()),
+                None,
+                None,
+                PrinterOptions::default(),
+            ),
+            StateOptions {
+                replay_buffer_slots: 3,
+                ..StateOptions::default()
+            },
+        );
+        state.lib = Some(99);
+        state.first_received_blockmeta = Some(101);
+        state.first_block_to_process = Some(101);
+        state.last_sent_block = Some(100);
+        for slot in 101..=105 {
+            state
+                .block_infos
+                .insert(slot, test_block_info(slot, slot - 1));
+            state.confirmed_slots.insert(slot);
+        }
+        assert!(state.process_upto(105).is_ok());
+
+        let wait_for = |expected: &dyn Fn() -> bool| {
+            let start = std::time::Instant::now();
+            while !expected() {
+                assert!(start.elapsed() < std::time::Duration::from_secs(5));
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        };
+        wait_for(&|| std::fs::read_to_string(&cursor_path).unwrap_or_default() == "105:hash1");
+
+        // only 103 to 105 are kept
+        assert_eq!(state.replay(102, 104).unwrap(), 2);
+        assert_eq!(state.replay(90, 95).unwrap(), 0);
+        let sent_slots = || -> Vec<u64> {
+            std::fs::read_to_string(block_file.path())
+                .unwrap()
+                .lines()
+                .map(|line| line.split(' ').nth(2).unwrap().parse().unwrap())
+                .collect()
+        };
+        wait_for(&|| sent_slots().len() == 7);
+        let mut replayed = sent_slots()[5..].to_vec();
+        replayed.sort();
+        assert_eq!(replayed, vec![103, 104]);
+        // the cursor does not move back
+        assert_eq!(std::fs::read_to_string(&cursor_path).unwrap(), "105:hash1");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_without_block_rewards() {
+        for include_rewards in [true, false] {
+            let mock_server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(body_partial_json(serde_json::json!({"method": "getBlock"})))
+                .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "result": {
+                        "blockhash": "hash101",
+                        "previousBlockhash": "hash100",
+                        "parentSlot": 100,
+                        "blockTime": 1234,
+                        "blockHeight": 101,
+                        "rewards": [{
+                            "pubkey": Pubkey::new_unique().to_string(),
+                            "lamports": 5000,
+                            "postBalance": 10000,
+                            "rewardType": "Fee",
+                            "commission": null,
+                        }],
+                    },
+                    "id": 1
+                })))
+                .mount(&mock_server)
+                .await;
+
+            let dir = tempfile::TempDir::new().unwrap();
+            let mut state = State::new(
+                Some(RpcClient::new(mock_server.uri())),
+                vec![],
+                None,
+                dir.path().join("cursor").to_str().unwrap().to_string(),
+                BlockPrinter::new(None, None, None, PrinterOptions::default()),
+                StateOptions {
+                    replay_buffer_slots: 1,
+                    without_block_rewards: !include_rewards,
+                    ..StateOptions::default()
+                },
+            );
+            state.lib = Some(99);
+            state.first_received_blockmeta = Some(101);
+            state.first_block_to_process = Some(101);
+            state.last_sent_block = Some(100);
+            assert!(state.cache_block_from_rpc(101).is_ok());
+            state.confirmed_slots.insert(101);
+            assert!(state.process_upto(101).is_ok());
+
+            let requests = mock_server.received_requests().await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+            assert_eq!(body["params"][1]["rewards"], include_rewards);
+            let sent = &state.sent_slots.back().unwrap().block;
+            assert_eq!(sent.slot, 101);
+            assert_eq!(sent.rewards.len(), include_rewards as usize);
+        }
+    }
+
+    #[test]
+    fn test_emit_skipped_slots() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let block_file = tempfile::NamedTempFile::new().unwrap();
+        let cursor_path = dir.path().join("cursor");
+        let mut state = State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![],
+            None,
+            cursor_path.to_str().unwrap().to_string(),
+            BlockPrinter::new(
+                Some(block_file.reopen().unwrap()),
+                None,
+                None,
+                PrinterOptions::default(),
+            ),
+            StateOptions {
+                emit_skipped_slots: true,
+                ..StateOptions::default()
+            },
+        );
+        state.lib = Some(99);
+        state.first_received_blockmeta = Some(100);
+        state.first_block_to_process = Some(100);
+        state.last_sent_block = Some(100);
+        // 101 and 102 were skipped by the cluster
+        state.block_infos.insert(103, test_block_info(103, 100));
+        state.confirmed_slots.insert(103);
+
+        assert!(state.process_upto(103).is_ok());
+        assert_eq!(state.last_sent_block, Some(103));
+
+        let start = std::time::Instant::now();
+        while std::fs::read_to_string(&cursor_path).unwrap_or_default() != "103:hash1" {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5));
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let mut blocks: Vec<Block> = std::fs::read_to_string(block_file.path())
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let payload = BASE64.decode(line.split(' ').nth(8).unwrap()).unwrap();
+                Block::decode(payload.as_slice()).unwrap()
+            })
+            .collect();
+        blocks.sort_by_key(|block| block.slot);
+        let sent: Vec<(u64, bool, &str, u64)> = blocks
+            .iter()
+            .map(|block| {
+                (
+                    block.slot,
+                    block.skipped,
+                    block.blockhash.as_str(),
+                    block.parent_slot,
+                )
+            })
+            .collect();
+        assert_eq!(
+            sent,
+            vec![
+                (101, true, "skipped-101", 100),
+                (102, true, "skipped-102", 101),
+                (103, false, "hash1", 100),
+            ]
+        );
+        // each placeholder is chained to the block before it
+        let parents: Vec<&str> = blocks
+            .iter()
+            .map(|block| block.previous_blockhash.as_str())
+            .collect();
+        assert_eq!(parents, vec!["parent1", "skipped-101", "parent1"]);
+    }
+
+    #[test]
+    fn test_live_blocks() {
+        let sink = crate::sink::MemorySink::default();
+        let mut state = State::new(
+            None,
+            vec![],
+            Some(100),
+            "test_cursor_file".to_string(),
+            sink.clone(),
+            StateOptions::default(),
+        );
+        // restarted from cursor 100, geyser gave slot 103 first: 101 and 102 are caught up
+        state.lib = Some(99);
+        state.first_received_blockmeta = Some(103);
+        state.first_block_to_process = Some(101);
+        state.last_sent_block = Some(100);
+        for slot in 101..=104 {
+            state
+                .block_infos
+                .insert(slot, test_block_info(slot, slot - 1));
+            state.confirmed_slots.insert(slot);
+        }
+        assert!(state.process_upto(104).is_ok());
+
+        let live: Vec<(u64, bool)> = sink
+            .slots()
+            .iter()
+            .map(|sent| (sent.block.slot, sent.block.live))
+            .collect();
+        assert_eq!(
+            live,
+            vec![(101, false), (102, false), (103, true), (104, true)]
+        );
+    }
+
+    #[test]
+    fn test_account_data_diff() {
+        let sink = crate::sink::MemorySink::default();
+        let mut state = State::new(
+            None,
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            sink.clone(),
+            StateOptions {
+                disable_account_dedup: true,
+                replay_buffer_slots: 10,
+                ..StateOptions::default()
+            },
+        )
+        .with_account_data_diff(AccountDataCache::new(10, 1 << 20));
+        state.lib = Some(99);
+        state.first_received_blockmeta = Some(101);
+        state.first_block_to_process = Some(101);
+        state.last_sent_block = Some(100);
+
+        let data = vec![7; 1_000];
+        let mut changed = data.clone();
+        changed[500] = 8;
+        for (slot, data) in [(101, &data), (102, &changed)] {
+            set_test_account(&mut state, slot, 1, data);
+            state
+                .block_infos
+                .insert(slot, test_block_info(slot, slot - 1));
+            state.confirmed_slots.insert(slot);
+        }
+        assert!(state.process_upto(102).is_ok());
+
+        let accounts: Vec<Account> = sink
+            .slots()
+            .into_iter()
+            .map(|sent| sent.account_block.accounts[0].clone())
+            .collect();
+        assert_eq!(accounts[0].data, data);
+        assert_eq!(accounts[0].data_diff, None);
+        assert!(accounts[1].data.is_empty());
+        assert_eq!(
+            crate::diff::apply_account_diff(&data, accounts[1].data_diff.as_ref().unwrap()),
+            Some(changed.clone())
+        );
+
+        // a replay sends the full data, which the readers then hold: the next slot is not diffed against later data
+        assert_eq!(state.replay(101, 101).unwrap(), 1);
+        let mut changed_again = changed.clone();
+        changed_again[0] = 9;
+        set_test_account(&mut state, 103, 1, &changed_again);
+        set_test_account(&mut state, 104, 1, &changed);
+        set_test_account(&mut state, 105, 1, &changed_again);
+        for slot in 103..=105 {
+            state
+                .block_infos
+                .insert(slot, test_block_info(slot, slot - 1));
+            state.confirmed_slots.insert(slot);
+            assert!(state.process_upto(slot).is_ok());
+            // a reader that lost the data last sent gets the full data too
+            if slot == 103 {
+                sink.lose_diff_base();
+            }
+        }
+
+        let accounts: Vec<(u64, bool, Account)> = sink
+            .slots()
+            .into_iter()
+            .skip(2)
+            .map(|sent| {
+                (
+                    sent.block.slot,
+                    sent.replayed,
+                    sent.account_block.accounts[0].clone(),
+                )
+            })
+            .collect();
+        assert_eq!(accounts[0].0, 101);
+        assert!(accounts[0].1);
+        assert_eq!(accounts[0].2.data, data);
+        assert_eq!(accounts[1].0, 103);
+        assert_eq!(accounts[1].2.data, changed_again);
+        assert_eq!(accounts[1].2.data_diff, None);
+        assert_eq!(accounts[2].0, 104);
+        assert_eq!(accounts[2].2.data, changed);
+        assert_eq!(accounts[2].2.data_diff, None);
+        // back to diffs against what the reader got
+        assert_eq!(accounts[3].0, 105);
+        assert!(accounts[3].2.data.is_empty());
+        assert!(accounts[3].2.data_diff.is_some());
+    }
+
+    #[test]
+    fn test_stop_at_slot() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let block_file = tempfile::NamedTempFile::new().unwrap();
+        let mut state = State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![],
+            None,
+            dir.path().join("cursor").to_str().unwrap().to_string(),
+            BlockPrinter::new(
+                Some(block_file.reopen().unwrap()),
+                None,
+                None,
+                PrinterOptions::default(),
+            ),
+            StateOptions {
+                stop_at_slot: Some(102),
+                ..StateOptions::default()
+            },
+        );
+        state.lib = Some(99);
+        state.first_received_blockmeta = Some(100);
+        state.first_block_to_process = Some(100);
+        for slot in 100..=105 {
+            state
+                .block_infos
+                .insert(slot, test_block_info(slot, slot - 1));
+            state.confirmed_slots.insert(slot);
+        }
+
+        assert!(state.process_upto(105).is_ok());
+        assert_eq!(state.last_sent_block, Some(102));
+        // later slots are neither sent nor buffered
+        assert!(state.process_upto(105).is_ok());
+        assert_eq!(state.last_sent_block, Some(102));
+        assert!(state.should_skip_slot(106));
+
+        let start = std::time::Instant::now();
+        while std::fs::read_to_string(block_file.path())
+            .unwrap()
+            .lines()
+            .count()
+            < 3
+        {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5));
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        // each block is written by its own thread, they can land in any order
+        let mut slots: Vec<String> = std::fs::read_to_string(block_file.path())
+            .unwrap()
+            .lines()
+            .map(|line| line.split(' ').nth(2).unwrap().to_string())
+            .collect();
+        slots.sort();
+        assert_eq!(slots, vec!["100", "101", "102"]);
+    }
+
+    #[test]
+    fn test_is_ready_without_transactions() {
+        for without_transactions in [false, true] {
+            let mut state = State::new(
+                Some(RpcClient::new("http://test.local")),
+                vec![],
+                None,
+                "test_cursor_file".to_string(),
+                BlockPrinter::new(None, None, None, PrinterOptions::default()),
+                StateOptions {
+                    without_transactions,
+                    ..StateOptions::default()
+                },
+            );
+            state.confirmed_slots.insert(3);
+            assert!(!state.is_ready(3));
+            state.block_infos.insert(
+                3,
+                BlockInfo {
+                    transaction_count: 2,
+                    ..test_block_info(3, 2)
+                },
+            );
+            assert_eq!(state.is_ready(3), without_transactions);
+        }
+    }
+
+    #[test]
+    fn test_process_error_display() {
+        let err = ProcessError::PrinterFailed {
+            slot: 10,
+            source: std::io::Error::new(std::io::ErrorKind::BrokenPipe, "broken pipe"),
+        };
+        assert_eq!(err.to_string(), "failed to print block 10: broken pipe");
+        assert!(std::error::Error::source(&err).is_some());
+        assert_eq!(ProcessError::MutexPoisoned.to_string(), "poisoned mutex");
+        assert_eq!(
+            ProcessError::MissingBlockInfo(7).to_string(),
+            "no block info for slot 7"
+        );
+        assert_eq!(
+            ProcessError::RpcUnavailable {
+                slot: 7,
+                error: "timeout".to_string()
+            }
+            .to_string(),
+            "no rpc could give block 7: timeout"
+        );
+    }
+
+    // ready_state has slot 101 confirmed with its block info and an account, ready to be sent after 100
+    fn ready_state(sink: crate::sink::MemorySink, spill_dir: Option<&std::path::Path>) -> State {
+        let mut state = State::new(
+            None,
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            sink,
+            StateOptions::default(),
+        );
+        if let Some(dir) = spill_dir {
+            state = state.with_spill_files(SpillFiles::new(dir).unwrap());
+        }
+        state.lib = Some(99);
+        state.first_received_blockmeta = Some(101);
+        state.first_block_to_process = Some(101);
+        state.last_sent_block = Some(100);
+        set_test_account(&mut state, 101, 1, &[1; 10]);
+        state.block_infos.insert(101, test_block_info(101, 100));
+        state.confirmed_slots.insert(101);
+        state
+    }
+
+    #[test]
+    fn test_process_upto_printer_failed() {
+        let sink = crate::sink::MemorySink::default();
+        sink.fail_prints();
+        let mut state = ready_state(sink.clone(), None);
+        match state.process_upto(101) {
+            Err(ProcessError::PrinterFailed { slot, .. }) => assert_eq!(slot, 101),
+            other => panic!("expected PrinterFailed, got {:?}", other),
+        }
+        assert!(sink.slots().is_empty());
+    }
+
+    #[test]
+    fn test_process_upto_spill_failed() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let sink = crate::sink::MemorySink::default();
+        let mut state = ready_state(sink.clone(), Some(dir.path()));
+        // the spilled data of the slot cannot be read back anymore
+        state.spill_files.as_mut().unwrap().remove(101);
+        match state.process_upto(101) {
+            Err(ProcessError::SpillFailed { slot, .. }) => assert_eq!(slot, 101),
+            other => panic!("expected SpillFailed, got {:?}", other),
+        }
+        assert!(sink.slots().is_empty());
+    }
+
+    #[test]
+    fn test_process_upto_without_rpc_client() {
+        // without a rpc client, the block info cannot be fetched
+        let mut state = ready_state(crate::sink::MemorySink::default(), None);
+        state.block_infos.remove(&101);
+        match state.process_upto(101) {
+            Err(ProcessError::MissingBlockInfo(slot)) => assert_eq!(slot, 101),
+            other => panic!("expected MissingBlockInfo, got {:?}", other),
+        }
+    }
+
+    fn test_transaction(index: usize, signature: u8) -> ConfirmTransactionWithIndex {
+        ConfirmTransactionWithIndex {
+            index,
+            transaction: ConfirmedTransaction {
+                transaction: Some(Transaction {
+                    signatures: vec![vec![signature; 64]],
+                    message: None,
+                }),
+                meta: None,
+            },
+        }
+    }
+
+    fn order_of(transactions: &[ConfirmTransactionWithIndex]) -> Vec<(usize, u8)> {
+        transactions
+            .iter()
+            .map(|ti| (ti.index, first_signature(ti)[0]))
+            .collect()
+    }
+
+    #[test]
+    fn test_sort_transactions_duplicate_indices() {
+        let transactions = vec![
+            test_transaction(1, 9),
+            test_transaction(0, 5),
+            test_transaction(1, 3),
+            test_transaction(2, 1),
+        ];
+
+        let mut by_index = transactions.clone();
+        sort_transactions(&mut by_index, TransactionOrder::GeyserIndex);
+        assert_eq!(order_of(&by_index), vec![(0, 5), (1, 3), (1, 9), (2, 1)]);
+
+        // same result whatever order they were received in
+        let mut reversed: Vec<_> = transactions.iter().rev().cloned().collect();
+        sort_transactions(&mut reversed, TransactionOrder::GeyserIndex);
+        assert_eq!(order_of(&reversed), order_of(&by_index));
+
+        let mut by_signature = transactions;
+        sort_transactions(&mut by_signature, TransactionOrder::Signature);
+        assert_eq!(
+            order_of(&by_signature),
+            vec![(2, 1), (1, 3), (0, 5), (1, 9)]
+        );
+    }
+
+    #[test]
+    fn test_expected_transaction_count() {
+        let block_info = BlockInfo {
+            transaction_count: 3,
+            ..test_block_info(10, 9)
+        };
+        // a block sent without all of its transactions, like one filled from the rpc
+        let block = compose_and_purge_block(
+            10,
+            &block_info,
+            vec![test_transaction(0, 1), test_transaction(1, 2)],
+            true,
+        );
+        assert_eq!(block.expected_transaction_count, 3);
+        assert_eq!(block.transactions.len(), 2);
+
+        let block = compose_and_purge_block(10, &test_block_info(10, 9), vec![], true);
+        assert_eq!(block.expected_transaction_count, 0);
+        assert!(block.transactions.is_empty());
+    }
+
+    #[test]
+    fn test_index_gaps() {
+        let contiguous = vec![
+            test_transaction(2, 1),
+            test_transaction(0, 2),
+            test_transaction(1, 3),
+        ];
+        assert_eq!(index_gaps(&contiguous), (vec![], vec![]));
+        assert_eq!(index_gaps(&[]), (vec![], vec![]));
+
+        let gapped = vec![
+            test_transaction(0, 1),
+            test_transaction(1, 2),
+            test_transaction(1, 3),
+            test_transaction(4, 4),
+        ];
+        assert_eq!(index_gaps(&gapped), (vec![2, 3], vec![1]));
+    }
+
+    #[test]
+    fn test_check_first_sent_slot() {
+        assert!(check_first_sent_slot(
+            Some(100),
+            Some(90),
+            &test_block_info(101, 100)
+        ));
+        // slot 101 was skipped by the cluster, 102 still follows the cursor
+        assert!(check_first_sent_slot(
+            Some(100),
+            Some(90),
+            &test_block_info(102, 100)
+        ));
+        assert!(!check_first_sent_slot(
+            Some(100),
+            Some(90),
+            &test_block_info(102, 101)
+        ));
+        // the cursor was behind the LIB and dropped
+        assert!(check_first_sent_slot(
+            None,
+            Some(110),
+            &test_block_info(111, 110)
+        ));
+    }
+
+    #[test]
+    fn test_cursor_self_test_passed() {
+        let sink = crate::sink::MemorySink::default();
+        let mut state = State::new(
+            None,
+            vec![],
+            Some(100),
+            "test_cursor_file".to_string(),
+            sink.clone(),
+            StateOptions {
+                cursor_self_test: true,
+                ..StateOptions::default()
+            },
+        );
+        state.lib = Some(99);
+        state.first_received_blockmeta = Some(102);
+        state.first_block_to_process = Some(102);
+        for slot in [102, 103] {
+            state
+                .block_infos
+                .insert(slot, test_block_info(slot, slot - 1));
+            state.confirmed_slots.insert(slot);
+        }
+        assert_eq!(state.cursor_self_test_passed(), None);
+        assert!(state.process_upto(103).is_ok());
+        // 102 has parent 101, slot 101 after the cursor was never sent
+        assert_eq!(state.cursor_self_test_passed(), Some(false));
+        assert_eq!(sink.slots().len(), 2);
+    }
+
+    #[test]
+    fn test_buffered_stats() {
+        let mut state = State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![RpcClient::new("http://test.remote")],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
+        );
+        assert_eq!(state.buffered_stats(), BufferedStats::default());
+
+        state.block_infos.insert(2, test_block_info(2, 1));
+        state.block_infos.insert(3, test_block_info(3, 2));
+        state.confirmed_slots.insert(2);
+        state.set_transaction(2, test_transaction(0, 1));
+        state.set_transaction(2, test_transaction(1, 2));
+        state.set_transaction(3, test_transaction(0, 3));
+        state
+            .block_account_changes
+            .insert(3, AccountChanges::default());
+
+        assert_eq!(
+            state.buffered_stats(),
+            BufferedStats {
+                block_account_changes: 1,
+                block_infos: 2,
+                transactions: 2,
+                confirmed_slots: 1,
+                account_data_bytes: 0,
+                evicted_slots: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_max_buffered_slots() {
+        let mut state = State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions {
+                max_buffered_slots: Some(10),
+                ..StateOptions::default()
+            },
+        );
+        state.first_block_to_process = Some(100);
+        state.last_sent_block = Some(99);
+        // 100 and 102 are confirmed but not sent, 101 is in between: none of them can be evicted
+        state.confirmed_slots.insert(100);
+        state.confirmed_slots.insert(102);
+
+        for slot in 100..300 {
+            set_test_account(&mut state, slot, 1, &[slot as u8]);
+            state.set_transaction(slot, test_transaction(0, slot as u8));
+            let stats = state.buffered_stats();
+            assert!(stats.block_account_changes <= 10, "at slot {}", slot);
+            assert!(stats.transactions <= 10, "at slot {}", slot);
+        }
+
+        assert_eq!(state.buffered_stats().evicted_slots, 190);
+        for slot in [100, 101, 102, 299] {
+            assert!(state.get_account_changes(slot).is_some(), "slot {}", slot);
+            assert!(state.transactions.contains_key(&slot), "slot {}", slot);
+        }
+        assert!(state.get_account_changes(103).is_none());
+        assert!(!state.transactions.contains_key(&103));
+    }
+
+    fn state_with_max_account_data(max: usize, action: OversizedAccountAction) -> State {
+        State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![RpcClient::new("http://test.remote")],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions {
+                max_account_data_bytes: Some(max),
+                oversized_accounts: action,
+                ..StateOptions::default()
+            },
+        )
+    }
+
+    fn set_test_account_version(state: &mut State, slot: u64, data: &[u8], write_version: u64) {
+        state.set_account(
+            slot,
+            &[1; 32],
+            data,
+            &[1; 32],
+            write_version,
+            1,
+            0,
+            None,
+            false,
+            data.len() as u64,
+            false,
+        );
+    }
+
+    #[test]
+    fn test_account_spill() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut state = State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
+        )
+        .with_spill_files(SpillFiles::new(dir.path()).unwrap());
+        set_test_account(&mut state, 100, 3, &[3; 10]);
+        set_test_account(&mut state, 100, 1, &[1; 5]);
+        // replaces the write of account 1
+        set_test_account_version(&mut state, 100, &[9; 7], 2);
+        set_test_account(&mut state, 101, 2, &[2; 4]);
+
+        // only the metadata stays in memory
+        let changes = state.get_account_changes(100).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert!(changes
+            .values()
+            .all(|change| change.account.data.is_empty()));
+        assert!(dir.path().join("100.spill").exists());
+
+        unspill_account_changes(
+            &mut state.spill_files,
+            &mut state.block_account_changes,
+            100,
+        )
+        .unwrap();
+        assert!(!dir.path().join("100.spill").exists());
+        let account_block = create_account_block(
+            state.get_account_changes(100).unwrap(),
+            &test_block_info(100, 99),
+            &HashSet::new(),
+            AddressEncoding::default(),
+            AccountSort::default(),
+            AccountBlockMode::All,
+        );
+        let accounts: Vec<(u8, Vec<u8>)> = account_block
+            .accounts
+            .iter()
+            .map(|account| (account.address[0], account.data.clone()))
+            .collect();
+        assert_eq!(accounts, vec![(1, vec![9; 7]), (3, vec![3; 10])]);
+
+        // the file of a purged slot goes with it
+        state.purge_blocks_up_to(101);
+        assert!(!dir.path().join("101.spill").exists());
+    }
+
+    #[test]
+    fn test_deletion_detection() {
+        let other_owner = [5; 32];
+        let system_owner = system_program::ID.to_bytes();
+        // (lamports, data, owner) -> deleted with LamportsOnly, with Strict
+        let cases = [
+            (1, vec![1], other_owner, false, false),
+            (0, vec![1], other_owner, true, false),
+            (0, vec![], other_owner, true, false),
+            (0, vec![], system_owner, true, true),
+        ];
+        for detection in [DeletionDetection::LamportsOnly, DeletionDetection::Strict] {
+            let mut state = State::new(
+                Some(RpcClient::new("http://test.local")),
+                vec![],
+                None,
+                "test_cursor_file".to_string(),
+                BlockPrinter::new(None, None, None, PrinterOptions::default()),
+                StateOptions {
+                    deletion_detection: detection,
+                    ..StateOptions::default()
+                },
+            );
+            for (index, (lamports, data, owner, lamports_only, strict)) in cases.iter().enumerate()
+            {
+                let address = [index as u8; 32];
+                state.set_account(
+                    100,
+                    &address,
+                    data,
+                    owner,
+                    1,
+                    *lamports,
+                    0,
+                    None,
+                    false,
+                    data.len() as u64,
+                    false,
+                );
+                let account = &state.get_account_changes(100).unwrap()[&address.to_vec()].account;
+                let expected = match detection {
+                    DeletionDetection::LamportsOnly => *lamports_only,
+                    DeletionDetection::Strict => *strict,
+                };
+                assert_eq!(account.deleted, expected, "{:?} case {}", detection, index);
+                // the data of an account left without lamports is kept
+                assert_eq!(&account.data, data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_account_write_version() {
+        let mut state = state_with_dedup(true);
+        set_test_account_version(&mut state, 100, &[1], 7);
+        set_test_account_version(&mut state, 100, &[2, 2], 8);
+        set_test_account_version(&mut state, 101, &[3, 3, 3], 12);
+
+        for (slot, write_version) in [(100, 8), (101, 12)] {
+            let account_block = create_account_block(
+                state.get_account_changes(slot).unwrap(),
+                &test_block_info(slot, slot - 1),
+                &HashSet::new(),
+                AddressEncoding::default(),
+                AccountSort::default(),
+                AccountBlockMode::All,
+            );
+            assert_eq!(account_block.accounts.len(), 1);
+            assert_eq!(account_block.accounts[0].write_version, write_version);
+        }
+    }
+
+    #[test]
+    fn test_equal_write_version() {
+        for (prefer_later, expected) in [(false, vec![1]), (true, vec![2, 2])] {
+            // the second write has other data, so dedup does not skip it
+            let mut state = State::new(
+                Some(RpcClient::new("http://test.local")),
+                vec![],
+                None,
+                "test_cursor_file".to_string(),
+                BlockPrinter::new(None, None, None, PrinterOptions::default()),
+                StateOptions {
+                    prefer_later_equal_write_version: prefer_later,
+                    ..StateOptions::default()
+                },
+            );
+            set_test_account_version(&mut state, 100, &[1], 7);
+            set_test_account_version(&mut state, 100, &[2, 2], 7);
+
+            let changes = state.get_account_changes(100).unwrap();
+            assert_eq!(changes.len(), 1);
+            assert_eq!(changes[&vec![1; 32]].account.data, expected);
+        }
+    }
+
+    fn state_with_dedup(dedup_accounts: bool) -> State {
+        let mut state = State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions {
+                disable_account_dedup: !dedup_accounts,
+                ..StateOptions::default()
+            },
+        );
+        state.first_block_to_process = Some(100);
+        state
+    }
+
+    #[test]
+    fn test_dedup_stats() {
+        let mut state = state_with_dedup(true);
+        set_test_account_version(&mut state, 100, &[1, 2], 1);
+        set_test_account_version(&mut state, 100, &[3, 4], 2);
+        assert_eq!(
+            state.dedup_stats(),
+            DedupStats {
+                account_updates: 2,
+                dedup_hits: 1,
+                older_write_version: 0,
+                emitted: 1,
+            }
+        );
+        assert_eq!(state.dedup_stats().hit_ratio(), 0.5);
+        // the first write is the one kept
+        let changes = state.get_account_changes(100).unwrap();
+        assert_eq!(changes[&vec![1; 32]].write_version, 1);
+
+        // a write older than the kept one is skipped before its data is compared
+        set_test_account_version(&mut state, 100, &[5, 6, 7], 0);
+        assert_eq!(state.dedup_stats().older_write_version, 1);
+        assert_eq!(state.dedup_stats().dedup_hits, 1);
+        assert_eq!(state.dedup_stats().emitted, 1);
+    }
+
+    #[test]
+    fn test_dedup_disabled() {
+        let mut state = state_with_dedup(false);
+        set_test_account_version(&mut state, 100, &[1, 2], 1);
+        set_test_account_version(&mut state, 100, &[3, 4], 2);
+        assert_eq!(
+            state.dedup_stats(),
+            DedupStats {
+                account_updates: 2,
+                dedup_hits: 0,
+                older_write_version: 0,
+                emitted: 2,
+            }
+        );
+        // the second write went through
+        let changes = state.get_account_changes(100).unwrap();
+        assert_eq!(changes[&vec![1; 32]].write_version, 2);
+        assert_eq!(changes[&vec![1; 32]].account.data, vec![3, 4]);
+    }
+
+    fn hot_account_data_bytes(compact_account_changes: bool) -> (usize, State) {
+        hot_account_data_bytes_with(compact_account_changes, |_| 7)
+    }
+
+    // the hot account is written in 20 pending slots with 10_000 bytes of `fill(slot)`
+    fn hot_account_data_bytes_with(
+        compact_account_changes: bool,
+        fill: impl Fn(u64) -> u8,
+    ) -> (usize, State) {
+        let mut state = State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions {
+                compact_account_changes,
+                ..StateOptions::default()
+            },
+        );
+        state.first_block_to_process = Some(100);
+        for slot in 100..120 {
+            set_test_account(&mut state, slot, 1, &[fill(slot); 10_000]);
+            set_test_account(&mut state, slot, slot as u8, &[1]);
+        }
+        (state.buffered_stats().account_data_bytes, state)
+    }
+
+    #[test]
+    fn test_compact_account_changes_memory() {
+        let (full, _) = hot_account_data_bytes(false);
+        let (compacted, state) = hot_account_data_bytes(true);
+        assert_eq!(full, 20 * 10_000 + 20);
+        assert_eq!(compacted, 10_000 + 20);
+
+        // every slot still sends its own write of the hot account, with the data
+        for slot in 100..120 {
+            let mut accounts = test_account_block(&state, slot).accounts.into_iter();
+            let hot = accounts.next().unwrap();
+            assert_eq!(hot.address, vec![1; 32]);
+            assert_eq!(hot.data, vec![7; 10_000]);
+            assert_eq!(accounts.next().unwrap().address, vec![slot as u8; 32]);
+        }
+    }
+
+    #[test]
+    fn test_compact_account_changes_changing_data() {
+        // only identical data is shared: data changing in every slot is kept once per slot
+        let changing = |slot: u64| slot as u8;
+        let (full, _) = hot_account_data_bytes_with(false, changing);
+        let (compacted, state) = hot_account_data_bytes_with(true, changing);
+        assert_eq!(full, 20 * 10_000 + 20);
+        assert_eq!(compacted, full);
+        for slot in 100..120 {
+            let hot = &test_account_block(&state, slot).accounts[0];
+            assert_eq!(hot.data, vec![slot as u8; 10_000]);
+        }
+
+        // a slot writing the data back to an older value does not share it, only the latest write is compared
+        let alternating = |slot: u64| (slot % 2) as u8;
+        let (compacted, _) = hot_account_data_bytes_with(true, alternating);
+        assert_eq!(compacted, full);
+    }
+
+    fn test_account_block(state: &State, slot: u64) -> AccountBlock {
+        create_account_block(
+            state.get_account_changes(slot).unwrap(),
+            &test_block_info(slot, slot - 1),
+            &HashSet::new(),
+            AddressEncoding::default(),
+            AccountSort::default(),
+            AccountBlockMode::All,
+        )
+    }
+
+    #[test]
+    fn test_compact_account_changes_sibling_fork() {
+        // slots 100 and 101 both have parent 99, 101 is on a fork that dies
+        for (first, second) in [(100, 101), (101, 100)] {
+            let (_, mut state) = hot_account_data_bytes(true);
+            state.purge_blocks_up_to(119);
+            set_test_account(&mut state, first, 1, &[5; 100]);
+            set_test_account(&mut state, second, 1, &[5; 100]);
+            assert_eq!(state.buffered_stats().account_data_bytes, 100);
+
+            state.evict_slot(101);
+            let accounts = test_account_block(&state, 100).accounts;
+            assert_eq!(accounts.len(), 1);
+            assert_eq!(accounts[0].data, vec![5; 100]);
+            assert_eq!(state.buffered_stats().account_data_bytes, 100);
+        }
+    }
+
+    #[test]
+    fn test_compact_account_changes_purge() {
+        let (_, mut state) = hot_account_data_bytes(true);
+        state.purge_blocks_up_to(119);
+        assert!(state.account_latest_slot.is_empty());
+
+        // an older slot of another fork does not remove the newer write
+        set_test_account(&mut state, 130, 1, &[1, 2]);
+        set_test_account(&mut state, 125, 1, &[1, 2, 3]);
+        assert!(state
+            .get_account_changes(130)
+            .unwrap()
+            .contains_key(&vec![1; 32]));
+        assert!(state
+            .get_account_changes(125)
+            .unwrap()
+            .contains_key(&vec![1; 32]));
+    }
+
+    #[test]
+    fn test_detect_owner_changes() {
+        let mut state = State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions {
+                detect_owner_changes: true,
+                ..StateOptions::default()
+            },
+        );
+        state.first_block_to_process = Some(100);
+        let write = |state: &mut State, slot: u64, owner: u8, write_version: u64| {
+            state.set_account(
+                slot,
+                &[1; 32],
+                &[1, 2],
+                &[owner; 32],
+                write_version,
+                1,
+                0,
+                None,
+                false,
+                2,
+                false,
+            );
+            state.get_account_changes(slot).unwrap()[&vec![1; 32]]
+                .account
+                .clone()
+        };
+
+        assert!(!write(&mut state, 100, 7, 1).owner_changed);
+        assert!(!write(&mut state, 101, 7, 2).owner_changed);
+        // same data, but the account was reassigned: not skipped by the dedup
+        let account = write(&mut state, 101, 8, 3);
+        assert!(account.owner_changed);
+        assert_eq!(account.owner, vec![8; 32]);
+        assert_eq!(state.dedup_stats().dedup_hits, 0);
+        assert!(!write(&mut state, 102, 8, 4).owner_changed);
+        // the owners are only stored once
+        assert_eq!(state.owners.len(), 2);
+    }
+
+    fn set_test_account(state: &mut State, slot: u64, address: u8, data: &[u8]) {
+        set_test_account_deleted(state, slot, address, data, false);
+    }
+
+    fn set_test_account_deleted(
+        state: &mut State,
+        slot: u64,
+        address: u8,
+        data: &[u8],
+        deleted: bool,
+    ) {
+        let data_hash = if data.is_empty() {
+            0
+        } else {
+            data.len() as u64
+        };
+        state.set_account(
+            slot,
+            &[address; 32],
+            data,
+            &[1; 32],
+            1,
+            if deleted { 0 } else { 1 },
+            0,
+            None,
+            false,
+            data_hash,
+            false,
+        );
+    }
+
+    #[test]
+    fn test_max_account_data_below_limit() {
+        let mut state = state_with_max_account_data(16, OversizedAccountAction::Drop);
+        set_test_account(&mut state, 100, 1, &[7; 16]);
+
+        let changes = state.get_account_changes(100).unwrap();
+        let account = &changes.get(&vec![1; 32]).unwrap().account;
+        assert_eq!(account.data, vec![7; 16]);
+        assert!(!account.truncated);
+    }
+
+    #[test]
+    fn test_max_account_data_above_limit_drop() {
+        let mut state = state_with_max_account_data(16, OversizedAccountAction::Drop);
+        set_test_account(&mut state, 100, 1, &[7; 8]);
+        set_test_account_version(&mut state, 100, &[7; 17], 2);
+        set_test_account(&mut state, 100, 2, &[7; 17]);
+
+        // the smaller version received before is not kept either, nor is its hash: it was never sent
+        assert!(state.get_account_changes(100).unwrap().is_empty());
+        assert_eq!(state.account_data_hash.get(&vec![1; 32]), None);
+        assert_eq!(state.account_data_hash.get(&vec![2; 32]), None);
+
+        // an oversized write without an earlier version in the slot keeps the hash of the data sent before
+        set_test_account(&mut state, 101, 1, &[7; 8]);
+        set_test_account_version(&mut state, 102, &[7; 17], 3);
+        assert_eq!(state.account_data_hash.get(&vec![1; 32]), Some(&8));
+    }
+
+    #[test]
+    fn test_max_account_data_above_limit_truncate() {
+        let mut state = state_with_max_account_data(16, OversizedAccountAction::Truncate);
+        set_test_account(&mut state, 100, 1, &[7; 17]);
+
+        let changes = state.get_account_changes(100).unwrap();
+        let account = &changes.get(&vec![1; 32]).unwrap().account;
+        assert!(account.data.is_empty());
+        assert!(account.truncated);
+        assert_eq!(state.account_data_hash.get(&vec![1; 32]), Some(&17));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_block_time_from_rpc() {
+        let local_server = MockServer::start().await;
+        let remote_server = MockServer::start().await;
+
+        // the local node does not have the block time anymore, the remote one does
+        Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({"method": "getBlockTime"})))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "jsonrpc": "2.0",
+                "error": {"code": -32009, "message": "Slot 100 was skipped, or missing in long-term storage"},
+                "id": 1
+            })))
+            .expect(1)
+            .mount(&local_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(
+                serde_json::json!({"method": "getBlockTime"}),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "jsonrpc": "2.0",
+                "result": 1_700_000_000,
+                "id": 1
+            })))
+            .expect(1)
+            .mount(&remote_server)
+            .await;
+
+        let mut state = State::new(
+            Some(RpcClient::new(local_server.uri())),
+            vec![RpcClient::new(remote_server.uri())],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
+        );
+
+        assert_eq!(state.block_time_from_rpc(100), Some(1_700_000_000));
+        // cached: no other request is made (checked by the expectations when the servers drop)
+        assert_eq!(state.block_time_from_rpc(100), Some(1_700_000_000));
+
+        // a block time no client has is not asked again either
+        let failing_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(
+                serde_json::json!({"method": "getBlockTime"}),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "jsonrpc": "2.0",
+                "error": {"code": -32004, "message": "Block not available for slot 200"},
+                "id": 1
+            })))
+            .expect(1)
+            .mount(&failing_server)
+            .await;
+        let mut state = State::new(
+            Some(RpcClient::new(failing_server.uri())),
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
+        );
+        assert_eq!(state.block_time_from_rpc(200), None);
+        assert_eq!(state.block_time_from_rpc(200), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_missing_block_time_filled_when_sent() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(
+                serde_json::json!({"method": "getBlockTime", "params": [101]}),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "jsonrpc": "2.0",
+                "result": 1_700_000_000,
+                "id": 1
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let sink = crate::sink::MemorySink::default();
+        let mut state = State::new(
+            Some(RpcClient::new(server.uri())),
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            sink.clone(),
+            StateOptions::default(),
+        );
+        state.lib = Some(99);
+        state.first_received_blockmeta = Some(101);
+        state.first_block_to_process = Some(101);
+        state.last_sent_block = Some(100);
+        for slot in 101..=102 {
+            state
+                .block_infos
+                .insert(slot, test_block_info(slot, slot - 1));
+        }
+        // only recorded when the block metadata comes: the rpc is asked when the slot is sent
+        state.set_block_time_missing(101);
+        state.confirmed_slots.insert(101);
+        state.confirmed_slots.insert(102);
+        assert!(state.process_upto(102).is_ok());
+
+        let block_times: Vec<(u64, i64)> = sink
+            .slots()
+            .iter()
+            .map(|sent| (sent.block.slot, sent.block.block_time.unwrap().timestamp))
+            .collect();
+        let geyser_block_time = test_block_info(102, 101).timestamp.seconds;
+        assert_eq!(
+            block_times,
+            vec![(101, 1_700_000_000), (102, geyser_block_time)]
+        );
+    }
+
+    async fn rpc_with_lib(lib: u64) -> MockServer {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "jsonrpc": "2.0",
+                "result": lib,
+                "id": 1
+            })))
+            .mount(&mock_server)
+            .await;
+        mock_server
+    }
+
+    fn state_with_cursor(rpc: &MockServer, cursor: u64) -> State {
+        State::new(
+            Some(RpcClient::new(rpc.uri())),
+            vec![],
+            Some(cursor),
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rpc_lib_reasonable() {
+        let rpc = rpc_with_lib(9_980).await;
+        let mut state = state_with_cursor(&rpc, 9_950);
+        state.set_confirmed_slot(10_000);
+        state.set_block_info(test_block_info(10_000, 9_999));
+
+        // the cursor is behind the lib, it is dropped
+        assert_eq!(state.lib, Some(9_980));
+        assert_eq!(state.cursor, None);
+        assert_eq!(state.first_block_to_process, Some(10_000));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rpc_lib_stale_low() {
+        let rpc = rpc_with_lib(100).await;
+        let mut state = state_with_cursor(&rpc, 9_950);
+        state.set_block_info(test_block_info(10_000, 9_999));
+        assert_eq!(state.lib, None);
+        assert_eq!(state.cursor, Some(9_950));
+
+        // not asked again on the next block meta
+        drop(rpc);
+        state.set_block_info(test_block_info(10_001, 10_000));
+        assert_eq!(state.lib, None);
+
+        // the rooted slot decides: the cursor is still ahead of it and kept
+        state.set_lib(9_940);
+        assert_eq!(state.lib, Some(9_940));
+        assert_eq!(state.cursor, Some(9_950));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rpc_lib_stale_low_cursor_behind_rooted() {
+        let rpc = rpc_with_lib(100).await;
+        let mut state = state_with_cursor(&rpc, 9_950);
+        state.set_block_info(test_block_info(10_000, 9_999));
+        assert_eq!(state.lib, None);
+
+        state.set_lib(9_970);
+        assert_eq!(state.cursor, None);
+        assert_eq!(state.first_block_to_process, Some(10_000));
+    }
+
+    #[test]
+    fn test_lag() {
+        let mut state = State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
+        );
+        assert_eq!(state.lag(), None);
+        state.last_sent_block = Some(90);
+        assert_eq!(state.lag(), None);
+        state.last_sent_block = None;
+        state.lib = Some(100);
+        assert_eq!(state.lag(), None);
+
+        state.last_sent_block = Some(90);
+        assert_eq!(state.lag(), Some(10));
+        // blocks are sent before they are rooted
+        state.last_sent_block = Some(130);
+        assert_eq!(state.lag(), Some(0));
+    }
+
+    #[test]
+    fn test_set_lib_out_of_order() {
+        let mut state = State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
+        );
+        let mut highest = 0;
+        for rooted in [10, 12, 11, 15, 13, 14, 15, 16] {
+            state.set_lib(rooted);
+            highest = highest.max(rooted);
+            assert_eq!(state.lib, Some(highest));
+        }
+    }
+
+    #[test]
+    fn test_apply_refreshed_lib() {
+        let refreshed = Arc::new(AtomicU64::new(0));
+        let mut state = State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions {
+                refreshed_lib: Some(refreshed.clone()),
+                ..StateOptions::default()
+            },
+        );
+        state.apply_refreshed_lib();
+        assert_eq!(state.lib, None);
+
+        state.set_lib(100);
+        refreshed.store(150, Ordering::Relaxed);
+        state.apply_refreshed_lib();
+        assert_eq!(state.lib, Some(150));
+
+        // a rooted slot notified later still moves it, an older refreshed value does not
+        state.set_lib(160);
+        state.apply_refreshed_lib();
+        assert_eq!(state.lib, Some(160));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_set_last_finalized_block_from_rpc_retries() {
+        let mock_server = MockServer::start().await;
+
+        // the first two attempts fail, the third one gets the lib
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "jsonrpc": "2.0",
+                "result": 100,
+                "id": 1
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut state = State::new(
+            Some(RpcClient::new(mock_server.uri())),
+            vec![RpcClient::new(mock_server.uri())],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions {
+                rpc_retry: RetryPolicy {
+                    max_retries: 3,
+                    backoff: std::time::Duration::from_millis(1),
+                },
+                ..StateOptions::default()
+            },
+        );
+
+        state.set_last_finalized_block_from_rpc(100);
+        assert_eq!(state.lib, Some(100));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cache_block_from_rpc_remote_failover() {
+        let local_server = MockServer::start().await;
+        let dead_remote = MockServer::start().await;
+        let remote = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .respond_with(GetBlockResponder {
+                parents: HashMap::new(),
+            })
+            .mount(&local_server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&dead_remote)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(GetBlockResponder {
+                parents: HashMap::from([(10, 9), (11, 10)]),
+            })
+            .expect(2)
+            .mount(&remote)
+            .await;
+
+        let mut state = State::new(
+            Some(RpcClient::new(local_server.uri())),
+            vec![
+                RpcClient::new(dead_remote.uri()),
+                RpcClient::new(remote.uri()),
+            ],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
+        );
+        state.lib = Some(1);
+
+        assert!(state.cache_block_from_rpc(10).is_ok());
+        assert!(state.has_block_info(10));
+        assert_eq!(state.healthy_remote, 1);
+
+        // the dead remote is not tried first anymore (it only expects one request)
+        assert!(state.cache_block_from_rpc(11).is_ok());
+        assert!(state.has_block_info(11));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cache_block_from_rpc_all_failing() {
+        let local_server = MockServer::start().await;
+        let remote = MockServer::start().await;
+        for server in [&local_server, &remote] {
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(503))
+                .mount(server)
+                .await;
+        }
+        let dir = tempfile::TempDir::new().unwrap();
+        let mut state = State::new(
+            Some(RpcClient::new(local_server.uri())),
+            vec![RpcClient::new(remote.uri())],
+            None,
+            dir.path().join("cursor").to_str().unwrap().to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions::default(),
+        );
+        state.lib = Some(1);
+        state.first_received_blockmeta = Some(10);
+        state.first_block_to_process = Some(10);
+        state.last_sent_block = Some(9);
+        state.confirmed_slots.insert(10);
+
+        // both fail: counted, and the slot is kept for the next run instead of being dropped
+        match state.process_upto(10) {
+            Err(ProcessError::RpcUnavailable { slot, error }) => {
+                assert_eq!(slot, 10);
+                assert!(error.starts_with("local: "), "{}", error);
+            }
+            other => panic!("expected RpcUnavailable, got {:?}", other),
+        }
+        assert_eq!(state.rpc_block_failures(), 1);
+        assert!(!local_server.received_requests().await.unwrap().is_empty());
+        assert!(!remote.received_requests().await.unwrap().is_empty());
+
+        // the local rpc is back, the next run fetches the block and sends it
+        local_server.reset().await;
+        Mock::given(method("POST"))
+            .respond_with(GetBlockResponder {
+                parents: HashMap::from([(10, 9)]),
+            })
+            .mount(&local_server)
+            .await;
+        assert!(state.process_upto(10).is_ok());
+        assert_eq!(state.last_sent_block, Some(10));
+        assert_eq!(state.rpc_block_failures(), 1);
+    }
+
+    #[test]
+    fn test_emit_startup_snapshot() {
+        let account_file = tempfile::NamedTempFile::new().unwrap();
+        let mut state = State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![RpcClient::new("http://test.remote")],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(
+                None,
+                Some(account_file.reopen().unwrap()),
+                None,
+                PrinterOptions {
+                    output_format: crate::config::OutputFormat::LengthPrefixedProto,
+                    ..PrinterOptions::default()
+                },
+            ),
+            StateOptions {
+                emit_startup_snapshot: true,
+                startup_snapshot_chunk_accounts: Some(2),
+                ..StateOptions::default()
+            },
+        );
+
+        for address in 1..=3 {
+            state.set_account(
+                50,
+                &[address; 32],
+                &[address; 8],
+                &[9; 32],
+                address as u64,
+                1,
+                0,
+                None,
+                true,
+                address as u64,
+                false,
+            );
+        }
+        state.end_of_startup();
+        // accounts received after the startup are not part of the snapshot
+        state.set_account(
+            51, &[4; 32], &[4; 8], &[9; 32], 4, 1, 0, None, true, 4, false,
+        );
+
+        let content = std::fs::read(account_file.path()).unwrap();
+        let mut blocks = vec![];
+        let mut rest = content.as_slice();
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            blocks.push(AccountBlock::decode(&rest[4..4 + length]).unwrap());
+            rest = &rest[4 + length..];
+        }
+
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks
+            .iter()
+            .all(|block| block.snapshot && block.slot == 50));
+        let addresses: Vec<u8> = blocks
+            .iter()
+            .flat_map(|block| block.accounts.iter().map(|account| account.address[0]))
+            .collect();
+        assert_eq!(addresses, vec![1, 2, 3]);
+        assert_eq!(state.get_hash_count(), 4);
+    }
+
+    #[test]
+    fn test_account_data_size_bounds() {
+        let mut state = State::new(
+            Some(RpcClient::new("http://test.local")),
+            vec![RpcClient::new("http://test.remote")],
+            None,
+            "test_cursor_file".to_string(),
+            BlockPrinter::new(None, None, None, PrinterOptions::default()),
+            StateOptions {
+                min_account_data_bytes: Some(4),
+                max_account_data_bytes: Some(16),
+                ..StateOptions::default()
+            },
+        );
+
+        set_test_account(&mut state, 100, 1, &[7; 3]); // under
+        set_test_account(&mut state, 100, 2, &[7; 4]); // within
+        set_test_account(&mut state, 100, 3, &[7; 16]); // within
+        set_test_account(&mut state, 100, 4, &[7; 17]); // over
+        set_test_account_deleted(&mut state, 100, 5, &[], true); // deletions always pass
+
+        let changes = state.get_account_changes(100).unwrap();
+        let mut kept: Vec<u8> = changes.keys().map(|address| address[0]).collect();
+        kept.sort();
+        assert_eq!(kept, vec![2, 3, 5]);
 
-        assert!(state.confirmed_slots.get(&2).is_some());
-        assert!(state.confirmed_slots.get(&4).is_some());
-        assert!(state.confirmed_slots.get(&6).is_some());
+        // skipped accounts do not poison the hash cache
+        assert_eq!(state.account_data_hash.get(&vec![1; 32]), None);
+        assert_eq!(state.account_data_hash.get(&vec![4; 32]), None);
     }
 }
@@ -1,44 +1,299 @@
+use crate::config::{AccountBlockMode, AccountSort, AddressEncoding, HashAlgorithm};
 use crate::pb::sf::solana::r#type::v1::{Account, AccountBlock};
-use crate::state::{AccountChanges, BlockInfo};
+use crate::state::{AccountChanges, AccountWithWriteVersion, BlockInfo};
 use base58::ToBase58;
-use log::debug;
+use gxhash::gxhash64;
+use log::info;
 use prost_types::Timestamp as ProstTimestamp;
 use solana_program::clock::UnixTimestamp;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashSet;
 
-const DERIVED_ACCOUNT: &str = "9QiiQiqg2riRns9CAuVvgFsAQ1RM6CH38EFysZ6R8Nac";
+const SEED: i64 = 76;
+
+// hash_account_data is what the change detection compares, empty data always hashes to 0
+pub fn hash_account_data(algorithm: HashAlgorithm, data: &[u8]) -> u64 {
+    if data.is_empty() {
+        return 0;
+    }
+    match algorithm {
+        HashAlgorithm::Gxhash => gxhash64(data, SEED),
+        HashAlgorithm::Xxhash => xxhash_rust::xxh3::xxh3_64_with_seed(data, SEED as u64),
+        HashAlgorithm::Blake3 => {
+            let hash = blake3::hash(data);
+            u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+        }
+    }
+}
+
+// hash_account is the dedup key of an account write: the data hash with the owner and lamports folded in,
+// so a reassignment or a lamport transfer that leaves the data unchanged is not skipped
+pub fn hash_account(algorithm: HashAlgorithm, data: &[u8], owner: &[u8], lamports: u64) -> u64 {
+    let mut key = Vec::with_capacity(8 + owner.len() + 8);
+    key.extend_from_slice(&hash_account_data(algorithm, data).to_le_bytes());
+    key.extend_from_slice(owner);
+    key.extend_from_slice(&lamports.to_le_bytes());
+    xxhash_rust::xxh3::xxh3_64_with_seed(&key, SEED as u64)
+}
+
+// encode_address is how the account addresses are written to the logs
+pub fn encode_address(encoding: AddressEncoding, address: &[u8]) -> String {
+    match encoding {
+        AddressEncoding::Hex => hex::encode(address),
+        AddressEncoding::Base58 => address.to_base58(),
+    }
+}
 
 pub fn convert_sol_timestamp(sol_timestamp: UnixTimestamp) -> ProstTimestamp {
-    let seconds = sol_timestamp as i64;
+    let seconds = sol_timestamp;
     ProstTimestamp { seconds, nanos: 0 }
 }
 
 pub fn create_account_block(
     account_changes: &AccountChanges,
     block_info: &BlockInfo,
+    watch_accounts: &HashSet<Pubkey>,
+    address_encoding: AddressEncoding,
+    sort: AccountSort,
+    mode: AccountBlockMode,
 ) -> AccountBlock {
-    let mut accounts: Vec<Account> = account_changes
+    let mut changes: Vec<&AccountWithWriteVersion> = account_changes.values().collect();
+    // the address breaks the ties, so the order does not depend on the map
+    match sort {
+        AccountSort::Address => changes.sort_by(|a, b| a.account.address.cmp(&b.account.address)),
+        AccountSort::OwnerAddress => changes.sort_by(|a, b| {
+            (&a.account.owner, &a.account.address).cmp(&(&b.account.owner, &b.account.address))
+        }),
+        AccountSort::WriteVersion => changes.sort_by(|a, b| {
+            (a.write_version, &a.account.address).cmp(&(b.write_version, &b.account.address))
+        }),
+    }
+    let accounts: Vec<Account> = changes
         .into_iter()
-        .map(|(_account_key, account)| account.account.clone())
+        .map(|change| {
+            let mut account = change.account.clone();
+            if let Some(shared) = &change.shared {
+                account.data = shared.to_vec();
+            }
+            account
+        })
         .collect();
 
-    accounts.sort_by(|a, b| a.address.cmp(&b.address));
-    for account in accounts.iter() {
-        if account.address.to_base58() == DERIVED_ACCOUNT {
-            debug!(
-                "creating block data: received my account: {} (owner: {}) on slot {}",
-                account.owner.to_base58(),
-                account.address.to_base58(),
+    if !watch_accounts.is_empty() {
+        for account in watched_accounts(&accounts, watch_accounts) {
+            info!(
+                "watched account {} (owner: {}) changed on slot {}",
+                encode_address(address_encoding, &account.address),
+                encode_address(address_encoding, &account.owner),
                 block_info.slot
             );
         }
     }
 
+    let accounts = match mode {
+        AccountBlockMode::All => accounts,
+        AccountBlockMode::OwnerChangedOnly => accounts
+            .into_iter()
+            .filter(|account| account.owner_changed)
+            .collect(),
+    };
+
     AccountBlock {
         slot: block_info.slot,
         hash: block_info.block_hash.clone(),
         parent_hash: block_info.parent_hash.clone(),
         parent_slot: block_info.parent_slot,
-        accounts: accounts,
+        accounts,
         timestamp: Some(block_info.timestamp.clone()),
+        snapshot: false,
+    }
+}
+
+// watched_accounts returns the accounts listed in watch_accounts
+fn watched_accounts<'a>(
+    accounts: &'a [Account],
+    watch_accounts: &'a HashSet<Pubkey>,
+) -> impl Iterator<Item = &'a Account> {
+    accounts.iter().filter(|account| {
+        Pubkey::try_from(account.address.as_slice())
+            .is_ok_and(|address| watch_accounts.contains(&address))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn account(address: u8) -> Account {
+        Account {
+            address: vec![address; 32],
+            owner: vec![9; 32],
+            ..Account::default()
+        }
+    }
+
+    #[test]
+    fn test_create_account_block_sort() {
+        let mut changes = AccountChanges::default();
+        for (address, owner, write_version) in [(1, 9, 30), (2, 5, 10), (3, 5, 5)] {
+            changes.insert(
+                vec![address; 32],
+                AccountWithWriteVersion {
+                    account: Account {
+                        owner: vec![owner; 32],
+                        ..account(address)
+                    },
+                    write_version,
+                    spilled: None,
+                    shared: None,
+                },
+            );
+        }
+        for (sort, expected) in [
+            (AccountSort::Address, vec![1, 2, 3]),
+            (AccountSort::OwnerAddress, vec![2, 3, 1]),
+            (AccountSort::WriteVersion, vec![3, 2, 1]),
+        ] {
+            let block = create_account_block(
+                &changes,
+                &BlockInfo::default(),
+                &HashSet::new(),
+                AddressEncoding::default(),
+                sort,
+                AccountBlockMode::All,
+            );
+            let addresses: Vec<u8> = block
+                .accounts
+                .iter()
+                .map(|account| account.address[0])
+                .collect();
+            assert_eq!(addresses, expected, "{:?}", sort);
+        }
+    }
+
+    #[test]
+    fn test_create_account_block_owner_changed_only() {
+        let mut changes = AccountChanges::default();
+        for (address, owner_changed) in [(1, false), (2, true), (3, false), (4, true)] {
+            changes.insert(
+                vec![address; 32],
+                AccountWithWriteVersion {
+                    account: Account {
+                        owner_changed,
+                        ..account(address)
+                    },
+                    write_version: 1,
+                    spilled: None,
+                    shared: None,
+                },
+            );
+        }
+        for (mode, expected) in [
+            (AccountBlockMode::All, vec![1, 2, 3, 4]),
+            (AccountBlockMode::OwnerChangedOnly, vec![2, 4]),
+        ] {
+            let block = create_account_block(
+                &changes,
+                &BlockInfo::default(),
+                &HashSet::new(),
+                AddressEncoding::default(),
+                AccountSort::default(),
+                mode,
+            );
+            let addresses: Vec<u8> = block
+                .accounts
+                .iter()
+                .map(|account| account.address[0])
+                .collect();
+            assert_eq!(addresses, expected, "{:?}", mode);
+        }
+
+        // a slot without owner change still has its account block
+        changes.retain(|address, _| address[0] == 1);
+        let block = create_account_block(
+            &changes,
+            &BlockInfo {
+                slot: 5,
+                ..BlockInfo::default()
+            },
+            &HashSet::new(),
+            AddressEncoding::default(),
+            AccountSort::default(),
+            AccountBlockMode::OwnerChangedOnly,
+        );
+        assert_eq!(block.slot, 5);
+        assert!(block.accounts.is_empty());
+    }
+
+    #[test]
+    fn test_encode_address() {
+        let address = Pubkey::from_str("Vote111111111111111111111111111111111111111").unwrap();
+        assert_eq!(
+            encode_address(AddressEncoding::Base58, address.as_ref()),
+            "Vote111111111111111111111111111111111111111"
+        );
+        assert_eq!(
+            encode_address(AddressEncoding::Hex, address.as_ref()),
+            "0761481d357474bb7c4d7624ebd3bdb3d8355e73d11043fc0da3538000000000"
+        );
+        assert_eq!(encode_address(AddressEncoding::default(), &[]), "");
+    }
+
+    #[test]
+    fn test_hash_account() {
+        let data = vec![7u8; 300];
+        for algorithm in [
+            HashAlgorithm::Gxhash,
+            HashAlgorithm::Xxhash,
+            HashAlgorithm::Blake3,
+        ] {
+            let hash = hash_account(algorithm, &data, &[2; 32], 10);
+            assert_eq!(hash, hash_account(algorithm, &data.clone(), &[2; 32], 10));
+            assert_ne!(hash, hash_account(algorithm, &data[1..], &[2; 32], 10));
+            assert_ne!(hash, hash_account(algorithm, &data, &[3; 32], 10));
+            assert_ne!(hash, hash_account(algorithm, &data, &[2; 32], 11));
+        }
+    }
+
+    #[test]
+    fn test_hash_account_data_stable() {
+        let data = vec![7u8; 300];
+        for algorithm in [
+            HashAlgorithm::Gxhash,
+            HashAlgorithm::Xxhash,
+            HashAlgorithm::Blake3,
+        ] {
+            let hash = hash_account_data(algorithm, &data);
+            assert_ne!(hash, 0);
+            assert_eq!(hash, hash_account_data(algorithm, &data.clone()));
+            assert_ne!(hash, hash_account_data(algorithm, &data[1..]));
+            assert_eq!(hash_account_data(algorithm, &[]), 0);
+        }
+
+        // portable algorithms give the same value everywhere
+        assert_eq!(
+            hash_account_data(HashAlgorithm::Xxhash, b"account data"),
+            xxhash_rust::xxh3::xxh3_64_with_seed(b"account data", 76)
+        );
+        assert_eq!(
+            hash_account_data(HashAlgorithm::Blake3, b"account data"),
+            u64::from_le_bytes(
+                blake3::hash(b"account data").as_bytes()[..8]
+                    .try_into()
+                    .unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_watched_accounts() {
+        let accounts = vec![account(1), account(2), account(3)];
+
+        let watch = HashSet::from([Pubkey::new_from_array([2; 32])]);
+        let watched: Vec<_> = watched_accounts(&accounts, &watch).collect();
+        assert_eq!(watched, vec![&accounts[1]]);
+
+        assert_eq!(watched_accounts(&accounts, &HashSet::new()).count(), 0);
     }
 }